@@ -3,7 +3,10 @@ fn main() {
     let target_os = env::var("CARGO_CFG_TARGET_OS");
     match target_os.as_ref().map(|x| &**x) {
         Ok("macos") | Ok("linux") => {}
-        Ok("windows") => println!("cargo:rustc-cdylib-link-arg=/DEF:driver.def"),
+        Ok("windows") => match env::var("CARGO_FEATURE_V0500") {
+            Ok(_) => println!("cargo:rustc-cdylib-link-arg=/DEF:driver_v0500.def"),
+            Err(_) => println!("cargo:rustc-cdylib-link-arg=/DEF:driver.def"),
+        },
         tos => panic!("unknown target os {:?}!", tos),
     }
 }
@@ -0,0 +1,9 @@
+#![no_main]
+// Fuzzes decoding of the frames the M2 sends to the driver
+
+use libfuzzer_sys::fuzz_target;
+use m2_driver::fuzzing;
+
+fuzz_target!(|data: &[u8]| {
+    fuzzing::decode_frame(data);
+});
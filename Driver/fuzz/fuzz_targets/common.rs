@@ -0,0 +1,43 @@
+// Shared by the fuzz targets. Inputs are built so every pointer and count handed to the
+// driver is honest, anything else would be the fuzzer's memory errors, not the driver's
+
+use arbitrary::Arbitrary;
+use J2534Common::*;
+
+/// PASSTHRU_MSG with fuzzed sizes. data_size and extra_data_size are not tied to the data
+#[derive(Arbitrary, Debug)]
+pub struct FuzzMsg {
+    pub protocol_id: u32,
+    pub rx_status: u32,
+    pub tx_flags: u32,
+    pub data_size: u32,
+    pub extra_data_size: u32,
+    pub data: Vec<u8>,
+}
+
+impl FuzzMsg {
+    pub fn to_msg(&self) -> PASSTHRU_MSG {
+        let mut msg = PASSTHRU_MSG {
+            protocol_id: self.protocol_id,
+            rx_status: self.rx_status,
+            tx_flags: self.tx_flags,
+            data_size: self.data_size,
+            extra_data_size: self.extra_data_size,
+            ..Default::default()
+        };
+        let len = self.data.len().min(msg.data.len());
+        msg.data[..len].copy_from_slice(&self.data[..len]);
+        // Logged by the driver, so must never panic either
+        let _ = msg.to_string();
+        msg
+    }
+}
+
+/// The driver turns panics into ERR_FAILED, so look for them in the last error
+pub fn check_status(function: &str, status: i32) {
+    if status == PassthruError::ERR_FAILED as i32 {
+        if let Some(detail) = m2_driver::api::last_error().as_ref().and_then(|e| e.detail()) {
+            assert!(!detail.starts_with("Driver panicked"), "{} {}", function, detail);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+#![no_main]
+// Fuzzes PassThruIoctl with arbitrary IOCTL IDs and inputs
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use J2534Common::*;
+use m2_driver::*;
+mod common;
+use common::*;
+
+#[derive(Arbitrary, Debug)]
+struct IoctlInput {
+    handle_id: u32,
+    ioctl_id: u32,
+    /// Pass a null input pointer
+    null_input: bool,
+    params: Vec<(u32, u32)>,
+    /// num_of_params is only set to this when it is over the limit,
+    /// a smaller count would have the driver read past the list
+    claimed_params: u32,
+    bytes: Vec<u8>,
+    msg: FuzzMsg,
+}
+
+fuzz_target!(|input: IoctlInput| {
+    let mut params: Vec<SConfig> = input.params.iter().map(|(parameter, value)| SConfig { parameter: *parameter, value: *value }).collect();
+    let num_of_params = if input.claimed_params > MAX_CONFIG_PARAMS { input.claimed_params } else { params.len() as u32 };
+    let mut list = SConfigList { num_of_params, config_ptr: if params.is_empty() { std::ptr::null_mut() } else { params.as_mut_ptr() } };
+    let mut array = SBYTE_ARRAY { num_of_bytes: input.bytes.len() as u32, byte_ptr: if input.bytes.is_empty() { std::ptr::null() } else { input.bytes.as_ptr() } };
+    let mut msg = input.msg.to_msg();
+    // Each IOCTL is given the input type it expects
+    let input_ptr: *mut libc::c_void = match IoctlID::from_raw(input.ioctl_id) {
+        _ if input.null_input => std::ptr::null_mut(),
+        Some(IoctlID::SET_CONFIG) | Some(IoctlID::GET_CONFIG) => &mut list as *mut SConfigList as *mut libc::c_void,
+        Some(IoctlID::FAST_INIT) => &mut msg as *mut PASSTHRU_MSG as *mut libc::c_void,
+        _ => &mut array as *mut SBYTE_ARRAY as *mut libc::c_void,
+    };
+    // Large enough for any IOCTL output
+    let mut output = PASSTHRU_MSG::default();
+    let output_ptr = &mut output as *mut PASSTHRU_MSG as *mut libc::c_void;
+    check_status("PassThruIoctl", PassThruIoctl(input.handle_id, input.ioctl_id, input_ptr, output_ptr));
+});
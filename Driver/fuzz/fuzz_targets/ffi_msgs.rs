@@ -0,0 +1,80 @@
+#![no_main]
+// Fuzzes the J2534 message and filter functions with arbitrary messages and IDs, on
+// channels opened on a fake M2 so the Tx queue, host filters and read loop are reached
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use J2534Common::*;
+use m2_driver::*;
+use m2_driver::fuzzing;
+mod common;
+use common::*;
+
+/// Channel a call is made on. Mostly one of the open channels, as other IDs stop at the channel lookup
+#[derive(Arbitrary, Debug)]
+enum ChannelRef {
+    Open(u8),
+    Raw(u32),
+}
+
+impl ChannelRef {
+    fn id(&self, open: &[u32]) -> u32 {
+        match self {
+            ChannelRef::Open(i) if !open.is_empty() => open[*i as usize % open.len()],
+            ChannelRef::Open(i) => *i as u32,
+            ChannelRef::Raw(id) => *id,
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Call {
+    WriteMsgs { channel: ChannelRef, msgs: Vec<FuzzMsg>, timeout: u8 },
+    ReadMsgs { channel: ChannelRef, num_msgs: u8, timeout: u8 },
+    StartMsgFilter { channel: ChannelRef, filter_type: u32, mask: Option<FuzzMsg>, pattern: Option<FuzzMsg>, flow_control: Option<FuzzMsg> },
+    StopMsgFilter { channel: ChannelRef, filter_id: u32 },
+    Disconnect { channel: ChannelRef },
+    /// Channel data from the M2 (Args of a ReceiveChannelData message), left queued for ReadMsgs
+    Receive { args: Vec<u8> },
+}
+
+fn msg_ptr(msg: &Option<PASSTHRU_MSG>) -> *const PASSTHRU_MSG {
+    msg.as_ref().map_or(std::ptr::null(), |m| m as *const PASSTHRU_MSG)
+}
+
+fuzz_target!(|calls: Vec<Call>| {
+    let open = fuzzing::reopen_channels();
+    for call in calls {
+        match call {
+            Call::WriteMsgs { channel, msgs, timeout } => {
+                let channel_id = channel.id(&open);
+                let msgs: Vec<PASSTHRU_MSG> = msgs.iter().map(|m| m.to_msg()).collect();
+                let mut num_msgs = msgs.len() as u32;
+                let ptr = if msgs.is_empty() { std::ptr::null() } else { msgs.as_ptr() };
+                check_status("PassThruWriteMsgs", PassThruWriteMsgs(channel_id, ptr, &mut num_msgs, timeout as u32));
+                assert!(num_msgs as usize <= msgs.len());
+            },
+            Call::ReadMsgs { channel, num_msgs, timeout } => {
+                let channel_id = channel.id(&open);
+                let mut msgs = vec![PASSTHRU_MSG::default(); num_msgs as usize];
+                let mut num_read = num_msgs as u32;
+                let ptr = if msgs.is_empty() { std::ptr::null_mut() } else { msgs.as_mut_ptr() };
+                check_status("PassThruReadMsgs", PassThruReadMsgs(channel_id, ptr, &mut num_read, timeout as u32));
+                assert!(num_read <= num_msgs as u32);
+            },
+            Call::StartMsgFilter { channel, filter_type, mask, pattern, flow_control } => {
+                let channel_id = channel.id(&open);
+                let (mask, pattern, flow_control) = (mask.map(|m| m.to_msg()), pattern.map(|m| m.to_msg()), flow_control.map(|m| m.to_msg()));
+                let mut filter_id = 0;
+                check_status("PassThruStartMsgFilter", PassThruStartMsgFilter(channel_id, filter_type, msg_ptr(&mask), msg_ptr(&pattern), msg_ptr(&flow_control), &mut filter_id));
+            },
+            Call::StopMsgFilter { channel, filter_id } => {
+                check_status("PassThruStopMsgFilter", PassThruStopMsgFilter(channel.id(&open), filter_id));
+            },
+            Call::Disconnect { channel } => {
+                check_status("PassThruDisconnect", PassThruDisconnect(channel.id(&open)));
+            },
+            Call::Receive { args } => fuzzing::dispatch_channel_data(&args),
+        }
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+// Fuzzes parsing of the M2's responses to commands
+
+use libfuzzer_sys::fuzz_target;
+use m2_driver::fuzzing;
+
+fuzz_target!(|data: &[u8]| {
+    fuzzing::parse_response(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+// Fuzzes dispatching of channel data received by the M2 to the open channels
+
+use libfuzzer_sys::fuzz_target;
+use m2_driver::fuzzing;
+
+fuzz_target!(|data: &[u8]| {
+    fuzzing::receive_channel_data(data);
+});
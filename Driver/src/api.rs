@@ -0,0 +1,477 @@
+// Safe Rust API for the M2. The J2534 C exports are thin shims over this,
+// so Rust tools can link against the crate directly instead of going through the C ABI
+
+use std::{marker::PhantomData, mem::ManuallyDrop, sync::mpsc::{channel, RecvTimeoutError}, time::{Duration, Instant}};
+use byteorder::{ByteOrder, LittleEndian};
+use J2534Common::*;
+use crate::channels::ChannelComm;
+use crate::comm::*;
+use crate::logger;
+use crate::{prog_voltage, vbatt};
+pub use crate::vbatt::VbattSample;
+pub use crate::error::{last_error, Error};
+use crate::error::{set_error_string, set_firmware_error};
+
+/// J2534 API Version supported - 04.04, or 05.00 when built with the v0500 feature
+#[cfg(not(feature = "v0500"))]
+pub const API_VERSION: &str = "04.04";
+#[cfg(feature = "v0500")]
+pub const API_VERSION: &str = "05.00";
+/// DLL (Driver) version of this library
+pub const DLL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Our device ID that will be returned back to the application (0x1234)
+const DEVICE_ID: u32 = 0x1234;
+
+/// How long Channel::send waits for the message to be sent
+const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Channel ID of an IOCTL handle, or None if the handle is the device
+pub(crate) fn channel_handle(handle: u32) -> Option<u32> {
+    if handle == DEVICE_ID { None } else { Some(handle) }
+}
+
+/// Versions reported by PassThruReadVersion
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version {
+    pub firmware: String,
+    pub dll: &'static str,
+    pub api: &'static str,
+}
+
+/// Connection to the M2. Only 1 can be open at a time, and it is closed when dropped
+#[derive(Debug)]
+pub struct Device {
+    id: u32,
+}
+
+impl Device {
+    /// Opens the M2 on the serial port set in the driver config
+    pub fn open() -> Result<Self> {
+        logger::log_info_str("Device open called");
+        // Check if the device is already loaded
+        if M2.read().unwrap().is_some() {
+            return Err(PassthruError::ERR_DEVICE_IN_USE.into())
+        }
+        // Try to open a connection
+        match MacchinaM2::open_connection() {
+            Ok(dev) => {
+                // Device loaded OK!
+                if let Ok(ptr) = M2.write().as_deref_mut() {
+                    *ptr = Some(dev);
+                    vbatt::start();
+                    Ok(Device { id: DEVICE_ID })
+                } else {
+                    // Something happened trying to write to the static reference of the M2
+                    set_error_string("Failed to obtain write access to M2".into());
+                    Err(PassthruError::ERR_FAILED.into())
+                }
+            }
+            Err(x) => {
+                // Error loading the device driver. Could be due to the device
+                // not being connected to the PC, or a serial error
+                logger::log_error(format!("Cannot open com port. Error: {}", x));
+                set_error_string(format!("Serial port open failed with error {}", x));
+                Err(PassthruError::ERR_DEVICE_NOT_CONNECTED.into())
+            }
+        }
+    }
+
+    /// Wraps a device ID that was handed out to a C application. This is
+    /// ManuallyDrop as the application decides when the device is closed
+    pub(crate) fn from_raw(id: u32) -> Result<ManuallyDrop<Self>> {
+        if id != DEVICE_ID {
+            set_error_string(format!("Not M2s device ID. Expected {}, got {}", DEVICE_ID, id));
+            return Err(PassthruError::ERR_INVALID_DEVICE_ID.into())
+        }
+        Ok(ManuallyDrop::new(Device { id }))
+    }
+
+    /// Releases the device without closing it, returning its ID
+    pub(crate) fn into_raw(self) -> u32 {
+        ManuallyDrop::new(self).id
+    }
+
+    /// Closes the M2, along with any channels that are still open
+    pub fn close(self) -> Result<()> {
+        ManuallyDrop::new(self).close_m2()
+    }
+
+    fn close_m2(&self) -> Result<()> {
+        logger::log_info(format!("Device close called. Device ID: {}", self.id));
+        // The sampler uses the M2, so must stop before it is closed
+        vbatt::stop();
+        prog_voltage::reset();
+        if let Ok(d) = M2.write().as_deref_mut() {
+            if let Some(dev) = d {
+                dev.stop(); // Terminate the M2 connection
+                // Kill all open channels if any exist
+                ChannelComm::force_destroy_all_channels();
+                *d = None; // Set M2 reference to None
+            }
+            // Already terminated, nothing to do
+            Ok(())
+        } else {
+            // Something unknown happened when trying to write to the RwLockGuard
+            set_error_string("Error obtaining access to RwLockGuard".into());
+            Err(PassthruError::ERR_FAILED.into())
+        }
+    }
+
+    pub fn version(&self) -> Result<Version> {
+        Ok(run_on_m2(|dev| {
+            let mut msg = CommMsg::new(MsgType::GetFwVersion);
+            match dev.write_and_read_ptcmd(&mut msg, 250) {
+                M2Resp::Ok(args) => Ok(Version { firmware: String::from_utf8_lossy(&args).into(), dll: DLL_VERSION, api: API_VERSION }),
+                M2Resp::Err{status, string} => {
+                    logger::log_warn(format!("M2 failed to respond to FW_VERSION request: {}", string));
+                    set_firmware_error(string);
+                    Err(status)
+                }
+            }
+        })?)
+    }
+
+    /// Reads the battery voltage in mV
+    pub fn read_vbatt(&self) -> Result<u32> {
+        Ok(vbatt::read()?)
+    }
+
+    /// State of the last battery voltage reading against the thresholds
+    pub fn vbatt_state(&self) -> VbattState {
+        VbattState::from_raw(vbatt::get_config(IoctlParam::MACCHINA_VBATT_STATE).unwrap_or(0)).unwrap_or(VbattState::NORMAL)
+    }
+
+    /// Sets the battery voltage thresholds. Readings crossing them are recorded as events
+    /// # Params
+    /// * low_mv - Voltage below which the battery is LOW, 0 for no threshold
+    /// * high_mv - Voltage above which the battery is HIGH, 0 for no threshold
+    pub fn set_vbatt_thresholds(&self, low_mv: u32, high_mv: u32) -> Result<()> {
+        Ok(vbatt::set_thresholds(low_mv, high_mv)?)
+    }
+
+    /// Sets how often the battery voltage is read in the background
+    pub fn set_vbatt_interval(&self, interval: Duration) -> Result<()> {
+        Ok(vbatt::set_config(IoctlParam::MACCHINA_VBATT_INTERVAL, interval.as_millis().min(u32::MAX as u128) as u32)?)
+    }
+
+    /// Applies a programming voltage to a J1962 pin. The M2 switches the battery voltage onto the pin,
+    /// so the voltage must be near the battery voltage
+    /// # Params
+    /// * pin - J1962 pin (0, 9, 12 or 13), or 15 for SHORT_TO_GROUND
+    /// * voltage - Voltage in mV, SHORT_TO_GROUND or VOLTAGE_OFF
+    pub fn set_programming_voltage(&self, pin: u32, voltage: u32) -> Result<()> {
+        Ok(prog_voltage::set(pin, voltage)?)
+    }
+
+    /// Measures the programming voltage in mV. 0 if no pin has a voltage applied
+    pub fn read_programming_voltage(&self) -> Result<u32> {
+        Ok(prog_voltage::read()?)
+    }
+
+    /// Battery voltage readings of the last few minutes, oldest first
+    pub fn vbatt_history(&self) -> Vec<VbattSample> {
+        vbatt::history()
+    }
+
+    /// Takes the battery voltage state changes recorded since they were last taken, oldest first
+    pub fn take_vbatt_events(&self) -> Vec<VbattSample> {
+        vbatt::take_events(usize::MAX)
+    }
+
+    /// Opens a communication channel with the vehicle
+    /// # Params
+    /// * protocol - Protocol to connect with
+    /// * flags - Connection protocol flags
+    /// * baud_rate - Bus speed of the communication channel
+    pub fn connect(&self, protocol: Protocol, flags: u32, baud_rate: u32) -> Result<Channel<'_>> {
+        Ok(ChannelComm::create_channel(protocol, baud_rate, flags).map(|id| Channel { id, _device: PhantomData })?)
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        let _ = self.close_m2();
+    }
+}
+
+/// ID of a filter on a channel
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FilterId(pub(crate) u32);
+
+/// Message filter, built with one of the constructors below
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    filter_type: FilterType,
+    mask: Vec<u8>,
+    pattern: Vec<u8>,
+    flow_control: Vec<u8>,
+}
+
+impl Filter {
+    /// Receive messages where (data & mask) == pattern
+    pub fn pass(mask: &[u8], pattern: &[u8]) -> Self {
+        Filter { filter_type: FilterType::PASS_FILTER, mask: mask.to_vec(), pattern: pattern.to_vec(), flow_control: Vec::new() }
+    }
+
+    /// Drop messages where (data & mask) == pattern
+    pub fn block(mask: &[u8], pattern: &[u8]) -> Self {
+        Filter { filter_type: FilterType::BLOCK_FILTER, mask: mask.to_vec(), pattern: pattern.to_vec(), flow_control: Vec::new() }
+    }
+
+    /// ISO 15765 flow control filter. Messages matching the pattern are received, and
+    /// flow control frames are sent with the flow control ID
+    pub fn flow_control(mask: &[u8], pattern: &[u8], flow_control: &[u8]) -> Self {
+        Filter { filter_type: FilterType::FLOW_CONTROL_FILTER, mask: mask.to_vec(), pattern: pattern.to_vec(), flow_control: flow_control.to_vec() }
+    }
+
+    /// Pass filter for a single CAN ID
+    pub fn can_id(id: u32) -> Self {
+        Filter::pass(&[0xFF; 4], &id.to_be_bytes())
+    }
+
+    /// Flow control filter for an ECU that responds on rx_id, and is sent requests on tx_id
+    pub fn iso15765(rx_id: u32, tx_id: u32) -> Self {
+        Filter::flow_control(&[0xFF; 4], &rx_id.to_be_bytes(), &tx_id.to_be_bytes())
+    }
+
+    pub fn filter_type(&self) -> FilterType {
+        self.filter_type
+    }
+}
+
+/// Statistics of the CAN bus a channel runs on, read from the M2
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BusStats {
+    pub rx_frames: u32,
+    pub tx_frames: u32,
+    pub rx_frames_dropped: u32,
+    pub tx_frames_failed: u32,
+    pub error_passive_events: u32,
+    pub bus_off_events: u32,
+    pub tx_error_count: u32,
+    pub rx_error_count: u32,
+    /// Percentage of the bus used since the stats were last read
+    pub bus_load: u32,
+    /// 0 - Error active, 1 - Error passive, 2 - Bus off
+    pub bus_state: u32,
+}
+
+impl BusStats {
+    /// Parses the stats sent by the M2, each a u32 in the order of the fields above
+    pub(crate) fn from_m2(args: &[u8]) -> Option<Self> {
+        if args.len() < 40 {
+            return None
+        }
+        let v = |i: usize| LittleEndian::read_u32(&args[i * 4..]);
+        Some(BusStats {
+            rx_frames: v(0),
+            tx_frames: v(1),
+            rx_frames_dropped: v(2),
+            tx_frames_failed: v(3),
+            error_passive_events: v(4),
+            bus_off_events: v(5),
+            tx_error_count: v(6),
+            rx_error_count: v(7),
+            bus_load: v(8),
+            bus_state: v(9),
+        })
+    }
+
+    pub fn get(&self, stat: BusStat) -> u32 {
+        match stat {
+            BusStat::RX_FRAMES => self.rx_frames,
+            BusStat::TX_FRAMES => self.tx_frames,
+            BusStat::RX_FRAMES_DROPPED => self.rx_frames_dropped,
+            BusStat::TX_FRAMES_FAILED => self.tx_frames_failed,
+            BusStat::ERROR_PASSIVE_EVENTS => self.error_passive_events,
+            BusStat::BUS_OFF_EVENTS => self.bus_off_events,
+            BusStat::TX_ERROR_COUNT => self.tx_error_count,
+            BusStat::RX_ERROR_COUNT => self.rx_error_count,
+            BusStat::BUS_LOAD => self.bus_load,
+            BusStat::BUS_STATE => self.bus_state,
+        }
+    }
+}
+
+/// Communication channel with the vehicle, disconnected when dropped
+#[derive(Debug)]
+pub struct Channel<'a> {
+    id: u32,
+    _device: PhantomData<&'a Device>,
+}
+
+impl Channel<'_> {
+    /// Wraps a channel ID that was handed out to a C application. This is
+    /// ManuallyDrop as the application decides when the channel is disconnected
+    pub(crate) fn from_raw(id: u32) -> ManuallyDrop<Channel<'static>> {
+        ManuallyDrop::new(Channel { id, _device: PhantomData })
+    }
+
+    /// Releases the channel without disconnecting it, returning its ID
+    pub(crate) fn into_raw(self) -> u32 {
+        ManuallyDrop::new(self).id
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Adds the channel to the error of a failed channel operation
+    fn on_channel<T>(&self, res: std::result::Result<T, PassthruError>) -> Result<T> {
+        res.map_err(|e| Error::from(e).with_channel(self.id))
+    }
+
+    pub fn disconnect(self) -> Result<()> {
+        let channel = ManuallyDrop::new(self);
+        channel.on_channel(ChannelComm::destroy_channel(channel.id))
+    }
+
+    /// Sets the J1962 pins of a pin switched channel
+    pub fn set_pins(&self, pins: J1962Pins) -> Result<()> {
+        self.set_config(IoctlParam::J1962_PINS, pins.to_raw())
+    }
+
+    pub fn add_filter(&self, filter: &Filter) -> Result<FilterId> {
+        self.on_channel(ChannelComm::create_channel_filter(self.id, filter.filter_type, &filter.mask, &filter.pattern, &filter.flow_control).map(FilterId))
+    }
+
+    pub fn remove_filter(&self, filter: FilterId) -> Result<()> {
+        self.on_channel(ChannelComm::remove_filter(self.id, filter.0))
+    }
+
+    /// Sends a message, waiting up to 1 second for the M2 to confirm it was sent
+    pub fn send(&self, msg: &PASSTHRU_MSG) -> Result<()> {
+        self.write(std::slice::from_ref(msg), SEND_TIMEOUT).1
+    }
+
+    /// Queues a message to be sent, without waiting for it to go out.
+    /// Fails with ERR_BUFFER_FULL if the channel's Tx queue is full
+    pub fn queue(&self, msg: &PASSTHRU_MSG) -> Result<()> {
+        self.write(std::slice::from_ref(msg), Duration::from_millis(0)).1
+    }
+
+    /// Writes messages like PassThruWriteMsgs. With a zero timeout, the messages are queued
+    /// and this returns straight away. Otherwise this waits up to timeout for every message to be sent
+    /// # Returns
+    /// Number of messages queued (Zero timeout) or sent, and the error that stopped the rest
+    pub fn write(&self, msgs: &[PASSTHRU_MSG], timeout: Duration) -> (usize, Result<()>) {
+        if timeout == Duration::from_millis(0) {
+            for (i, msg) in msgs.iter().enumerate() {
+                if let Err(e) = ChannelComm::queue_channel_data(self.id, msg, None, None) {
+                    return (i, self.on_channel(Err(e)))
+                }
+            }
+            return (msgs.len(), Ok(()))
+        }
+        let deadline = Instant::now() + timeout;
+        let (reply, results) = channel();
+        let mut queued = 0;
+        let mut res = Ok(());
+        for msg in msgs {
+            match ChannelComm::queue_channel_data(self.id, msg, Some(reply.clone()), Some(deadline)) {
+                Ok(()) => queued += 1,
+                Err(e) => {
+                    res = self.on_channel(Err(e));
+                    break
+                }
+            }
+        }
+        drop(reply);
+        let mut sent = 0;
+        while sent < queued {
+            match results.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Ok(())) => sent += 1,
+                Ok(Err(e)) => return (sent, Err(e.with_channel(self.id))),
+                Err(RecvTimeoutError::Timeout) => return (sent, self.on_channel(Err(PassthruError::ERR_TIMEOUT))),
+                Err(RecvTimeoutError::Disconnected) => {
+                    set_error_string("Messages were discarded from the Tx queue before they were sent".into());
+                    return (sent, self.on_channel(Err(PassthruError::ERR_FAILED)))
+                }
+            }
+        }
+        (sent, res)
+    }
+
+    /// Returns true if received messages were dropped since this was last called, as the Rx queue was full
+    pub fn take_rx_overflow(&self) -> Result<bool> {
+        self.on_channel(ChannelComm::take_rx_overflow(self.id))
+    }
+
+    /// Returns the next received message, if there is one
+    pub fn try_recv(&self) -> Result<Option<PASSTHRU_MSG>> {
+        self.on_channel(ChannelComm::read_channel_data(self.id))
+    }
+
+    /// Waits up to timeout for a message to be received
+    pub fn recv(&self, timeout: Duration) -> Result<PASSTHRU_MSG> {
+        let start_time = Instant::now();
+        loop {
+            if let Some(msg) = self.try_recv()? {
+                return Ok(msg)
+            }
+            if timeout == Duration::from_millis(0) {
+                return self.on_channel(Err(PassthruError::ERR_BUFFER_EMPTY))
+            }
+            if start_time.elapsed() > timeout {
+                return self.on_channel(Err(PassthruError::ERR_TIMEOUT))
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    pub fn get_config(&self, param: IoctlParam) -> Result<u32> {
+        self.on_channel(ChannelComm::ioctl_get_cfg(self.id, param))
+    }
+
+    pub fn set_config(&self, param: IoctlParam, value: u32) -> Result<()> {
+        self.on_channel(ChannelComm::ioctl_set_cfg(self.id, param, value))
+    }
+
+    /// Reads the statistics of the CAN bus the channel runs on
+    pub fn bus_stats(&self) -> Result<BusStats> {
+        let args = self.on_channel(ChannelComm::ioctl_cmd(self.id, IoctlID::MACCHINA_GET_BUS_STATS, &[]))?;
+        self.on_channel(BusStats::from_m2(&args).ok_or_else(|| {
+            set_error_string(format!("M2 sent {} bytes of bus stats", args.len()));
+            PassthruError::ERR_FAILED
+        }))
+    }
+
+    /// Resets the frame and event counts of the channel's CAN bus
+    pub fn clear_bus_stats(&self) -> Result<()> {
+        self.on_channel(ChannelComm::ioctl_cmd(self.id, IoctlID::MACCHINA_CLEAR_BUS_STATS, &[]).map(|_| ()))
+    }
+
+    /// Finds the baud rate of the CAN bus from its traffic, and switches the channel to it
+    pub fn detect_baud_rate(&self) -> Result<u32> {
+        let args = self.on_channel(ChannelComm::ioctl_cmd(self.id, IoctlID::MACCHINA_DETECT_BAUD_RATE, &[]))?;
+        self.on_channel(if args.len() == 4 {
+            Ok(LittleEndian::read_u32(&args))
+        } else {
+            set_error_string(format!("M2 sent {} bytes of baud rate", args.len()));
+            Err(PassthruError::ERR_FAILED)
+        })
+    }
+
+    pub fn clear_rx_buffer(&self) -> Result<()> {
+        match ChannelComm::clear_rx_buffer(self.id) {
+            PassthruError::STATUS_NOERROR => Ok(()),
+            e => self.on_channel(Err(e))
+        }
+    }
+
+    pub fn clear_tx_buffer(&self) -> Result<()> {
+        match ChannelComm::clear_tx_buffer(self.id) {
+            PassthruError::STATUS_NOERROR => Ok(()),
+            e => self.on_channel(Err(e))
+        }
+    }
+}
+
+impl Drop for Channel<'_> {
+    fn drop(&mut self) {
+        let _ = ChannelComm::destroy_channel(self.id);
+    }
+}
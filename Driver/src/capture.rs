@@ -0,0 +1,262 @@
+// Capture and replay of the CommMsg stream between the driver and the M2.
+//
+// When CAPTURE-FILE is set in the driver config, every CommMsg sent to or received from
+// the M2 is written to it as a JSON line. Setting COM-PORT to "replay:<capture file>"
+// replaces the M2 with a ReplayPort, which plays a captured session back to the driver
+
+use std::{collections::{HashMap, VecDeque}, fs::File, io::{BufRead, BufReader, Error, ErrorKind, Read, Write}, sync::{Arc, Mutex}, time::Instant};
+use byteorder::{ByteOrder, LittleEndian};
+use lazy_static::lazy_static;
+use serde_json::json;
+use crate::comm::{CommMsg, MsgType, COMM_MSG_SIZE};
+use crate::logger::{log_error, log_info, log_info_str, log_warn};
+
+/// COM-PORT prefix that selects the replay transport
+pub const REPLAY_PREFIX: &str = "replay:";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Driver -> M2
+    Tx,
+    /// M2 -> Driver
+    Rx,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Tx => "tx",
+            Direction::Rx => "rx",
+        }
+    }
+}
+
+/// CommMsg in a capture file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedMsg {
+    /// Microseconds since the capture was started
+    pub time_us: u64,
+    pub dir: Direction,
+    pub msg: CommMsg,
+}
+
+impl CapturedMsg {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "time_us": self.time_us,
+            "dir": self.dir.as_str(),
+            "id": self.msg.msg_id,
+            "type": self.msg.msg_type as u8,
+            "args": self.msg.args.iter().map(|b| format!("{:02X}", b)).collect::<String>(),
+        })
+    }
+
+    fn from_json(line: &str) -> Option<Self> {
+        let v: serde_json::Value = serde_json::from_str(line).ok()?;
+        let dir = match v["dir"].as_str()? {
+            "tx" => Direction::Tx,
+            "rx" => Direction::Rx,
+            _ => return None
+        };
+        let hex = v["args"].as_str()?;
+        if hex.len() % 2 != 0 {
+            return None
+        }
+        let args = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i+2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let mut msg = CommMsg::new_with_args(MsgType::from_u8(&(v["type"].as_u64()? as u8)), &args);
+        msg.msg_id = v["id"].as_u64()? as u8;
+        Some(CapturedMsg { time_us: v["time_us"].as_u64()?, dir, msg })
+    }
+}
+
+struct Capture {
+    file: File,
+    start: Instant,
+}
+
+lazy_static! {
+    static ref CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+}
+
+/// Starts capturing all CommMsgs to a file, replacing any capture in progress
+pub fn start_capture(path: &str) {
+    match File::create(path) {
+        Ok(file) => {
+            log_info(format!("Capturing M2 traffic to {}", path));
+            *CAPTURE.lock().unwrap() = Some(Capture { file, start: Instant::now() });
+        },
+        Err(e) => log_error(format!("Cannot create capture file {}: {}", path, e))
+    }
+}
+
+pub fn stop_capture() {
+    *CAPTURE.lock().unwrap() = None;
+}
+
+/// Records a CommMsg, if a capture is running
+pub fn record(dir: Direction, msg: &CommMsg) {
+    if let Some(c) = CAPTURE.lock().unwrap().as_mut() {
+        let entry = CapturedMsg { time_us: c.start.elapsed().as_micros() as u64, dir, msg: msg.clone() };
+        if let Err(e) = writeln!(c.file, "{}", entry.to_json()) {
+            log_warn(format!("Could not write to capture file: {}", e));
+        }
+    }
+}
+
+/// Reads a capture file. Lines that cannot be parsed are skipped
+pub fn load_capture(path: &str) -> std::io::Result<Vec<CapturedMsg>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut msgs = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        match CapturedMsg::from_json(&line?) {
+            Some(m) => msgs.push(m),
+            None => log_warn(format!("Skipping invalid capture line {}", i + 1))
+        }
+    }
+    Ok(msgs)
+}
+
+/// Serializes a CommMsg the way the M2 sends it (Fixed size frame)
+pub(crate) fn to_m2_frame(msg: &CommMsg) -> Vec<u8> {
+    let mut frame = vec![0u8; COMM_MSG_SIZE];
+    frame[0] = msg.msg_id;
+    frame[1] = msg.msg_type as u8;
+    LittleEndian::write_u16(&mut frame[2..4], msg.args.len() as u16);
+    frame[4..4 + msg.args.len()].copy_from_slice(&msg.args);
+    frame
+}
+
+/// Takes the driver -> M2 messages out of the bytes written so far. They are length
+/// prefixed, see CommMsg::to_slice. Bytes of an incomplete message are left in tx_buf
+pub(crate) fn split_driver_msgs(tx_buf: &mut Vec<u8>) -> std::io::Result<Vec<CommMsg>> {
+    let mut msgs = Vec::new();
+    while tx_buf.len() >= 2 {
+        let size = LittleEndian::read_u16(&tx_buf[0..2]) as usize;
+        if size < 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid message length"))
+        }
+        if tx_buf.len() < size + 2 {
+            break
+        }
+        let bytes: Vec<u8> = tx_buf.drain(..size + 2).collect();
+        let mut msg = CommMsg::new_with_args(MsgType::from_u8(&bytes[3]), &bytes[4..]);
+        msg.msg_id = bytes[2];
+        msgs.push(msg);
+    }
+    Ok(msgs)
+}
+
+/// Reads M2 frames waiting in rx_buf into buf, returning the number of bytes read
+pub(crate) fn read_m2_frames(rx_buf: &mut VecDeque<u8>, buf: &mut [u8]) -> usize {
+    let count = buf.len().min(rx_buf.len());
+    for (dst, src) in buf.iter_mut().zip(rx_buf.drain(..count)) {
+        *dst = src;
+    }
+    count
+}
+
+struct ReplayState {
+    /// Captured messages that have not been replayed yet
+    pending: VecDeque<CapturedMsg>,
+    /// Bytes written by the driver that do not form a full message yet
+    tx_buf: Vec<u8>,
+    /// M2 frames waiting to be read by the driver
+    rx_buf: VecDeque<u8>,
+    /// Captured msg ID -> ID the driver used for the same request.
+    /// Responses are sent back with the driver's ID, so the captured session
+    /// does not have to start with the same ID counter
+    id_map: HashMap<u8, u8>,
+}
+
+impl ReplayState {
+    /// Queues captured Rx messages up to the next captured Tx message
+    fn release_rx(&mut self) {
+        while let Some(m) = self.pending.front() {
+            if m.dir == Direction::Tx {
+                break
+            }
+            let mut msg = self.pending.pop_front().unwrap().msg;
+            if let Some(id) = self.id_map.get(&msg.msg_id) {
+                msg.msg_id = *id;
+            }
+            self.rx_buf.extend(to_m2_frame(&msg));
+        }
+    }
+
+    /// Handles a message written by the driver. The next captured Tx message should match it
+    fn on_tx(&mut self, msg: CommMsg) {
+        match self.pending.front() {
+            Some(m) if m.dir == Direction::Tx => {
+                let expected = self.pending.pop_front().unwrap().msg;
+                if expected.msg_type != msg.msg_type || expected.args != msg.args {
+                    log_warn(format!("Replay diverged. Expected {}, driver sent {}", expected, msg));
+                }
+                if expected.msg_id != 0 {
+                    self.id_map.insert(expected.msg_id, msg.msg_id);
+                }
+                self.release_rx();
+                if self.pending.is_empty() {
+                    log_info_str("Replay complete, no captured messages left");
+                }
+            },
+            // Capture stops before the M2 is told the driver is exiting
+            None if msg.msg_type == MsgType::StatusMsg => {},
+            _ => log_warn(format!("Replay has no captured message for {}", msg))
+        }
+    }
+}
+
+/// Transport that plays back a captured session instead of talking to an M2.
+/// Clones share the same session, like a cloned serial port
+#[derive(Clone)]
+pub struct ReplayPort {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+impl ReplayPort {
+    pub fn new(msgs: Vec<CapturedMsg>) -> Self {
+        let mut state = ReplayState { pending: msgs.into(), tx_buf: Vec::new(), rx_buf: VecDeque::new(), id_map: HashMap::new() };
+        state.release_rx(); // Anything the M2 sent before the first request
+        ReplayPort { state: Arc::new(Mutex::new(state)) }
+    }
+
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let msgs = load_capture(path)?;
+        if msgs.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} has no captured messages", path)))
+        }
+        log_info(format!("Replaying {} captured messages from {}", msgs.len(), path));
+        Ok(ReplayPort::new(msgs))
+    }
+
+    /// True once every captured message has been replayed and read
+    #[allow(dead_code)] // Used by tests
+    pub fn is_finished(&self) -> bool {
+        let s = self.state.lock().unwrap();
+        s.pending.is_empty() && s.rx_buf.is_empty()
+    }
+}
+
+impl Read for ReplayPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut s = self.state.lock().unwrap();
+        Ok(read_m2_frames(&mut s.rx_buf, buf))
+    }
+}
+
+impl Write for ReplayPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut s = self.state.lock().unwrap();
+        s.tx_buf.extend_from_slice(buf);
+        for msg in split_driver_msgs(&mut s.tx_buf)? {
+            s.on_tx(msg);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -5,28 +5,52 @@ use std::collections::VecDeque;
 use std::sync::*;
 use crate::comm::*;
 use byteorder::{LittleEndian, ByteOrder, WriteBytesExt};
-use crate::passthru_drv::set_error_string;
+use crate::error::{set_error_string, set_firmware_error};
+use crate::pins;
+use crate::capture::Direction;
+use crate::filters::{self, HwFilter, MsgFilter, MAX_HW_FILTERS};
+use crate::recorder::{self, RecordFormat, TrafficRecorder};
+use crate::timebase::Timebase;
+use crate::tx_queue::{TxMsg, TxQueue, TxReply};
+use std::time::Instant;
 
 lazy_static! {
     static ref CAN_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
     static ref KLINE_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
     static ref J1850_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
     static ref SCI_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
+    static ref CAN_PS_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
+    static ref SW_CAN_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
+    static ref J1939_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
+    static ref TP2_0_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
+    // Held whilst checking and assigning J1962 pins, so 2 channels cannot claim the same pins
+    static ref PIN_LOCK: Mutex<()> = Mutex::new(());
 }
 
 
 // Defined in J2534 spec. Each channel can have up to 10 filters
 const MAX_FILTERS_PER_CHANNEL: usize = 10;
 
+/// v05.00 logical channel IDs start here, so they never collide with a physical channel ID.
+/// Logical channel ID = LOGICAL_CHANNEL_ID_START + (Physical channel ID * MAX_FILTERS_PER_CHANNEL) + Filter ID
+const LOGICAL_CHANNEL_ID_START: u32 = 0x100;
+
 type Result<T> = std::result::Result<T, PassthruError>;
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum ChannelID {
     Can = 0,
     Kline = 1,
     J1850 = 2,
-    Sci = 3
+    Sci = 3,
+    CanPs = 4,
+    SwCan = 5,
+    J1939 = 6,
+    Tp20 = 7,
 }
 
+const ALL_CHANNELS: [ChannelID; 8] = [ChannelID::Can, ChannelID::Kline, ChannelID::J1850, ChannelID::Sci, ChannelID::CanPs, ChannelID::SwCan, ChannelID::J1939, ChannelID::Tp20];
+
 
 impl ChannelID {
     fn get_channel(&self) -> &'static RwLock<Option<Channel>> {
@@ -34,7 +58,11 @@ impl ChannelID {
             ChannelID::Can => &CAN_CHANNEL,
             ChannelID::Kline => &KLINE_CHANNEL,
             ChannelID::J1850 => &J1850_CHANNEL,
-            ChannelID::Sci => &SCI_CHANNEL
+            ChannelID::Sci => &SCI_CHANNEL,
+            ChannelID::CanPs => &CAN_PS_CHANNEL,
+            ChannelID::SwCan => &SW_CAN_CHANNEL,
+            ChannelID::J1939 => &J1939_CHANNEL,
+            ChannelID::Tp20 => &TP2_0_CHANNEL,
         }
     }
 
@@ -44,6 +72,10 @@ impl ChannelID {
             1 => Ok(ChannelID::Kline),
             2 => Ok(ChannelID::J1850),
             3 => Ok(ChannelID::Sci),
+            4 => Ok(ChannelID::CanPs),
+            5 => Ok(ChannelID::SwCan),
+            6 => Ok(ChannelID::J1939),
+            7 => Ok(ChannelID::Tp20),
             _ => Err(PassthruError::ERR_INVALID_CHANNEL_ID)
         }
     }
@@ -53,7 +85,11 @@ impl ChannelID {
             Protocol::ISO15765 | Protocol::CAN => ChannelID::Can,
             Protocol::ISO14230 | Protocol::ISO9141 => ChannelID::Kline,
             Protocol::J1850PWM | Protocol::J1850VPW => ChannelID::J1850,
-            Protocol::SCI_A_ENGINE | Protocol::SCI_A_TRANS | Protocol::SCI_B_ENGINE | Protocol::SCI_B_TRANS => ChannelID::Sci
+            Protocol::SCI_A_ENGINE | Protocol::SCI_A_TRANS | Protocol::SCI_B_ENGINE | Protocol::SCI_B_TRANS => ChannelID::Sci,
+            Protocol::CAN_PS | Protocol::ISO15765_PS => ChannelID::CanPs,
+            Protocol::SW_CAN_PS | Protocol::SW_ISO15765_PS => ChannelID::SwCan,
+            Protocol::J1939_PS => ChannelID::J1939,
+            Protocol::TP2_0_PS => ChannelID::Tp20,
         }
     }
 }
@@ -61,11 +97,26 @@ impl ChannelID {
 pub struct ChannelComm{}
 
 impl ChannelComm {
+    /// Splits a channel ID from the application into the physical channel, and the
+    /// logical channel (filter ID) within it if the ID is a v05.00 logical channel
+    fn split_channel_id(channel_id: u32) -> Result<(ChannelID, Option<usize>)> {
+        if channel_id < LOGICAL_CHANNEL_ID_START {
+            return Ok((ChannelID::from_u32(channel_id)?, None))
+        }
+        let idx = channel_id - LOGICAL_CHANNEL_ID_START;
+        let physical = ChannelID::from_u32(idx / MAX_FILTERS_PER_CHANNEL as u32)?;
+        Ok((physical, Some((idx % MAX_FILTERS_PER_CHANNEL as u32) as usize)))
+    }
+
     /// Attempts to create a new communication channel
     /// # Returns
     /// Channel ID if operation was OK
     pub fn create_channel(protocol: Protocol, baud_rate: u32, flags: u32) -> Result<u32> {
         let protocol_id = ChannelID::from_protocol(protocol);
+        let _pin_guard = PIN_LOCK.lock().unwrap();
+        if let Some(p) = pins::get_fixed_pins(protocol) {
+            ChannelComm::check_pins_free(p, protocol_id)?;
+        }
         match protocol_id.get_channel().write() {
             Ok(mut channel) => {
                 if channel.is_some() { // Already occupied!
@@ -93,6 +144,49 @@ impl ChannelComm {
         KLINE_CHANNEL.write().unwrap().take().take();
         J1850_CHANNEL.write().unwrap().take().take();
         SCI_CHANNEL.write().unwrap().take().take();
+        CAN_PS_CHANNEL.write().unwrap().take();
+        SW_CAN_CHANNEL.write().unwrap().take();
+        J1939_CHANNEL.write().unwrap().take();
+        TP2_0_CHANNEL.write().unwrap().take();
+    }
+
+    /// Checks that no other channel is using the requested J1962 pins
+    fn check_pins_free(pins: J1962Pins, requester: ChannelID) -> Result<()> {
+        for id in ALL_CHANNELS.iter().filter(|id| **id != requester) {
+            if let Some(c) = id.get_channel().read().unwrap().as_ref() {
+                if let Some(used) = c.pins.filter(|p| p.overlaps(&pins)) {
+                    set_error_string(format!("{} conflict with {} used by channel {} ({})", pins, used, c.id, c.protocol));
+                    return Err(PassthruError::ERR_CHANNEL_IN_USE)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Assigns J1962 pins to a pin switched channel (SET_CONFIG J1962_PINS)
+    fn set_channel_pins(id: ChannelID, value: u32) -> Result<()> {
+        let pins = match J1962Pins::from_raw(value) {
+            Some(p) => p,
+            None => {
+                set_error_string(format!("0x{:08X} is not a valid J1962_PINS value", value));
+                return Err(PassthruError::ERR_PIN_INVALID)
+            }
+        };
+        let _pin_guard = PIN_LOCK.lock().unwrap();
+        ChannelComm::check_pins_free(pins, id)?;
+        match id.get_channel().write() {
+            Ok(mut channel) => {
+                if let Some(c) = channel.as_mut() {
+                    c.set_pins(pins)
+                } else {
+                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+                }
+            }
+            Err(e) => {
+                set_error_string(format!("Write guard failed: {}", e));
+                Err(PassthruError::ERR_FAILED)
+            }
+        }
     }
 
     pub fn destroy_channel(channel_id: u32) -> Result<()> {
@@ -127,20 +221,33 @@ impl ChannelComm {
         }
     }
 
-    pub fn write_channel_data(channel_id: u32, msg: &PASSTHRU_MSG, require_response: bool) -> Result<()> {
-        match ChannelID::from_u32(channel_id)?.get_channel().read() {
+    /// Adds a message to the channel's Tx queue
+    /// # Params
+    /// * channel_id - Channel to send on
+    /// * msg - Message to send
+    /// * reply - Where to send the result of sending the message, if the caller waits for it
+    /// * deadline - If the Tx queue is full, wait until this time for space. If None, fail straight away
+    pub fn queue_channel_data(channel_id: u32, msg: &PASSTHRU_MSG, reply: Option<TxReply>, deadline: Option<Instant>) -> Result<()> {
+        let (id, logical) = ChannelComm::split_channel_id(channel_id)?;
+        // Only hold the channel whilst building the message, so the channel can be used whilst we wait for space
+        let (queue, tx) = match id.get_channel().read() {
             Ok(channel) => {
                 if let Some(c) = channel.as_ref() {
-                    c.transmit_data(msg, require_response)
+                    let tx = match logical {
+                        Some(l) => c.build_logical_tx(l, msg, reply)?,
+                        None => c.build_tx(msg, reply)?
+                    };
+                    (c.tx_queue.clone(), tx)
                 } else {
-                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+                    return Err(PassthruError::ERR_INVALID_CHANNEL_ID)
                 }
             }
             Err(e) => {
                 set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
+                return Err(PassthruError::ERR_FAILED)
             }
-        }
+        };
+        queue.push(tx, deadline)
     }
 
     pub fn ioctl_get_cfg(channel_id: u32, param_name: IoctlParam) -> Result<u32> {
@@ -160,7 +267,11 @@ impl ChannelComm {
     }
 
     pub fn ioctl_set_cfg(channel_id: u32, param_name: IoctlParam, value: u32) -> Result<()> {
-        match ChannelID::from_u32(channel_id)?.get_channel().write() {
+        let id = ChannelID::from_u32(channel_id)?;
+        if let IoctlParam::J1962_PINS = param_name {
+            return ChannelComm::set_channel_pins(id, value)
+        }
+        match id.get_channel().write() {
             Ok(mut channel) => {
                 if let Some(c) = channel.as_mut() {
                     c.ioctl_set_config(param_name, value)
@@ -175,6 +286,22 @@ impl ChannelComm {
         }
     }
 
+    pub fn ioctl_cmd(channel_id: u32, ioctl_id: IoctlID, input: &[u8]) -> Result<Vec<u8>> {
+        match ChannelID::from_u32(channel_id)?.get_channel().write() {
+            Ok(mut channel) => {
+                if let Some(c) = channel.as_mut() {
+                    c.ioctl_cmd(ioctl_id, input)
+                } else {
+                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+                }
+            }
+            Err(e) => {
+                set_error_string(format!("Write guard failed: {}", e));
+                Err(PassthruError::ERR_FAILED)
+            }
+        }
+    }
+
     pub fn remove_filter(channel_id: u32, filter_id: u32) -> Result<()> {
         match ChannelID::from_u32(channel_id)?.get_channel().write() {
             Ok(mut channel) => {
@@ -211,11 +338,30 @@ impl ChannelComm {
         }
     }
 
+    /// Starts recording the channel's CAN frames to a file, replacing any recording in progress
+    pub fn start_recording(channel_id: u32, format: RecordFormat, path: &std::path::Path) -> Result<()> {
+        match ChannelID::from_u32(channel_id)?.get_channel().write().unwrap().as_mut() {
+            Some(c) => c.start_recording(format, path),
+            None => Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+        }
+    }
+
+    pub fn stop_recording(channel_id: u32) -> Result<()> {
+        match ChannelID::from_u32(channel_id)?.get_channel().write().unwrap().as_mut() {
+            Some(c) => {
+                c.recorder = None;
+                Ok(())
+            },
+            None => Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+        }
+    }
+
     pub fn read_channel_data(channel_id: u32) -> Result<Option<PASSTHRU_MSG>> {
-        let channel = ChannelID::from_u32(channel_id)?.get_channel();
+        let (id, logical) = ChannelComm::split_channel_id(channel_id)?;
+        let channel = id.get_channel();
 
         if let Some(c) = channel.read().unwrap().as_ref() {
-            if c.rx_available() == 0 {
+            if c.rx_available(logical)? == 0 {
                 return Ok(None)
             }
         } else {
@@ -224,7 +370,7 @@ impl ChannelComm {
 
         match channel.write() {
             Ok(mut c) => {
-                Ok(c.as_mut().unwrap().pop_rx_queue())
+                Ok(c.as_mut().unwrap().pop_rx_queue(logical))
             },
             Err(e) => {
                 set_error_string(format!("Write guard failed: {}", e));
@@ -233,15 +379,85 @@ impl ChannelComm {
         }
     }
 
+    /// Returns true if received messages were dropped since this was last called, as the Rx queue was full
+    pub fn take_rx_overflow(channel_id: u32) -> Result<bool> {
+        let (id, _) = ChannelComm::split_channel_id(channel_id)?;
+        match id.get_channel().write() {
+            Ok(mut channel) => match channel.as_mut() {
+                Some(c) => Ok(c.take_rx_overflow()),
+                None => Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+            },
+            Err(e) => {
+                set_error_string(format!("Write guard failed: {}", e));
+                Err(PassthruError::ERR_FAILED)
+            }
+        }
+    }
+
+    /// Returns how many messages are waiting to be read on a channel
+    #[cfg(feature = "v0500")]
+    pub fn rx_available(channel_id: u32) -> Result<usize> {
+        let (id, logical) = ChannelComm::split_channel_id(channel_id)?;
+        match id.get_channel().read().unwrap().as_ref() {
+            Some(c) => c.rx_available(logical),
+            None => Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+        }
+    }
+
+    /// Creates a v05.00 ISO 15765 logical channel on a physical channel
+    /// # Returns
+    /// Logical channel ID if operation was OK
+    #[cfg(feature = "v0500")]
+    pub fn create_logical_channel(physical_id: u32, local_addr: &[u8], remote_addr: &[u8]) -> Result<u32> {
+        match ChannelID::from_u32(physical_id)?.get_channel().write() {
+            Ok(mut channel) => {
+                if let Some(c) = channel.as_mut() {
+                    c.add_logical_channel(local_addr, remote_addr)
+                        .map(|filter_id| LOGICAL_CHANNEL_ID_START + physical_id * MAX_FILTERS_PER_CHANNEL as u32 + filter_id)
+                } else {
+                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+                }
+            }
+            Err(e) => {
+                set_error_string(format!("Write guard failed: {}", e));
+                Err(PassthruError::ERR_FAILED)
+            }
+        }
+    }
+
+    #[cfg(feature = "v0500")]
+    pub fn destroy_logical_channel(channel_id: u32) -> Result<()> {
+        let (id, logical) = ChannelComm::split_channel_id(channel_id)?;
+        let logical = logical.ok_or(PassthruError::ERR_INVALID_CHANNEL_ID)?;
+        match id.get_channel().write() {
+            Ok(mut channel) => {
+                if let Some(c) = channel.as_mut() {
+                    c.remove_logical_channel(logical)
+                } else {
+                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+                }
+            }
+            Err(e) => {
+                set_error_string(format!("Write guard failed: {}", e));
+                Err(PassthruError::ERR_FAILED)
+            }
+        }
+    }
+
     /// Used by the receiver thread running on the M2 to write data to our Rx buffer
     pub fn receive_channel_data(msg: &CommMsg) {
+        // Channel ID, then the Rx status, then the M2's timestamp, then the message data
+        if msg.args.len() < 9 {
+            log_warn(format!("Channel data from M2 is too short ({} bytes)", msg.args.len()));
+            return
+        }
         if let Ok(c) = ChannelID::from_u32(msg.args[0] as u32) {
             match c.get_channel().write() {
                 Ok(mut wg) => {
                     if let Some(channel) = wg.as_mut() {
-                        let tx_flags = LittleEndian::read_u32(&msg.args[1..5]);
-                        let data = &msg.args[5..];
-                        channel.on_receive_data(tx_flags, data)
+                        let rx_status = LittleEndian::read_u32(&msg.args[1..5]);
+                        let fw_time = LittleEndian::read_u32(&msg.args[5..9]);
+                        channel.on_receive_data(rx_status, fw_time, &msg.args[9..])
                     }
                 },
                 Err(_) => {
@@ -253,61 +469,295 @@ impl ChannelComm {
 }
 
 
-const MAX_QUEUE_MSGS: usize = 500;
-/// J2534 API Channel
+/// Rx queue size of a new channel (MACCHINA_RX_QUEUE_SIZE)
+const DEFAULT_RX_QUEUE_MSGS: usize = 500;
+
+/// Largest Rx queue a channel can have
+pub const MAX_RX_QUEUE_MSGS: u32 = 100_000;
+
+/// Bus speeds supported by single wire CAN (Normal and high speed mode)
+const SW_CAN_BAUD_RATES: [u32; 2] = [33_333, 83_333];
+
+/// Bus speeds defined by J1939 (J1939-11 and J1939-14)
+const J1939_BAUD_RATES: [u32; 2] = [250_000, 500_000];
+
+/// v05.00 ISO 15765 logical channel. On the M2 this is a flow control filter
+/// of the physical channel, so the physical channel does all the ISO-TP work
+#[derive(Debug, Clone)]
+struct LogicalChannel {
+    /// Address the ECU sends to us on (Filter pattern)
+    local_addr: Vec<u8>,
+    /// Address we send to the ECU on (Flow control ID)
+    remote_addr: Vec<u8>,
+}
+
+/// Message in a channel's Rx queue. Only the data received is stored,
+/// as a PASSTHRU_MSG is over 4KB
 #[derive(Debug, Clone)]
-struct Channel {
+struct RxMsg {
+    rx_status: u32,
+    timestamp: u32,
+    data: Box<[u8]>,
+}
+
+impl RxMsg {
+    fn to_msg(&self, protocol_id: u32) -> PASSTHRU_MSG {
+        let mut msg = PASSTHRU_MSG {
+            protocol_id,
+            rx_status: self.rx_status,
+            timestamp: self.timestamp,
+            data_size: self.data.len() as u32,
+            ..Default::default()
+        };
+        msg.data[..self.data.len()].copy_from_slice(&self.data);
+        msg
+    }
+}
+
+/// J2534 API Channel
+#[derive(Debug)]
+pub(crate) struct Channel {
     id: u32,
     protocol: Protocol,
     baud_rate: u32,
     flags: u32,
-    filters: [u8; MAX_FILTERS_PER_CHANNEL],
-    tx_data: VecDeque<PASSTHRU_MSG>, // 1000 Tx messages (~4MB)
-    rx_data: VecDeque<PASSTHRU_MSG>, // 1000 Rx messages (~4MB)
+    /// J1962 pins the channel is running on. None if this is a pin switched
+    /// channel and the application has not yet set J1962_PINS
+    pins: Option<J1962Pins>,
+    /// J1939 source address claimed by the M2 on this channel. Tracked here as the M2
+    /// reports the claim with an Rx indication, and J2534-2 errors don't fit in its status byte
+    j1939_address: Option<u8>,
+    /// Module address of the TP 2.0 connection on this channel. Tracked the same way as j1939_address
+    tp2_0_connection: Option<u8>,
+    filters: [Option<MsgFilter>; MAX_FILTERS_PER_CHANNEL],
+    /// Acceptance filters in the M2's mailboxes, on channels the driver filters itself
+    hw_filters: [Option<HwFilter>; MAX_HW_FILTERS],
+    /// v05.00 logical channels, indexed by the ID of the filter they run on
+    logical_channels: [Option<LogicalChannel>; MAX_FILTERS_PER_CHANNEL],
+    /// Records the channel's CAN frames to a file, if recording was switched on
+    recorder: Option<Arc<Mutex<TrafficRecorder>>>,
+    /// Rx timestamps are relative to when the channel was connected
+    timebase: Timebase,
+    /// Messages waiting to be sent by the channel's Tx thread
+    tx_queue: Arc<TxQueue>,
+    rx_data: VecDeque<RxMsg>,
+    /// Most messages rx_data can hold (MACCHINA_RX_QUEUE_SIZE)
+    rx_queue_size: usize,
+    /// Messages dropped as rx_data was full (MACCHINA_RX_DROPPED)
+    rx_dropped: u32,
+    /// Messages were dropped since the application last read messages
+    rx_overflowed: bool,
+    /// The M2's CAN controller only listens to the bus (MACCHINA_LISTEN_ONLY), so nothing can be sent
+    listen_only: bool,
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.tx_queue.stop();
+    }
 }
 
 impl Channel {
     pub fn new(id: u32, protocol: Protocol, baud_rate: u32, flags: u32) -> Result<Self> {
+        if matches!(protocol, Protocol::SW_CAN_PS | Protocol::SW_ISO15765_PS) && !SW_CAN_BAUD_RATES.contains(&baud_rate) {
+            set_error_string(format!("Single wire CAN cannot run at {}bps", baud_rate));
+            return Err(PassthruError::ERR_INVALID_BAUDRATE)
+        }
+        if matches!(protocol, Protocol::J1939_PS) && !J1939_BAUD_RATES.contains(&baud_rate) {
+            set_error_string(format!("J1939 cannot run at {}bps", baud_rate));
+            return Err(PassthruError::ERR_INVALID_BAUDRATE)
+        }
+        // The M2 detects the baud rate when the channel opens
+        if baud_rate == 0 && !matches!(protocol, Protocol::CAN | Protocol::CAN_PS) {
+            set_error_string(format!("{} cannot detect its baud rate", protocol));
+            return Err(PassthruError::ERR_INVALID_BAUDRATE)
+        }
+        let listen_only = flags & ConnectFlags::MACCHINA_LISTEN_ONLY as u32 != 0;
+        if listen_only && !matches!(protocol, Protocol::CAN | Protocol::CAN_PS) {
+            set_error_string(format!("{} channels cannot be listen only", protocol));
+            return Err(PassthruError::ERR_INVALID_FLAGS)
+        }
+        let mut channel = Self {
+            id,
+            protocol,
+            baud_rate,
+            flags,
+            pins: None,
+            j1939_address: None,
+            tp2_0_connection: None,
+            filters: Default::default(),
+            hw_filters: [None; MAX_HW_FILTERS],
+            logical_channels: Default::default(),
+            recorder: None,
+            timebase: Timebase::new(),
+            tx_queue: TxQueue::start(id),
+            rx_data: VecDeque::new(),
+            rx_queue_size: DEFAULT_RX_QUEUE_MSGS,
+            rx_dropped: 0,
+            rx_overflowed: false,
+            listen_only,
+        };
+        // Pin switched channels are only opened on the M2 once the application tells us which pins to use
+        if let Some(p) = pins::get_fixed_pins(protocol) {
+            channel.open_on_m2(p)?;
+            channel.pins = Some(p);
+        } else if pins::is_pin_switched(protocol) {
+            log_debug(format!("Channel {} ({}) will be opened once J1962_PINS is set", id, protocol));
+        } else {
+            channel.open_on_m2(J1962Pins::new(0, 0))?;
+        }
+        channel.start_configured_recording();
+        Ok(channel)
+    }
+
+    /// Starts recording if RECORD-FORMAT and RECORD-DIR are set in the driver config.
+    /// Each channel records to <RECORD-DIR>/<protocol>_<channel ID>.<format extension>
+    fn start_configured_recording(&mut self) {
+        let format = match get_setting("RECORD-FORMAT") {
+            Some(f) => match RecordFormat::from_name(&f) {
+                Some(x) => x,
+                None => {
+                    log_warn(format!("RECORD-FORMAT {} is not candump, asc or pcap", f));
+                    return
+                }
+            },
+            None => return
+        };
+        let dir = match get_setting("RECORD-DIR") {
+            Some(d) => std::path::PathBuf::from(d),
+            None => {
+                log_warn_str("RECORD-FORMAT is set, but RECORD-DIR is not");
+                return
+            }
+        };
+        if !recorder::can_record(self.protocol) {
+            log_debug(format!("Channel {} ({}) does not carry CAN frames, not recording it", self.id, self.protocol));
+            return
+        }
+        let path = dir.join(format!("{:?}_{}.{}", self.protocol, self.id, format.extension()));
+        let _ = self.start_recording(format, &path);
+    }
+
+    pub fn start_recording(&mut self, format: RecordFormat, path: &std::path::Path) -> Result<()> {
+        if !recorder::can_record(self.protocol) {
+            set_error_string(format!("{} channels cannot be recorded, only CAN channels", self.protocol));
+            return Err(PassthruError::ERR_NOT_SUPPORTED)
+        }
+        match TrafficRecorder::create(path, format, self.id) {
+            Ok(r) => {
+                log_info(format!("Channel {} recording to {}", self.id, path.display()));
+                self.recorder = Some(Arc::new(Mutex::new(r)));
+                Ok(())
+            },
+            Err(e) => {
+                log_error(format!("Cannot create record file {}: {}", path.display(), e));
+                set_error_string(format!("Cannot create record file: {}", e));
+                Err(PassthruError::ERR_FAILED)
+            }
+        }
+    }
+
+    fn record(&self, dir: Direction, msg: &PASSTHRU_MSG, flags: u32) {
+        if let Some(r) = &self.recorder {
+            r.lock().unwrap().record(dir, msg, flags);
+        }
+    }
+
+    fn open_on_m2(&mut self, pins: J1962Pins) -> Result<()> {
         // First arg id (u32)
         // Second arg protocol (RAW)
         // Third arg baud rate
         // fourth arg flags
+        // fifth arg J1962 pins
         let mut dst: Vec<u8> = Vec::new();
-        for arg in [id, protocol as u32, baud_rate, flags].iter() {
+        for arg in [self.id, self.protocol as u32, self.baud_rate, self.flags, pins.to_raw()].iter() {
             dst.write_u32::<LittleEndian>(*arg).unwrap();
         }
-        log_debug(format!("Requesting channel open. ID: {}, Protocol: {:?}, baud: {}, flags: 0x{:04X}", id, protocol, baud_rate, flags));
+        log_debug(format!("Requesting channel open. ID: {}, Protocol: {:?}, baud: {}, flags: 0x{:04X}, {}", self.id, self.protocol, self.baud_rate, self.flags, pins));
         let mut msg = CommMsg::new_with_args(MsgType::OpenChannel, dst.as_mut_slice());
+        // M2 listens at each baud rate in turn when detecting it
+        let timeout = if self.baud_rate == 0 { 2000 } else { 100 };
         run_on_m2(|dev |{
-            match dev.write_and_read_ptcmd(&mut msg, 100) {
+            match dev.write_and_read_ptcmd(&mut msg, timeout) {
                 M2Resp::Ok(_) => {
                     log_debug_str("M2 opened channel!");
-                    Ok(Self{
-                        id, 
-                        protocol, 
-                        baud_rate, 
-                        flags, 
-                        filters: [0x00; MAX_FILTERS_PER_CHANNEL], 
-                        tx_data: VecDeque::new(), 
-                        rx_data: VecDeque::new(),
-                    })
+                    Ok(())
                 },
                 M2Resp::Err{status, string} => {
-                    log_error(format!("M2 failed to open channel {} (Status {:?}): {}", id, status, string));
-                    set_error_string(string);
+                    log_error(format!("M2 failed to open channel {} (Status {:?}): {}", self.id, status, string));
+                    set_firmware_error(string);
                     Err(status)
                 }
             }
-        })
+        })?;
+        if self.baud_rate == 0 {
+            self.baud_rate = self.get_m2_config(IoctlParam::DATA_RATE)?;
+            log_info(format!("Channel {} detected baud rate {}bps", self.id, self.baud_rate));
+        }
+        Ok(())
     }
 
-    pub fn add_filter(&mut self, filter_type: FilterType, mask_bytes: &[u8], pattern_bytes: &[u8], fc_bytes: &[u8]) -> Result<u32> {
-        let free_id = self.filters.iter().enumerate().find(| (_, v) | {**v == 0}).map_or(99, |x| x.0);
+    /// Sets the J1962 pins of a pin switched channel, and opens the channel on the M2
+    pub fn set_pins(&mut self, pins: J1962Pins) -> Result<()> {
+        if !pins::is_pin_switched(self.protocol) {
+            set_error_string(format!("{} does not support pin switching", self.protocol));
+            return Err(PassthruError::ERR_NOT_SUPPORTED)
+        }
+        if let Some(current) = self.pins {
+            if current == pins {
+                return Ok(())
+            }
+            set_error_string(format!("Channel {} is already running on {}", self.id, current));
+            return Err(PassthruError::ERR_PIN_INVALID)
+        }
+        if pins::get_interface(self.protocol, pins).is_none() {
+            set_error_string(format!("M2 cannot run {} on J1962 {}", self.protocol, pins));
+            return Err(PassthruError::ERR_PIN_INVALID)
+        }
+        self.open_on_m2(pins)?;
+        self.pins = Some(pins);
+        Ok(())
+    }
 
-        if free_id == 99 {
-            return Err(PassthruError::ERR_EXCEEDED_LIMIT)
+    /// Pin switched channels cannot be used until J1962_PINS is set
+    fn check_pins_set(&self) -> Result<()> {
+        if self.pins.is_none() && pins::is_pin_switched(self.protocol) {
+            set_error_string(format!("J1962_PINS has not been set on channel {}", self.id));
+            return Err(PassthruError::ERR_PIN_INVALID)
+        }
+        Ok(())
+    }
+
+    pub fn add_filter(&mut self, filter_type: FilterType, mask_bytes: &[u8], pattern_bytes: &[u8], fc_bytes: &[u8]) -> Result<u32> {
+        self.check_pins_set()?;
+        let free_id = self.filters.iter().position(|f| f.is_none()).ok_or(PassthruError::ERR_EXCEEDED_LIMIT)?;
+        if let Protocol::J1939_PS = self.protocol {
+            // J1939 filters are applied to the message header (PGN, priority, and addresses)
+            if filter_type == FilterType::FLOW_CONTROL_FILTER || mask_bytes.len() > J1939_HEADER_SIZE || mask_bytes.len() != pattern_bytes.len() {
+                set_error_string(format!("J1939 filters must be a pass or block filter of up to {} bytes", J1939_HEADER_SIZE));
+                return Err(PassthruError::ERR_INVALID_MSG)
+            }
+        }
+        if filters::host_filtered(self.protocol) {
+            if filter_type == FilterType::FLOW_CONTROL_FILTER || mask_bytes.len() != pattern_bytes.len() {
+                set_error_string(format!("{} filters must be a pass or block filter, with a mask and pattern of the same size", self.protocol));
+                return Err(PassthruError::ERR_INVALID_MSG)
+            }
+            self.filters[free_id] = Some(MsgFilter::new(filter_type, mask_bytes, pattern_bytes));
+            if let Err(e) = self.sync_hw_filters() {
+                self.filters[free_id] = None;
+                return Err(e)
+            }
+            log_debug(format!("Set {} {} on channel {}. Mask: {:02X?}, Pattern: {:02X?}", filter_type, free_id, self.id, mask_bytes, pattern_bytes));
+            return Ok(free_id as u32)
         }
+        self.set_m2_filter(free_id, filter_type, mask_bytes, pattern_bytes, fc_bytes)?;
+        self.filters[free_id] = Some(MsgFilter::new(filter_type, mask_bytes, pattern_bytes));
+        Ok(free_id as u32)
+    }
 
+    /// Sets a filter in one of the M2's mailboxes
+    fn set_m2_filter(&self, free_id: usize, filter_type: FilterType, mask_bytes: &[u8], pattern_bytes: &[u8], fc_bytes: &[u8]) -> Result<()> {
         // Mask and pattern MUST be present, Flow control is only if FilterType is ISO15765
         // Create our args
         // First arg: channel id (u32)
@@ -329,12 +779,11 @@ impl Channel {
             match dev.write_and_read_ptcmd(&mut msg, 250) {
                 M2Resp::Ok(_) => {
                     log_debug(format!("M2 set filter {} on channel {}!", free_id, self.id));
-                    self.filters[free_id] = 1; // Mark it as used
-                    Ok(free_id as u32)
+                    Ok(())
                 },
                 M2Resp::Err{status, string} => {
                     log_error(format!("M2 failed to set filter {} on channel {} (Status {:?}): {}", free_id, self.id, status, string));
-                    set_error_string(string);
+                    set_firmware_error(string);
                     Err(status)
                 }
             }
@@ -342,9 +791,26 @@ impl Channel {
     }
 
     pub fn remove_filter(&mut self, id: usize) -> Result<()> {
-        if self.filters[id] == 0 {
+        if self.filters.get(id).and_then(|f| f.as_ref()).is_none() {
             return Err(PassthruError::ERR_INVALID_MSG_ID)
         }
+        if filters::host_filtered(self.protocol) {
+            let removed = self.filters[id].take();
+            if let Err(e) = self.sync_hw_filters() {
+                self.filters[id] = removed;
+                return Err(e)
+            }
+            log_debug(format!("Removed channel {} filter {}", self.id, id));
+            return Ok(())
+        }
+        self.remove_m2_filter(id)?;
+        self.filters[id] = None;
+        self.logical_channels[id] = None;
+        Ok(())
+    }
+
+    /// Removes a filter from one of the M2's mailboxes
+    fn remove_m2_filter(&self, id: usize) -> Result<()> {
         let mut dst: Vec<u8> = Vec::new();
         for arg in [self.id, id as u32].iter() {
             dst.write_u32::<LittleEndian>(*arg).unwrap();
@@ -355,19 +821,45 @@ impl Channel {
             match dev.write_and_read_ptcmd(&mut msg, 100) {
                 M2Resp::Ok(_) => {
                     log_debug_str("M2 closed filter OK!");
-                    self.filters[id] = 0; // Mark it as used
                     Ok(())
                 },
                 M2Resp::Err{status, string} => {
                     log_error(format!("M2 failed to close filter {} on channel {} (Status {:?}): {}", id, self.id, status, string));
-                    set_error_string(string);
+                    set_firmware_error(string);
                     Err(status)
                 }
             }
         })
     }
 
+    /// Programs the M2's mailboxes with the acceptance filters needed for the channel's filters.
+    /// Mailboxes that already have a filter still needed are left alone, so no messages are missed
+    fn sync_hw_filters(&mut self) -> Result<()> {
+        let wanted = filters::hw_filters(self.filters.iter().flatten());
+        for slot in 0..MAX_HW_FILTERS {
+            if let Some(f) = self.hw_filters[slot] {
+                if !wanted.contains(&f) {
+                    self.remove_m2_filter(slot)?;
+                    self.hw_filters[slot] = None;
+                }
+            }
+        }
+        for f in wanted {
+            if self.hw_filters.contains(&Some(f)) {
+                continue
+            }
+            let slot = self.hw_filters.iter().position(|h| h.is_none()).ok_or(PassthruError::ERR_EXCEEDED_LIMIT)?;
+            self.set_m2_filter(slot, FilterType::PASS_FILTER, &f.mask.to_be_bytes(), &f.id.to_be_bytes(), &[])?;
+            self.hw_filters[slot] = Some(f);
+        }
+        Ok(())
+    }
+
     pub fn destroy(&self) -> Result<()> {
+        self.tx_queue.stop(); // Anything still queued is not sent once the channel closes
+        if self.pins.is_none() && pins::is_pin_switched(self.protocol) {
+            return Ok(()) // Never opened on the M2
+        }
         log_debug(format!("Requesting channel destroy. ID: {}", self.id));
         let mut dst: Vec<u8> = Vec::new();
         dst.write_u32::<LittleEndian>(self.id).unwrap();
@@ -383,73 +875,266 @@ impl Channel {
         })
     }
 
-    pub fn transmit_data(&self, ptmsg: &PASSTHRU_MSG, require_response: bool) -> Result<()> {
+    /// Checks a message can be sent on the channel, and builds the message for the Tx queue
+    fn build_tx(&self, ptmsg: &PASSTHRU_MSG, reply: Option<TxReply>) -> Result<TxMsg> {
         if ptmsg.protocol_id != self.protocol as u32 {
             return Err(PassthruError::ERR_MSG_PROTOCOL_ID);
         }
+        self.check_pins_set()?;
+        if self.listen_only {
+            set_error_string(format!("Channel {} is listen only, so cannot send messages", self.id));
+            return Err(PassthruError::ERR_NOT_SUPPORTED)
+        }
+        match self.protocol {
+            Protocol::J1939_PS => self.check_j1939_msg(ptmsg)?,
+            Protocol::TP2_0_PS => self.check_tp2_0_msg(ptmsg)?,
+            _ => {}
+        }
 
         // Build Tx message
         let mut dst: Vec<u8> = Vec::new();
         for arg in [self.id, ptmsg.tx_flags].iter() {
             dst.write_u32::<LittleEndian>(*arg).unwrap();
         }
-        dst.extend_from_slice(&ptmsg.data[0..ptmsg.data_size as usize]);
-        let mut msg = CommMsg::new_with_args(MsgType::TransmitChannelData, dst.as_mut_slice());
-        log_debug(format!("Channel {} writing message: {}. Response required?: {}", self.id, ptmsg, require_response));
-        run_on_m2(|dev| {
-            if require_response {
-                match dev.write_and_read_ptcmd(&mut msg, 100) {
-                    M2Resp::Ok(_) => Ok(()),
-                    M2Resp::Err{status, string}  => {
-                        log_error(format!("M2 failed to write data to channel {} (Status {:?}): {}", self.id, status, string));
-                        set_error_string(string);
-                        Err(status)
-                    }
-                }
-            } else {
-                dev.write_comm_struct(msg)
-            }
+        dst.extend_from_slice(ptmsg.data());
+        log_debug(format!("Channel {} queueing message: {}. Waited on?: {}", self.id, ptmsg, reply.is_some()));
+        Ok(TxMsg {
+            msg: CommMsg::new_with_args(MsgType::TransmitChannelData, dst.as_mut_slice()),
+            ptmsg: *ptmsg,
+            recorder: self.recorder.clone(),
+            reply,
         })
     }
 
-    pub fn pop_rx_queue(&mut self) -> Option<PASSTHRU_MSG> {
-        self.rx_data.pop_front()
+    /// J1939 messages must start with a valid header, fit within a single transport protocol session,
+    /// and be sent from the address the channel has claimed
+    fn check_j1939_msg(&self, ptmsg: &PASSTHRU_MSG) -> Result<()> {
+        let size = ptmsg.data_size as usize;
+        let header = match J1939Header::from_bytes(&ptmsg.data[..size.min(ptmsg.data.len())]) {
+            Some(h) if size <= J1939_HEADER_SIZE + J1939_MAX_PAYLOAD => h,
+            _ => {
+                set_error_string(format!("J1939 message must be between {} and {} bytes", J1939_HEADER_SIZE, J1939_HEADER_SIZE + J1939_MAX_PAYLOAD));
+                return Err(PassthruError::ERR_INVALID_MSG)
+            }
+        };
+        if self.j1939_address != Some(header.source) {
+            set_error_string(format!("J1939 address 0x{:02X} has not been claimed", header.source));
+            return Err(PassthruError::ERR_ADDRESS_NOT_CLAIMED)
+        }
+        Ok(())
+    }
+
+    /// TP 2.0 messages must be sent to a module we have an open connection with
+    fn check_tp2_0_msg(&self, ptmsg: &PASSTHRU_MSG) -> Result<()> {
+        let size = ptmsg.data_size as usize;
+        let addr = match tp2_0_module_address(&ptmsg.data[..size.min(ptmsg.data.len())]) {
+            Some(a) if size > TP2_0_HEADER_SIZE => a,
+            _ => {
+                set_error_string("TP 2.0 message must contain a module address and at least 1 byte of data".into());
+                return Err(PassthruError::ERR_INVALID_MSG)
+            }
+        };
+        if self.tp2_0_connection != Some(addr) {
+            set_error_string(format!("No TP 2.0 connection to module 0x{:02X}", addr));
+            return Err(PassthruError::ERR_NO_CONNECTION_ESTABLISHED)
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "v0500")]
+    pub fn add_logical_channel(&mut self, local_addr: &[u8], remote_addr: &[u8]) -> Result<u32> {
+        if !matches!(self.protocol, Protocol::ISO15765 | Protocol::ISO15765_PS | Protocol::SW_ISO15765_PS) {
+            set_error_string(format!("Logical channels need an ISO 15765 physical channel. Channel {} is {}", self.id, self.protocol));
+            return Err(PassthruError::ERR_INVALID_PROTOCOL_ID)
+        }
+        if self.logical_channels.iter().flatten().any(|l| l.local_addr == local_addr || l.remote_addr == remote_addr) {
+            return Err(PassthruError::ERR_NOT_UNIQUE)
+        }
+        let mask = vec![0xFF; local_addr.len()];
+        let filter_id = self.add_filter(FilterType::FLOW_CONTROL_FILTER, &mask, local_addr, remote_addr)?;
+        self.logical_channels[filter_id as usize] = Some(LogicalChannel {
+            local_addr: local_addr.to_vec(),
+            remote_addr: remote_addr.to_vec(),
+        });
+        Ok(filter_id)
+    }
+
+    #[cfg(feature = "v0500")]
+    pub fn remove_logical_channel(&mut self, id: usize) -> Result<()> {
+        if self.logical_channels[id].is_none() {
+            return Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+        }
+        self.remove_filter(id)
+    }
+
+    /// Transmits a message from a logical channel via this (physical) channel
+    fn build_logical_tx(&self, id: usize, ptmsg: &PASSTHRU_MSG, reply: Option<TxReply>) -> Result<TxMsg> {
+        let logical = self.logical_channels[id].as_ref().ok_or(PassthruError::ERR_INVALID_CHANNEL_ID)?;
+        if ptmsg.protocol_id != ISO15765_LOGICAL {
+            return Err(PassthruError::ERR_MSG_PROTOCOL_ID);
+        }
+        let size = (ptmsg.data_size as usize).min(ptmsg.data.len());
+        if !ptmsg.data[..size].starts_with(&logical.remote_addr) {
+            set_error_string(format!("Logical channel messages must be sent to {:02X?}", logical.remote_addr));
+            return Err(PassthruError::ERR_INVALID_MSG);
+        }
+        let mut msg = *ptmsg;
+        msg.protocol_id = self.protocol as u32;
+        self.build_tx(&msg, reply)
+    }
+
+    /// Returns if a received message belongs to a logical channel, or to the physical channel
+    /// if logical is None. The physical channel gets everything no logical channel wants
+    fn is_rx_msg_for(&self, msg: &RxMsg, logical: Option<usize>) -> bool {
+        let data = &msg.data;
+        match logical {
+            Some(id) => self.logical_channels[id].as_ref().is_some_and(|l| data.starts_with(&l.local_addr)),
+            None => !self.logical_channels.iter().flatten().any(|l| data.starts_with(&l.local_addr))
+        }
+    }
+
+    pub fn pop_rx_queue(&mut self, logical: Option<usize>) -> Option<PASSTHRU_MSG> {
+        let idx = self.rx_data.iter().position(|m| self.is_rx_msg_for(m, logical))?;
+        let msg = self.rx_data.remove(idx)?;
+        Some(msg.to_msg(if logical.is_some() { ISO15765_LOGICAL } else { self.protocol as u32 }))
     }
 
-    pub fn rx_available(&self) -> usize {
-        self.rx_data.len()
+    /// Returns true if messages were dropped since this was last called
+    pub fn take_rx_overflow(&mut self) -> bool {
+        std::mem::replace(&mut self.rx_overflowed, false)
     }
 
-    pub fn on_receive_data(&mut self, rx_status: u32, data: &[u8]) {
-        if self.rx_data.len() < MAX_QUEUE_MSGS {
-            let mut msg = PASSTHRU_MSG {
-                data_size: data.len() as u32,
-                rx_status,
-                protocol_id: self.protocol as u32,
-                timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_micros() as u32,
-                ..Default::default()
-            };
-            msg.data[..data.len()].copy_from_slice(data);
+    pub fn rx_available(&self, logical: Option<usize>) -> Result<usize> {
+        if let Some(id) = logical {
+            if self.logical_channels[id].is_none() {
+                return Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+            }
+        }
+        Ok(self.rx_data.iter().filter(|m| self.is_rx_msg_for(m, logical)).count())
+    }
+
+    /// Queues data the M2 received on the channel
+    /// # Params
+    /// * rx_status - RxStatus of the message
+    /// * fw_time - M2's timestamp of when it received the message (Or sent it, for Tx indications)
+    /// * data - Message data
+    pub fn on_receive_data(&mut self, rx_status: u32, fw_time: u32, data: &[u8]) {
+        match self.protocol {
+            Protocol::J1939_PS => self.update_j1939_address(rx_status, data),
+            Protocol::TP2_0_PS => self.update_tp2_0_connection(rx_status, data),
+            _ => {}
+        }
+        if data.len() > PASSTHRU_MSG_DATA_SIZE {
+            log_warn(format!("Channel {} received {} bytes, more than a message can hold", self.id, data.len()));
+            return
+        }
+        let flags = RxFlag::from_bits_truncate(rx_status);
+        let loopback = flags.contains(RxFlag::TX_MSG_TYPE);
+        // Bus errors and state changes are not CAN frames, so are never filtered or recorded
+        let bus_event = flags.intersects(RxFlag::MACCHINA_BUS_ERROR | RxFlag::MACCHINA_BUS_STATE);
+        // The M2's acceptance filters can be wider than the channel's filters
+        if filters::host_filtered(self.protocol) && !loopback && !bus_event && !filters::passes(self.filters.iter().flatten(), data) {
+            return
+        }
+        let msg = RxMsg { rx_status, timestamp: self.timebase.stamp(fw_time), data: data.into() };
+        // Loopback echoes of our own messages were already recorded when they were sent
+        if self.recorder.is_some() && !loopback && !bus_event {
+            self.record(Direction::Rx, &msg.to_msg(self.protocol as u32), rx_status);
+        }
+        if self.rx_data.len() < self.rx_queue_size {
             //log_debug(format!("Channel {} buffering message. RxStatus: {:08X}, data: {:02X?}", self.id, rx_status, &data));
             self.rx_data.push_back(msg);
         } else {
-            // Data is lost if queue is too big!
-            log_warn(format!("Rx queue in channel {} is full. Data has been lost!", self.id));
+            // Data is lost if queue is too big! The application is told on its next read
+            if !self.rx_overflowed {
+                log_warn(format!("Rx queue in channel {} is full ({} messages). Data has been lost!", self.id, self.rx_queue_size));
+            }
+            self.rx_overflowed = true;
+            self.rx_dropped = self.rx_dropped.saturating_add(1);
+        }
+    }
+
+    /// Sets the size of the Rx queue. Messages past the new size are dropped
+    fn set_rx_queue_size(&mut self, size: u32) -> Result<()> {
+        if size == 0 || size > MAX_RX_QUEUE_MSGS {
+            set_error_string(format!("Rx queue size must be between 1 and {} messages", MAX_RX_QUEUE_MSGS));
+            return Err(PassthruError::ERR_INVALID_IOCTL_VALUE)
+        }
+        self.rx_queue_size = size as usize;
+        if self.rx_data.len() > self.rx_queue_size {
+            let dropped = self.rx_data.len() - self.rx_queue_size;
+            self.rx_data.truncate(self.rx_queue_size);
+            self.rx_overflowed = true;
+            self.rx_dropped = self.rx_dropped.saturating_add(dropped as u32);
+        }
+        Ok(())
+    }
+
+
+    /// Tracks the claimed J1939 address from the M2's address claim indications
+    fn update_j1939_address(&mut self, rx_status: u32, data: &[u8]) {
+        let flags = RxFlag::from_bits_truncate(rx_status);
+        if let Some(header) = J1939Header::from_bytes(data) {
+            if flags.contains(RxFlag::J1939_ADDRESS_CLAIMED) {
+                log_debug(format!("Channel {} claimed J1939 address 0x{:02X}", self.id, header.source));
+                self.j1939_address = Some(header.source);
+            } else if flags.contains(RxFlag::J1939_ADDRESS_LOST) {
+                log_warn(format!("Channel {} lost J1939 address 0x{:02X}", self.id, header.source));
+                self.j1939_address = None;
+            }
         }
     }
 
+    /// Tracks the TP 2.0 connection state from the M2's connection indications
+    fn update_tp2_0_connection(&mut self, rx_status: u32, data: &[u8]) {
+        let flags = RxFlag::from_bits_truncate(rx_status);
+        if let Some(addr) = tp2_0_module_address(data) {
+            if flags.contains(RxFlag::CONNECTION_ESTABLISHED) {
+                log_debug(format!("Channel {} connected to TP 2.0 module 0x{:02X}", self.id, addr));
+                self.tp2_0_connection = Some(addr);
+            } else if flags.contains(RxFlag::CONNECTION_LOST) {
+                log_warn(format!("Channel {} lost TP 2.0 connection to module 0x{:02X}", self.id, addr));
+                self.tp2_0_connection = None;
+            }
+        }
+    }
 
     pub fn clear_rx_buffer(&mut self) -> PassthruError {
         self.rx_data.clear();
+        // The application discarded the messages, so nothing it wanted was lost
+        self.rx_overflowed = false;
         PassthruError::STATUS_NOERROR
     }
 
     pub fn clear_tx_buffer(&mut self) -> PassthruError {
-        // nothing to do
+        self.tx_queue.clear();
         PassthruError::STATUS_NOERROR
     }
 
+    /// Only raw CAN channels can send bus errors and state changes (MACCHINA_ERROR_FRAMES)
+    fn check_error_frames_supported(&self) -> Result<()> {
+        if matches!(self.protocol, Protocol::CAN | Protocol::CAN_PS) {
+            return Ok(())
+        }
+        set_error_string(format!("MACCHINA_ERROR_FRAMES is not supported on {} channels", self.protocol));
+        Err(PassthruError::ERR_NOT_SUPPORTED)
+    }
+
     pub fn ioctl_set_config(&mut self, pname: IoctlParam, pvalue: u32) -> Result<()> {
+        match pname {
+            IoctlParam::MACCHINA_RX_QUEUE_SIZE => return self.set_rx_queue_size(pvalue),
+            IoctlParam::MACCHINA_RX_DROPPED if pvalue == 0 => {
+                self.rx_dropped = 0;
+                return Ok(())
+            },
+            IoctlParam::MACCHINA_RX_DROPPED => {
+                set_error_string("MACCHINA_RX_DROPPED can only be reset to 0".into());
+                return Err(PassthruError::ERR_INVALID_IOCTL_VALUE)
+            },
+            IoctlParam::MACCHINA_ERROR_FRAMES => self.check_error_frames_supported()?,
+            _ => {}
+        }
+        self.check_pins_set()?;
         let mut dst: Vec<u8> = Vec::new();
         dst.push(self.id as u8);
         for arg in [pname as u32, pvalue].iter() {
@@ -462,14 +1147,81 @@ impl Channel {
                 M2Resp::Ok(_) => Ok(()),
                 M2Resp::Err{status, string}  => {
                     log_error(format!("M2 failed to set IOCTL {} (Status {:?}): {}", self.id, status, string));
-                    set_error_string(string);
+                    set_firmware_error(string);
                     Err(status)
                 }
             }
         })
     }
 
+    /// Runs an IOCTL on the M2 which is not a config get or set operation
+    /// # Params
+    /// * ioctl_id - IOCTL to run
+    /// * input - Input data for the IOCTL (Can be empty)
+    /// # Returns
+    /// Output data of the IOCTL
+    pub fn ioctl_cmd(&mut self, ioctl_id: IoctlID, input: &[u8]) -> Result<Vec<u8>> {
+        self.check_pins_set()?;
+        let mut timeout = 250;
+        match ioctl_id {
+            IoctlID::PROTECT_J1939_ADDR => self.j1939_address = None, // Until the M2 reports the new claim
+            IoctlID::TEARDOWN_CONNECTION => self.tp2_0_connection = None,
+            IoctlID::REQUEST_CONNECTION => timeout = 2000, // M2 blocks whilst the connection is set up
+            IoctlID::MACCHINA_DETECT_BAUD_RATE if !matches!(self.protocol, Protocol::CAN | Protocol::CAN_PS) => {
+                set_error_string(format!("{} cannot detect its baud rate", self.protocol));
+                return Err(PassthruError::ERR_NOT_SUPPORTED)
+            },
+            IoctlID::MACCHINA_DETECT_BAUD_RATE => timeout = 2000, // M2 listens at each baud rate in turn
+            IoctlID::MACCHINA_GET_BUS_STATS | IoctlID::MACCHINA_CLEAR_BUS_STATS if self.pins.and_then(|p| pins::get_interface(self.protocol, p)).is_none() => {
+                set_error_string(format!("{} does not run on a CAN bus", self.protocol));
+                return Err(PassthruError::ERR_NOT_SUPPORTED)
+            },
+            _ => {}
+        }
+        let mut dst: Vec<u8> = Vec::new();
+        dst.push(self.id as u8);
+        dst.write_u32::<LittleEndian>(ioctl_id as u32).unwrap();
+        dst.extend_from_slice(input);
+        let mut msg = CommMsg::new_with_args(MsgType::IoctlCmd, dst.as_mut_slice());
+        log_debug(format!("Channel {} running IOCTL: {}. Input: {:02X?}", self.id, ioctl_id, input));
+        let res = run_on_m2(|dev| {
+            match dev.write_and_read_ptcmd(&mut msg, timeout) {
+                M2Resp::Ok(v) => Ok(v),
+                M2Resp::Err{status, string}  => {
+                    log_error(format!("M2 failed to run IOCTL {} (Status {:?}): {}", ioctl_id, status, string));
+                    set_firmware_error(string);
+                    Err(status)
+                }
+            }
+        });
+        // The M2 only responds OK once the connection is up, so don't wait for the Rx indication
+        if let (Ok(_), IoctlID::REQUEST_CONNECTION) = (&res, ioctl_id) {
+            self.tp2_0_connection = input.first().copied();
+        }
+        if let (Ok(v), IoctlID::MACCHINA_DETECT_BAUD_RATE) = (&res, ioctl_id) {
+            if v.len() == 4 {
+                self.baud_rate = LittleEndian::read_u32(v);
+                log_info(format!("Channel {} detected baud rate {}bps", self.id, self.baud_rate));
+            }
+        }
+        res
+    }
+
     pub fn ioctl_get_config(&mut self, pname: IoctlParam) -> Result<u32> {
+        match pname {
+            IoctlParam::J1962_PINS => return Ok(self.pins.map_or(0, |p| p.to_raw())),
+            IoctlParam::MACCHINA_RX_QUEUE_SIZE => return Ok(self.rx_queue_size as u32),
+            IoctlParam::MACCHINA_RX_DROPPED => return Ok(self.rx_dropped),
+            IoctlParam::MACCHINA_ERROR_FRAMES => self.check_error_frames_supported()?,
+            IoctlParam::DATA_RATE => return Ok(self.baud_rate),
+            _ => {}
+        }
+        self.check_pins_set()?;
+        self.get_m2_config(pname)
+    }
+
+    /// Reads a config param from the channel on the M2
+    fn get_m2_config(&self, pname: IoctlParam) -> Result<u32> {
         let mut dst: Vec<u8> = Vec::new();
         dst.push(self.id as u8);
         for arg in [pname as u32].iter() {
@@ -491,7 +1243,7 @@ impl Channel {
                 },
                 M2Resp::Err{status, string}  => {
                     log_error(format!("M2 failed to get IOCTL {} (Status {:?}): {}", pname, status, string));
-                    set_error_string(string);
+                    set_firmware_error(string);
                     Err(status)
                 }
             }
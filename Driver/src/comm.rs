@@ -7,9 +7,9 @@ use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread::spawn;
 use std::sync::RwLock;
 use lazy_static::lazy_static;
-use crate::{channels, logger::{self, log_debug_str, log_error_str, log_m2_msg}};
+use crate::{capture::{self, Direction, ReplayPort}, channels, logger::{self, log_error_str, log_m2_msg}};
 use J2534Common::{PassthruError, Parsable};
-use crate::passthru_drv::set_error_string;
+use crate::error::set_error_string;
 use byteorder::{ByteOrder, WriteBytesExt, LittleEndian};
 
 #[cfg(windows)]
@@ -37,6 +37,46 @@ pub enum M2Resp {
     Err { status: PassthruError, string: String }
 }
 
+impl M2Resp {
+    /// Processes the M2's response to a command. The first arg is the status
+    /// (A PassthruError), followed by either the response data or an error string
+    pub fn from_msg(mut resp: CommMsg) -> Self {
+        if resp.args.is_empty() {
+            return M2Resp::Err { status: PassthruError::ERR_FAILED, string: "M2 response has no status".into() }
+        }
+        // Process the status of the message, this should be a PassthruError
+        let status = match PassthruError::from_raw(resp.args[0] as u32) {
+            Some(x) => x, // Error processed successfully!
+            None => {
+                // M2 responded with an error code not found in J2534 Spec??
+                return M2Resp::Err{ status: PassthruError::ERR_FAILED, string: format!("Unrecognized status {}", resp.args[0]) }
+            }
+        };
+        resp.args.drain(0..1); // Drain the first byte from args as that was the status ID
+        // Match the status returned
+        match status {
+            PassthruError::STATUS_NOERROR => { // Operation completed successfully
+                match resp.args.len() {
+                    1 => M2Resp::Ok(Vec::new()), // No args in M2's response
+                    _ => M2Resp::Ok(resp.args) // Store M2's args
+                }
+            },
+            _ => { // M2 returned an error!
+                // Check if M2 responded with an error string
+                let text = if resp.args.len() > 1 {
+                    // Yes, set the error string
+                    String::from_utf8_lossy(&resp.args).into_owned()
+                } else {
+                    // No error string
+                    String::new()
+                };
+                // Return The formatted error
+                M2Resp::Err { status, string: text }
+            }
+        }
+    }
+}
+
 pub struct MacchinaM2 {
     is_running: Arc<AtomicBool>,
     tx_send_queue: Sender<CommMsg>,
@@ -48,32 +88,58 @@ unsafe impl Sync for MacchinaM2{}
 
 type Result<T> = std::io::Result<T>;
 
+/// Byte stream to the M2. Either its serial port, or a ReplayPort
+pub trait Transport: Read + Write + Send {}
+impl<T: Read + Write + Send> Transport for T {}
+
+/// Reads an attribute from macchina.json. Numbers and booleans are returned as strings
 #[cfg(unix)]
-fn get_comm_port() -> Option<String> {
+pub fn get_setting(key: &str) -> Option<String> {
     if let Ok(content) = std::fs::read_to_string(shellexpand::tilde("~/.passthru/macchina.json").to_string()) {
         return match serde_json::from_str::<serde_json::Value>(content.as_str()) {
-            Ok(v) => v["COM-PORT"].as_str().map(String::from),
+            Ok(v) => match &v[key] {
+                serde_json::Value::String(s) => Some(shellexpand::tilde(s).to_string()),
+                serde_json::Value::Null => None,
+                x => Some(x.to_string())
+            },
             Err(_) => None
         }
     }
     None
 }
 
+/// Registry key the driver is installed under. v05.00 drivers are listed separately to 04.04 drivers
+#[cfg(all(windows, not(feature = "v0500")))]
+const REG_KEY: &str = "SOFTWARE\\WOW6432Node\\PassThruSupport.04.04\\Macchina-Passthru";
+#[cfg(all(windows, feature = "v0500"))]
+const REG_KEY: &str = "SOFTWARE\\WOW6432Node\\PassThruSupport.05.00\\Macchina-Passthru";
+
+/// Reads a string value from the driver's registry key
 #[cfg(windows)]
-fn get_comm_port() -> Option<String> {
-    if let Ok(reg) = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey("SOFTWARE\\WOW6432Node\\PassThruSupport.04.04\\Macchina-Passthru") {
-        logger::log_info_str("Found regkey");
-        return match reg.get_value("COM-PORT") {
-            Ok(s) => {
-                logger::log_info(format!("Com port is {}", s));
-                Some(s)
-            },
-            Err(_) => None
-        }
+pub fn get_setting(key: &str) -> Option<String> {
+    if let Ok(reg) = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey(REG_KEY) {
+        return reg.get_value(key).ok()
     }
     None
 }
 
+fn get_comm_port() -> Option<String> {
+    let port = get_setting("COM-PORT");
+    if let Some(p) = &port {
+        logger::log_info(format!("Com port is {}", p));
+    }
+    port
+}
+
+/// Checks if the M2's serial port is currently present on the system
+#[cfg(feature = "v0500")]
+pub fn is_port_present() -> bool {
+    match get_comm_port() {
+        Some(port) => serialport::available_ports().is_ok_and(|ports| ports.iter().any(|p| p.port_name == port)),
+        None => false
+    }
+}
+
 pub type PTResult<T> = std::result::Result<T, PassthruError>;
 pub fn run_on_m2<T, F: FnOnce(&MacchinaM2) -> PTResult<T>>(op: F) -> PTResult<T> {
     match M2.read() {
@@ -94,9 +160,20 @@ const MAX_BUFFER_SIZE: usize = 16;
 
 impl MacchinaM2 {
     pub fn open_connection() -> Result<Self> {
-        match get_comm_port() {
-            Some(s) => MacchinaM2::open_conn(s.as_str()),
-            None => Err(Error::new(ErrorKind::NotFound, "Cannot find COM-PORT attribute"))
+        let port = match get_comm_port() {
+            Some(s) => s,
+            None => return Err(Error::new(ErrorKind::NotFound, "Cannot find COM-PORT attribute"))
+        };
+        if let Some(path) = get_setting("CAPTURE-FILE") {
+            capture::start_capture(&path);
+        }
+        match port.strip_prefix(capture::REPLAY_PREFIX) {
+            Some(path) => {
+                // Replay a captured session instead of using an M2
+                let replay = ReplayPort::open(path)?;
+                MacchinaM2::start(Box::new(replay.clone()), Box::new(replay))
+            },
+            None => MacchinaM2::open_conn(port.as_str())
         }
     }
 
@@ -114,6 +191,15 @@ impl MacchinaM2 {
             },
             Err(e) => {return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Error opening port {}", e.to_string())));}
         };
+        let port_write = port.try_clone().unwrap();
+        MacchinaM2::start(Box::new(port), Box::new(port_write))
+    }
+
+    /// Starts the threads that talk to the M2
+    /// # Params
+    /// * port - Used by the reader thread
+    /// * port_write - Used by the writer thread
+    pub(crate) fn start(mut port: Box<dyn Transport>, mut port_write: Box<dyn Transport>) -> Result<Self> {
 
         // For data going from Caller -> M2
         let (send_tx, send_rx) : (Sender<CommMsg>, Receiver<CommMsg>) = channel();
@@ -137,7 +223,6 @@ impl MacchinaM2 {
         let is_running_ts = is_running.clone();
         // Since UNIX has a 4KB Page size, I want to store more data,
         // Use a 16KB Buffer
-        let mut port_write = port.try_clone().unwrap();
 
         // This thread is responsible for writing data to the M2's
         // serial port.
@@ -146,6 +231,7 @@ impl MacchinaM2 {
             while is_running_tw.load(Ordering::Relaxed) {
                 // Any messages to write?
                 if let Ok(m) = send_rx.recv() {
+                    capture::record(Direction::Tx, &m);
                     if let Err(e) = port_write.write_all(&m.to_slice()) {
                         log_warn(format!("Could not write TxPayload to M2 {}", e));
                     }
@@ -180,6 +266,7 @@ impl MacchinaM2 {
         spawn(move || {
             logger::log_debug_str("M2 serial reader thread starting!");
             let msg = CommMsg::new_with_args(MsgType::StatusMsg, &[0x01]);
+            capture::record(Direction::Tx, &msg);
             if port.write_all(&msg.to_slice()).is_err() {
                 logger::log_error_str("Timeout writing init struct!");
                 is_running_t.store(false, Ordering::Relaxed);
@@ -201,13 +288,21 @@ impl MacchinaM2 {
                 //}
                 while read_count >= COMM_MSG_SIZE {
                     activity = true;
-                    let msg = CommMsg::from_vec(&read_buffer[0..COMM_MSG_SIZE]);
+                    let frame = CommMsg::from_vec(&read_buffer[0..COMM_MSG_SIZE]);
                     unsafe {
                         std::ptr::copy(&read_buffer[COMM_MSG_SIZE], &mut read_buffer[0], COMM_MSG_SIZE*(MAX_BUFFER_SIZE-1));
                     }
                     read_count -= COMM_MSG_SIZE;
+                    let msg = match frame {
+                        Ok(m) => m,
+                        Err(e) => {
+                            log_error(format!("Dropping invalid frame from M2: {}", e));
+                            continue
+                        }
+                    };
+                    capture::record(Direction::Rx, &msg);
                     match msg.msg_type {
-                        MsgType::LogMsg => log_m2_msg(String::from_utf8(msg.args).unwrap()),
+                        MsgType::LogMsg => log_m2_msg(String::from_utf8_lossy(&msg.args).into_owned()),
                         MsgType::ReceiveChannelData => {
                             if chan_tx.send(msg).is_err() {
                                 log_error_str("Could not write data to channel thread receiver!");
@@ -230,6 +325,7 @@ impl MacchinaM2 {
                 }
             }
             let msg = CommMsg::new_with_args(MsgType::StatusMsg, &[0x00]);
+            capture::record(Direction::Tx, &msg);
             if let Err(e) = port.write_all(&msg.to_slice()) {
                 log_warn(format!("Could not write exit message to M2 {}", e));
             }
@@ -248,19 +344,6 @@ impl MacchinaM2 {
         Ok(m)
     }
 
-    /// Writes a CommMsg to the M2, and does not retrieve a response
-    /// from the M2
-    pub fn write_comm_struct(&self, mut s: CommMsg) -> PTResult<()> {
-        s.msg_id = 0x00; // Tell M2 it doesn't have to respond to request
-        match self.tx_send_queue.send(s) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                set_error_string(format!("Error sending data to M2 TxChannel {}", e));
-                Err(PassthruError::ERR_FAILED)
-            }
-        }
-    }
-
     /// Writes a commMsg to the M2, and then waits for its response
     /// # Params
     /// * s - CommMsg to write to the M2
@@ -270,38 +353,7 @@ impl MacchinaM2 {
             // Error writing or reading data from the M2
             Err(e) => M2Resp::Err { status: e, string: format!("M2 communication failure: {:?}", e) },
             // M2 responded with a message, process it
-            Ok(mut resp) => {
-                // Process the status of the message, this should be a PassthruError
-                let status = match PassthruError::from_raw(resp.args[0] as u32) {
-                    Some(x) => x, // Error processed successfully!
-                    None => {
-                        // M2 responded with an error code not found in J2534 Spec??
-                        return M2Resp::Err{ status: PassthruError::ERR_FAILED, string: format!("Unrecognized status {}", resp.args[0]) }
-                    }
-                };
-                resp.args.drain(0..1); // Drain the first byte from args as that was the status ID
-                // Match the status returned
-                match status {
-                    PassthruError::STATUS_NOERROR => { // Operation completed successfully
-                        match resp.args.len() {
-                            1 => M2Resp::Ok(Vec::new()), // No args in M2's response
-                            _ => M2Resp::Ok(resp.args) // Store M2's args
-                        }
-                    },
-                    _ => { // M2 returned an error!
-                        // Check if M2 responded with an error string
-                        let text = if resp.args.len() > 1 {
-                            // Yes, set the error string
-                            String::from_utf8(resp.args).unwrap()
-                        } else {
-                            // No error string
-                            format!("")
-                        };
-                        // Return The formatted error
-                        M2Resp::Err { status, string: text }
-                    }
-                }
-            }
+            Ok(resp) => M2Resp::from_msg(resp)
         }
     }
 
@@ -329,11 +381,12 @@ impl MacchinaM2 {
 
     pub fn stop(&mut self) {
         self.is_running.store(false, Ordering::Relaxed);
+        capture::stop_capture();
     }
 }
 
 
-const COMM_MSG_SIZE: usize = 4096;
+pub const COMM_MSG_SIZE: usize = 4096;
 const COMM_MSG_ARG_SIZE: usize = COMM_MSG_SIZE - 4;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -351,6 +404,9 @@ pub enum MsgType {
     ReadBatt = 0x08,
     IoctlSet = 0x09,
     IoctlGet = 0x10,
+    IoctlCmd = 0x11,
+    SetProgVoltage = 0x12,
+    ReadProgVoltage = 0x13,
     StatusMsg = 0xAA,
     GetFwVersion = 0xAB,
     #[cfg(test)]
@@ -358,7 +414,7 @@ pub enum MsgType {
 }
 
 impl MsgType {
-    fn from_u8(s: &u8) -> MsgType {
+    pub fn from_u8(s: &u8) -> MsgType {
         match s {
             0x01 => MsgType::LogMsg,
             0x02 => MsgType::OpenChannel,
@@ -370,6 +426,9 @@ impl MsgType {
             0x08 => MsgType::ReadBatt,
             0x09 => MsgType::IoctlSet,
             0x10 => MsgType::IoctlGet,
+            0x11 => MsgType::IoctlCmd,
+            0x12 => MsgType::SetProgVoltage,
+            0x13 => MsgType::ReadProgVoltage,
             0xAA => MsgType::StatusMsg,
             0xAB => MsgType::GetFwVersion,
             #[cfg(test)]
@@ -399,13 +458,22 @@ impl std::fmt::Display for CommMsg {
 }
 
 impl CommMsg {
-    pub fn from_vec(buf: &[u8]) -> Self {
+    /// Decodes a frame sent by the M2. Fails if the frame is too short for its
+    /// header, or its size is larger than the frame
+    pub fn from_vec(buf: &[u8]) -> std::result::Result<Self, String> {
+        if buf.len() < 4 {
+            return Err(format!("Frame is {} bytes, too short for its header", buf.len()))
+        }
         let size = LittleEndian::read_u16(&buf[2..4]) as usize;
-        CommMsg {
+        let args = match buf.get(4..size + 4) {
+            Some(a) => a,
+            None => return Err(format!("Args size {} is larger than the {} byte frame", size, buf.len()))
+        };
+        Ok(CommMsg {
             msg_id: buf[0],
             msg_type: MsgType::from_u8(&buf[1]),
-            args: Vec::from(&buf[4..size+4]),
-        }
+            args: Vec::from(args),
+        })
     }
 
     pub fn new(msg_type: MsgType) -> Self {
@@ -0,0 +1,178 @@
+// Driver errors. Each thread keeps its own error state, so PassThruGetLastError
+// returns the error of the last failed call made on the calling thread
+
+use std::{cell::RefCell, fmt, panic::AssertUnwindSafe};
+use J2534Common::*;
+use crate::logger::log_error;
+
+/// Error from the driver, with as much context as is known about the failure
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    status: PassthruError,
+    /// J2534 function that failed. Eg: PassThruConnect
+    function: Option<&'static str>,
+    channel: Option<u32>,
+    /// Description of the error from the driver
+    detail: Option<String>,
+    /// Description of the error from the M2 firmware
+    firmware: Option<String>,
+}
+
+impl Error {
+    /// J2534 status code of the error
+    pub fn status(&self) -> PassthruError {
+        self.status
+    }
+
+    pub fn function(&self) -> Option<&'static str> {
+        self.function
+    }
+
+    pub fn channel(&self) -> Option<u32> {
+        self.channel
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    pub fn firmware(&self) -> Option<&str> {
+        self.firmware.as_deref()
+    }
+
+    pub(crate) fn with_channel(mut self, id: u32) -> Self {
+        self.channel = Some(id);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(function) = self.function {
+            write!(f, "{}: ", function)?;
+        }
+        if let Some(channel) = self.channel {
+            write!(f, "channel {}: ", channel)?;
+        }
+        match &self.detail {
+            Some(detail) => write!(f, "{} ({:?})", detail, self.status)?,
+            None => write!(f, "{:?}", self.status)?
+        }
+        if let Some(firmware) = &self.firmware {
+            write!(f, ". M2: {}", firmware)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Builds an error from a status code, taking the details set on this thread
+/// with set_error_string and set_firmware_error
+impl From<PassthruError> for Error {
+    fn from(status: PassthruError) -> Self {
+        STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            Error {
+                status,
+                function: s.function,
+                channel: s.channel,
+                detail: s.detail.take(),
+                firmware: s.firmware.take(),
+            }
+        })
+    }
+}
+
+#[derive(Default)]
+struct ThreadState {
+    /// J2534 function being run on this thread
+    function: Option<&'static str>,
+    /// Channel the running function was called with
+    channel: Option<u32>,
+    detail: Option<String>,
+    firmware: Option<String>,
+    /// Error returned by the safe API during the running function
+    call_error: Option<Error>,
+    /// Error of the last failed function
+    last: Option<Error>,
+}
+
+thread_local! {
+    static STATE: RefCell<ThreadState> = RefCell::new(ThreadState::default());
+}
+
+/// Sets the description of the error that is about to be returned.
+/// This string is then retrieved by the application using the driver
+/// by calling PassThruGetLastError
+pub fn set_error_string(input: String) {
+    STATE.with(|s| s.borrow_mut().detail = Some(input));
+}
+
+/// Sets the error description the M2 responded with, for the error that is about to be returned
+pub fn set_firmware_error(input: String) {
+    STATE.with(|s| s.borrow_mut().firmware = Some(input));
+}
+
+/// Returns the last error of a J2534 function called on this thread
+pub fn last_error() -> Option<Error> {
+    STATE.with(|s| s.borrow().last.clone())
+}
+
+/// Keeps an error returned by the safe API, so the J2534 function running
+/// on this thread reports it. Returns its status code
+pub(crate) fn set_call_error(e: Error) -> PassthruError {
+    let status = e.status;
+    STATE.with(|s| s.borrow_mut().call_error = Some(e));
+    status
+}
+
+/// Runs a J2534 function. If it fails, its error becomes the last error of this thread.
+/// Successful calls leave the last error alone
+/// # Params
+/// * function - Name of the J2534 function
+/// * channel - Channel the function was called with, if any
+/// * f - Function to run
+pub(crate) fn api_call<F: FnOnce() -> PassthruError>(function: &'static str, channel: Option<u32>, f: F) -> PassthruError {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.function = Some(function);
+        s.channel = channel;
+        s.detail = None;
+        s.firmware = None;
+        s.call_error = None;
+    });
+    let status = catch_panic(f);
+    if status != PassthruError::STATUS_NOERROR {
+        let call_error = STATE.with(|s| s.borrow_mut().call_error.take());
+        let mut err = match call_error {
+            Some(e) if e.status == status => e,
+            _ => Error::from(status)
+        };
+        err.function = Some(function);
+        err.channel = err.channel.or(channel);
+        STATE.with(|s| s.borrow_mut().last = Some(err));
+    }
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.function = None;
+        s.channel = None;
+    });
+    status
+}
+
+/// Runs a function called by the application. A panic must never unwind into the
+/// application, so it is turned into ERR_FAILED with the panic as the error description
+pub(crate) fn catch_panic<F: FnOnce() -> PassthruError>(f: F) -> PassthruError {
+    match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(status) => status,
+        Err(payload) => {
+            let reason = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Unknown panic".into());
+            log_error(format!("Driver panicked: {}", reason));
+            set_error_string(format!("Driver panicked: {}", reason));
+            PassthruError::ERR_FAILED
+        }
+    }
+}
@@ -0,0 +1,61 @@
+// Fake M2 for the tests and fuzz targets. It answers each command the driver sends
+// from a handler, so the driver runs end to end without hardware
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use crate::capture::{read_m2_frames, split_driver_msgs, to_m2_frame};
+use crate::comm::{CommMsg, MacchinaM2, M2};
+
+/// Returns the args of the M2's response to a command, starting with the status byte
+pub type Handler = dyn Fn(&CommMsg) -> Vec<u8> + Send + Sync;
+
+#[derive(Clone)]
+pub struct FakeM2Port {
+    handler: Arc<Handler>,
+    /// Bytes written by the driver that do not form a full message yet
+    tx_buf: Arc<Mutex<Vec<u8>>>,
+    /// M2 frames waiting to be read by the driver
+    rx_buf: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl FakeM2Port {
+    pub fn new(handler: impl Fn(&CommMsg) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        FakeM2Port { handler: Arc::new(handler), tx_buf: Default::default(), rx_buf: Default::default() }
+    }
+
+    /// Fake M2 which accepts every command it is sent
+    pub fn acking() -> Self {
+        Self::new(|_| vec![0x00])
+    }
+
+    /// Starts the driver's connection to the fake M2, as Device::open would
+    pub fn install(self) {
+        let m2 = MacchinaM2::start(Box::new(self.clone()), Box::new(self)).expect("Could not start fake M2");
+        *M2.write().unwrap() = Some(m2);
+    }
+}
+
+impl Read for FakeM2Port {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(read_m2_frames(&mut self.rx_buf.lock().unwrap(), buf))
+    }
+}
+
+impl Write for FakeM2Port {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut tx = self.tx_buf.lock().unwrap();
+        tx.extend_from_slice(buf);
+        // Messages with an ID expect a response
+        for cmd in split_driver_msgs(&mut tx)?.into_iter().filter(|m| m.msg_id != 0) {
+            let mut resp = CommMsg::new_with_args(cmd.msg_type, &(self.handler)(&cmd));
+            resp.msg_id = cmd.msg_id;
+            self.rx_buf.lock().unwrap().extend(to_m2_frame(&resp));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+// Pass and block filters of CAN channels. The M2 has only 7 CAN mailboxes, but J2534 gives
+// each channel 10 filters, so the driver matches received messages against the channel's
+// filters itself. The mailboxes are programmed with CAN ID acceptance filters, widened
+// where needed, so every message that passes the channel's filters reaches the driver
+
+use J2534Common::*;
+
+/// CAN mailboxes the M2 has for each channel
+pub const MAX_HW_FILTERS: usize = 7;
+
+/// Returns if the driver filters a protocol's messages. On the other protocols each
+/// J2534 filter runs on the M2 (Flow control filters need a mailbox each)
+pub fn host_filtered(protocol: Protocol) -> bool {
+    matches!(protocol, Protocol::CAN | Protocol::CAN_PS | Protocol::SW_CAN_PS)
+}
+
+/// J2534 filter, as set by the application
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsgFilter {
+    pub filter_type: FilterType,
+    pub mask: Vec<u8>,
+    pub pattern: Vec<u8>,
+}
+
+impl MsgFilter {
+    pub fn new(filter_type: FilterType, mask: &[u8], pattern: &[u8]) -> Self {
+        MsgFilter { filter_type, mask: mask.to_vec(), pattern: pattern.to_vec() }
+    }
+
+    /// Returns if the message data matches the filter. Messages shorter than the mask never match
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.mask.len() && self.mask.iter().zip(&self.pattern).zip(data).all(|((m, p), d)| d & m == p & m)
+    }
+
+    /// Acceptance filter on the CAN ID (The first 4 bytes of the message)
+    fn id_filter(&self) -> HwFilter {
+        let mut mask = [0u8; 4];
+        let mut pattern = [0u8; 4];
+        for i in 0..self.mask.len().min(4) {
+            mask[i] = self.mask[i];
+            pattern[i] = self.pattern[i] & self.mask[i];
+        }
+        HwFilter { id: u32::from_be_bytes(pattern), mask: u32::from_be_bytes(mask) }
+    }
+}
+
+/// CAN ID acceptance filter in one of the M2's mailboxes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HwFilter {
+    pub id: u32,
+    pub mask: u32,
+}
+
+impl HwFilter {
+    /// Returns if every ID other accepts is also accepted by this filter
+    fn covers(&self, other: &HwFilter) -> bool {
+        self.mask & !other.mask == 0 && other.id & self.mask == self.id
+    }
+
+    /// Narrowest filter accepting every ID either filter accepts
+    fn merge(&self, other: &HwFilter) -> HwFilter {
+        let mask = self.mask & other.mask & !(self.id ^ other.id);
+        HwFilter { id: self.id & mask, mask }
+    }
+}
+
+/// Returns the acceptance filters the M2 needs for every message passing the filters to
+/// reach the driver. Block filters only need checking on the driver, so have none
+pub fn hw_filters<'a>(filters: impl Iterator<Item=&'a MsgFilter>) -> Vec<HwFilter> {
+    let mut hw: Vec<HwFilter> = Vec::new();
+    for f in filters.filter(|f| f.filter_type == FilterType::PASS_FILTER).map(|f| f.id_filter()) {
+        if !hw.iter().any(|h| h.covers(&f)) {
+            hw.retain(|h| !f.covers(h));
+            hw.push(f);
+        }
+    }
+    // Out of mailboxes. Merge the 2 filters that together let the fewest extra IDs through
+    while hw.len() > MAX_HW_FILTERS {
+        let mut best = (0, 1, hw[0].merge(&hw[1]));
+        for i in 0..hw.len() {
+            for j in i + 1..hw.len() {
+                let merged = hw[i].merge(&hw[j]);
+                if merged.mask.count_ones() > best.2.mask.count_ones() {
+                    best = (i, j, merged);
+                }
+            }
+        }
+        let (i, j, merged) = best;
+        hw.remove(j);
+        hw.remove(i);
+        hw.retain(|h| !merged.covers(h));
+        hw.push(merged);
+    }
+    hw
+}
+
+/// Returns if a message passes the filters. As J2534-1 specifies, a message must match
+/// a pass filter and no block filters, so nothing passes until a pass filter is set
+pub fn passes<'a>(mut filters: impl Iterator<Item=&'a MsgFilter> + Clone, data: &[u8]) -> bool {
+    !filters.clone().any(|f| f.filter_type == FilterType::BLOCK_FILTER && f.matches(data))
+        && filters.any(|f| f.filter_type == FilterType::PASS_FILTER && f.matches(data))
+}
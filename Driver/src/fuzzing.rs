@@ -0,0 +1,67 @@
+// Entry points for the fuzz targets in fuzz/. These reach the parts of the driver
+// that handle data sent by the M2, which the J2534 exports cannot feed directly
+
+use std::sync::Once;
+use J2534Common::*;
+use crate::channels::ChannelComm;
+use crate::comm::{CommMsg, M2Resp, MsgType, M2};
+use crate::fake_m2::FakeM2Port;
+
+/// Decodes a frame as if the M2 sent it
+pub fn decode_frame(data: &[u8]) {
+    let _ = CommMsg::from_vec(data);
+}
+
+/// Parses a frame as the M2's response to a command
+pub fn parse_response(data: &[u8]) {
+    if let Ok(msg) = CommMsg::from_vec(data) {
+        let _ = M2Resp::from_msg(msg);
+    }
+}
+
+/// Dispatches channel data to the open channels as if the M2 received it,
+/// then reads back everything the channels queued
+/// # Params
+/// * args - Args of the ReceiveChannelData message
+pub fn receive_channel_data(args: &[u8]) {
+    static OPEN: Once = Once::new();
+    OPEN.call_once(|| { reopen_channels(); });
+    dispatch_channel_data(args);
+    for id in 0..8 { // Physical channel IDs
+        while let Ok(Some(_)) = ChannelComm::read_channel_data(id) {}
+    }
+}
+
+/// Starts a fake M2 if there isn't one, closes every channel, then opens a CAN (With filters),
+/// J1939 and single wire CAN channel on it
+/// # Returns
+/// IDs of the channels
+pub fn reopen_channels() -> Vec<u32> {
+    if M2.read().unwrap().is_none() {
+        FakeM2Port::acking().install();
+    }
+    ChannelComm::force_destroy_all_channels();
+    let mut ids = Vec::new();
+    if let Ok(id) = ChannelComm::create_channel(Protocol::CAN, 500000, 0) {
+        // Filters the driver matches on the host, so they are fuzzed too
+        let _ = ChannelComm::create_channel_filter(id, FilterType::PASS_FILTER, &[0x00, 0x00, 0x04, 0x00], &[0x00, 0x00, 0x04, 0x00], &[]);
+        let _ = ChannelComm::create_channel_filter(id, FilterType::BLOCK_FILTER, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF], &[0x00, 0x00, 0x07, 0xDF, 0x02], &[]);
+        ids.push(id);
+    }
+    if let Ok(id) = ChannelComm::create_channel(Protocol::J1939_PS, 250000, 0) {
+        let _ = ChannelComm::ioctl_set_cfg(id, IoctlParam::J1962_PINS, J1962Pins::new(3, 11).to_raw());
+        ids.push(id);
+    }
+    if let Ok(id) = ChannelComm::create_channel(Protocol::SW_CAN_PS, 33333, 0) {
+        let _ = ChannelComm::ioctl_set_cfg(id, IoctlParam::J1962_PINS, J1962Pins::new(1, 0).to_raw());
+        ids.push(id);
+    }
+    ids
+}
+
+/// Dispatches channel data to the open channels as if the M2 received it, leaving it queued
+/// # Params
+/// * args - Args of the ReceiveChannelData message
+pub fn dispatch_channel_data(args: &[u8]) {
+    ChannelComm::receive_channel_data(&CommMsg::new_with_args(MsgType::ReceiveChannelData, args));
+}
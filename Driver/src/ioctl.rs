@@ -1,33 +1,17 @@
-use J2534Common::{IoctlParam, PASSTHRU_MSG, Parsable, PassthruError, SBYTE_ARRAY, SConfigList};
-use crate::{channels, comm::*, logger::{log_warn, log_warn_str}, passthru_drv::set_error_string};
-use crate::logger::{log_error};
-use byteorder::{ByteOrder, LittleEndian};
+use J2534Common::{BusStat, IoctlID, IoctlParam, MAX_CONFIG_PARAMS, PASSTHRU_MSG, Parsable, PassthruError, SBYTE_ARRAY, SConfigList};
+use crate::{channels, logger::{log_warn, log_warn_str}, error::{set_call_error, set_error_string}};
+use crate::api::Channel;
+use crate::prog_voltage;
+use crate::recorder::RecordFormat;
+use crate::vbatt::{self, VbattSample, VBATT_RECORD_SIZE};
 
 
 /// Reads the battery voltage into an output pointer, storing the value as mV
 /// # Params
 /// * output_ptr - Output pointer to store batter voltage into
-
-static mut last_vbatt : u32 = 0;
 pub fn read_vbatt(output_ptr: *mut u32) -> PassthruError {
-    match run_on_m2(|dev| {
-        match dev.write_and_read_ptcmd(&mut CommMsg::new(MsgType::ReadBatt), 250) {
-            M2Resp::Ok(args) => {
-                if args.len() < 4 { // This should stop a panic from randomly occurring when M2 is under load
-                    log_error("Error reading battery voltage - Args size was not correct, returning last known".into());
-                    Ok(unsafe { last_vbatt })
-                } else {
-                    Ok(LittleEndian::read_u32(&args))
-                }
-            },
-            M2Resp::Err{status, string} => {
-                log_error(format!("Error reading battery voltage (Status {:?}): {}", status, string));
-                Err(status)
-            }
-        }
-    }) {
+    match vbatt::read() {
         Ok(v) => {
-            unsafe { last_vbatt = v };
             unsafe { *output_ptr = v };
             PassthruError::STATUS_NOERROR
         },
@@ -35,21 +19,87 @@ pub fn read_vbatt(output_ptr: *mut u32) -> PassthruError {
     }
 }
 
-#[allow(unused_variables)]
+/// Copies battery voltage records into an SBYTE_ARRAY, as many as fit
+fn write_vbatt_records(output: &mut SBYTE_ARRAY, records: &[VbattSample]) -> PassthruError {
+    let bytes: Vec<u8> = records.iter().flat_map(|r| r.to_bytes()).collect();
+    unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), output.byte_ptr as *mut u8, bytes.len()) };
+    output.num_of_bytes = bytes.len() as u32;
+    PassthruError::STATUS_NOERROR
+}
+
+/// Reads the newest battery voltage readings that fit in the output
+/// # Params
+/// * output - Array for the readings. num_of_bytes is the space on input, and the bytes written on output
+pub fn read_vbatt_history(output: &mut SBYTE_ARRAY) -> PassthruError {
+    if output.byte_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    let history = vbatt::history();
+    let fits = (output.num_of_bytes as usize / VBATT_RECORD_SIZE).min(history.len());
+    write_vbatt_records(output, &history[history.len() - fits..])
+}
+
+/// Takes the oldest battery voltage events that fit in the output
+/// # Params
+/// * output - Array for the events. num_of_bytes is the space on input, and the bytes written on output
+pub fn read_vbatt_events(output: &mut SBYTE_ARRAY) -> PassthruError {
+    if output.byte_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    let events = vbatt::take_events(output.num_of_bytes as usize / VBATT_RECORD_SIZE);
+    write_vbatt_records(output, &events)
+}
+
+/// Measures the programming voltage into an output pointer, as mV
+/// # Params
+/// * output_ptr - Output pointer to store the voltage into. 0 if no pin has a voltage applied
 pub fn read_prog_voltage(output_ptr: *mut u32) -> PassthruError {
-    log_warn_str("Read programming voltage unimplemented");
+    match prog_voltage::read() {
+        Ok(v) => {
+            unsafe { *output_ptr = v };
+            PassthruError::STATUS_NOERROR
+        },
+        Err(x) => x
+    }
+}
+
+/// Params between the last J2534-1 param and the J2534-2 range are either reserved,
+/// or 04.04 only params which the M2 ignores
+fn is_reserved_param(param: u32) -> bool {
+    (0x20..0x8000).contains(&param)
+}
+
+/// Checks an SConfigList from the application can be read
+fn check_config_list(cfg_ptr: &SConfigList) -> PassthruError {
+    let num_of_params = cfg_ptr.num_of_params;
+    if num_of_params > MAX_CONFIG_PARAMS {
+        set_error_string(format!("SCONFIG_LIST has {} params, the most allowed is {}", num_of_params, MAX_CONFIG_PARAMS));
+        return PassthruError::ERR_INVALID_IOCTL_VALUE
+    }
+    if num_of_params != 0 && cfg_ptr.config_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
     PassthruError::STATUS_NOERROR
 }
 
 pub fn set_config(channel_id: u32, cfg_ptr: &SConfigList) -> PassthruError {
+    let res = check_config_list(cfg_ptr);
+    if res != PassthruError::STATUS_NOERROR {
+        return res
+    }
     for i in 0..cfg_ptr.num_of_params as isize {
         match unsafe { cfg_ptr.config_ptr.offset(i).as_ref() } {
             None => return PassthruError::ERR_NULL_PARAMETER,
             Some(param) => {
-                if param.parameter >= 0x20 {
-                    log_warn(format!("setconfig param name is reserved / tool specific?. Param: {:08X}, value: {:08X}", param.parameter, param.value));
+                if is_reserved_param(param.parameter) {
+                    log_warn(format!("setconfig param name is reserved / tool specific?. Param: {:08X}, value: {:08X}", { param.parameter }, { param.value }));
                 } else if let Some(pname) = IoctlParam::from_raw(param.parameter) {
-                    if let Err(e) = channels::ChannelComm::ioctl_set_cfg(channel_id, pname, param.value) {
+                    let res = if vbatt::is_param(pname) {
+                        vbatt::set_config(pname, param.value)
+                    } else {
+                        channels::ChannelComm::ioctl_set_cfg(channel_id, pname, param.value)
+                    };
+                    if let Err(e) = res {
                         return e
                     }
                 } else {
@@ -62,17 +112,25 @@ pub fn set_config(channel_id: u32, cfg_ptr: &SConfigList) -> PassthruError {
 }
 
 pub fn get_config(channel_id: u32, cfg_ptr: &SConfigList) -> PassthruError {
+    let res = check_config_list(cfg_ptr);
+    if res != PassthruError::STATUS_NOERROR {
+        return res
+    }
     for i in 0..cfg_ptr.num_of_params as isize {
         match unsafe { cfg_ptr.config_ptr.offset(i).as_mut() } {
             None => return PassthruError::ERR_NULL_PARAMETER,
             Some(mut param) => {
-                if param.parameter >= 0x20 {
-                    log_warn(format!("get config param name is reserved / tool specific?. Param: {:08X}, value: {:08X}", param.parameter, param.value));
+                if is_reserved_param(param.parameter) {
+                    log_warn(format!("get config param name is reserved / tool specific?. Param: {:08X}, value: {:08X}", { param.parameter }, { param.value }));
                 } else if let Some(pname) = IoctlParam::from_raw(param.parameter) {
-                    if let Ok(pvalue) = channels::ChannelComm::ioctl_get_cfg(channel_id, pname) {
-                        param.value = pvalue;
+                    let res = if vbatt::is_param(pname) {
+                        vbatt::get_config(pname)
                     } else {
-                        return PassthruError::ERR_FAILED
+                        channels::ChannelComm::ioctl_get_cfg(channel_id, pname)
+                    };
+                    match res {
+                        Ok(pvalue) => param.value = pvalue,
+                        Err(e) => return e
                     }
                 } else {
                     return PassthruError::ERR_NOT_SUPPORTED
@@ -83,6 +141,51 @@ pub fn get_config(channel_id: u32, cfg_ptr: &SConfigList) -> PassthruError {
     PassthruError::STATUS_NOERROR
 }
 
+/// Reads a CAN channel's bus statistics into a config list
+/// # Params
+/// * channel_id - CAN channel to read
+/// * cfg_ptr - List of BusStat IDs. Each value is set to the stat
+pub fn get_bus_stats(channel_id: u32, cfg_ptr: &SConfigList) -> PassthruError {
+    let res = check_config_list(cfg_ptr);
+    if res != PassthruError::STATUS_NOERROR {
+        return res
+    }
+    let stats = match Channel::from_raw(channel_id).bus_stats() {
+        Ok(s) => s,
+        Err(e) => return set_call_error(e)
+    };
+    for i in 0..cfg_ptr.num_of_params as isize {
+        match unsafe { cfg_ptr.config_ptr.offset(i).as_mut() } {
+            None => return PassthruError::ERR_NULL_PARAMETER,
+            Some(param) => match BusStat::from_raw(param.parameter) {
+                Some(stat) => param.value = stats.get(stat),
+                None => {
+                    set_error_string(format!("0x{:08X} is not a bus stat", { param.parameter }));
+                    return PassthruError::ERR_NOT_SUPPORTED
+                }
+            }
+        }
+    }
+    PassthruError::STATUS_NOERROR
+}
+
+pub fn clear_bus_stats(channel_id: u32) -> PassthruError {
+    match Channel::from_raw(channel_id).clear_bus_stats() {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => set_call_error(e)
+    }
+}
+
+pub fn detect_baud_rate(channel_id: u32, output_ptr: *mut u32) -> PassthruError {
+    match Channel::from_raw(channel_id).detect_baud_rate() {
+        Ok(baud) => {
+            unsafe { *output_ptr = baud };
+            PassthruError::STATUS_NOERROR
+        },
+        Err(e) => set_call_error(e)
+    }
+}
+
 #[allow(unused_variables)] // TODO
 pub fn five_baud_init(channel_id: u32, input: &mut SBYTE_ARRAY, output: &mut SBYTE_ARRAY) -> PassthruError {
     log_warn_str("Five baud init unimplemented");
@@ -95,6 +198,121 @@ pub fn fast_init(channel_id: u32, input: &mut PASSTHRU_MSG, output: &mut PASSTHR
     PassthruError::STATUS_NOERROR
 }
 
+/// Switches a single wire CAN channel between normal and high speed mode
+/// # Params
+/// * channel_id - Single wire CAN channel
+/// * mode - Either SW_CAN_HS or SW_CAN_NS
+pub fn sw_can_set_mode(channel_id: u32, mode: IoctlID) -> PassthruError {
+    match channels::ChannelComm::ioctl_cmd(channel_id, mode, &[]) {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
+    }
+}
+
+/// Claims a J1939 source address on a J1939 channel. The outcome of the claim is
+/// reported later as an Rx message with either J1939_ADDRESS_CLAIMED or J1939_ADDRESS_LOST set
+/// # Params
+/// * channel_id - J1939 channel
+/// * input - 9 bytes. Byte 0 is the address to claim, bytes 1-8 are the J1939 NAME
+pub fn protect_j1939_addr(channel_id: u32, input: &SBYTE_ARRAY) -> PassthruError {
+    if input.byte_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    if input.num_of_bytes != 9 {
+        set_error_string(format!("PROTECT_J1939_ADDR input must be 9 bytes, got {}", { input.num_of_bytes }));
+        return PassthruError::ERR_INVALID_IOCTL_VALUE
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(input.byte_ptr, 9) };
+    if bytes[0] >= 0xFE {
+        set_error_string(format!("0x{:02X} cannot be claimed as a J1939 address", bytes[0]));
+        return PassthruError::ERR_INVALID_IOCTL_VALUE
+    }
+    match channels::ChannelComm::ioctl_cmd(channel_id, IoctlID::PROTECT_J1939_ADDR, bytes) {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
+    }
+}
+
+/// Opens a TP 2.0 connection to a module. Blocks until the connection is established
+/// # Params
+/// * channel_id - TP 2.0 channel
+/// * input - Byte 0 is the logical address of the module, and optional byte 1 is
+///   the application type (Defaults to 0x01 - KWP2000)
+pub fn request_connection(channel_id: u32, input: &SBYTE_ARRAY) -> PassthruError {
+    if input.byte_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    if input.num_of_bytes != 1 && input.num_of_bytes != 2 {
+        set_error_string(format!("REQUEST_CONNECTION input must be 1 or 2 bytes, got {}", { input.num_of_bytes }));
+        return PassthruError::ERR_INVALID_IOCTL_VALUE
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(input.byte_ptr, input.num_of_bytes as usize) };
+    match channels::ChannelComm::ioctl_cmd(channel_id, IoctlID::REQUEST_CONNECTION, bytes) {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
+    }
+}
+
+/// Closes the TP 2.0 connection to a module
+/// # Params
+/// * channel_id - TP 2.0 channel
+/// * input - Byte 0 is the logical address of the module
+pub fn teardown_connection(channel_id: u32, input: &SBYTE_ARRAY) -> PassthruError {
+    if input.byte_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    if input.num_of_bytes != 1 {
+        set_error_string(format!("TEARDOWN_CONNECTION input must be 1 byte, got {}", { input.num_of_bytes }));
+        return PassthruError::ERR_INVALID_IOCTL_VALUE
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(input.byte_ptr, 1) };
+    match channels::ChannelComm::ioctl_cmd(channel_id, IoctlID::TEARDOWN_CONNECTION, bytes) {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
+    }
+}
+
+/// Starts recording a CAN channel's frames to a file
+/// # Params
+/// * channel_id - CAN channel to record
+/// * input - Byte 0 is the format (0x00 - candump, 0x01 - Vector ASC, 0x02 - PCAP),
+///   the remaining bytes are the UTF-8 path of the file to record to
+pub fn start_recording(channel_id: u32, input: &SBYTE_ARRAY) -> PassthruError {
+    if input.byte_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    if input.num_of_bytes < 2 {
+        set_error_string("MACCHINA_START_RECORDING input must be a format byte followed by a file path".into());
+        return PassthruError::ERR_INVALID_IOCTL_VALUE
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(input.byte_ptr, input.num_of_bytes as usize) };
+    let format = match RecordFormat::from_raw(bytes[0]) {
+        Some(f) => f,
+        None => {
+            set_error_string(format!("0x{:02X} is not a recording format", bytes[0]));
+            return PassthruError::ERR_INVALID_IOCTL_VALUE
+        }
+    };
+    let path = match std::str::from_utf8(&bytes[1..]) {
+        Ok(p) => p.trim_end_matches('\0'),
+        Err(_) => {
+            set_error_string("Recording path is not valid UTF-8".into());
+            return PassthruError::ERR_INVALID_IOCTL_VALUE
+        }
+    };
+    match channels::ChannelComm::start_recording(channel_id, format, std::path::Path::new(path)) {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
+    }
+}
+
+pub fn stop_recording(channel_id: u32) -> PassthruError {
+    match channels::ChannelComm::stop_recording(channel_id) {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
+    }
+}
+
 pub fn clear_tx_buffer(channel_id: u32) -> PassthruError {
     channels::ChannelComm::clear_tx_buffer(channel_id)
 }
@@ -1,12 +1,29 @@
 use libc::c_char;
 use J2534Common::*;
+pub mod api;
+mod error;
 mod logger;
+mod capture;
 mod comm;
 mod channels;
+mod filters;
 mod ioctl;
 mod passthru_drv;
-use logger::{log_error_str};
+mod pins;
+mod prog_voltage;
+mod recorder;
+mod timebase;
+mod tx_queue;
+mod vbatt;
+#[cfg(feature = "v0500")]
+mod passthru_drv_v0500;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+#[cfg(any(test, feature = "fuzzing"))]
+mod fake_m2;
 use passthru_drv::*;
+pub use api::{BusStats, Channel, Device, Error, Filter, FilterId, VbattSample, Version};
+use error::{api_call, catch_panic, set_error_string};
 
 #[cfg(test)]
 mod lib_tests;
@@ -22,15 +39,16 @@ pub extern "stdcall" fn DllMain(_module: u32, _reason: u32, _reserved: *mut std:
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruOpen(_name: *mut libc::c_void, device_id: *mut u32) -> i32 {
-    passthru_open(device_id) as i32
+    api_call("PassThruOpen", None, || passthru_open(device_id)) as i32
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruClose(pDeviceID: u32) -> i32 {
-    passthru_close(pDeviceID) as i32
+    api_call("PassThruClose", None, || passthru_close(pDeviceID)) as i32
 }
 
+#[cfg(not(feature = "v0500"))]
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruConnect(
@@ -40,32 +58,33 @@ pub extern "stdcall" fn PassThruConnect(
     BaudRate: u32,
     pChannelID: *mut u32,
 ) -> i32 {
-    passthru_connect(DeviceID, ProtocolID, Flags, BaudRate, pChannelID) as i32
+    api_call("PassThruConnect", None, || passthru_connect(DeviceID, ProtocolID, Flags, BaudRate, pChannelID)) as i32
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruDisconnect(ChannelID: u32) -> i32 {
-    passthru_disconnect(ChannelID) as i32
+    api_call("PassThruDisconnect", Some(ChannelID), || passthru_disconnect(ChannelID)) as i32
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruReadVersion(
-    _device_id: u32,
+    DeviceID: u32,
     fw_version_ptr: *mut c_char,
     dll_version_ptr: *mut c_char,
     api_version_ptr: *mut c_char,
 ) -> i32 {
-    passthru_read_version(fw_version_ptr, dll_version_ptr, api_version_ptr) as i32
+    api_call("PassThruReadVersion", None, || passthru_read_version(DeviceID, fw_version_ptr, dll_version_ptr, api_version_ptr)) as i32
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruGetLastError(pErrorDescription: *mut c_char) -> i32 {
-    passthru_get_last_error(pErrorDescription) as i32
+    catch_panic(|| passthru_get_last_error(pErrorDescription)) as i32
 }
 
+#[cfg(not(feature = "v0500"))]
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruReadMsgs(
@@ -74,9 +93,10 @@ pub extern "stdcall" fn PassThruReadMsgs(
     pNumMsgs: *mut u32,
     Timeout: u32,
 ) -> i32 {
-    passthru_drv::read_msgs(ChannelID, pMsg, pNumMsgs, Timeout) as i32
+    api_call("PassThruReadMsgs", Some(ChannelID), || passthru_drv::read_msgs(ChannelID, pMsg, pNumMsgs, Timeout)) as i32
 }
 
+#[cfg(not(feature = "v0500"))]
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruStartMsgFilter(
@@ -87,22 +107,25 @@ pub extern "stdcall" fn PassThruStartMsgFilter(
     pFlowControlMsg: *const PASSTHRU_MSG,
     pMsgID: *mut u32,
 ) -> i32 {
-    let filter: FilterType = match FilterType::from_raw(FilterType) {
-        Some(f) => f,
-        None => {
-            set_error_string(format!("0x{:02X} is not a valid filter type", FilterType));
-            return PassthruError::ERR_FAILED as i32;
-        } 
-    };
-    passthru_drv::set_channel_filter(ChannelID, filter, pMaskMsg, pPatternMsg, pFlowControlMsg, pMsgID) as i32
+    api_call("PassThruStartMsgFilter", Some(ChannelID), || {
+        let filter: FilterType = match FilterType::from_raw(FilterType) {
+            Some(f) => f,
+            None => {
+                set_error_string(format!("0x{:02X} is not a valid filter type", FilterType));
+                return PassthruError::ERR_FAILED;
+            } 
+        };
+        passthru_drv::set_channel_filter(ChannelID, filter, pMaskMsg, pPatternMsg, pFlowControlMsg, pMsgID)
+    }) as i32
 }
 
 #[no_mangle]
 #[allow(non_snake_case, unused_variables)]
 pub extern "stdcall" fn PassThruStopMsgFilter(ChannelID: u32, MsgID: u32) -> i32 {
-    passthru_drv::del_channel_filter(ChannelID, MsgID) as i32
+    api_call("PassThruStopMsgFilter", Some(ChannelID), || passthru_drv::del_channel_filter(ChannelID, MsgID)) as i32
 }
 
+#[cfg(not(feature = "v0500"))]
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruWriteMsgs(
@@ -111,9 +134,10 @@ pub extern "stdcall" fn PassThruWriteMsgs(
     pNumMsgs: *mut u32,
     Timeout: u32,
 ) -> i32 {
-    passthru_drv::write_msgs(ChannelID, pMsg, pNumMsgs, Timeout) as i32
+    api_call("PassThruWriteMsgs", Some(ChannelID), || passthru_drv::write_msgs(ChannelID, pMsg, pNumMsgs, Timeout)) as i32
 }
 
+#[cfg(not(feature = "v0500"))]
 #[no_mangle]
 #[allow(non_snake_case, unused_variables)]
 pub extern "stdcall" fn PassThruStartPeriodicMsg(
@@ -139,18 +163,137 @@ pub extern "stdcall" fn PassThruIoctl(
     pInput: *mut libc::c_void,
     pOutput: *mut libc::c_void,
 ) -> i32 {
-    passthru_ioctl(HandleID, IoctlID, pInput, pOutput) as i32
+    api_call("PassThruIoctl", api::channel_handle(HandleID), || passthru_ioctl(HandleID, IoctlID, pInput, pOutput)) as i32
 }
 
+#[cfg(not(feature = "v0500"))]
 #[no_mangle]
-#[allow(non_snake_case, unused_variables)]
+#[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruSetProgrammingVoltage(
     DeviceID: u32,
     PinNumber: u32,
     Voltage: u32,
 ) -> i32 {
-    // This isn't used as Macchina hardware does not support this
-    log_error_str("Programming voltage setting not supported");
-    set_error_string("Programming voltage is not supported".to_string());
-    PassthruError::ERR_FAILED as i32
-}
\ No newline at end of file
+    api_call("PassThruSetProgrammingVoltage", None, || passthru_set_prog_voltage(DeviceID, PinNumber, Voltage)) as i32
+}
+
+// J2534 v05.00 API. Functions which are the same as 04.04 are shared with the exports above
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruScanForDevices(pDeviceCount: *mut u32) -> i32 {
+    api_call("PassThruScanForDevices", None, || passthru_drv_v0500::scan_for_devices(pDeviceCount)) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruGetNextDevice(psDevice: *mut SDEVICE) -> i32 {
+    api_call("PassThruGetNextDevice", None, || passthru_drv_v0500::get_next_device(psDevice)) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruConnect(
+    DeviceID: u32,
+    ProtocolID: u32,
+    Flags: u32,
+    BaudRate: u32,
+    ResourceStruct: RESOURCE_STRUCT,
+    pChannelID: *mut u32,
+) -> i32 {
+    api_call("PassThruConnect", None, || passthru_drv_v0500::connect(DeviceID, ProtocolID, Flags, BaudRate, ResourceStruct, pChannelID)) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruLogicalConnect(
+    PhysicalChannelID: u32,
+    ProtocolID: u32,
+    Flags: u32,
+    pChannelDescriptor: *const ISO15765_CHANNEL_DESCRIPTOR,
+    pChannelID: *mut u32,
+) -> i32 {
+    api_call("PassThruLogicalConnect", Some(PhysicalChannelID), || passthru_drv_v0500::logical_connect(PhysicalChannelID, ProtocolID, Flags, pChannelDescriptor, pChannelID)) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruLogicalDisconnect(ChannelID: u32) -> i32 {
+    api_call("PassThruLogicalDisconnect", Some(ChannelID), || passthru_drv_v0500::logical_disconnect(ChannelID)) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruSelect(ChannelSetPtr: *mut SCHANNELSET, SelectType: u32, Timeout: u32) -> i32 {
+    api_call("PassThruSelect", None, || passthru_drv_v0500::select(ChannelSetPtr, SelectType, Timeout)) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruReadMsgs(
+    ChannelID: u32,
+    pMsg: *mut PASSTHRU_MSG_V0500,
+    pNumMsgs: *mut u32,
+    Timeout: u32,
+) -> i32 {
+    api_call("PassThruReadMsgs", Some(ChannelID), || passthru_drv_v0500::read_msgs(ChannelID, pMsg, pNumMsgs, Timeout)) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruQueueMsgs(ChannelID: u32, pMsg: *const PASSTHRU_MSG_V0500, pNumMsgs: *mut u32) -> i32 {
+    api_call("PassThruQueueMsgs", Some(ChannelID), || passthru_drv_v0500::queue_msgs(ChannelID, pMsg, pNumMsgs)) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruStartMsgFilter(
+    ChannelID: u32,
+    FilterType: u32,
+    pMaskMsg: *const PASSTHRU_MSG_V0500,
+    pPatternMsg: *const PASSTHRU_MSG_V0500,
+    pFilterID: *mut u32,
+) -> i32 {
+    api_call("PassThruStartMsgFilter", Some(ChannelID), || {
+        let filter: FilterType = match FilterType::from_raw(FilterType) {
+            Some(f) => f,
+            None => {
+                set_error_string(format!("0x{:02X} is not a valid filter type", FilterType));
+                return PassthruError::ERR_FAILED;
+            }
+        };
+        passthru_drv_v0500::set_channel_filter(ChannelID, filter, pMaskMsg, pPatternMsg, pFilterID)
+    }) as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case, unused_variables)]
+pub extern "stdcall" fn PassThruStartPeriodicMsg(
+    ChannelID: u32,
+    pMsg: *const PASSTHRU_MSG_V0500,
+    pMsgID: *const u32,
+    TimeInterval: u32,
+) -> i32 {
+    PassthruError::STATUS_NOERROR as i32
+}
+
+#[no_mangle]
+#[cfg(feature = "v0500")]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruSetProgrammingVoltage(
+    DeviceID: u32,
+    ResourceStruct: RESOURCE_STRUCT,
+    Voltage: u32,
+) -> i32 {
+    api_call("PassThruSetProgrammingVoltage", None, || passthru_drv_v0500::set_prog_voltage(DeviceID, ResourceStruct, Voltage)) as i32
+}
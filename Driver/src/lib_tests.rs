@@ -6,9 +6,33 @@ mod tests {
     use crate::comm::*;
     use J2534Common::*;
     use passthru_drv::{passthru_close, passthru_connect, passthru_open, set_channel_filter};
+    use crate::fake_m2::FakeM2Port;
+
+    /// Tests which use the M2 and the channels run one at a time
+    static M2_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Runs a test against a fake M2. The M2 and its channels are closed afterwards, even if the test fails
+    fn with_fake_m2(port: FakeM2Port, test: impl FnOnce()) {
+        struct Close;
+        impl Drop for Close {
+            fn drop(&mut self) {
+                let mut m2 = M2.write().unwrap_or_else(|e| e.into_inner());
+                if let Some(dev) = m2.as_mut() {
+                    dev.stop();
+                }
+                crate::channels::ChannelComm::force_destroy_all_channels();
+                *m2 = None;
+            }
+        }
+        let _lock = M2_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        port.install();
+        let _close = Close;
+        test();
+    }
 
     #[test]
     fn test_channel() {
+        let _lock = M2_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         let mut dev_idx: u32 = 0;
         assert!(passthru_open(&mut dev_idx) == PassthruError::STATUS_NOERROR);
         
@@ -36,4 +60,531 @@ mod tests {
         std::thread::sleep(std::time::Duration::from_millis(1000));
         assert!(passthru_close(dev_idx) == PassthruError::STATUS_NOERROR);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_listen_only() {
+        use crate::channels::{Channel, ChannelComm};
+        let flag = ConnectFlags::MACCHINA_LISTEN_ONLY as u32;
+        for (protocol, baud) in [(Protocol::ISO15765, 500_000), (Protocol::SW_CAN_PS, 33_333), (Protocol::J1939_PS, 250_000)] {
+            assert_eq!(Channel::new(0, protocol, baud, flag).err(), Some(PassthruError::ERR_INVALID_FLAGS));
+        }
+        with_fake_m2(FakeM2Port::acking(), || {
+            let id = ChannelComm::create_channel(Protocol::CAN, 500_000, flag).unwrap();
+            let msg = PASSTHRU_MSG::new(Protocol::CAN, 0, &[0x00, 0x00, 0x07, 0xDF, 0x02, 0x01, 0x00]).unwrap();
+            assert_eq!(ChannelComm::queue_channel_data(id, &msg, None, None), Err(PassthruError::ERR_NOT_SUPPORTED));
+        });
+    }
+
+    #[test]
+    fn test_filter_builders() {
+        let filter = crate::Filter::iso15765(0x7E8, 0x7E0);
+        assert_eq!(filter, crate::Filter::flow_control(&[0xFF; 4], &[0x00, 0x00, 0x07, 0xE8], &[0x00, 0x00, 0x07, 0xE0]));
+        assert_eq!(filter.filter_type(), FilterType::FLOW_CONTROL_FILTER);
+        assert_eq!(crate::Filter::can_id(0x18DAF110), crate::Filter::pass(&[0xFF; 4], &[0x18, 0xDA, 0xF1, 0x10]));
+    }
+
+    #[test]
+    fn test_replay() {
+        use crate::capture::{CapturedMsg, Direction, ReplayPort};
+        let captured = |dir, msg_id, msg_type, args: &[u8]| {
+            let mut msg = CommMsg::new_with_args(msg_type, args);
+            msg.msg_id = msg_id;
+            CapturedMsg { time_us: 0, dir, msg }
+        };
+        // Session captured with a different msg ID to the one the driver will use
+        let port = ReplayPort::new(vec![
+            captured(Direction::Tx, 0, MsgType::StatusMsg, &[0x01]),
+            captured(Direction::Tx, 42, MsgType::GetFwVersion, &[]),
+            captured(Direction::Rx, 42, MsgType::GetFwVersion, &[0x00, b'1', b'.', b'0']),
+        ]);
+        let mut m2 = MacchinaM2::start(Box::new(port.clone()), Box::new(port.clone())).unwrap();
+        let mut msg = CommMsg::new(MsgType::GetFwVersion);
+        match m2.write_and_read_ptcmd(&mut msg, 250) {
+            M2Resp::Ok(args) => assert_eq!(args, b"1.0"),
+            M2Resp::Err { status, string } => panic!("{:?} {}", status, string)
+        }
+        assert!(port.is_finished());
+        m2.stop();
+    }
+
+    #[test]
+    fn test_recorder() {
+        use crate::capture::Direction;
+        use crate::recorder::{RecordFormat, TrafficRecorder};
+        let dir = std::env::temp_dir();
+        let msg = PASSTHRU_MSG::new(Protocol::CAN, TxFlag::CAN_29BIT_ID.bits(), &[0x18, 0xDA, 0xF1, 0x10, 0x02, 0x10, 0x03]).unwrap();
+
+        let path = dir.join("m2_test_recorder.log");
+        let mut recorder = TrafficRecorder::create(&path, RecordFormat::Candump, 0).unwrap();
+        recorder.record(Direction::Tx, &msg, msg.tx_flags);
+        drop(recorder);
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.trim_end().ends_with(") can0 18DAF110#021003"), "{}", log);
+
+        let path = dir.join("m2_test_recorder.pcap");
+        let mut recorder = TrafficRecorder::create(&path, RecordFormat::Pcap, 0).unwrap();
+        recorder.record(Direction::Rx, &msg, 0);
+        drop(recorder);
+        let pcap = std::fs::read(&path).unwrap();
+        assert_eq!(pcap.len(), 24 + 16 + 16);
+        assert_eq!(&pcap[20..24], &227u32.to_le_bytes()); // SocketCAN link type
+        assert_eq!(&pcap[40..48], &[0x98, 0xDA, 0xF1, 0x10, 0x03, 0x00, 0x00, 0x00]);
+        assert_eq!(&pcap[48..51], &[0x02, 0x10, 0x03]);
+    }
+
+    #[test]
+    fn test_log_levels() {
+        use crate::logger::{Level, LevelFilter, UtcTime};
+        let filter = LevelFilter::parse("warn,channels=debug,comm=off,bad=level");
+        assert!(filter.enabled(Level::Warn, "passthru_drv"));
+        assert!(!filter.enabled(Level::Info, "passthru_drv"));
+        assert!(filter.enabled(Level::Debug, "channels"));
+        assert!(!filter.enabled(Level::Error, "comm"));
+        assert!(filter.enabled(Level::Warn, "bad"));
+        let time = UtcTime::from(std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_614_556_800_250)); // 1st March 2021
+        assert_eq!(time.iso8601(), "2021-03-01T00:00:00.250Z");
+        assert_eq!(time.weekday, 1);
+    }
+
+    #[test]
+    fn test_last_error() {
+        use crate::error::{api_call, last_error, set_error_string, set_firmware_error};
+        let status = api_call("PassThruIoctl", Some(3), || {
+            set_error_string("x".repeat(200));
+            set_firmware_error("Bus off".into());
+            PassthruError::ERR_FAILED
+        });
+        assert_eq!(status, PassthruError::ERR_FAILED);
+        let err = last_error().unwrap();
+        assert_eq!(err.function(), Some("PassThruIoctl"));
+        assert_eq!(err.channel(), Some(3));
+        assert_eq!(err.firmware(), Some("Bus off"));
+        // Successful calls leave the error alone, and other threads have their own
+        api_call("PassThruReadVersion", None, || PassthruError::STATUS_NOERROR);
+        assert_eq!(last_error(), Some(err));
+        assert!(std::thread::spawn(|| last_error().is_none()).join().unwrap());
+        // Description is cut short to fit the 80 character buffer
+        let mut buf = [0x7F as libc::c_char; 100];
+        assert_eq!(passthru_drv::passthru_get_last_error(buf.as_mut_ptr()), PassthruError::STATUS_NOERROR);
+        assert_eq!(buf.iter().position(|c| *c == 0), Some(79));
+        assert_eq!(buf[80], 0x7F);
+    }
+
+    #[test]
+    fn test_ffi_hardening() {
+        use crate::error::{api_call, last_error};
+        // Panics are caught before they reach the application
+        assert_eq!(api_call("PassThruReadMsgs", Some(1), || panic!("Bad message")), PassthruError::ERR_FAILED);
+        assert_eq!(last_error().unwrap().detail(), Some("Driver panicked: Bad message"));
+        // Sizes larger than the message are rejected before the message is read
+        #[cfg(not(feature = "v0500"))]
+        {
+            let msg = PASSTHRU_MSG { protocol_id: Protocol::CAN as u32, data_size: 5000, ..Default::default() };
+            let mut num_msgs = 1;
+            assert_eq!(passthru_drv::write_msgs(0, &msg, &mut num_msgs, 0), PassthruError::ERR_INVALID_MSG);
+            assert_eq!(num_msgs, 0);
+        }
+    }
+
+    #[test]
+    fn test_m2_frames() {
+        // Size larger than the frame, and frames too short for a header
+        let mut frame = vec![0x01, MsgType::ReceiveChannelData as u8, 0xFF, 0xFF, 0x00];
+        assert!(CommMsg::from_vec(&frame).is_err());
+        assert!(CommMsg::from_vec(&frame[..3]).is_err());
+        frame[2] = 1;
+        frame[3] = 0;
+        assert_eq!(CommMsg::from_vec(&frame).unwrap().args, vec![0x00]);
+        // Responses without a status are an error, not a panic
+        match M2Resp::from_msg(CommMsg::new_with_args(MsgType::IoctlGet, &[])) {
+            M2Resp::Err { status, .. } => assert_eq!(status, PassthruError::ERR_FAILED),
+            M2Resp::Ok(_) => panic!("Empty response was accepted")
+        }
+        // Channel data too short to have a channel ID and Rx status is dropped
+        crate::channels::ChannelComm::receive_channel_data(&CommMsg::new_with_args(MsgType::ReceiveChannelData, &[0x00, 0x01]));
+    }
+
+    #[test]
+    fn test_timebase() {
+        let mut tb = crate::timebase::Timebase::new();
+        // First message took 500us to reach the driver, 1ms after the channel was connected
+        assert_eq!(tb.stamp_at(1_000_000, 1_500), 1_500);
+        // Firmware spacing is kept, even if the driver got the message late
+        assert_eq!(tb.stamp_at(1_000_250, 9_000), 1_750);
+        // Faster message moves the timebase back, but timestamps never go backwards
+        assert_eq!(tb.stamp_at(1_000_300, 1_600), 1_750);
+        assert_eq!(tb.stamp_at(1_000_600, 2_000), 1_900);
+        // Firmware counter wrapping does not make the time jump
+        let mut tb = crate::timebase::Timebase::new();
+        assert_eq!(tb.stamp_at(u32::MAX - 99, 100), 100);
+        assert_eq!(tb.stamp_at(100, 400), 300);
+    }
+
+    #[test]
+    fn test_tx_queue() {
+        use crate::tx_queue::{TxMsg, TxQueue};
+        let queue = TxQueue::start(0);
+        let tx = |reply| TxMsg { msg: CommMsg::new(MsgType::TransmitChannelData), ptmsg: PASSTHRU_MSG::default(), recorder: None, reply };
+        // Writes waiting on a message are told why it was not sent
+        let (reply, results) = std::sync::mpsc::channel();
+        queue.push(tx(Some(reply)), None).unwrap();
+        let err = results.recv_timeout(std::time::Duration::from_secs(1)).unwrap().unwrap_err();
+        assert_eq!(err.status(), PassthruError::ERR_DEVICE_NOT_CONNECTED);
+        // Once the channel is closed, nothing more is queued
+        queue.stop();
+        assert_eq!(queue.push(tx(None), None), Err(PassthruError::ERR_INVALID_CHANNEL_ID));
+    }
+
+    #[test]
+    fn test_host_filters() {
+        use crate::filters::{hw_filters, passes, MsgFilter, MAX_HW_FILTERS};
+        // 10 pass filters on 0x7E0-0x7E9 and a block filter on 0x7E5
+        let mut filters: Vec<MsgFilter> = (0..10u32).map(|i| MsgFilter::new(FilterType::PASS_FILTER, &[0xFF; 4], &(0x7E0 + i).to_be_bytes())).collect();
+        filters.push(MsgFilter::new(FilterType::BLOCK_FILTER, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF], &[0x00, 0x00, 0x07, 0xE5, 0x10]));
+        let hw = hw_filters(filters.iter());
+        assert!(hw.len() <= MAX_HW_FILTERS);
+        // The M2 still accepts every ID a pass filter wants
+        for id in 0x7E0..0x7EAu32 {
+            assert!(hw.iter().any(|h| id & h.mask == h.id), "0x{:X} not accepted", id);
+        }
+        // Exact matching is done on the driver
+        assert!(passes(filters.iter(), &[0x00, 0x00, 0x07, 0xE9, 0x01]));
+        assert!(passes(filters.iter(), &[0x00, 0x00, 0x07, 0xE5, 0x02]));
+        assert!(!passes(filters.iter(), &[0x00, 0x00, 0x07, 0xE5, 0x10]));
+        assert!(!passes(filters.iter(), &[0x00, 0x00, 0x07, 0xEA, 0x01]));
+        assert!(!passes(filters[10..].iter(), &[0x00, 0x00, 0x07, 0xE0]));
+    }
+
+    #[test]
+    fn test_bus_stats() {
+        use crate::BusStats;
+        let args: Vec<u8> = (1..=10u32).flat_map(|v| v.to_le_bytes()).collect();
+        let stats = BusStats::from_m2(&args).unwrap();
+        assert_eq!(stats.get(BusStat::RX_FRAMES), 1);
+        assert_eq!(stats.get(BusStat::RX_ERROR_COUNT), 8);
+        assert_eq!(stats.get(BusStat::BUS_STATE), 10);
+        assert!(BusStats::from_m2(&args[..36]).is_none());
+    }
+
+    #[test]
+    fn test_bit_timing_config() {
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+        use byteorder::{ByteOrder, LittleEndian};
+        use crate::channels::ChannelComm;
+        use crate::ioctl::{get_config, set_config};
+        // Keeps the bit timing like the firmware does, which only allows 0-100%
+        let timing = Arc::new(Mutex::new(HashMap::new()));
+        let m2_timing = timing.clone();
+        let port = FakeM2Port::new(move |cmd| {
+            let param = LittleEndian::read_u32(&cmd.args[1..5]);
+            match cmd.msg_type {
+                MsgType::IoctlSet if LittleEndian::read_u32(&cmd.args[5..9]) > 100 => {
+                    let mut resp = vec![PassthruError::ERR_INVALID_IOCTL_VALUE as u8];
+                    resp.extend_from_slice(b"Bit timing must be 0-100%");
+                    resp
+                },
+                MsgType::IoctlSet => {
+                    m2_timing.lock().unwrap().insert(param, LittleEndian::read_u32(&cmd.args[5..9]));
+                    vec![0x00]
+                },
+                MsgType::IoctlGet => {
+                    let mut resp = vec![0x00];
+                    resp.extend_from_slice(&m2_timing.lock().unwrap()[&param].to_le_bytes());
+                    resp
+                },
+                _ => vec![0x00]
+            }
+        });
+        with_fake_m2(port, || {
+            let id = ChannelComm::create_channel(Protocol::CAN, 500_000, 0).unwrap();
+            let mut params = [
+                SConfig { parameter: IoctlParam::BIT_SAMPLE_POINT as u32, value: 80 },
+                SConfig { parameter: IoctlParam::SYNCH_JUMP_WIDTH as u32, value: 15 },
+            ];
+            let list = |params: &mut [SConfig]| SConfigList { num_of_params: params.len() as u32, config_ptr: params.as_mut_ptr() };
+            assert_eq!(set_config(id, &list(&mut params)), PassthruError::STATUS_NOERROR);
+            params[0].value = 0;
+            params[1].value = 0;
+            assert_eq!(get_config(id, &list(&mut params)), PassthruError::STATUS_NOERROR);
+            assert_eq!((params[0].value, params[1].value), (80, 15));
+            // Errors from the M2 and the channel reach the application as they are
+            params[0].value = 101;
+            assert_eq!(set_config(id, &list(&mut params)), PassthruError::ERR_INVALID_IOCTL_VALUE);
+            assert_eq!(timing.lock().unwrap()[&(IoctlParam::BIT_SAMPLE_POINT as u32)], 80);
+            assert_eq!(get_config(id + 1, &list(&mut params)), PassthruError::ERR_INVALID_CHANNEL_ID);
+        });
+    }
+
+    #[test]
+    fn test_rx_queue() {
+        use crate::channels::{Channel, MAX_RX_QUEUE_MSGS};
+        // Pin switched, so nothing is sent to the M2 until J1962_PINS is set
+        let mut channel = Channel::new(0, Protocol::CAN_PS, 500_000, 0).unwrap();
+        for size in [0, MAX_RX_QUEUE_MSGS + 1] {
+            assert_eq!(channel.ioctl_set_config(IoctlParam::MACCHINA_RX_QUEUE_SIZE, size), Err(PassthruError::ERR_INVALID_IOCTL_VALUE));
+        }
+        channel.ioctl_set_config(IoctlParam::MACCHINA_RX_QUEUE_SIZE, 2).unwrap();
+        // Loopback messages skip the host filters
+        let loopback = RxFlag::TX_MSG_TYPE.bits();
+        for i in 0..5u8 {
+            channel.on_receive_data(loopback, 0, &[0x00, 0x00, 0x07, 0xE0, i]);
+        }
+        assert_eq!(channel.ioctl_get_config(IoctlParam::MACCHINA_RX_DROPPED), Ok(3));
+        assert!(channel.take_rx_overflow());
+        assert!(!channel.take_rx_overflow());
+        assert_eq!(channel.pop_rx_queue(None).unwrap().data(), &[0x00, 0x00, 0x07, 0xE0, 0x00]);
+        // Shrinking the queue drops the newest messages
+        channel.on_receive_data(loopback, 0, &[0x00, 0x00, 0x07, 0xE0, 0x05]);
+        channel.ioctl_set_config(IoctlParam::MACCHINA_RX_QUEUE_SIZE, 1).unwrap();
+        assert_eq!(channel.ioctl_get_config(IoctlParam::MACCHINA_RX_DROPPED), Ok(4));
+        // Clearing the queue is not an overflow, and the drop counter only resets to 0
+        channel.clear_rx_buffer();
+        assert!(!channel.take_rx_overflow());
+        assert_eq!(channel.ioctl_set_config(IoctlParam::MACCHINA_RX_DROPPED, 1), Err(PassthruError::ERR_INVALID_IOCTL_VALUE));
+        channel.ioctl_set_config(IoctlParam::MACCHINA_RX_DROPPED, 0).unwrap();
+        assert_eq!(channel.ioctl_get_config(IoctlParam::MACCHINA_RX_DROPPED), Ok(0));
+    }
+
+    #[test]
+    fn test_error_frames() {
+        use crate::channels::{Channel, ChannelComm};
+        use crate::recorder::RecordFormat;
+        // Single wire CAN does not report bus errors
+        let mut channel = Channel::new(0, Protocol::SW_CAN_PS, 33_333, 0).unwrap();
+        assert_eq!(channel.ioctl_set_config(IoctlParam::MACCHINA_ERROR_FRAMES, 1), Err(PassthruError::ERR_NOT_SUPPORTED));
+        assert_eq!(channel.ioctl_get_config(IoctlParam::MACCHINA_ERROR_FRAMES), Err(PassthruError::ERR_NOT_SUPPORTED));
+
+        let path = std::env::temp_dir().join("m2_test_error_frames.log");
+        with_fake_m2(FakeM2Port::acking(), || {
+            let id = ChannelComm::create_channel(Protocol::CAN, 500_000, 0).unwrap();
+            ChannelComm::ioctl_set_cfg(id, IoctlParam::MACCHINA_ERROR_FRAMES, 1).unwrap();
+            ChannelComm::create_channel_filter(id, FilterType::PASS_FILTER, &[0xFF; 4], &[0x00, 0x00, 0x07, 0xE8], &[]).unwrap();
+            ChannelComm::start_recording(id, RecordFormat::Candump, &path).unwrap();
+            let receive = |rx_status: RxFlag, data: &[u8]| {
+                let mut args = vec![id as u8];
+                args.extend_from_slice(&rx_status.bits().to_le_bytes());
+                args.extend_from_slice(&[0; 4]);
+                args.extend_from_slice(data);
+                ChannelComm::receive_channel_data(&CommMsg::new_with_args(MsgType::ReceiveChannelData, &args));
+            };
+            // Bus errors and state changes have a CAN ID of 0, which the pass filter would block
+            receive(RxFlag::MACCHINA_BUS_ERROR, &[0x00, 0x00, 0x00, 0x00, 0x04, 0x08, 0x00]);
+            receive(RxFlag::MACCHINA_BUS_STATE, &[0x00, 0x00, 0x00, 0x00, 0x01, 0x80, 0x00]);
+            receive(RxFlag::empty(), &[0x00, 0x00, 0x07, 0xE0, 0x01]);
+            receive(RxFlag::empty(), &[0x00, 0x00, 0x07, 0xE8, 0x02]);
+            let read = || ChannelComm::read_channel_data(id).unwrap().map(|m| (m.rx_status, m.data()[4]));
+            assert_eq!(read(), Some((RxFlag::MACCHINA_BUS_ERROR.bits(), 0x04)));
+            assert_eq!(read(), Some((RxFlag::MACCHINA_BUS_STATE.bits(), 0x01)));
+            assert_eq!(read(), Some((0, 0x02)));
+            assert_eq!(read(), None);
+        });
+        // Only the frame that passed the filter is recorded
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 1, "{}", log);
+        assert!(log.trim_end().ends_with(" can0 7E8#02"), "{}", log);
+    }
+
+    #[test]
+    fn test_vbatt_thresholds() {
+        use crate::vbatt::{next_state, VbattSample};
+        // 11.5V - 14.5V is normal
+        let readings = [12_000, 11_450, 11_550, 11_650, 14_600, 14_450, 14_350, 12_000];
+        let mut state = VbattState::NORMAL;
+        let states: Vec<VbattState> = readings.iter().map(|&mv| { state = next_state(state, mv, 11_500, 14_500); state }).collect();
+        // Leaving a threshold is immediate, coming back needs 100mV of hysteresis
+        assert_eq!(states, [VbattState::NORMAL, VbattState::LOW, VbattState::LOW, VbattState::NORMAL,
+            VbattState::HIGH, VbattState::HIGH, VbattState::NORMAL, VbattState::NORMAL]);
+        // Thresholds of 0 are off
+        assert_eq!(next_state(VbattState::NORMAL, 5_000, 0, 0), VbattState::NORMAL);
+        let sample = VbattSample { timestamp: 1_000, voltage: 12_345, state: VbattState::LOW };
+        assert_eq!(sample.to_bytes(), [0xE8, 0x03, 0, 0, 0x39, 0x30, 0, 0, 0x01, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_prog_voltage_pins() {
+        use crate::prog_voltage::{read, set};
+        // Pins the M2 cannot switch, or J2534 does not allow, are rejected before the M2 is asked
+        assert_eq!(set(14, 12_000), Err(PassthruError::ERR_PIN_INVALID));
+        assert_eq!(set(12, SHORT_TO_GROUND), Err(PassthruError::ERR_PIN_INVALID));
+        assert_eq!(set(15, 12_000), Err(PassthruError::ERR_PIN_INVALID));
+        assert_eq!(set(13, 25_000), Err(PassthruError::ERR_EXCEEDED_LIMIT));
+        // Nothing has a voltage applied
+        assert_eq!(read(), Ok(0));
+    }
+
+    #[test]
+    fn test_detect_baud_rate() {
+        use std::sync::{Arc, Mutex};
+        use crate::channels::{Channel, ChannelComm};
+        // Only CAN can detect its baud rate
+        assert!(Channel::new(0, Protocol::CAN_PS, 0, 0).is_ok());
+        for protocol in [Protocol::ISO15765_PS, Protocol::J1850PWM, Protocol::ISO9141] {
+            assert_eq!(Channel::new(0, protocol, 0, 0).err(), Some(PassthruError::ERR_INVALID_BAUDRATE));
+        }
+        // Args of the M2's reply to the detect IOCTL, after the status
+        let reply = Arc::new(Mutex::new(Vec::new()));
+        let m2_reply = reply.clone();
+        let port = FakeM2Port::new(move |cmd| {
+            let mut resp = vec![0x00];
+            match cmd.msg_type {
+                MsgType::IoctlCmd => resp.extend_from_slice(&m2_reply.lock().unwrap()),
+                MsgType::IoctlGet => resp.extend_from_slice(&500_000u32.to_le_bytes()), // DATA_RATE
+                _ => {}
+            }
+            resp
+        });
+        with_fake_m2(port, || {
+            // Detected when the channel opens
+            let id = ChannelComm::create_channel(Protocol::CAN, 0, 0).unwrap();
+            assert_eq!(ChannelComm::ioctl_get_cfg(id, IoctlParam::DATA_RATE), Ok(500_000));
+            let channel = crate::api::Channel::from_raw(id);
+            *reply.lock().unwrap() = 250_000u32.to_le_bytes().to_vec();
+            assert_eq!(channel.detect_baud_rate().unwrap(), 250_000);
+            assert_eq!(ChannelComm::ioctl_get_cfg(id, IoctlParam::DATA_RATE), Ok(250_000));
+            // A reply that is not a baud rate leaves the channel as it was
+            *reply.lock().unwrap() = vec![0x90, 0xD0, 0x03];
+            assert_eq!(channel.detect_baud_rate().unwrap_err().status(), PassthruError::ERR_FAILED);
+            assert_eq!(ChannelComm::ioctl_get_cfg(id, IoctlParam::DATA_RATE), Ok(250_000));
+        });
+    }
+
+    #[test]
+    fn test_sw_can() {
+        use std::sync::{Arc, Mutex};
+        use crate::channels::{Channel, ChannelComm};
+        use crate::pins::{get_interface, M2Interface};
+        for (protocol, baud, ok) in [(Protocol::SW_CAN_PS, 33_333, true), (Protocol::SW_CAN_PS, 83_333, true), (Protocol::SW_CAN_PS, 500_000, false),
+                                     (Protocol::SW_ISO15765_PS, 33_333, true), (Protocol::SW_ISO15765_PS, 125_000, false)] {
+            assert_eq!(Channel::new(0, protocol, baud, 0).err(), if ok { None } else { Some(PassthruError::ERR_INVALID_BAUDRATE) });
+        }
+        // Single wire CAN is on pin 1 only, and nothing else can use it
+        let sw_pins = J1962Pins::new(1, 0);
+        assert_eq!(get_interface(Protocol::SW_CAN_PS, sw_pins), Some(M2Interface::SwCan));
+        assert_eq!(get_interface(Protocol::SW_ISO15765_PS, sw_pins), Some(M2Interface::SwCan));
+        assert_eq!(get_interface(Protocol::SW_CAN_PS, J1962Pins::new(6, 14)), None);
+        assert_eq!(get_interface(Protocol::CAN_PS, sw_pins), None);
+        assert_eq!(get_interface(Protocol::CAN_PS, J1962Pins::new(6, 14)), Some(M2Interface::Can0));
+
+        let cmds = Arc::new(Mutex::new(Vec::new()));
+        let m2_cmds = cmds.clone();
+        let port = FakeM2Port::new(move |cmd| {
+            if let MsgType::IoctlCmd = cmd.msg_type {
+                m2_cmds.lock().unwrap().push(cmd.args.clone());
+            }
+            vec![0x00]
+        });
+        with_fake_m2(port, || {
+            let id = ChannelComm::create_channel(Protocol::SW_CAN_PS, 33_333, 0).unwrap();
+            let set_mode = |mode: IoctlID| passthru_drv::passthru_ioctl(id, mode as u32, std::ptr::null_mut(), std::ptr::null_mut());
+            assert_eq!(set_mode(IoctlID::SW_CAN_HS), PassthruError::ERR_PIN_INVALID);
+            ChannelComm::ioctl_set_cfg(id, IoctlParam::J1962_PINS, sw_pins.to_raw()).unwrap();
+            assert_eq!(set_mode(IoctlID::SW_CAN_HS), PassthruError::STATUS_NOERROR);
+            assert_eq!(set_mode(IoctlID::SW_CAN_NS), PassthruError::STATUS_NOERROR);
+            assert_eq!(*cmds.lock().unwrap(), [vec![id as u8, 0x00, 0x80, 0x00, 0x00], vec![id as u8, 0x01, 0x80, 0x00, 0x00]]);
+        });
+    }
+
+    #[test]
+    fn test_pin_conflicts() {
+        use crate::channels::ChannelComm;
+        with_fake_m2(FakeM2Port::acking(), || {
+            let can = ChannelComm::create_channel(Protocol::CAN_PS, 500_000, 0).unwrap();
+            let j1939 = ChannelComm::create_channel(Protocol::J1939_PS, 250_000, 0).unwrap();
+            ChannelComm::ioctl_set_cfg(can, IoctlParam::J1962_PINS, J1962Pins::new(6, 14).to_raw()).unwrap();
+            // Any shared pin is a conflict, not just the same pair
+            for pins in [J1962Pins::new(6, 14), J1962Pins::new(14, 6), J1962Pins::new(6, 3), J1962Pins::new(3, 14)] {
+                assert_eq!(ChannelComm::ioctl_set_cfg(j1939, IoctlParam::J1962_PINS, pins.to_raw()), Err(PassthruError::ERR_CHANNEL_IN_USE), "{}", pins);
+            }
+            ChannelComm::ioctl_set_cfg(j1939, IoctlParam::J1962_PINS, J1962Pins::new(3, 11).to_raw()).unwrap();
+        });
+        assert!(J1962Pins::new(1, 0).overlaps(&J1962Pins::new(3, 1)));
+        assert!(!J1962Pins::new(1, 0).overlaps(&J1962Pins::new(6, 0)));
+    }
+
+    #[test]
+    fn test_tp2_0_connection() {
+        use crate::channels::ChannelComm;
+        use crate::ioctl::{request_connection, teardown_connection};
+        let bytes = |b: &[u8]| SBYTE_ARRAY { num_of_bytes: b.len() as u32, byte_ptr: b.as_ptr() };
+        let msg = |data: &[u8]| PASSTHRU_MSG::new(Protocol::TP2_0_PS, 0, data).unwrap();
+        with_fake_m2(FakeM2Port::acking(), || {
+            let id = ChannelComm::create_channel(Protocol::TP2_0_PS, 500_000, 0).unwrap();
+            ChannelComm::ioctl_set_cfg(id, IoctlParam::J1962_PINS, J1962Pins::new(6, 14).to_raw()).unwrap();
+            // Module address, then optionally the application type
+            assert_eq!(request_connection(id, &SBYTE_ARRAY { num_of_bytes: 1, byte_ptr: std::ptr::null() }), PassthruError::ERR_NULL_PARAMETER);
+            assert_eq!(request_connection(id, &bytes(&[])), PassthruError::ERR_INVALID_IOCTL_VALUE);
+            assert_eq!(request_connection(id, &bytes(&[0x01, 0x01, 0x00])), PassthruError::ERR_INVALID_IOCTL_VALUE);
+            assert_eq!(teardown_connection(id, &bytes(&[0x01, 0x01])), PassthruError::ERR_INVALID_IOCTL_VALUE);
+            // Messages need a connection to the module they are sent to
+            assert_eq!(ChannelComm::queue_channel_data(id, &msg(&[0x00, 0x00, 0x00, 0x01, 0x10, 0x89]), None, None), Err(PassthruError::ERR_NO_CONNECTION_ESTABLISHED));
+            assert_eq!(request_connection(id, &bytes(&[0x01, 0x01])), PassthruError::STATUS_NOERROR);
+            assert_eq!(ChannelComm::queue_channel_data(id, &msg(&[0x00, 0x00, 0x00, 0x01, 0x10, 0x89]), None, None), Ok(()));
+            assert_eq!(ChannelComm::queue_channel_data(id, &msg(&[0x00, 0x00, 0x00, 0x02, 0x10, 0x89]), None, None), Err(PassthruError::ERR_NO_CONNECTION_ESTABLISHED));
+            // Header without any data, or without a module address
+            assert_eq!(ChannelComm::queue_channel_data(id, &msg(&[0x00, 0x00, 0x00, 0x01]), None, None), Err(PassthruError::ERR_INVALID_MSG));
+            assert_eq!(ChannelComm::queue_channel_data(id, &msg(&[0x00, 0x07, 0x00, 0x01, 0x10]), None, None), Err(PassthruError::ERR_INVALID_MSG));
+            assert_eq!(teardown_connection(id, &bytes(&[0x01])), PassthruError::STATUS_NOERROR);
+            assert_eq!(ChannelComm::queue_channel_data(id, &msg(&[0x00, 0x00, 0x00, 0x01, 0x10, 0x89]), None, None), Err(PassthruError::ERR_NO_CONNECTION_ESTABLISHED));
+        });
+    }
+
+    #[cfg(feature = "v0500")]
+    #[test]
+    fn test_logical_channels() {
+        use crate::channels::ChannelComm;
+        use crate::passthru_drv_v0500::{logical_connect, logical_disconnect, select};
+        let descriptor = |local: u8, remote: u8| ISO15765_CHANNEL_DESCRIPTOR {
+            local_tx_flags: 0,
+            remote_tx_flags: 0,
+            local_address: [0x00, 0x00, 0x07, local, 0x00],
+            remote_address: [0x00, 0x00, 0x07, remote, 0x00],
+        };
+        with_fake_m2(FakeM2Port::acking(), || {
+            let physical = ChannelComm::create_channel(Protocol::ISO15765, 500_000, 0).unwrap();
+            let connect = |d: ISO15765_CHANNEL_DESCRIPTOR| {
+                let mut id = 0;
+                (logical_connect(physical, ISO15765_LOGICAL, 0, &d, &mut id), id)
+            };
+            let mut id = 0;
+            assert_eq!(logical_connect(physical, Protocol::ISO15765 as u32, 0, &descriptor(0xE8, 0xE0), &mut id), PassthruError::ERR_INVALID_PROTOCOL_ID);
+            assert_eq!(logical_connect(physical, ISO15765_LOGICAL, 1, &descriptor(0xE8, 0xE0), &mut id), PassthruError::ERR_INVALID_FLAGS);
+            // IDs come after the physical channel IDs, one per filter of the physical channel
+            let (res, ecu1) = connect(descriptor(0xE8, 0xE0));
+            assert_eq!((res, ecu1), (PassthruError::STATUS_NOERROR, 0x100 + physical * 10));
+            let (res, ecu2) = connect(descriptor(0xE9, 0xE1));
+            assert_eq!((res, ecu2), (PassthruError::STATUS_NOERROR, ecu1 + 1));
+            assert_eq!(connect(descriptor(0xE8, 0xE2)).0, PassthruError::ERR_NOT_UNIQUE);
+
+            // Nothing to read yet
+            let mut ids = [ecu1, ecu2, physical];
+            let mut set = SCHANNELSET { channel_count: 3, channel_threshold: 1, channel_list: ids.as_mut_ptr() };
+            assert_eq!(select(&mut set, READABLE_TYPE, 0), PassthruError::ERR_TIMEOUT);
+            assert_eq!({ set.channel_count }, 0);
+            // Messages go to the logical channel with their local address, and the rest to the physical channel
+            let receive = |addr: u8| {
+                let mut args = vec![physical as u8, 0, 0, 0, 0, 0, 0, 0, 0];
+                args.extend_from_slice(&[0x00, 0x00, 0x07, addr, 0x3E, 0x00]);
+                ChannelComm::receive_channel_data(&CommMsg::new_with_args(MsgType::ReceiveChannelData, &args));
+            };
+            receive(0xE8);
+            receive(0xEA);
+            let mut ids = [ecu1, ecu2, physical];
+            let mut set = SCHANNELSET { channel_count: 3, channel_threshold: 2, channel_list: ids.as_mut_ptr() };
+            assert_eq!(select(&mut set, READABLE_TYPE, 0), PassthruError::STATUS_NOERROR);
+            assert_eq!(&ids[..{ set.channel_count } as usize], &[ecu1, physical]);
+            let msg = ChannelComm::read_channel_data(ecu1).unwrap().unwrap();
+            assert_eq!((msg.protocol_id, msg.data()), (ISO15765_LOGICAL, &[0x00, 0x00, 0x07, 0xE8, 0x3E, 0x00][..]));
+            assert_eq!(ChannelComm::read_channel_data(ecu1), Ok(None));
+            assert_eq!(ChannelComm::read_channel_data(physical).unwrap().unwrap().data()[3], 0xEA);
+
+            // Disconnected IDs are invalid until the filter is used again
+            assert_eq!(logical_disconnect(ecu2), PassthruError::STATUS_NOERROR);
+            assert_eq!(logical_disconnect(ecu2), PassthruError::ERR_INVALID_CHANNEL_ID);
+            let mut ids = [ecu2];
+            let mut set = SCHANNELSET { channel_count: 1, channel_threshold: 1, channel_list: ids.as_mut_ptr() };
+            assert_eq!(select(&mut set, READABLE_TYPE, 0), PassthruError::ERR_INVALID_CHANNEL_ID);
+            assert_eq!(connect(descriptor(0xEB, 0xE3)), (PassthruError::STATUS_NOERROR, ecu2));
+        });
+    }
+}
@@ -1,116 +1,341 @@
-use std::{path::PathBuf, sync::RwLock};
-use std::fs::File;
+use std::{path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 use lazy_static::*;
 use std::sync::mpsc::{channel, Sender, Receiver};
+use crate::comm::get_setting;
+
+// Driver log. Each message has a level, and a target which is the module that logged it.
+// Configured with these attributes in macchina.json (Or the registry key on Windows):
+// * LOG-LEVEL - Level to log at, optionally per target. Eg: "info,channels=debug,comm=warn".
+//   The MACCHINA_LOG environment variable overrides this
+// * LOG-FILE - File to log to
+// * LOG-MAX-SIZE - Size in bytes the log file can reach before it is rotated
+// * LOG-FILES - Number of rotated log files to keep
+// * LOG-FORMAT - "text" or "json" (One JSON object per line)
+// * LOG-STDOUT - Set to false to stop log messages being echoed to stdout
 
 #[cfg(windows)]
-lazy_static!{
-    static ref LOG_PATH: PathBuf = PathBuf::from(r"C:\Program Files (x86)\macchina\passthru\macchina_log.txt");
-}
+const DEFAULT_LOG_PATH: &str = r"C:\Program Files (x86)\macchina\passthru\macchina_log.txt";
 
 #[cfg(unix)]
-lazy_static! {
-    static ref LOG_PATH: PathBuf = PathBuf::from(r"macchina_log.txt");
-}
+const DEFAULT_LOG_PATH: &str = "macchina_log.txt";
+
+/// Log file is rotated once it reaches this size (10MB), unless LOG-MAX-SIZE is set
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Rotated log files kept, unless LOG-FILES is set
+const DEFAULT_MAX_FILES: u32 = 3;
 
 lazy_static! {
-    static ref LOGGER : RwLock<Logger> = RwLock::new(Logger::new());
+    static ref LOGGER: Logger = Logger::new(LogConfig::load());
 }
 
-
-/// Logs an info message
+/// Logs a debug message
+#[track_caller]
 pub fn log_debug(msg: String) {
-    LOGGER.read().unwrap().queue_msg(format!("[DEBUG] - {}", msg))
+    LOGGER.log(Level::Debug, caller_target(), msg)
 }
 
+#[track_caller]
 pub fn log_debug_str(msg: &str) {
     log_debug(msg.to_string())
 }
 
+#[track_caller]
 pub fn log_error(msg: String) {
-    LOGGER.read().unwrap().queue_msg(format!("[ERROR] - {}", msg))
+    LOGGER.log(Level::Error, caller_target(), msg)
 }
 
+#[track_caller]
 pub fn log_error_str(msg: &str) {
     log_error(msg.to_string())
 }
 
+#[track_caller]
 pub fn log_warn(msg: String) {
-    LOGGER.read().unwrap().queue_msg(format!("[WARN ] - {}", msg))
+    LOGGER.log(Level::Warn, caller_target(), msg)
 }
 
+#[track_caller]
 pub fn log_warn_str(msg: &str) {
     log_warn(msg.to_string())
 }
 
+#[track_caller]
 pub fn log_info(msg: String) {
-    LOGGER.read().unwrap().queue_msg(format!("[INFO ] - {}", msg))
+    LOGGER.log(Level::Info, caller_target(), msg)
 }
 
+#[track_caller]
 pub fn log_info_str(msg: &str) {
     log_info(msg.to_string())
 }
 
+/// Logs a message sent by the M2's firmware. These have the target "m2"
 pub fn log_m2_msg(msg: String) {
-    LOGGER.read().unwrap().queue_msg(format!("[M2LOG] - {}", msg))
+    LOGGER.log(Level::Info, "m2", msg)
+}
+
+/// Name of the module that called a log function. Eg: "channels" for src/channels.rs
+#[track_caller]
+fn caller_target() -> &'static str {
+    let file = std::panic::Location::caller().file();
+    Path::new(file).file_stem().and_then(|s| s.to_str()).unwrap_or(file)
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+/// Log levels parsed from a LOG-LEVEL string
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelFilter {
+    /// Level of targets that are not listed
+    default: Level,
+    /// Target specific levels
+    targets: Vec<(String, Level)>,
+}
+
+impl LevelFilter {
+    /// Parses a level string. Eg: "info,channels=debug". Invalid entries are ignored
+    pub fn parse(spec: &str) -> Self {
+        let mut filter = LevelFilter { default: Level::Info, targets: Vec::new() };
+        for part in spec.split(',') {
+            match part.split_once('=') {
+                Some((target, level)) => {
+                    if let Some(l) = Level::from_name(level) {
+                        filter.targets.push((target.trim().to_string(), l));
+                    }
+                },
+                None => {
+                    if let Some(l) = Level::from_name(part) {
+                        filter.default = l;
+                    }
+                }
+            }
+        }
+        filter
+    }
+
+    pub fn enabled(&self, level: Level, target: &str) -> bool {
+        let max = self.targets.iter()
+            .find(|(t, _)| t == target)
+            .map_or(self.default, |(_, l)| *l);
+        level != Level::Off && level <= max
+    }
+}
+
+struct LogConfig {
+    filter: LevelFilter,
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    json: bool,
+    stdout: bool,
+}
+
+impl LogConfig {
+    fn load() -> Self {
+        let spec = std::env::var("MACCHINA_LOG").ok().or_else(|| get_setting("LOG-LEVEL"));
+        LogConfig {
+            filter: LevelFilter::parse(spec.as_deref().unwrap_or("info")),
+            path: PathBuf::from(get_setting("LOG-FILE").unwrap_or_else(|| DEFAULT_LOG_PATH.to_string())),
+            max_size: get_setting("LOG-MAX-SIZE").and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_MAX_SIZE),
+            max_files: get_setting("LOG-FILES").and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_MAX_FILES),
+            json: matches!(get_setting("LOG-FORMAT"), Some(f) if f.eq_ignore_ascii_case("json")),
+            stdout: !matches!(get_setting("LOG-STDOUT").as_deref(), Some("false") | Some("0")),
+        }
+    }
+}
+
+struct Record {
+    time: SystemTime,
+    level: Level,
+    target: &'static str,
+    msg: String,
+}
+
+impl Record {
+    fn to_text(&self) -> String {
+        format!("{} [{:<5}] {} - {}", UtcTime::from(self.time).iso8601(), self.level.as_str(), self.target, self.msg)
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "time": UtcTime::from(self.time).iso8601(),
+            "level": self.level.as_str(),
+            "target": self.target,
+            "msg": self.msg,
+        }).to_string()
+    }
+}
 
 pub struct Logger {
-    tx_queue: Arc<Mutex<Sender<String>>>
+    filter: LevelFilter,
+    tx_queue: Mutex<Sender<Record>>,
 }
 
 impl Logger {
-    fn new() -> Self {
-        let (tx, rx): (Sender<String>, Receiver<String>) = channel();
-        std::thread::spawn(move||{
-            loop {
-                if let Ok(s) = rx.recv() {
-                    Logger::write_to_file(s);
-                }
+    fn new(config: LogConfig) -> Self {
+        let (tx, rx): (Sender<Record>, Receiver<Record>) = channel();
+        let filter = config.filter.clone();
+        std::thread::spawn(move || {
+            let mut writer = LogWriter::new(config);
+            while let Ok(r) = rx.recv() {
+                writer.write(&r);
             }
         });
-        Logger{
-            tx_queue: Arc::new(Mutex::new(tx))
+        Logger {
+            filter,
+            tx_queue: Mutex::new(tx)
+        }
+    }
+
+    fn log(&self, level: Level, target: &'static str, msg: String) {
+        if self.filter.enabled(level, target) {
+            let _ = self.tx_queue.lock().unwrap().send(Record { time: SystemTime::now(), level, target, msg });
         }
     }
+}
+
+/// Writes log records from the logger thread. The log file stays open between messages
+struct LogWriter {
+    config: LogConfig,
+    file: Option<File>,
+    size: u64,
+}
 
-    #[allow(unused_must_use)]
-    pub fn queue_msg(&self, msg: String) {
-        self.tx_queue.lock().unwrap().send(msg);
+impl LogWriter {
+    fn new(config: LogConfig) -> Self {
+        let mut w = LogWriter { config, file: None, size: 0 };
+        if !cfg!(test) {
+            w.open();
+        }
+        w
     }
 
+    fn open(&mut self) {
+        match OpenOptions::new().create(true).append(true).open(&self.config.path) {
+            Ok(f) => {
+                self.size = f.metadata().map_or(0, |m| m.len());
+                self.file = Some(f);
+            },
+            Err(x) => eprintln!("LOG FILE CREATE ERROR! [{}]", x)
+        }
+    }
 
-    #[cfg(not(test))]
-    // Not test mode - Write to file
-    fn write_to_file(txt: String) {
-        let p: &std::path::Path = LOG_PATH.as_path();
+    /// Moves macchina_log.txt to macchina_log.txt.1, .1 to .2 and so on, deleting the oldest
+    fn rotate(&mut self) {
+        self.file = None;
+        let rotated = |i: u32| PathBuf::from(format!("{}.{}", self.config.path.display(), i));
+        if self.config.max_files == 0 {
+            let _ = std::fs::remove_file(&self.config.path);
+        } else {
+            let _ = std::fs::remove_file(rotated(self.config.max_files));
+            for i in (1..self.config.max_files).rev() {
+                let _ = std::fs::rename(rotated(i), rotated(i + 1));
+            }
+            let _ = std::fs::rename(&self.config.path, rotated(1));
+        }
+        self.open();
+    }
 
-        if !p.exists() {
-            if let Err(x) = File::create(p) {
-                eprintln!("LOG FILE CREATE ERROR! [{}]", x);
+    fn write(&mut self, record: &Record) {
+        let txt = if self.config.json { record.to_json() } else { record.to_text() };
+        // In test mode we only print to stdout
+        if self.config.stdout || cfg!(test) {
+            println!("{}", txt);
+        }
+        if self.file.is_none() {
+            return
+        }
+        if self.size + txt.len() as u64 + 1 > self.config.max_size {
+            self.rotate();
+        }
+        if let Some(f) = self.file.as_mut() {
+            match writeln!(f, "{}", txt) {
+                Ok(_) => self.size += txt.len() as u64 + 1,
+                Err(e) => eprintln!("WRITE ERROR! [{}] - '{}'", e, txt)
             }
         }
-        println!("{}", txt);
-        let mut ops = std::fs::OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(false)
-            .open(p)
-            .unwrap();
-
-        if let Err(e) = writeln!(ops, "{}", txt) {
-            eprintln!("WRITE ERROR! [{}] - '{}'", e, txt);
+    }
+}
+
+/// Calendar date and time in UTC
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UtcTime {
+    pub year: i64,
+    /// 1 - 12
+    pub month: u32,
+    /// 1 - 31
+    pub day: u32,
+    /// 0 is Sunday
+    pub weekday: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millis: u32,
+}
+
+impl From<SystemTime> for UtcTime {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let days = (secs / 86400) as i64;
+        let secs_of_day = (secs % 86400) as u32;
+        // Days since the epoch to a calendar date (Howard Hinnant's civil_from_days)
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+        UtcTime {
+            year: yoe + era * 400 + if month <= 2 { 1 } else { 0 },
+            month,
+            day: (doy - (153 * mp + 2) / 5 + 1) as u32,
+            weekday: ((days + 4) % 7) as u32, // 1st Jan 1970 was a Thursday
+            hour: secs_of_day / 3600,
+            minute: (secs_of_day / 60) % 60,
+            second: secs_of_day % 60,
+            millis: since_epoch.subsec_millis(),
         }
-        // Mutex gets unlocked at end of scope
     }
+}
 
-    #[cfg(test)]
-    // In test mode we print to stdout
-    fn write_to_file(txt: String) {
-        println!("{}", txt);
+impl UtcTime {
+    /// Eg: 2021-01-01T12:30:00.000Z
+    pub fn iso8601(&self) -> String {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis)
     }
 }
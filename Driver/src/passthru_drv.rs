@@ -1,91 +1,78 @@
 use libc::{c_char};
-use std::{ffi::CString, time::Instant};
+use std::mem::ManuallyDrop;
 use J2534Common::*;
-use crate::{channels, ioctl, logger};
-use crate::comm::*;
-use lazy_static::lazy_static;
-use std::sync::Mutex;
-use crate::channels::ChannelComm;
+use crate::{ioctl, logger};
+use crate::api::{self, Channel, Device, FilterId};
+use crate::error::{self, set_call_error, set_error_string};
 use crate::logger::*;
 use std::ptr::write;
 
-/// J2534 API Version supported - In this case 04.04
-const API_VERSION: &str = "04.04";
-/// DLL (Driver) version of this library
-const DLL_VERSION: &str = env!("CARGO_PKG_VERSION");
+// C API functions. These only deal with the raw pointers from the application,
+// everything else is done by the safe API in api.rs
 
-lazy_static! {
-    pub static ref LAST_ERROR_STR: Mutex<String> = Mutex::new(String::from(""));
-}
-
-/// Sets the driver error string if a function returned ERR_FAILED
-/// This string is then retrieved by the application using the driver
-/// by calling Passthru_get_last_error
-pub fn set_error_string(input: String) {
-    let mut state = LAST_ERROR_STR.lock().unwrap();
-    *state = input;
+/// Converts a safe API result into the J2534 status code
+fn to_status(res: api::Result<()>) -> PassthruError {
+    match res {
+        Ok(()) => PassthruError::STATUS_NOERROR,
+        Err(e) => set_call_error(e)
+    }
 }
 
-/// Our device ID that will be returned back to the application (0x1234)
-const DEVICE_ID: u32 = 0x1234;
+/// Size of the J2534 version and error string buffers, including the NUL terminator
+const J2534_STR_SIZE: usize = 80;
 
+/// Copies a string to an 80 character J2534 buffer. The string is cut short
+/// if it does not fit, and the buffer is always NUL terminated
 fn copy_str_unsafe(dst: *mut c_char, src: &str) -> bool {
     if dst.is_null() {
-        logger::log_info(format!("Error copying '{}' - Source ptr is null", src));
+        logger::log_info(format!("Error copying '{}' - Destination ptr is null", src));
         return false
     }
-    match CString::new(src) {
-        Err(_) => {
-            logger::log_info(format!("Error copying '{}' - CString creation failed", src));
-            false
-        }
-        Ok(x) => {
-            unsafe { std::ptr::copy_nonoverlapping(x.as_c_str().as_ptr(), dst, src.len()) };
-            true
-        }
+    // Stop at any NUL in the string, and don't split a UTF-8 character when cutting it short
+    let src = src.split('\0').next().unwrap_or_default();
+    let mut len = src.len().min(J2534_STR_SIZE - 1);
+    while !src.is_char_boundary(len) {
+        len -= 1;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(src.as_ptr() as *const c_char, dst, len);
+        *dst.add(len) = 0;
     }
+    true
 }
 
 /// Copies the API_VERSION, DLL_VERSION and FW_VERSION
 /// back to the pointers set by the source application
 pub fn passthru_read_version(
+    device_id: u32,
     fw_version_ptr: *mut c_char,
     dll_version_ptr: *mut c_char,
     api_version_ptr: *mut c_char
 ) -> PassthruError {
-    let fw_version = run_on_m2(|dev| {
-        let mut msg = CommMsg::new(MsgType::GetFwVersion);
-        match dev.write_and_read_ptcmd(&mut msg, 250) {
-            M2Resp::Ok(args) => { Ok(String::from_utf8(args).unwrap()) },
-            M2Resp::Err{status, string} => {
-                log_warn(format!("M2 failed to respond to FW_VERSION request: {}", string));
-                Err(status)   
-            }
-        }
-    });
-    if let Err(e) = fw_version {
-        return e;
-    }
-
-    if !copy_str_unsafe(fw_version_ptr, fw_version.unwrap().as_str()) {
+    let version = match Device::from_raw(device_id).and_then(|dev| dev.version()) {
+        Ok(v) => v,
+        Err(e) => return set_call_error(e)
+    };
+    if !copy_str_unsafe(fw_version_ptr, version.firmware.as_str()) {
         set_error_string("FW Version copy failed".to_string());
         return PassthruError::ERR_FAILED
     }
-    if !copy_str_unsafe(api_version_ptr, API_VERSION) {
+    if !copy_str_unsafe(api_version_ptr, version.api) {
         set_error_string("API Version copy failed".to_string());
         return PassthruError::ERR_FAILED
     }
-    if !copy_str_unsafe(dll_version_ptr, DLL_VERSION) {
+    if !copy_str_unsafe(dll_version_ptr, version.dll) {
         set_error_string("DLL Version copy failed".to_string());
         return PassthruError::ERR_FAILED
     }
     PassthruError::STATUS_NOERROR
 }
 
-/// This retrieves the last error string which was set when a function returned
-/// ERR_FAILED
+/// This retrieves the description of the last error returned by a function
+/// called on this thread
 pub fn passthru_get_last_error(dest: *mut c_char) -> PassthruError {
-    match copy_str_unsafe(dest, LAST_ERROR_STR.lock().unwrap().as_str()) {
+    let desc = error::last_error().map(|e| e.to_string()).unwrap_or_default();
+    match copy_str_unsafe(dest, &desc) {
         false => PassthruError::ERR_FAILED,
         true => PassthruError::STATUS_NOERROR
     }
@@ -93,60 +80,30 @@ pub fn passthru_get_last_error(dest: *mut c_char) -> PassthruError {
 
 
 pub fn passthru_open(device_id: *mut u32) -> PassthruError {
-    logger::log_info_str("PassthruOpen called");
-    // Check if the device is already loaded
-    if M2.read().unwrap().is_some() {
-        PassthruError::ERR_DEVICE_IN_USE
-    } else {
-        // Try to open a connection
-        match MacchinaM2::open_connection() {
-            Ok(dev) => {
-                // Device loaded OK!
-                if let Ok(ptr) = M2.write().as_deref_mut() {
-                    *ptr = Some(dev);
-                    unsafe { write(device_id, DEVICE_ID) };
-                    PassthruError::STATUS_NOERROR
-                } else {
-                    // Something happened trying to write to the static reference of the M2
-                    set_error_string("Failed to obtain write access to M2".into());
-                    PassthruError::ERR_FAILED
-                }
-            }
-            Err(x) => {
-                // Error loading the device driver. Could be due to the device
-                // not being connected to the PC, or a serial error
-                logger::log_error(format!("Cannot open com port. Error: {}", x));
-                set_error_string(format!("Serial port open failed with error {}", x));
-                PassthruError::ERR_DEVICE_NOT_CONNECTED
-            }
-        }
+    if device_id.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    match Device::open() {
+        Ok(dev) => {
+            unsafe { write(device_id, dev.into_raw()) };
+            PassthruError::STATUS_NOERROR
+        },
+        Err(e) => set_call_error(e)
     }
 }
 
 /// Attempts to close the device
 pub fn passthru_close(device_id: u32) -> PassthruError {
-    logger::log_info(format!("PassthruClose called. Device ID: {}", device_id));
-    // Device ID which isn't our device ID - So it cannot be for this driver!
-    if device_id != DEVICE_ID {
-        return PassthruError::ERR_INVALID_DEVICE_ID;
-    }
-    if let Ok(d) = M2.write().as_deref_mut() {
-        match d {
-            Some(dev) => {
-                dev.stop(); // Terminate the M2 connection
-                // Kill all open channels if any exist
-                channels::ChannelComm::force_destroy_all_channels();
-                *d = None; // Set M2 reference to None
-                PassthruError::STATUS_NOERROR
-            },
-            // Already terminated, just return NO_ERROR
-            None => PassthruError::STATUS_NOERROR
-        }
-    } else {
-        // Something unknown happened when trying to write to the RwLockGuard
-        set_error_string("Error obtaining access to RwLockGuard".into());
-        PassthruError::ERR_FAILED
-    }
+    to_status(Device::from_raw(device_id).and_then(|dev| ManuallyDrop::into_inner(dev).close()))
+}
+
+/// Applies a programming voltage to a J1962 pin
+/// # Params
+/// * device_id - Device ID of the adapter
+/// * pin - J1962 pin, or 0 for the auxiliary output
+/// * voltage - Voltage in mV, SHORT_TO_GROUND or VOLTAGE_OFF
+pub fn passthru_set_prog_voltage(device_id: u32, pin: u32, voltage: u32) -> PassthruError {
+    to_status(Device::from_raw(device_id).and_then(|dev| dev.set_programming_voltage(pin, voltage)))
 }
 
 /// Attempts to connect to a logical communication channel with the vehicle
@@ -157,28 +114,26 @@ pub fn passthru_close(device_id: u32) -> PassthruError {
 /// * Baud_rate - Bus speed of the communication channel
 /// * channel_id_ptr - Pointer to write the channel ID of the opened communication link to
 pub fn passthru_connect(device_id: u32, protocol_id: u32, flags: u32, baud_rate: u32, channel_id_ptr: *mut u32) -> PassthruError {
-    if device_id != DEVICE_ID {
-        // Diagnostic Software messed up here. Not my device ID!
-        set_error_string(format!("Not M2s device ID. Expected {}, got {}", DEVICE_ID, device_id));
-        return PassthruError::ERR_DEVICE_NOT_CONNECTED;
-    }
     // Fatal error by diagnostic software - Cannot happen!
     if channel_id_ptr.is_null() {
         logger::log_error_str("Channel destination pointer is null!?");
         return PassthruError::ERR_NULL_PARAMETER;
     }
-
+    let device = match Device::from_raw(device_id) {
+        Ok(d) => d,
+        Err(e) => return set_call_error(e)
+    };
     // Obtain the protocol type
     match Protocol::from_raw(protocol_id) {
         Some(protocol) => { // Valid protocol
             // Try to create the logical communication channel
-            match ChannelComm::create_channel(protocol, baud_rate, flags) {
-                Ok(channel_id) => { // Channel ID creation was OK! - Save it to the pointer
-                    unsafe { *channel_id_ptr = channel_id };
+            match device.connect(protocol, flags, baud_rate) {
+                Ok(channel) => { // Channel creation was OK! - Save its ID to the pointer
+                    unsafe { *channel_id_ptr = channel.into_raw() };
                     PassthruError::STATUS_NOERROR
                 },
                 // Error creating channel, return the error
-                Err(x) => x
+                Err(x) => set_call_error(x)
             }
         },
         None => { // Protocol ID was invalid (Not found in J2534 spec), throw an error
@@ -192,11 +147,7 @@ pub fn passthru_connect(device_id: u32, protocol_id: u32, flags: u32, baud_rate:
 /// # Params
 /// * channel_id - Channel ID set by passthru_connect to destroy
 pub fn passthru_disconnect(channel_id: u32) -> PassthruError {
-    // Try to destroy the channel
-    match ChannelComm::destroy_channel(channel_id as u32) {
-        Ok(_) => PassthruError::STATUS_NOERROR, // All good!
-        Err(e) => e // Error destroying, return the error
-    }
+    to_status(ManuallyDrop::into_inner(Channel::from_raw(channel_id)).disconnect())
 }
 
 /// Runs an IOCTL operation on a provided channel
@@ -264,13 +215,13 @@ pub fn passthru_ioctl(
                 return PassthruError::ERR_NULL_PARAMETER 
             }
             if output_ptr.is_null() {
-                log_error_str("Cannot run five baud init. Input ptr is null");
+                log_error_str("Cannot run five baud init. Output ptr is null");
                 return PassthruError::ERR_NULL_PARAMETER 
             }
             ioctl::five_baud_init(
                 channel_id, 
                 unsafe { (input_ptr as *mut SBYTE_ARRAY).as_mut().unwrap() },
-                unsafe { (input_ptr as *mut SBYTE_ARRAY).as_mut().unwrap() }
+                unsafe { (output_ptr as *mut SBYTE_ARRAY).as_mut().unwrap() }
             )
         },
 
@@ -281,13 +232,13 @@ pub fn passthru_ioctl(
                 return PassthruError::ERR_NULL_PARAMETER 
             }
             if output_ptr.is_null() {
-                log_error_str("Cannot run fast init. Input ptr is null");
+                log_error_str("Cannot run fast init. Output ptr is null");
                 return PassthruError::ERR_NULL_PARAMETER 
             }
             ioctl::fast_init(
                 channel_id, 
                 unsafe { (input_ptr as *mut PASSTHRU_MSG).as_mut().unwrap() },
-                unsafe { (input_ptr as *mut PASSTHRU_MSG).as_mut().unwrap() }
+                unsafe { (output_ptr as *mut PASSTHRU_MSG).as_mut().unwrap() }
             )
         },
 
@@ -322,7 +273,88 @@ pub fn passthru_ioctl(
                 return PassthruError::ERR_NULL_PARAMETER 
             }
             ioctl::delete_from_funct_msg_lookup_table(channel_id, unsafe { (input_ptr as *mut SBYTE_ARRAY).as_mut().unwrap() })
-        }
+        },
+
+        // SW CAN HS / SW CAN NS : Input: NULL, Output: NULL
+        IoctlID::SW_CAN_HS | IoctlID::SW_CAN_NS => ioctl::sw_can_set_mode(channel_id, ioctl_opt),
+
+        // PROTECT J1939 ADDR : Input: SBYTE_ARRAY, Output: NULL
+        IoctlID::PROTECT_J1939_ADDR => {
+            if input_ptr.is_null() {
+                log_error_str("Cannot protect J1939 address. Input ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            ioctl::protect_j1939_addr(channel_id, unsafe { (input_ptr as *mut SBYTE_ARRAY).as_ref().unwrap() })
+        },
+
+        // REQUEST CONNECTION : Input: SBYTE_ARRAY, Output: NULL
+        IoctlID::REQUEST_CONNECTION => {
+            if input_ptr.is_null() {
+                log_error_str("Cannot request connection. Input ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            ioctl::request_connection(channel_id, unsafe { (input_ptr as *mut SBYTE_ARRAY).as_ref().unwrap() })
+        },
+
+        // TEARDOWN CONNECTION : Input: SBYTE_ARRAY, Output: NULL
+        IoctlID::TEARDOWN_CONNECTION => {
+            if input_ptr.is_null() {
+                log_error_str("Cannot teardown connection. Input ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            ioctl::teardown_connection(channel_id, unsafe { (input_ptr as *mut SBYTE_ARRAY).as_ref().unwrap() })
+        },
+
+        // MACCHINA START RECORDING : Input: SBYTE_ARRAY, Output: NULL
+        IoctlID::MACCHINA_START_RECORDING => {
+            if input_ptr.is_null() {
+                log_error_str("Cannot start recording. Input ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            ioctl::start_recording(channel_id, unsafe { (input_ptr as *mut SBYTE_ARRAY).as_ref().unwrap() })
+        },
+
+        // MACCHINA STOP RECORDING : Input: NULL, Output: NULL
+        IoctlID::MACCHINA_STOP_RECORDING => ioctl::stop_recording(channel_id),
+
+        // MACCHINA GET BUS STATS : Input: SCONFIG_LIST, Output: NULL
+        IoctlID::MACCHINA_GET_BUS_STATS => {
+            if input_ptr.is_null() {
+                log_error_str("Cannot get bus stats. Input ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            ioctl::get_bus_stats(channel_id, unsafe { (input_ptr as *mut SConfigList).as_ref().unwrap() })
+        },
+
+        // MACCHINA CLEAR BUS STATS : Input: NULL, Output: NULL
+        IoctlID::MACCHINA_CLEAR_BUS_STATS => ioctl::clear_bus_stats(channel_id),
+
+        // MACCHINA DETECT BAUD RATE : Input: NULL, Output: unsigned long
+        IoctlID::MACCHINA_DETECT_BAUD_RATE => {
+            if output_ptr.is_null() {
+                log_error_str("Cannot detect baud rate. Output ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            ioctl::detect_baud_rate(channel_id, output_ptr as *mut u32)
+        },
+
+        // MACCHINA READ VBATT HISTORY : Input: NULL, Output: SBYTE_ARRAY
+        IoctlID::MACCHINA_READ_VBATT_HISTORY => {
+            if output_ptr.is_null() {
+                log_error_str("Cannot read battery voltage history. Output ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            ioctl::read_vbatt_history(unsafe { (output_ptr as *mut SBYTE_ARRAY).as_mut().unwrap() })
+        },
+
+        // MACCHINA READ VBATT EVENTS : Input: NULL, Output: SBYTE_ARRAY
+        IoctlID::MACCHINA_READ_VBATT_EVENTS => {
+            if output_ptr.is_null() {
+                log_error_str("Cannot read battery voltage events. Output ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            ioctl::read_vbatt_events(unsafe { (output_ptr as *mut SBYTE_ARRAY).as_mut().unwrap() })
+        },
     }
 }
 
@@ -332,8 +364,8 @@ pub fn passthru_ioctl(
 /// * filter_type - Type of filter
 ///
 pub fn set_channel_filter(channel_id: u32, filter_type: FilterType, mask_ptr: *const PASSTHRU_MSG, pattern_ptr: *const PASSTHRU_MSG, fc_ptr: *const PASSTHRU_MSG, msg_id_ptr: *mut u32) -> PassthruError {
-    if mask_ptr.is_null() || pattern_ptr.is_null() {
-        log_error_str("Mask or pattern is null!?");
+    if mask_ptr.is_null() || pattern_ptr.is_null() || msg_id_ptr.is_null() {
+        log_error_str("Mask, pattern or filter ID is null!?");
         return PassthruError::ERR_NULL_PARAMETER
     }
     
@@ -342,35 +374,37 @@ pub fn set_channel_filter(channel_id: u32, filter_type: FilterType, mask_ptr: *c
         return PassthruError::ERR_NULL_PARAMETER
     }
 
-    fn log_filter(name: &str, msg: *const PASSTHRU_MSG) {
-        let ptr = unsafe { msg.as_ref() };
-        if let Some(msg) = ptr { 
-            logger::log_debug(format!("Filter specified. Type: {}, Data: {:?}", name, &msg.data[0..msg.data_size as usize])) 
+    for (name, ptr) in [("Mask", mask_ptr), ("Pattern", pattern_ptr), ("Flow control", fc_ptr)].iter() {
+        if let Some(Err(reason)) = unsafe { (*ptr).as_ref() }.map(|m| m.validate_tx()) {
+            set_error_string(format!("{} message is invalid: {}", name, reason));
+            return PassthruError::ERR_INVALID_MSG
         }
     }
-    log_filter("Mask filter", mask_ptr);
-    log_filter("Pattern filter", pattern_ptr);
-    log_filter("Flow control filter", fc_ptr);
 
     fn get_filter_bytes(msg: *const PASSTHRU_MSG) -> Vec<u8> {
         match unsafe { msg.as_ref() } {
             None => Vec::new(),
-            Some(msg) => msg.data[0..msg.data_size as usize].to_vec()
+            Some(msg) => msg.data().to_vec()
         }
     }
 
-
     let mask: Vec<u8> = get_filter_bytes(mask_ptr);
     let pattern: Vec<u8> = get_filter_bytes(pattern_ptr);
     let flowcontrol: Vec<u8> = get_filter_bytes(fc_ptr);
+    logger::log_debug(format!("Filter specified. Type: {}, Mask: {:02X?}, Pattern: {:02X?}, Flow control: {:02X?}", filter_type, mask, pattern, flowcontrol));
 
-    match channels::ChannelComm::create_channel_filter(channel_id, filter_type, mask.as_slice(), pattern.as_slice(), flowcontrol.as_slice()) {
-        Ok(filter_id) => {
+    let filter = match filter_type {
+        FilterType::PASS_FILTER => api::Filter::pass(&mask, &pattern),
+        FilterType::BLOCK_FILTER => api::Filter::block(&mask, &pattern),
+        FilterType::FLOW_CONTROL_FILTER => api::Filter::flow_control(&mask, &pattern, &flowcontrol),
+    };
+    match Channel::from_raw(channel_id).add_filter(&filter) {
+        Ok(FilterId(filter_id)) => {
             // Assign the filter ID
             unsafe { *msg_id_ptr = filter_id };
             PassthruError::STATUS_NOERROR
         },
-        Err(e) => e
+        Err(e) => set_call_error(e)
     }
 }
 
@@ -380,67 +414,92 @@ pub fn set_channel_filter(channel_id: u32, filter_type: FilterType, mask_ptr: *c
 /// * filter_type - Filter ID
 ///
 pub fn del_channel_filter(channel_id: u32, filter_id: u32) -> PassthruError {
-    match channels::ChannelComm::remove_filter(channel_id, filter_id) {
-        Ok(_) => PassthruError::STATUS_NOERROR,
-        Err(e) => e
-    }
+    to_status(Channel::from_raw(channel_id).remove_filter(FilterId(filter_id)))
 }
 
+#[cfg(not(feature = "v0500"))] // v05.00 messages are handled by passthru_drv_v0500
 pub fn write_msgs(channel_id: u32, msg_ptr: *const PASSTHRU_MSG, num_msg_ptr: *mut u32, timeout_ms: u32) -> PassthruError {
     if msg_ptr.is_null() || num_msg_ptr.is_null() {
         return PassthruError::ERR_NULL_PARAMETER
     }
 
     let max_msgs = *unsafe { num_msg_ptr.as_ref() }.unwrap() as usize;
-    // Set num_msg_ptr to 0, we will increment it as reading to keep track how many messages have been written
+    // Set num_msg_ptr to 0, it is set to how many messages were queued or sent once writing is done
     unsafe { *num_msg_ptr = 0 };
-    let start_time = Instant::now();
-    for i in 0..max_msgs as isize {
-        if timeout_ms != 0 && start_time.elapsed().as_millis() > timeout_ms as u128 { // Timeout!
-            return PassthruError::ERR_TIMEOUT
-        }
-        let curr_msg = match unsafe { msg_ptr.offset(i).as_ref() } {
-            Some(m) => m,
-            None => return PassthruError::ERR_NULL_PARAMETER
-        };
-        match channels::ChannelComm::write_channel_data(channel_id, curr_msg, timeout_ms != 0) {
-            Ok(()) => {}, // Continue
-            Err(e) => return e // Stop sending and return the error to the application
+    let msgs = unsafe { std::slice::from_raw_parts(msg_ptr, max_msgs) };
+    // Nothing is sent if any message is invalid
+    for (i, msg) in msgs.iter().enumerate() {
+        if let Err(reason) = msg.validate_tx() {
+            set_error_string(format!("Message {} is invalid: {}", i, reason));
+            return PassthruError::ERR_INVALID_MSG
         }
-        unsafe { *num_msg_ptr += 1 };
     }
-    PassthruError::STATUS_NOERROR
+    // Timeout 0 - Queue as many messages as fit in the Tx queue.
+    // Otherwise - Wait for every message to be sent, or the timeout to pass
+    let (count, res) = Channel::from_raw(channel_id).write(msgs, std::time::Duration::from_millis(timeout_ms as u64));
+    unsafe { *num_msg_ptr = count as u32 };
+    match res {
+        Ok(()) => PassthruError::STATUS_NOERROR,
+        Err(e) => set_call_error(e)
+    }
 }
 
+/// Reports messages lost since the last read. J2534-1 returns ERR_BUFFER_OVERFLOW
+/// from the next PassThruReadMsgs, along with any messages it read
+/// # Params
+/// * channel - Channel being read
+/// * status - Status of the read
+pub fn with_rx_overflow(channel: &Channel, status: PassthruError) -> PassthruError {
+    match status {
+        PassthruError::STATUS_NOERROR | PassthruError::ERR_BUFFER_EMPTY | PassthruError::ERR_TIMEOUT => {
+            match channel.take_rx_overflow() {
+                Ok(true) => {
+                    set_error_string("Rx queue was full, received messages were lost".into());
+                    PassthruError::ERR_BUFFER_OVERFLOW
+                },
+                Ok(false) => status,
+                Err(e) => set_call_error(e)
+            }
+        },
+        _ => status
+    }
+}
+
+#[cfg(not(feature = "v0500"))] // v05.00 messages are handled by passthru_drv_v0500
 pub fn read_msgs(channel_id: u32, msg_ptr: *mut PASSTHRU_MSG, num_msg_ptr: *mut u32, timeout_ms: u32) -> PassthruError {
     if msg_ptr.is_null() || num_msg_ptr.is_null() {
         return PassthruError::ERR_NULL_PARAMETER
     }
+    let channel = Channel::from_raw(channel_id);
+    let status = read_queued_msgs(&channel, msg_ptr, num_msg_ptr, timeout_ms);
+    with_rx_overflow(&channel, status)
+}
+
+#[cfg(not(feature = "v0500"))]
+fn read_queued_msgs(channel: &Channel, msg_ptr: *mut PASSTHRU_MSG, num_msg_ptr: *mut u32, timeout_ms: u32) -> PassthruError {
     let max_msgs = *unsafe { num_msg_ptr.as_ref() }.unwrap() as usize;
     // Set num_msg_ptr to 0, we will increment it as reading to keep track how many messages have been read
     unsafe { *num_msg_ptr = 0 };
-    let start_time = Instant::now();
-    for i in 0..max_msgs as isize {
-        if timeout_ms != 0 && start_time.elapsed().as_millis() > timeout_ms as u128 { // Timeout!
-            return PassthruError::ERR_TIMEOUT
-        }
-        match channels::ChannelComm::read_channel_data(channel_id) {
-            Ok(opt) => {
-                match opt {
-                    Some(msg) => {
-                        //log_debug(format!("Channel {} sending data back to application! {}", channel_id, msg));
-                        unsafe { *msg_ptr.offset(i) = msg; }
-                        unsafe { *num_msg_ptr += 1 };
-                    }
-                    None => {
-                        if timeout_ms == 0 {
-                            return PassthruError::ERR_BUFFER_EMPTY
-                        }
-                    }
+    let start_time = std::time::Instant::now();
+    let mut read = 0;
+    while read < max_msgs {
+        match channel.try_recv() {
+            Ok(Some(msg)) => {
+                unsafe { *msg_ptr.add(read) = msg };
+                read += 1;
+                unsafe { *num_msg_ptr = read as u32 };
+            },
+            Ok(None) => {
+                if timeout_ms == 0 {
+                    return if read == 0 { PassthruError::ERR_BUFFER_EMPTY } else { PassthruError::STATUS_NOERROR }
                 }
-            }
-            Err(e) => return e
+                if start_time.elapsed().as_millis() > timeout_ms as u128 {
+                    return PassthruError::ERR_TIMEOUT
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            },
+            Err(e) => return set_call_error(e)
         }
     }
     PassthruError::STATUS_NOERROR
-}
\ No newline at end of file
+}
@@ -0,0 +1,279 @@
+// J2534 v05.00 API functions. Everything here converts to and from the 04.04
+// structures, so both APIs share the same channel and device code
+
+use libc::c_char;
+use std::{sync::Mutex, time::{Duration, Instant}};
+use J2534Common::*;
+use lazy_static::lazy_static;
+use crate::api::Channel;
+use crate::channels::ChannelComm;
+use crate::comm::*;
+use crate::logger::*;
+use crate::error::{set_call_error, set_error_string};
+use crate::passthru_drv::{passthru_connect, passthru_set_prog_voltage, with_rx_overflow};
+use crate::pins;
+
+/// Name of the M2, returned by PassThruGetNextDevice
+const DEVICE_NAME: &str = "Macchina M2 Under the dash";
+
+lazy_static! {
+    /// Devices left to be returned by PassThruGetNextDevice. None if PassThruScanForDevices has not been called
+    static ref DEVICES_LEFT: Mutex<Option<u32>> = Mutex::new(None);
+}
+
+/// Looks for the M2, there can only ever be 1
+pub fn scan_for_devices(device_count_ptr: *mut u32) -> PassthruError {
+    if device_count_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    // If the M2 is open then its port is in use, so will not show up in the port list
+    let count = if M2.read().unwrap().is_some() || is_port_present() { 1 } else { 0 };
+    log_debug(format!("PassThruScanForDevices found {} device(s)", count));
+    *DEVICES_LEFT.lock().unwrap() = Some(count);
+    unsafe { *device_count_ptr = count };
+    PassthruError::STATUS_NOERROR
+}
+
+/// Returns the next device found by scan_for_devices
+pub fn get_next_device(device_ptr: *mut SDEVICE) -> PassthruError {
+    let device = match unsafe { device_ptr.as_mut() } {
+        Some(d) => d,
+        None => return PassthruError::ERR_NULL_PARAMETER
+    };
+    let mut left = DEVICES_LEFT.lock().unwrap();
+    match *left {
+        None => {
+            set_error_string("PassThruScanForDevices has not been called".into());
+            return PassthruError::ERR_BUFFER_EMPTY
+        },
+        Some(0) => return PassthruError::ERR_EXCEEDED_LIMIT,
+        Some(x) => *left = Some(x - 1)
+    }
+    let mut name = [0 as c_char; 80];
+    for (dst, src) in name.iter_mut().zip(DEVICE_NAME.bytes()) {
+        *dst = src as c_char;
+    }
+    *device = SDEVICE {
+        device_name: name,
+        device_available: if M2.read().unwrap().is_some() { DEVICE_IN_USE } else { DEVICE_AVAILABLE },
+        device_dll_fw_status: DEVICE_DLL_FW_COMPATIBLE,
+        device_connect_media: DEVICE_CONN_WIRED,
+        device_connect_speed: 500_000,
+        device_signal_quality: 100,
+        device_signal_strength: 100,
+    };
+    PassthruError::STATUS_NOERROR
+}
+
+/// v05.00 programming voltage. The pin comes from the resource list, which must be a single J1962 pin
+pub fn set_prog_voltage(device_id: u32, resources: RESOURCE_STRUCT, voltage: u32) -> PassthruError {
+    if resources.connector != J1962_CONNECTOR || resources.num_of_resources != 1 || resources.resource_list_ptr.is_null() {
+        set_error_string("Resource list is not a single J1962 pin".into());
+        return PassthruError::ERR_PIN_INVALID
+    }
+    let pin = unsafe { *resources.resource_list_ptr };
+    passthru_set_prog_voltage(device_id, pin, voltage)
+}
+
+/// v05.00 connect. Pin switched protocols get their pins from the resource list
+/// rather than J1962_PINS
+pub fn connect(device_id: u32, protocol_id: u32, flags: u32, baud_rate: u32, resources: RESOURCE_STRUCT, channel_id_ptr: *mut u32) -> PassthruError {
+    let res = passthru_connect(device_id, protocol_id, flags, baud_rate, channel_id_ptr);
+    if res != PassthruError::STATUS_NOERROR {
+        return res
+    }
+    let protocol = Protocol::from_raw(protocol_id).unwrap(); // Already checked by passthru_connect
+    if !pins::is_pin_switched(protocol) || resources.num_of_resources == 0 {
+        return PassthruError::STATUS_NOERROR
+    }
+    let channel_id = unsafe { *channel_id_ptr };
+    let pins = if resources.connector != J1962_CONNECTOR || resources.num_of_resources > 2 || resources.resource_list_ptr.is_null() {
+        None
+    } else {
+        let list = unsafe { std::slice::from_raw_parts(resources.resource_list_ptr, resources.num_of_resources as usize) };
+        J1962Pins::from_raw(list.iter().fold(0, |acc, pin| (acc << 8) | (*pin & 0xFF)) << (8 * (2 - list.len())))
+    };
+    let res = match pins {
+        Some(p) => match ChannelComm::ioctl_set_cfg(channel_id, IoctlParam::J1962_PINS, p.to_raw()) {
+            Ok(()) => return PassthruError::STATUS_NOERROR,
+            Err(e) => e
+        },
+        None => {
+            set_error_string("Resource list is not a valid set of J1962 pins".into());
+            PassthruError::ERR_PIN_INVALID
+        }
+    };
+    // Don't leave a channel open that the application doesn't know about
+    let _ = ChannelComm::destroy_channel(channel_id);
+    res
+}
+
+/// Creates an ISO 15765 logical channel on a physical channel
+pub fn logical_connect(physical_channel_id: u32, protocol_id: u32, flags: u32, descriptor_ptr: *const ISO15765_CHANNEL_DESCRIPTOR, channel_id_ptr: *mut u32) -> PassthruError {
+    if channel_id_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    let descriptor = match unsafe { descriptor_ptr.as_ref() } {
+        Some(d) => *d,
+        None => return PassthruError::ERR_NULL_PARAMETER
+    };
+    if protocol_id != ISO15765_LOGICAL {
+        log_error(format!("{} is not a logical channel protocol", protocol_id));
+        return PassthruError::ERR_INVALID_PROTOCOL_ID
+    }
+    if flags != 0 {
+        return PassthruError::ERR_INVALID_FLAGS
+    }
+    // 5th address byte is only used with extended addressing
+    let addr_len = if TxFlag::from_bits_truncate(descriptor.local_tx_flags).contains(TxFlag::ISO15765_ADDR_TYPE) { 5 } else { 4 };
+    let local = descriptor.local_address;
+    let remote = descriptor.remote_address;
+    match ChannelComm::create_logical_channel(physical_channel_id, &local[..addr_len], &remote[..addr_len]) {
+        Ok(id) => {
+            unsafe { *channel_id_ptr = id };
+            PassthruError::STATUS_NOERROR
+        },
+        Err(e) => e
+    }
+}
+
+pub fn logical_disconnect(channel_id: u32) -> PassthruError {
+    match ChannelComm::destroy_logical_channel(channel_id) {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
+    }
+}
+
+/// Waits until at least channel_threshold channels in the set have messages to read.
+/// On return, the set only contains the channels that have messages
+pub fn select(set_ptr: *mut SCHANNELSET, select_type: u32, timeout_ms: u32) -> PassthruError {
+    let set = match unsafe { set_ptr.as_mut() } {
+        Some(s) => s,
+        None => return PassthruError::ERR_NULL_PARAMETER
+    };
+    if select_type != READABLE_TYPE {
+        set_error_string(format!("Select type {} is not supported", select_type));
+        return PassthruError::ERR_NOT_SUPPORTED
+    }
+    if set.channel_count != 0 && set.channel_list.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    if set.channel_threshold > set.channel_count {
+        set_error_string(format!("Threshold {} is larger than the channel set", { set.channel_threshold }));
+        return PassthruError::ERR_FAILED
+    }
+    let channels = unsafe { std::slice::from_raw_parts_mut(set.channel_list, set.channel_count as usize) };
+    let start_time = Instant::now();
+    let (ready, res) = loop {
+        let mut ready = Vec::new();
+        for id in channels.iter() {
+            match ChannelComm::rx_available(*id) {
+                Ok(0) => {},
+                Ok(_) => ready.push(*id),
+                Err(e) => return e
+            }
+        }
+        if ready.len() as u32 >= set.channel_threshold {
+            break (ready, PassthruError::STATUS_NOERROR)
+        }
+        if start_time.elapsed().as_millis() >= timeout_ms as u128 {
+            break (ready, PassthruError::ERR_TIMEOUT)
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    };
+    channels[..ready.len()].copy_from_slice(&ready);
+    set.channel_count = ready.len() as u32;
+    res
+}
+
+/// Reads messages into the application's v05.00 messages
+pub fn read_msgs(channel_id: u32, msg_ptr: *mut PASSTHRU_MSG_V0500, num_msg_ptr: *mut u32, timeout_ms: u32) -> PassthruError {
+    if msg_ptr.is_null() || num_msg_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    let channel = Channel::from_raw(channel_id);
+    let status = read_queued_msgs(&channel, msg_ptr, num_msg_ptr, timeout_ms);
+    with_rx_overflow(&channel, status)
+}
+
+fn read_queued_msgs(channel: &Channel, msg_ptr: *mut PASSTHRU_MSG_V0500, num_msg_ptr: *mut u32, timeout_ms: u32) -> PassthruError {
+    let max_msgs = unsafe { *num_msg_ptr } as usize;
+    // Set num_msg_ptr to 0, we will increment it as reading to keep track how many messages have been read
+    unsafe { *num_msg_ptr = 0 };
+    let start_time = Instant::now();
+    let mut read = 0;
+    while read < max_msgs {
+        match channel.try_recv() {
+            Ok(Some(msg)) => {
+                let dst = unsafe { &mut *msg_ptr.add(read) };
+                if !unsafe { dst.copy_from_v0404(&msg) } {
+                    set_error_string(format!("Message {} data buffer is too small for {} bytes", read, { msg.data_size }));
+                    return PassthruError::ERR_FAILED
+                }
+                read += 1;
+                unsafe { *num_msg_ptr = read as u32 };
+            },
+            Ok(None) => {
+                if timeout_ms == 0 {
+                    return if read == 0 { PassthruError::ERR_BUFFER_EMPTY } else { PassthruError::STATUS_NOERROR }
+                }
+                if start_time.elapsed().as_millis() > timeout_ms as u128 {
+                    return PassthruError::ERR_TIMEOUT
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            },
+            Err(e) => return set_call_error(e)
+        }
+    }
+    PassthruError::STATUS_NOERROR
+}
+
+/// Queues messages for transmission, without waiting for them to be sent
+pub fn queue_msgs(channel_id: u32, msg_ptr: *const PASSTHRU_MSG_V0500, num_msg_ptr: *mut u32) -> PassthruError {
+    if msg_ptr.is_null() || num_msg_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    let max_msgs = unsafe { *num_msg_ptr } as usize;
+    unsafe { *num_msg_ptr = 0 };
+    let mut msgs = Vec::with_capacity(max_msgs);
+    for i in 0..max_msgs {
+        let msg = match unsafe { (*msg_ptr.add(i)).to_v0404() } {
+            Some(m) => m,
+            None => {
+                set_error_string(format!("Message {} has an invalid data buffer", i));
+                return PassthruError::ERR_INVALID_MSG
+            }
+        };
+        if let Err(reason) = msg.validate_tx() {
+            set_error_string(format!("Message {} is invalid: {}", i, reason));
+            return PassthruError::ERR_INVALID_MSG
+        }
+        msgs.push(msg);
+    }
+    // Queue as many messages as fit in the Tx queue
+    let (count, res) = Channel::from_raw(channel_id).write(&msgs, Duration::from_millis(0));
+    unsafe { *num_msg_ptr = count as u32 };
+    match res {
+        Ok(()) => PassthruError::STATUS_NOERROR,
+        Err(e) => set_call_error(e)
+    }
+}
+
+/// v05.00 filters have no flow control message, ISO 15765 uses logical channels instead
+pub fn set_channel_filter(channel_id: u32, filter_type: FilterType, mask_ptr: *const PASSTHRU_MSG_V0500, pattern_ptr: *const PASSTHRU_MSG_V0500, filter_id_ptr: *mut u32) -> PassthruError {
+    if filter_id_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    if filter_type == FilterType::FLOW_CONTROL_FILTER {
+        set_error_string("Flow control filters are replaced by logical channels in v05.00".into());
+        return PassthruError::ERR_NOT_SUPPORTED
+    }
+    let (mask, pattern) = match unsafe { (mask_ptr.as_ref(), pattern_ptr.as_ref()) } {
+        (Some(m), Some(p)) => match unsafe { (m.to_v0404(), p.to_v0404()) } {
+            (Some(m), Some(p)) => (m, p),
+            _ => return PassthruError::ERR_INVALID_MSG
+        },
+        _ => return PassthruError::ERR_NULL_PARAMETER
+    };
+    crate::passthru_drv::set_channel_filter(channel_id, filter_type, &mask, &pattern, std::ptr::null(), filter_id_ptr)
+}
@@ -0,0 +1,61 @@
+use J2534Common::{J1962Pins, Protocol};
+
+/// Physical bus interfaces on the M2 that are wired to the J1962 (OBD-II) connector
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum M2Interface {
+    /// CAN0 transceiver
+    Can0 = 0,
+    /// CAN1 transceiver
+    Can1 = 1,
+    /// Single wire CAN (GMLAN) transceiver
+    SwCan = 2,
+}
+
+/// How the M2 Under the dash routes its interfaces to the J1962 connector.
+/// Anything not listed here cannot be reached by the M2
+const M2_J1962_WIRING: &[(J1962Pins, M2Interface)] = &[
+    (J1962Pins::new(6, 14), M2Interface::Can0),
+    (J1962Pins::new(3, 11), M2Interface::Can1),
+    (J1962Pins::new(1, 0), M2Interface::SwCan),
+];
+
+/// How the M2's 12V IO outputs (1-6) are wired to the J1962 connector, for programming voltage.
+/// Pin 0 is the auxiliary output, which J2534 tools wire up themselves
+const M2_PROG_VOLTAGE_WIRING: &[(u32, u8)] = &[(0, 1), (9, 2), (12, 3), (13, 4), (15, 5)];
+
+/// Returns the 12V IO output wired to a J1962 pin, or None if the M2 cannot switch the pin
+pub fn get_prog_voltage_output(pin: u32) -> Option<u8> {
+    M2_PROG_VOLTAGE_WIRING.iter().find(|(p, _)| *p == pin).map(|(_, o)| *o)
+}
+
+/// Pins used by the base (non pin switched) CAN protocols
+pub const DEFAULT_CAN_PINS: J1962Pins = J1962Pins::new(6, 14);
+
+/// Returns the M2 interface that a protocol can use on the requested J1962 pins,
+/// or None if the M2 has no interface wired to those pins that can run the protocol
+pub fn get_interface(protocol: Protocol, pins: J1962Pins) -> Option<M2Interface> {
+    let iface = M2_J1962_WIRING.iter().find(|(p, _)| *p == pins).map(|(_, i)| *i)?;
+    match (protocol, iface) {
+        (Protocol::SW_CAN_PS, M2Interface::SwCan) | (Protocol::SW_ISO15765_PS, M2Interface::SwCan) => Some(iface),
+        (Protocol::SW_CAN_PS, _) | (Protocol::SW_ISO15765_PS, _) => None,
+        (_, M2Interface::SwCan) => None,
+        (Protocol::CAN, _) | (Protocol::ISO15765, _) |
+        (Protocol::CAN_PS, _) | (Protocol::ISO15765_PS, _) | (Protocol::J1939_PS, _) |
+        (Protocol::TP2_0_PS, _) => Some(iface),
+        _ => None
+    }
+}
+
+/// Returns true if the protocol is a J2534-2 pin switched protocol, which requires
+/// J1962_PINS to be set before it can be used
+pub fn is_pin_switched(protocol: Protocol) -> bool {
+    matches!(protocol, Protocol::CAN_PS | Protocol::ISO15765_PS | Protocol::SW_CAN_PS | Protocol::SW_ISO15765_PS | Protocol::J1939_PS | Protocol::TP2_0_PS)
+}
+
+/// Returns the pins used by a protocol which does not support pin switching
+pub fn get_fixed_pins(protocol: Protocol) -> Option<J1962Pins> {
+    match protocol {
+        Protocol::CAN | Protocol::ISO15765 => Some(DEFAULT_CAN_PINS),
+        _ => None
+    }
+}
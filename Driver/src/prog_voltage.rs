@@ -0,0 +1,122 @@
+// Programming voltage (PassThruSetProgrammingVoltage). The M2 cannot generate a voltage,
+// but its 12V IO outputs can switch the battery voltage onto a J1962 pin, or connect the
+// pin to ground. So a voltage is only applied when the battery can supply it
+
+use std::sync::Mutex;
+use byteorder::{ByteOrder, LittleEndian};
+use lazy_static::lazy_static;
+use J2534Common::*;
+use crate::comm::*;
+use crate::error::{set_error_string, set_firmware_error};
+use crate::logger::*;
+use crate::pins::get_prog_voltage_output;
+use crate::vbatt;
+
+/// Voltages J2534 allows on a pin, in mV
+const MIN_VOLTAGE_MV: u32 = 5000;
+const MAX_VOLTAGE_MV: u32 = 20000;
+/// Most the battery voltage can differ from the voltage requested
+const VOLTAGE_TOLERANCE_MV: u32 = 2000;
+/// The only pin that can be shorted to ground. It never has a voltage
+const GROUND_PIN: u32 = 15;
+
+/// What a 12V IO output is doing. Values match PROG_VOLTAGE_* in the firmware
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OutputMode {
+    Off = 0,
+    Supply = 1,
+    Ground = 2,
+}
+
+lazy_static! {
+    /// J1962 pin with a voltage applied, and its 12V IO output. J2534 allows one at a time
+    static ref VOLTAGE_PIN: Mutex<Option<(u32, u8)>> = Mutex::new(None);
+}
+
+/// Applies a voltage to a J1962 pin, shorts it to ground, or turns it off
+/// # Params
+/// * pin - J1962 pin, or 0 for the auxiliary output
+/// * voltage - Voltage in mV, SHORT_TO_GROUND or VOLTAGE_OFF
+pub fn set(pin: u32, voltage: u32) -> PTResult<()> {
+    let output = match get_prog_voltage_output(pin) {
+        Some(o) => o,
+        None => {
+            set_error_string(format!("M2 cannot switch J1962 pin {}", pin));
+            return Err(PassthruError::ERR_PIN_INVALID)
+        }
+    };
+    let mode = match voltage {
+        VOLTAGE_OFF => OutputMode::Off,
+        SHORT_TO_GROUND if pin == GROUND_PIN => OutputMode::Ground,
+        SHORT_TO_GROUND => {
+            set_error_string(format!("Only pin {} can be shorted to ground", GROUND_PIN));
+            return Err(PassthruError::ERR_PIN_INVALID)
+        },
+        _ if pin == GROUND_PIN => {
+            set_error_string(format!("Pin {} can only be shorted to ground", GROUND_PIN));
+            return Err(PassthruError::ERR_PIN_INVALID)
+        },
+        _ if !(MIN_VOLTAGE_MV..=MAX_VOLTAGE_MV).contains(&voltage) => {
+            set_error_string(format!("{}mV is outside the {}-{}mV J2534 allows", voltage, MIN_VOLTAGE_MV, MAX_VOLTAGE_MV));
+            return Err(PassthruError::ERR_EXCEEDED_LIMIT)
+        },
+        _ => OutputMode::Supply
+    };
+    let mut voltage_pin = VOLTAGE_PIN.lock().unwrap();
+    if mode == OutputMode::Supply {
+        if let Some((p, _)) = *voltage_pin {
+            if p != pin {
+                set_error_string(format!("J1962 pin {} already has a programming voltage", p));
+                return Err(PassthruError::ERR_EXCEEDED_LIMIT)
+            }
+        }
+        let vbatt = vbatt::read()?;
+        if vbatt.abs_diff(voltage) > VOLTAGE_TOLERANCE_MV {
+            set_error_string(format!("M2 can only output the battery voltage ({}mV), not {}mV", vbatt, voltage));
+            return Err(PassthruError::ERR_EXCEEDED_LIMIT)
+        }
+    }
+    run_on_m2(|dev| {
+        match dev.write_and_read_ptcmd(&mut CommMsg::new_with_args(MsgType::SetProgVoltage, &[output, mode as u8]), 250) {
+            M2Resp::Ok(_) => Ok(()),
+            M2Resp::Err{status, string} => {
+                log_error(format!("M2 failed to switch 12V IO output {} (Status {:?}): {}", output, status, string));
+                set_firmware_error(string);
+                Err(status)
+            }
+        }
+    })?;
+    log_info(format!("J1962 pin {} (12V IO output {}) is now {:?}", pin, output, mode));
+    if mode == OutputMode::Supply {
+        *voltage_pin = Some((pin, output));
+    } else if matches!(*voltage_pin, Some((p, _)) if p == pin) {
+        *voltage_pin = None;
+    }
+    Ok(())
+}
+
+/// Measures the programming voltage in mV. 0 if no pin has a voltage applied
+pub fn read() -> PTResult<u32> {
+    let output = match *VOLTAGE_PIN.lock().unwrap() {
+        Some((_, o)) => o,
+        None => return Ok(0)
+    };
+    run_on_m2(|dev| {
+        match dev.write_and_read_ptcmd(&mut CommMsg::new_with_args(MsgType::ReadProgVoltage, &[output]), 250) {
+            M2Resp::Ok(args) if args.len() >= 4 => Ok(LittleEndian::read_u32(&args)),
+            M2Resp::Ok(args) => {
+                set_error_string(format!("M2 sent {} bytes of programming voltage", args.len()));
+                Err(PassthruError::ERR_FAILED)
+            },
+            M2Resp::Err{status, string} => {
+                set_firmware_error(string);
+                Err(status)
+            }
+        }
+    })
+}
+
+/// Forgets the pin with a voltage applied. The M2 turns its outputs off when the driver disconnects
+pub fn reset() {
+    *VOLTAGE_PIN.lock().unwrap() = None;
+}
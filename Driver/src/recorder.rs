@@ -0,0 +1,213 @@
+// Records the CAN frames a channel sends and receives to standard log formats,
+// so captures can be opened in Wireshark, SavvyCAN or can-utils
+
+use std::{fs::File, io::Write, path::Path, time::{SystemTime, UNIX_EPOCH}};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use J2534Common::*;
+use crate::capture::Direction;
+use crate::logger::{log_warn, UtcTime};
+
+/// PCAP link type for SocketCAN frames
+const LINKTYPE_CAN_SOCKETCAN: u32 = 227;
+/// Set in a SocketCAN ID for 29 bit frames
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// Linux can-utils candump log (candump -l)
+    Candump = 0x00,
+    /// Vector ASC
+    Asc = 0x01,
+    /// PCAP with the SocketCAN link type
+    Pcap = 0x02,
+}
+
+impl RecordFormat {
+    /// Format from its macchina.json name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "candump" | "log" => Some(RecordFormat::Candump),
+            "asc" => Some(RecordFormat::Asc),
+            "pcap" => Some(RecordFormat::Pcap),
+            _ => None
+        }
+    }
+
+    pub fn from_raw(x: u8) -> Option<Self> {
+        match x {
+            0x00 => Some(RecordFormat::Candump),
+            0x01 => Some(RecordFormat::Asc),
+            0x02 => Some(RecordFormat::Pcap),
+            _ => None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Candump => "log",
+            RecordFormat::Asc => "asc",
+            RecordFormat::Pcap => "pcap",
+        }
+    }
+}
+
+/// Only channels where each PASSTHRU_MSG is a single CAN frame can be recorded. On the
+/// transport protocol channels, the M2 splits and joins the frames so the driver never sees them
+pub fn can_record(protocol: Protocol) -> bool {
+    matches!(protocol, Protocol::CAN | Protocol::CAN_PS | Protocol::SW_CAN_PS)
+}
+
+/// CAN frame taken from a PASSTHRU_MSG
+struct CanFrame<'a> {
+    id: u32,
+    extended: bool,
+    data: &'a [u8],
+}
+
+impl<'a> CanFrame<'a> {
+    /// flags - Tx flags or Rx status of the message. Both use the same bit for 29 bit IDs
+    fn from_msg(msg: &'a PASSTHRU_MSG, flags: u32) -> Option<Self> {
+        let data = msg.data();
+        if data.len() < 4 || data.len() > 12 {
+            return None
+        }
+        let id = BigEndian::read_u32(&data[0..4]);
+        Some(CanFrame { id, extended: flags & TxFlag::CAN_29BIT_ID.bits() != 0 || id > 0x7FF, data: &data[4..] })
+    }
+}
+
+pub struct TrafficRecorder {
+    format: RecordFormat,
+    file: File,
+    /// Interface name written to candump logs
+    interface: String,
+    /// ASC timestamps are relative to this
+    start: SystemTime,
+}
+
+impl std::fmt::Debug for TrafficRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrafficRecorder").field("format", &self.format).finish()
+    }
+}
+
+impl TrafficRecorder {
+    /// Creates the record file, and writes its header
+    /// # Params
+    /// * path - File to record to. Overwritten if it exists
+    /// * format - Format of the file
+    /// * channel_id - Channel being recorded, used for the candump interface name
+    pub fn create(path: &Path, format: RecordFormat, channel_id: u32) -> std::io::Result<Self> {
+        let mut recorder = TrafficRecorder {
+            format,
+            file: File::create(path)?,
+            interface: format!("can{}", channel_id),
+            start: SystemTime::now(),
+        };
+        recorder.write_header()?;
+        Ok(recorder)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        match self.format {
+            RecordFormat::Candump => Ok(()),
+            RecordFormat::Asc => {
+                let date = asc_date(self.start);
+                write!(self.file, "date {}\r\nbase hex  timestamps absolute\r\ninternal events logged\r\nBegin Triggerblock {}\r\n", date, date)
+            },
+            RecordFormat::Pcap => {
+                let mut header = Vec::with_capacity(24);
+                header.write_u32::<LittleEndian>(0xA1B2_C3D4)?; // Magic (Microsecond timestamps)
+                header.write_u16::<LittleEndian>(2)?; // Version 2.4
+                header.write_u16::<LittleEndian>(4)?;
+                header.write_i32::<LittleEndian>(0)?; // UTC
+                header.write_u32::<LittleEndian>(0)?; // Timestamp accuracy
+                header.write_u32::<LittleEndian>(65535)?; // Snapshot length
+                header.write_u32::<LittleEndian>(LINKTYPE_CAN_SOCKETCAN)?;
+                self.file.write_all(&header)
+            }
+        }
+    }
+
+    /// Records a message sent or received on the channel. Messages that are not a CAN frame are skipped
+    pub fn record(&mut self, dir: Direction, msg: &PASSTHRU_MSG, flags: u32) {
+        let frame = match CanFrame::from_msg(msg, flags) {
+            Some(f) => f,
+            None => return
+        };
+        let now = SystemTime::now();
+        let res = match self.format {
+            RecordFormat::Candump => self.write_candump(now, &frame),
+            RecordFormat::Asc => self.write_asc(now, dir, &frame),
+            RecordFormat::Pcap => self.write_pcap(now, &frame),
+        };
+        if let Err(e) = res {
+            log_warn(format!("Could not write to {} traffic record: {}", self.interface, e));
+        }
+    }
+
+    fn write_candump(&mut self, now: SystemTime, frame: &CanFrame) -> std::io::Result<()> {
+        let time = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let id = if frame.extended { format!("{:08X}", frame.id) } else { format!("{:03X}", frame.id) };
+        let data: String = frame.data.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(self.file, "({}.{:06}) {} {}#{}", time.as_secs(), time.subsec_micros(), self.interface, id, data)
+    }
+
+    fn write_asc(&mut self, now: SystemTime, dir: Direction, frame: &CanFrame) -> std::io::Result<()> {
+        let time = now.duration_since(self.start).unwrap_or_default();
+        let id = if frame.extended { format!("{:X}x", frame.id) } else { format!("{:X}", frame.id) };
+        let dir = match dir {
+            Direction::Tx => "Tx",
+            Direction::Rx => "Rx",
+        };
+        let data: Vec<String> = frame.data.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(self.file, "{:>11.6} 1  {:<15} {:<4} d {:X} {}\r\n", time.as_secs_f64(), id, dir, frame.data.len(), data.join(" "))
+    }
+
+    fn write_pcap(&mut self, now: SystemTime, frame: &CanFrame) -> std::io::Result<()> {
+        let time = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(32);
+        record.write_u32::<LittleEndian>(time.as_secs() as u32)?;
+        record.write_u32::<LittleEndian>(time.subsec_micros())?;
+        record.write_u32::<LittleEndian>(16)?; // Captured length
+        record.write_u32::<LittleEndian>(16)?; // Original length
+        // SocketCAN frame. The ID is in network byte order for this link type
+        record.write_u32::<BigEndian>(if frame.extended { frame.id | CAN_EFF_FLAG } else { frame.id })?;
+        record.push(frame.data.len() as u8);
+        record.extend_from_slice(&[0; 3]); // Padding and reserved
+        let mut data = [0u8; 8];
+        data[..frame.data.len()].copy_from_slice(frame.data);
+        record.extend_from_slice(&data);
+        self.file.write_all(&record)
+    }
+}
+
+impl Drop for TrafficRecorder {
+    fn drop(&mut self) {
+        if self.format == RecordFormat::Asc {
+            let _ = write!(self.file, "End TriggerBlock\r\n");
+        }
+    }
+}
+
+/// Formats a time the way ASC headers expect it (UTC). Eg: Fri Jan 01 12:30:00.000 pm 2021
+fn asc_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let t = UtcTime::from(time);
+    let hour_12 = match t.hour % 12 {
+        0 => 12,
+        h => h
+    };
+    format!("{} {} {:02} {:02}:{:02}:{:02}.{:03} {} {}",
+        DAYS[t.weekday as usize],
+        MONTHS[(t.month - 1) as usize],
+        t.day,
+        hour_12,
+        t.minute,
+        t.second,
+        t.millis,
+        if t.hour < 12 { "am" } else { "pm" },
+        t.year
+    )
+}
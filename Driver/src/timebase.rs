@@ -0,0 +1,54 @@
+// Maps the time the M2 stamps on received messages (Its micros() counter)
+// onto J2534 timestamps, which count microseconds from PassThruConnect
+
+use std::time::Instant;
+
+/// Timebase of a channel, starting when the channel was connected
+#[derive(Debug, Clone)]
+pub struct Timebase {
+    start: Instant,
+    /// Last firmware timestamp seen
+    last_fw: Option<u32>,
+    /// Firmware time in microseconds, carried on past the u32 counter wrapping
+    fw_time: i64,
+    /// Host time - firmware time. This is the lowest seen, as that message
+    /// had the least serial and thread latency before it reached us
+    offset: Option<i64>,
+    /// Last timestamp given out. Timestamps never go backwards
+    last: u64,
+}
+
+impl Timebase {
+    pub fn new() -> Self {
+        Timebase { start: Instant::now(), last_fw: None, fw_time: 0, offset: None, last: 0 }
+    }
+
+    /// Microseconds since the channel was connected
+    pub fn elapsed(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    /// Returns the J2534 timestamp of a message the M2 stamped with fw_micros.
+    /// Like all J2534 timestamps, this wraps after ~71 minutes
+    pub fn stamp(&mut self, fw_micros: u32) -> u32 {
+        let now = self.elapsed();
+        self.stamp_at(fw_micros, now)
+    }
+
+    /// Same as stamp, with the time the message reached the driver
+    /// # Params
+    /// * fw_micros - Firmware timestamp of the message
+    /// * host_micros - Microseconds since the channel was connected, when the message was received
+    pub(crate) fn stamp_at(&mut self, fw_micros: u32, host_micros: u64) -> u32 {
+        if let Some(last) = self.last_fw {
+            // Signed, so a message stamped just before the previous one does not look like a wrap
+            self.fw_time += fw_micros.wrapping_sub(last) as i32 as i64;
+        }
+        self.last_fw = Some(fw_micros);
+        let offset = host_micros as i64 - self.fw_time;
+        let offset = self.offset.map_or(offset, |o| o.min(offset));
+        self.offset = Some(offset);
+        self.last = self.last.max((self.fw_time + offset).max(0) as u64);
+        self.last as u32
+    }
+}
@@ -0,0 +1,161 @@
+// Tx queue of a channel. Messages written by the application are queued here,
+// and a sender thread per channel sends them to the M2 one at a time, waiting for
+// the M2 to confirm each one went out
+
+use std::collections::VecDeque;
+use std::sync::{mpsc::Sender, Arc, Condvar, Mutex};
+use std::thread::spawn;
+use std::time::Instant;
+use J2534Common::*;
+use crate::capture::Direction;
+use crate::comm::*;
+use crate::error::{set_error_string, set_firmware_error, Error};
+use crate::logger::*;
+use crate::recorder::TrafficRecorder;
+
+/// Most messages a channel can have waiting to be sent
+pub const MAX_TX_QUEUE_MSGS: usize = 500;
+
+/// How long the M2 has to confirm a message was sent
+const TX_CONFIRM_TIMEOUT_MS: u128 = 100;
+
+/// Receives the result of sending a message, for writes that wait for their messages to go out
+pub type TxReply = Sender<std::result::Result<(), Error>>;
+
+/// Message waiting in the Tx queue
+pub struct TxMsg {
+    /// TransmitChannelData message for the M2
+    pub msg: CommMsg,
+    /// Message as written by the application, for recording once it is sent
+    pub ptmsg: PASSTHRU_MSG,
+    pub recorder: Option<Arc<Mutex<TrafficRecorder>>>,
+    pub reply: Option<TxReply>,
+}
+
+struct TxState {
+    pending: VecDeque<TxMsg>,
+    running: bool,
+}
+
+pub struct TxQueue {
+    channel_id: u32,
+    state: Mutex<TxState>,
+    /// Signalled when a message is queued, a message is taken by the sender, or the queue stops
+    cond: Condvar,
+}
+
+impl std::fmt::Debug for TxQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pending = self.state.lock().unwrap().pending.len();
+        f.debug_struct("TxQueue").field("channel_id", &self.channel_id).field("pending", &pending).finish()
+    }
+}
+
+impl TxQueue {
+    /// Creates the queue of a channel, and starts its sender thread
+    pub fn start(channel_id: u32) -> Arc<Self> {
+        let queue = Arc::new(TxQueue {
+            channel_id,
+            state: Mutex::new(TxState { pending: VecDeque::new(), running: true }),
+            cond: Condvar::new(),
+        });
+        let sender = queue.clone();
+        spawn(move || sender.run());
+        queue
+    }
+
+    /// Adds a message to the end of the queue
+    /// # Params
+    /// * msg - Message to queue
+    /// * deadline - If the queue is full, wait until this time for space. If None, fail straight away
+    /// # Returns
+    /// ERR_BUFFER_FULL if the queue is full and there is no deadline, ERR_TIMEOUT if the deadline passed
+    pub fn push(&self, msg: TxMsg, deadline: Option<Instant>) -> std::result::Result<(), PassthruError> {
+        let mut state = self.state.lock().unwrap();
+        while state.running && state.pending.len() >= MAX_TX_QUEUE_MSGS {
+            let deadline = match deadline {
+                Some(d) => d,
+                None => {
+                    set_error_string(format!("Tx queue of channel {} is full ({} messages)", self.channel_id, MAX_TX_QUEUE_MSGS));
+                    return Err(PassthruError::ERR_BUFFER_FULL)
+                }
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                set_error_string(format!("Tx queue of channel {} stayed full", self.channel_id));
+                return Err(PassthruError::ERR_TIMEOUT)
+            }
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        if !state.running {
+            return Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+        }
+        state.pending.push_back(msg);
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    /// Discards every message waiting to be sent (CLEAR_TX_BUFFER).
+    /// Writes waiting on discarded messages are told the messages were discarded
+    pub fn clear(&self) {
+        let discarded: Vec<TxMsg> = self.state.lock().unwrap().pending.drain(..).collect();
+        self.cond.notify_all();
+        if !discarded.is_empty() {
+            log_debug(format!("Channel {} discarded {} queued Tx messages", self.channel_id, discarded.len()));
+        }
+    }
+
+    /// Stops the sender thread. Messages still queued are discarded
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        state.pending.clear();
+        self.cond.notify_all();
+    }
+
+    fn run(&self) {
+        log_debug(format!("Channel {} Tx thread starting", self.channel_id));
+        loop {
+            let mut tx = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if !state.running {
+                        log_debug(format!("Channel {} Tx thread exiting", self.channel_id));
+                        return
+                    }
+                    if let Some(m) = state.pending.pop_front() {
+                        break m
+                    }
+                    state = self.cond.wait(state).unwrap();
+                }
+            };
+            self.cond.notify_all(); // Space for writes waiting on a full queue
+            let res = self.send(&mut tx.msg);
+            if res.is_ok() {
+                if let Some(r) = &tx.recorder {
+                    r.lock().unwrap().record(Direction::Tx, &tx.ptmsg, tx.ptmsg.tx_flags);
+                }
+            }
+            match tx.reply {
+                Some(reply) => { let _ = reply.send(res.map_err(Error::from)); },
+                None => if let Err(e) = res {
+                    log_warn(format!("Channel {} could not send queued message {}: {:?}", self.channel_id, tx.ptmsg, e));
+                }
+            }
+        }
+    }
+
+    /// Sends a message to the M2, and waits for the M2 to confirm it was sent
+    fn send(&self, msg: &mut CommMsg) -> std::result::Result<(), PassthruError> {
+        run_on_m2(|dev| {
+            match dev.write_and_read_ptcmd(msg, TX_CONFIRM_TIMEOUT_MS) {
+                M2Resp::Ok(_) => Ok(()),
+                M2Resp::Err{status, string} => {
+                    log_error(format!("M2 failed to write data to channel {} (Status {:?}): {}", self.channel_id, status, string));
+                    set_firmware_error(string);
+                    Err(status)
+                }
+            }
+        })
+    }
+}
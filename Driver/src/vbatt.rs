@@ -0,0 +1,268 @@
+// Battery voltage monitor. Whilst the M2 is open, a sampler thread reads the battery
+// voltage in the background, keeping a history of the readings, and records an event
+// whenever the voltage crosses the low or high threshold set by the application
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, LittleEndian};
+use lazy_static::lazy_static;
+use J2534Common::*;
+use crate::comm::*;
+use crate::error::{set_error_string, set_firmware_error};
+use crate::logger::*;
+
+/// Readings kept in the history (5 minutes at the default interval)
+pub const VBATT_HISTORY_SIZE: usize = 600;
+/// Events kept until the application reads them. Past this, the oldest are dropped
+pub const VBATT_MAX_EVENTS: usize = 100;
+/// Bytes of each reading or event given to C applications
+pub const VBATT_RECORD_SIZE: usize = 12;
+
+/// Time between readings until MACCHINA_VBATT_INTERVAL is set
+const DEFAULT_INTERVAL_MS: u32 = 500;
+const MIN_INTERVAL_MS: u32 = 50;
+const MAX_INTERVAL_MS: u32 = 60_000;
+
+/// How far the voltage must come back inside a threshold before it is normal again,
+/// so a voltage sitting right on a threshold does not flood the application with events
+const HYSTERESIS_MV: u32 = 100;
+
+/// Battery voltage reading, or a change of VbattState
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VbattSample {
+    /// Milliseconds since the M2 was opened
+    pub timestamp: u32,
+    /// Battery voltage in mV
+    pub voltage: u32,
+    /// State of the battery voltage once this reading was taken
+    pub state: VbattState,
+}
+
+impl VbattSample {
+    /// Record given to C applications: timestamp, voltage and state, each a little endian u32
+    pub fn to_bytes(&self) -> [u8; VBATT_RECORD_SIZE] {
+        let mut buf = [0u8; VBATT_RECORD_SIZE];
+        LittleEndian::write_u32(&mut buf[0..4], self.timestamp);
+        LittleEndian::write_u32(&mut buf[4..8], self.voltage);
+        LittleEndian::write_u32(&mut buf[8..12], self.state as u32);
+        buf
+    }
+}
+
+/// Returns the state of a battery voltage reading
+/// # Params
+/// * state - State of the previous reading
+/// * voltage - Reading in mV
+/// * low_mv - Low threshold in mV, 0 if unset
+/// * high_mv - High threshold in mV, 0 if unset
+pub fn next_state(state: VbattState, voltage: u32, low_mv: u32, high_mv: u32) -> VbattState {
+    if low_mv != 0 && (voltage < low_mv || (state == VbattState::LOW && voltage < low_mv + HYSTERESIS_MV)) {
+        VbattState::LOW
+    } else if high_mv != 0 && (voltage > high_mv || (state == VbattState::HIGH && voltage + HYSTERESIS_MV > high_mv)) {
+        VbattState::HIGH
+    } else {
+        VbattState::NORMAL
+    }
+}
+
+struct Monitor {
+    start: Instant,
+    interval_ms: u32,
+    low_mv: u32,
+    high_mv: u32,
+    state: VbattState,
+    history: VecDeque<VbattSample>,
+    events: VecDeque<VbattSample>,
+    running: bool,
+}
+
+impl Monitor {
+    fn new() -> Self {
+        Monitor {
+            start: Instant::now(),
+            interval_ms: DEFAULT_INTERVAL_MS,
+            low_mv: 0,
+            high_mv: 0,
+            state: VbattState::NORMAL,
+            history: VecDeque::with_capacity(VBATT_HISTORY_SIZE),
+            events: VecDeque::new(),
+            running: false,
+        }
+    }
+
+    fn record(&mut self, voltage: u32) {
+        let state = next_state(self.state, voltage, self.low_mv, self.high_mv);
+        let sample = VbattSample { timestamp: self.start.elapsed().as_millis() as u32, voltage, state };
+        if self.history.len() == VBATT_HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(sample);
+        if state != self.state {
+            match state {
+                VbattState::NORMAL => log_info(format!("Battery voltage back to normal ({}mV)", voltage)),
+                _ => log_warn(format!("Battery voltage {} ({}mV)", state, voltage)),
+            }
+            if self.events.len() == VBATT_MAX_EVENTS {
+                log_warn_str("Battery voltage events were not read, dropping the oldest");
+                self.events.pop_front();
+            }
+            self.events.push_back(sample);
+            self.state = state;
+        }
+    }
+}
+
+lazy_static! {
+    static ref MONITOR: Mutex<Monitor> = Mutex::new(Monitor::new());
+    /// Signalled when the sampler should stop, or its interval changes
+    static ref WAKE: Condvar = Condvar::new();
+    static ref SAMPLER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+/// Starts the sampler thread, with a clear history and the default settings.
+/// Called once the M2 is open
+pub fn start() {
+    let mut sampler = SAMPLER.lock().unwrap();
+    if sampler.is_some() {
+        return
+    }
+    let mut monitor = MONITOR.lock().unwrap();
+    *monitor = Monitor::new();
+    monitor.running = true;
+    *sampler = Some(spawn(run));
+}
+
+/// Stops the sampler thread, waiting for any reading in progress. Called before the M2 is closed
+pub fn stop() {
+    let handle = SAMPLER.lock().unwrap().take();
+    MONITOR.lock().unwrap().running = false;
+    WAKE.notify_all();
+    if let Some(h) = handle {
+        let _ = h.join();
+    }
+}
+
+fn run() {
+    log_debug_str("Battery voltage sampler starting");
+    loop {
+        {
+            let mut monitor = MONITOR.lock().unwrap();
+            if !monitor.running {
+                break
+            }
+            let interval = Duration::from_millis(monitor.interval_ms as u64);
+            monitor = WAKE.wait_timeout(monitor, interval).unwrap().0;
+            if !monitor.running {
+                break
+            }
+        }
+        // Failures are already logged, and the next reading may well work
+        let _ = read();
+    }
+    log_debug_str("Battery voltage sampler stopped");
+}
+
+/// Reads the battery voltage from the M2 in mV, adding it to the history.
+/// If the M2 is too busy to send a full reply, the last reading is returned
+pub fn read() -> PTResult<u32> {
+    let reading = run_on_m2(|dev| {
+        match dev.write_and_read_ptcmd(&mut CommMsg::new(MsgType::ReadBatt), 250) {
+            M2Resp::Ok(args) if args.len() >= 4 => Ok(Some(LittleEndian::read_u32(&args))),
+            M2Resp::Ok(args) => {
+                log_warn(format!("M2 sent {} bytes of battery voltage", args.len()));
+                Ok(None)
+            },
+            M2Resp::Err{status, string} => {
+                log_error(format!("Error reading battery voltage (Status {:?}): {}", status, string));
+                set_firmware_error(string);
+                Err(status)
+            }
+        }
+    })?;
+    let mut monitor = MONITOR.lock().unwrap();
+    match reading {
+        Some(v) => {
+            monitor.record(v);
+            Ok(v)
+        },
+        None => match monitor.history.back() {
+            Some(last) => Ok(last.voltage),
+            None => {
+                set_error_string("M2 did not send the battery voltage".into());
+                Err(PassthruError::ERR_FAILED)
+            }
+        }
+    }
+}
+
+/// Returns if a config param belongs to the battery voltage monitor rather than a channel
+pub fn is_param(param: IoctlParam) -> bool {
+    matches!(param, IoctlParam::MACCHINA_VBATT_INTERVAL | IoctlParam::MACCHINA_VBATT_LOW | IoctlParam::MACCHINA_VBATT_HIGH | IoctlParam::MACCHINA_VBATT_STATE)
+}
+
+pub fn get_config(param: IoctlParam) -> PTResult<u32> {
+    let monitor = MONITOR.lock().unwrap();
+    match param {
+        IoctlParam::MACCHINA_VBATT_INTERVAL => Ok(monitor.interval_ms),
+        IoctlParam::MACCHINA_VBATT_LOW => Ok(monitor.low_mv),
+        IoctlParam::MACCHINA_VBATT_HIGH => Ok(monitor.high_mv),
+        IoctlParam::MACCHINA_VBATT_STATE => Ok(monitor.state as u32),
+        _ => Err(PassthruError::ERR_NOT_SUPPORTED)
+    }
+}
+
+pub fn set_config(param: IoctlParam, value: u32) -> PTResult<()> {
+    let mut monitor = MONITOR.lock().unwrap();
+    match param {
+        IoctlParam::MACCHINA_VBATT_INTERVAL => {
+            if !(MIN_INTERVAL_MS..=MAX_INTERVAL_MS).contains(&value) {
+                set_error_string(format!("Battery voltage interval must be {}-{}ms", MIN_INTERVAL_MS, MAX_INTERVAL_MS));
+                return Err(PassthruError::ERR_INVALID_IOCTL_VALUE)
+            }
+            monitor.interval_ms = value;
+            WAKE.notify_all();
+        },
+        IoctlParam::MACCHINA_VBATT_LOW => {
+            let high = monitor.high_mv;
+            drop(monitor);
+            return set_thresholds(value, high)
+        },
+        IoctlParam::MACCHINA_VBATT_HIGH => {
+            let low = monitor.low_mv;
+            drop(monitor);
+            return set_thresholds(low, value)
+        },
+        IoctlParam::MACCHINA_VBATT_STATE => {
+            set_error_string("MACCHINA_VBATT_STATE is read only".into());
+            return Err(PassthruError::ERR_NOT_SUPPORTED)
+        },
+        _ => return Err(PassthruError::ERR_NOT_SUPPORTED)
+    }
+    Ok(())
+}
+
+/// Sets both battery voltage thresholds in mV. 0 turns a threshold off
+pub fn set_thresholds(low_mv: u32, high_mv: u32) -> PTResult<()> {
+    if low_mv != 0 && high_mv != 0 && low_mv + HYSTERESIS_MV > high_mv {
+        set_error_string(format!("Battery voltage low threshold ({}mV) must be at least {}mV below the high threshold ({}mV)", low_mv, HYSTERESIS_MV, high_mv));
+        return Err(PassthruError::ERR_INVALID_IOCTL_VALUE)
+    }
+    let mut monitor = MONITOR.lock().unwrap();
+    monitor.low_mv = low_mv;
+    monitor.high_mv = high_mv;
+    Ok(())
+}
+
+/// Readings in the history, oldest first
+pub fn history() -> Vec<VbattSample> {
+    MONITOR.lock().unwrap().history.iter().copied().collect()
+}
+
+/// Takes up to max of the oldest events from the queue
+pub fn take_events(max: usize) -> Vec<VbattSample> {
+    let mut monitor = MONITOR.lock().unwrap();
+    let n = max.min(monitor.events.len());
+    monitor.events.drain(..n).collect()
+}
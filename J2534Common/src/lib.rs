@@ -33,6 +33,14 @@ pub enum Protocol {
     SCI_A_TRANS = 0x08,
     SCI_B_ENGINE = 0x09,
     SCI_B_TRANS = 0x0A,
+
+    // J2534-2 pin switched protocols
+    CAN_PS = 0x8004,
+    ISO15765_PS = 0x8005,
+    SW_ISO15765_PS = 0x8007,
+    SW_CAN_PS = 0x8008,
+    J1939_PS = 0x800C,
+    TP2_0_PS = 0x800E,
 }
 
 impl Display for Protocol {
@@ -48,6 +56,12 @@ impl Display for Protocol {
             Protocol::SCI_A_TRANS => "SCI A TRANS",
             Protocol::SCI_B_ENGINE => "SCI B ENGINE",
             Protocol::SCI_B_TRANS => "SCI B TRANS",
+            Protocol::CAN_PS => "CAN (Pin switched)",
+            Protocol::ISO15765_PS => "ISO 15765 (Pin switched)",
+            Protocol::SW_ISO15765_PS => "Single wire ISO 15765",
+            Protocol::SW_CAN_PS => "Single wire CAN",
+            Protocol::J1939_PS => "J1939",
+            Protocol::TP2_0_PS => "VW TP 2.0",
         })
     }
 }
@@ -75,6 +89,30 @@ pub enum IoctlID {
     ADD_TO_FUNCT_MSG_LOOKUP_TABLE = 0x0C,
     DELETE_FROM_FUNCT_MSG_LOOKUP_TABLE = 0x0D,
     READ_PROG_VOLTAGE = 0x0E,
+
+    // J2534-2 IOCTLs
+    SW_CAN_HS = 0x8000,
+    SW_CAN_NS = 0x8001,
+    PROTECT_J1939_ADDR = 0x8009,
+    REQUEST_CONNECTION = 0x800A,
+    TEARDOWN_CONNECTION = 0x800B,
+
+    // Macchina M2 vendor IOCTLs
+    MACCHINA_START_RECORDING = 0x10000,
+    MACCHINA_STOP_RECORDING = 0x10001,
+    /// Reads a CAN channel's bus statistics. Input is an SCONFIG_LIST of BusStat IDs, like GET_CONFIG
+    MACCHINA_GET_BUS_STATS = 0x10002,
+    /// Resets a CAN channel's bus statistics
+    MACCHINA_CLEAR_BUS_STATS = 0x10003,
+    /// Finds a CAN channel's baud rate from the bus traffic, and switches the channel to it.
+    /// Output is the baud rate (unsigned long)
+    MACCHINA_DETECT_BAUD_RATE = 0x10004,
+    /// Reads the battery voltage readings of the last few minutes, oldest first. Output is an
+    /// SBYTE_ARRAY of 12 byte records: timestamp (ms), voltage (mV) and VbattState, each 4 bytes LE
+    MACCHINA_READ_VBATT_HISTORY = 0x10005,
+    /// Takes the battery voltage state changes not yet read, oldest first. Output is like
+    /// MACCHINA_READ_VBATT_HISTORY. Events that do not fit stay queued
+    MACCHINA_READ_VBATT_EVENTS = 0x10006,
 }
 
 impl std::fmt::Display for IoctlID {
@@ -133,6 +171,46 @@ pub enum IoctlParam {
     STMIN_TX = 0x23,
     T3_MAX = 0x24,
     ISO15765_WFT_MAX = 0x25,
+
+    // J2534-2 config parameters
+    J1962_PINS = 0x8001,
+    SW_CAN_HS_DATA_RATE = 0x8010,
+    SW_CAN_SPEEDCHANGE_ENABLE = 0x8011,
+    SW_CAN_RES_SWITCH = 0x8012,
+    J1939_T1 = 0x803D,
+    J1939_T2 = 0x803E,
+    J1939_T3 = 0x803F,
+    J1939_T4 = 0x8040,
+    J1939_BRDCST_MIN_DELAY = 0x8041,
+    TP2_0_T_BR_INT = 0x8042,
+    TP2_0_T_E = 0x8043,
+    TP2_0_MNTC = 0x8044,
+    TP2_0_T_CTA = 0x8045,
+    TP2_0_MNCT = 0x8046,
+    TP2_0_MNTB = 0x8047,
+    TP2_0_MNT = 0x8048,
+    TP2_0_T_WAIT = 0x8049,
+    TP2_0_T1 = 0x804A,
+    TP2_0_T3 = 0x804B,
+    TP2_0_IDENTIFER = 0x804C,
+    TP2_0_RXIDPASSIVE = 0x804D,
+
+    // Macchina M2 vendor config parameters
+    /// Most messages the channel's Rx queue holds before messages are dropped
+    MACCHINA_RX_QUEUE_SIZE = 0x10000,
+    /// Messages dropped since the channel was connected, as the Rx queue was full. Set to 0 to reset
+    MACCHINA_RX_DROPPED = 0x10001,
+    /// 0(OFF)/1(ON). Puts CAN bus errors and controller state changes in the Rx queue,
+    /// flagged with MACCHINA_BUS_ERROR and MACCHINA_BUS_STATE. CAN and CAN_PS only
+    MACCHINA_ERROR_FRAMES = 0x10002,
+    /// Milliseconds between battery voltage readings (50-60000). Default 500. Any handle
+    MACCHINA_VBATT_INTERVAL = 0x10003,
+    /// Battery voltage in mV below which the voltage is LOW. 0 (Default) turns the threshold off. Any handle
+    MACCHINA_VBATT_LOW = 0x10004,
+    /// Battery voltage in mV above which the voltage is HIGH. 0 (Default) turns the threshold off. Any handle
+    MACCHINA_VBATT_HIGH = 0x10005,
+    /// Read only. VbattState of the last battery voltage reading. Any handle
+    MACCHINA_VBATT_STATE = 0x10006,
 }
 
 impl std::fmt::Display for IoctlParam {
@@ -148,6 +226,67 @@ impl Parsable for IoctlParam {
     }
 }
 
+/// State of the battery voltage, against the MACCHINA_VBATT_LOW and MACCHINA_VBATT_HIGH thresholds
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+#[allow(non_camel_case_types)]
+pub enum VbattState {
+    NORMAL = 0,
+    LOW = 1,
+    HIGH = 2,
+}
+
+impl std::fmt::Display for VbattState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(format!("{:?}", self).as_str())
+    }
+}
+
+impl Parsable for VbattState {
+    fn from_raw(x: u32) -> Option<Self> {
+        FromPrimitive::from_u32(x)
+    }
+}
+
+/// Bus statistics of a CAN channel, read with MACCHINA_GET_BUS_STATS
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+#[allow(non_camel_case_types)]
+pub enum BusStat {
+    /// Frames received by the M2
+    RX_FRAMES = 0x10100,
+    /// Frames sent by the M2
+    TX_FRAMES = 0x10101,
+    /// Frames received that the M2 had no room for
+    RX_FRAMES_DROPPED = 0x10102,
+    /// Frames the CAN controller would not send
+    TX_FRAMES_FAILED = 0x10103,
+    /// Times the CAN controller went error passive
+    ERROR_PASSIVE_EVENTS = 0x10104,
+    /// Times the CAN controller went bus off
+    BUS_OFF_EVENTS = 0x10105,
+    /// Transmit error counter (TEC) of the CAN controller
+    TX_ERROR_COUNT = 0x10106,
+    /// Receive error counter (REC) of the CAN controller
+    RX_ERROR_COUNT = 0x10107,
+    /// Percentage of the bus used by the frames sent and received since the stats were last read
+    BUS_LOAD = 0x10108,
+    /// 0 - Error active, 1 - Error passive, 2 - Bus off
+    BUS_STATE = 0x10109,
+}
+
+impl std::fmt::Display for BusStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(format!("{:?}", self).as_str())
+    }
+}
+
+impl Parsable for BusStat {
+    fn from_raw(x: u32) -> Option<Self> {
+        FromPrimitive::from_u32(x)
+    }
+}
+
 #[repr(u32)]
 #[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
 #[allow(non_camel_case_types, dead_code)]
@@ -181,6 +320,10 @@ pub enum PassthruError {
     ERR_NOT_UNIQUE = 0x18,
     ERR_INVALID_BAUDRATE = 0x19,
     ERR_INVALID_DEVICE_ID = 0x1A,
+
+    // J2534-2 errors
+    ERR_ADDRESS_NOT_CLAIMED = 0x10000,
+    ERR_NO_CONNECTION_ESTABLISHED = 0x10001,
 }
 
 impl Loggable for PassthruError {
@@ -213,6 +356,8 @@ impl Loggable for PassthruError {
             PassthruError::ERR_NOT_UNIQUE => "An existing filter already matches",
             PassthruError::ERR_INVALID_BAUDRATE => "Unable to set requested baudrate",
             PassthruError::ERR_INVALID_DEVICE_ID => "Device ID not recognized",
+            PassthruError::ERR_ADDRESS_NOT_CLAIMED => "J1939 source address has not been claimed",
+            PassthruError::ERR_NO_CONNECTION_ESTABLISHED => "TP 2.0 connection has not been established",
         }
     }
 }
@@ -297,6 +442,9 @@ pub enum ConnectFlags {
     CAN_ID_BOTH = 0x00000800,
     ISO15765_ADDR_TYPE = 0x00000080,
     ISO9141_K_LINE_ONLY = 0x00001000,
+    /// Macchina M2 vendor flag. The CAN controller only listens to the bus, so never
+    /// ACKs frames, and the channel cannot send messages. CAN and CAN_PS only
+    MACCHINA_LISTEN_ONLY = 0x01000000,
 }
 impl Loggable for ConnectFlags {
     fn to_string(&self) -> &str {
@@ -305,7 +453,8 @@ impl Loggable for ConnectFlags {
             ConnectFlags::ISO9141_NO_CHECKSUM => "ISO9141 no checksum",
             ConnectFlags::CAN_ID_BOTH => "unknown",
             ConnectFlags::ISO15765_ADDR_TYPE => "ISO-TP Extended addressing",
-            ConnectFlags::ISO9141_K_LINE_ONLY => "ISO9141 only use K-Line"
+            ConnectFlags::ISO9141_K_LINE_ONLY => "ISO9141 only use K-Line",
+            ConnectFlags::MACCHINA_LISTEN_ONLY => "CAN listen only"
         }
     }
 }
@@ -317,8 +466,145 @@ impl Parsable for ConnectFlags {
     }
 }
 
+/// J1962 pin pair used by a J2534-2 pin switched protocol (Set via the `J1962_PINS` config param).
+/// The raw value is encoded as 0x0000PPSS, where PP is the primary pin (CAN-H for CAN)
+/// and SS is the secondary pin (CAN-L for CAN), or 0x00 if the protocol uses a single wire
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct J1962Pins {
+    pub primary: u8,
+    pub secondary: u8,
+}
+
+impl J1962Pins {
+    pub const fn new(primary: u8, secondary: u8) -> Self {
+        J1962Pins { primary, secondary }
+    }
+
+    pub fn to_raw(&self) -> u32 {
+        (self.primary as u32) << 8 | self.secondary as u32
+    }
+
+    /// True if both use any of the same pins
+    pub fn overlaps(&self, other: &J1962Pins) -> bool {
+        let uses = |pin: u8| pin != 0 && (pin == other.primary || pin == other.secondary);
+        uses(self.primary) || uses(self.secondary)
+    }
+}
+
+impl std::fmt::Display for J1962Pins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.secondary == 0 {
+            write!(f, "Pin {}", self.primary)
+        } else {
+            write!(f, "Pins {}/{}", self.primary, self.secondary)
+        }
+    }
+}
+
+impl Parsable for J1962Pins {
+    fn from_raw(x: u32) -> Option<Self>
+    where Self: Sized {
+        // Upper 16 bits are reserved, and J1962 only has 16 pins
+        let primary = (x >> 8) as u8;
+        let secondary = x as u8;
+        if x & 0xFFFF0000 != 0 || primary == 0 || primary > 16 || secondary > 16 || primary == secondary {
+            return None
+        }
+        Some(J1962Pins { primary, secondary })
+    }
+}
+
+/// Header at the start of every J1939_PS message. Bytes 0-3 are the 29 bit CAN ID
+/// (Priority, PGN and source address), and byte 4 is the destination address.
+/// For PDU1 (Destination specific) PGNs, the PS field of the PGN is always 0
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct J1939Header {
+    pub priority: u8,
+    pub pgn: u32,
+    pub source: u8,
+    pub destination: u8,
+}
+
+/// Size of [J1939Header] in a J1939_PS message
+pub const J1939_HEADER_SIZE: usize = 5;
+/// Largest payload that can be sent using the J1939 transport protocol
+pub const J1939_MAX_PAYLOAD: usize = 1785;
+/// J1939 global (broadcast) address
+pub const J1939_GLOBAL_ADDRESS: u8 = 0xFF;
+
+impl J1939Header {
+    /// Returns true if the PGN is destination specific (PDU1 format)
+    pub fn is_pdu1(pgn: u32) -> bool {
+        (pgn >> 8) & 0xFF < 0xF0
+    }
+
+    /// Reads the header from the start of a J1939_PS message
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < J1939_HEADER_SIZE || data[0] & 0xE0 != 0 {
+            return None
+        }
+        let can_id = (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32;
+        let mut pgn = (can_id >> 8) & 0x3FFFF;
+        if J1939Header::is_pdu1(pgn) {
+            pgn &= 0x3FF00;
+        }
+        Some(J1939Header {
+            priority: (can_id >> 26) as u8 & 0x07,
+            pgn,
+            source: can_id as u8,
+            destination: data[4],
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; J1939_HEADER_SIZE] {
+        let mut pgn = self.pgn & 0x3FFFF;
+        if J1939Header::is_pdu1(pgn) {
+            pgn = (pgn & 0x3FF00) | self.destination as u32;
+        }
+        let can_id = (self.priority as u32 & 0x07) << 26 | pgn << 8 | self.source as u32;
+        [(can_id >> 24) as u8, (can_id >> 16) as u8, (can_id >> 8) as u8, can_id as u8, self.destination]
+    }
+}
+
+impl std::fmt::Display for J1939Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PGN: 0x{:05X}, Priority: {}, SA: 0x{:02X}, DA: 0x{:02X}", self.pgn, self.priority, self.source, self.destination)
+    }
+}
+
+/// TP2_0_PS messages start with a 4 byte header holding the logical address
+/// of the module the connection is with (0x000000AA)
+pub const TP2_0_HEADER_SIZE: usize = 4;
+
+/// Returns the module address from the header of a TP2_0_PS message
+pub fn tp2_0_module_address(data: &[u8]) -> Option<u8> {
+    match data {
+        [0x00, 0x00, 0x00, addr, ..] => Some(*addr),
+        _ => None
+    }
+}
+
 bitflags! {
     pub struct RxFlag: u32 {
+        // J2534-2 single wire CAN
+        const SW_CAN_NS_RX = 0x00040000;
+        const SW_CAN_HS_RX = 0x00020000;
+        const SW_CAN_HV_RX = 0x00010000;
+
+        // J2534-2 J1939
+        const J1939_ADDRESS_LOST = 0x00020000;
+        const J1939_ADDRESS_CLAIMED = 0x00010000;
+
+        // J2534-2 TP 2.0
+        const CONNECTION_LOST = 0x00020000;
+        const CONNECTION_ESTABLISHED = 0x00010000;
+
+        // Macchina M2 vendor flags (MACCHINA_ERROR_FRAMES). The message data is a CAN ID of 0, then
+        // 1 byte of BusError flags or the new bus state (0 - Error active, 1 - Error passive, 2 - Bus off),
+        // then the transmit and receive error counters
+        const MACCHINA_BUS_ERROR = 0x01000000;
+        const MACCHINA_BUS_STATE = 0x02000000;
+
         const CAN_29BIT_ID = 0x00000100;
         const ISO15765_ADDR_TYPE = 0x00000080;
         const ISO15765_PADDING_ERROR = 0x00000010;
@@ -330,11 +616,23 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// CAN bus errors in a MACCHINA_BUS_ERROR message
+    pub struct BusError: u8 {
+        const CRC = 0x01;
+        const STUFF = 0x02;
+        const ACK = 0x04;
+        const FORM = 0x08;
+        const BIT = 0x10;
+    }
+}
+
 bitflags! {
     pub struct TxFlag: u32 {
         const SCI_TX_VOLTAGE = 0x00800000;
         const SCI_MODE = 0x00400000;
         const BLOCKING = 0x00010000;
+        const SW_CAN_HV_TX = 0x00000400;
         const WAIT_P3_MIN_ONLY = 0x00000200;
         const CAN_29BIT_ID = 0x00000100;
         const CAN_EXTENDED_ID = 0x00000100;
@@ -345,6 +643,9 @@ bitflags! {
     }
 }
 
+/// Size of the data buffer of a PASSTHRU_MSG
+pub const PASSTHRU_MSG_DATA_SIZE: usize = 4128;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[repr(C, packed(1))]
 pub struct PASSTHRU_MSG {
@@ -354,7 +655,7 @@ pub struct PASSTHRU_MSG {
     pub timestamp: u32,
     pub data_size: u32,
     pub extra_data_size: u32,
-    pub data: [u8; 4128],
+    pub data: [u8; PASSTHRU_MSG_DATA_SIZE],
 }
 
 impl Default for PASSTHRU_MSG {
@@ -366,20 +667,63 @@ impl Default for PASSTHRU_MSG {
             timestamp: 0,
             data_size: 0,
             extra_data_size: 0,
-            data: [0; 4128],
+            data: [0; PASSTHRU_MSG_DATA_SIZE],
         }
     }
 }
 
+impl PASSTHRU_MSG {
+    /// Creates a message to send. Returns None if the data is too large for a message
+    pub fn new(protocol: Protocol, tx_flags: u32, data: &[u8]) -> Option<Self> {
+        let mut msg = PASSTHRU_MSG {
+            protocol_id: protocol as u32,
+            tx_flags,
+            data_size: data.len() as u32,
+            ..Default::default()
+        };
+        msg.data.get_mut(..data.len())?.copy_from_slice(data);
+        Some(msg)
+    }
+
+    /// Data of the message. Clamped to the data buffer if data_size is larger
+    pub fn data(&self) -> &[u8] {
+        &self.data[..(self.data_size as usize).min(self.data.len())]
+    }
+
+    /// Checks data_size fits the message. Used for Tx and filter messages, where
+    /// ExtraDataIndex is ignored. Returns the reason if it doesn't
+    pub fn validate_tx(&self) -> Result<(), String> {
+        let data_size = self.data_size;
+        if data_size as usize > self.data.len() {
+            return Err(format!("data_size {} is larger than {} bytes", data_size, self.data.len()))
+        }
+        Ok(())
+    }
+
+    /// Checks both data_size and ExtraDataIndex fit the message. Used for received and replayed messages.
+    /// Returns the reason if they don't
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_tx()?;
+        let (data_size, extra_data_size) = (self.data_size, self.extra_data_size);
+        if extra_data_size > data_size {
+            return Err(format!("extra_data_size {} is larger than data_size {}", extra_data_size, data_size))
+        }
+        Ok(())
+    }
+}
+
 impl std::fmt::Display for PASSTHRU_MSG {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let protocol = match Protocol::from_raw(self.protocol_id) {
+            Some(p) => format!("{}", p),
+            None => format!("Unknown ({:08X})", { self.protocol_id })
+        };
         f.write_str(format!(
-            "Protocol: {}, RxStatus: {:08X}, TxFlags: {:08X}, Data: {:02X?}", 
-            Protocol::from_raw(self.protocol_id).unwrap(),
-            self.rx_status,
-            self.tx_flags,
-            &self.data[0..self.data_size as usize]
-            
+            "Protocol: {}, RxStatus: {:08X}, TxFlags: {:08X}, Data: {:02X?}",
+            protocol,
+            { self.rx_status },
+            { self.tx_flags },
+            self.data()
         ).as_str())
     }
 }
@@ -405,9 +749,194 @@ pub struct SConfigList {
     pub config_ptr: *mut SConfig,
 }
 
+/// Most params an SConfigList can hold. There are far fewer config params than
+/// this, so a larger list is from an uninitialized or corrupt structure
+pub const MAX_CONFIG_PARAMS: u32 = 256;
+
+/// PassThruSetProgrammingVoltage voltage - Connect the pin to ground (Pin 15 only)
+pub const SHORT_TO_GROUND: u32 = 0xFFFFFFFE;
+/// PassThruSetProgrammingVoltage voltage - Turn the pin's voltage off
+pub const VOLTAGE_OFF: u32 = 0xFFFFFFFF;
+
+// SAE J2534-1 v05.00 API definitions
+// Everything above is shared with v05.00, only the message layout
+// and the device discovery / logical channel structures are new
+
+/// Protocol ID of an ISO 15765 logical channel (PassThruLogicalConnect)
+pub const ISO15765_LOGICAL: u32 = 0x200;
+/// RESOURCE_STRUCT connector - SAE J1962
+pub const J1962_CONNECTOR: u32 = 0x01;
+/// PassThruSelect type - Wait for channels to have messages to read
+pub const READABLE_TYPE: u32 = 0x01;
+
+/// SDEVICE.DeviceAvailable values
+pub const DEVICE_AVAILABLE: u32 = 0x01;
+pub const DEVICE_IN_USE: u32 = 0x02;
+/// SDEVICE.DeviceDLLFWStatus - DLL and firmware are compatible
+pub const DEVICE_DLL_FW_COMPATIBLE: u32 = 0x01;
+/// SDEVICE.DeviceConnectMedia - Wired (USB) connection
+pub const DEVICE_CONN_WIRED: u32 = 0x02;
+
+/// v05.00 PASSTHRU_MSG. Unlike 04.04 the data is not part of the message,
+/// the application provides a buffer for it
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed(1))]
+pub struct PASSTHRU_MSG_V0500 {
+    pub protocol_id: u32,
+    pub msg_handle: u32,
+    pub rx_status: u32,
+    pub tx_flags: u32,
+    pub timestamp: u32,
+    pub data_length: u32,
+    pub extra_data_index: u32,
+    pub data_buffer: *mut u8,
+    pub data_buffer_size: u32,
+}
+
+impl PASSTHRU_MSG_V0500 {
+    /// Converts the message to the 04.04 layout. Returns None if the data buffer
+    /// is null, or the data is too large for a 04.04 message
+    /// # Safety
+    /// data_buffer must point to at least data_length bytes
+    pub unsafe fn to_v0404(&self) -> Option<PASSTHRU_MSG> {
+        let mut msg = PASSTHRU_MSG::default();
+        let len = self.data_length as usize;
+        if len > msg.data.len() || (len != 0 && self.data_buffer.is_null()) {
+            return None
+        }
+        if len != 0 {
+            std::ptr::copy_nonoverlapping(self.data_buffer, msg.data.as_mut_ptr(), len);
+        }
+        msg.protocol_id = self.protocol_id;
+        msg.rx_status = self.rx_status;
+        msg.tx_flags = self.tx_flags;
+        msg.timestamp = self.timestamp;
+        msg.data_size = self.data_length;
+        msg.extra_data_size = self.extra_data_index;
+        Some(msg)
+    }
+
+    /// Copies a 04.04 message into this message and its data buffer.
+    /// Returns false if the data buffer is null or too small
+    /// # Safety
+    /// data_buffer must point to at least data_buffer_size bytes
+    pub unsafe fn copy_from_v0404(&mut self, msg: &PASSTHRU_MSG) -> bool {
+        let len = msg.data_size as usize;
+        if len > self.data_buffer_size as usize || (len != 0 && self.data_buffer.is_null()) {
+            return false
+        }
+        if len != 0 {
+            std::ptr::copy_nonoverlapping(msg.data.as_ptr(), self.data_buffer, len);
+        }
+        self.protocol_id = msg.protocol_id;
+        self.rx_status = msg.rx_status;
+        self.tx_flags = msg.tx_flags;
+        self.timestamp = msg.timestamp;
+        self.data_length = msg.data_size;
+        self.extra_data_index = msg.extra_data_size;
+        true
+    }
+}
+
+/// Device information returned by PassThruGetNextDevice
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed(1))]
+pub struct SDEVICE {
+    pub device_name: [std::os::raw::c_char; 80],
+    pub device_available: u32,
+    pub device_dll_fw_status: u32,
+    pub device_connect_media: u32,
+    pub device_connect_speed: u32,
+    pub device_signal_quality: u32,
+    pub device_signal_strength: u32,
+}
+
+/// Connector pins a physical channel uses (Replaces J1962_PINS in v05.00)
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed(1))]
+pub struct RESOURCE_STRUCT {
+    pub connector: u32,
+    pub num_of_resources: u32,
+    pub resource_list_ptr: *mut u32,
+}
+
+/// Addressing of an ISO 15765 logical channel
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed(1))]
+pub struct ISO15765_CHANNEL_DESCRIPTOR {
+    pub local_tx_flags: u32,
+    pub remote_tx_flags: u32,
+    pub local_address: [u8; 5],
+    pub remote_address: [u8; 5],
+}
+
+/// Channels to wait on with PassThruSelect
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed(1))]
+pub struct SCHANNELSET {
+    pub channel_count: u32,
+    pub channel_threshold: u32,
+    pub channel_list: *mut u32,
+}
+
+#[test]
+fn test_j1962_pins() {
+    assert_eq!(J1962Pins::from_raw(0x060E), Some(J1962Pins::new(6, 14)));
+    assert_eq!(J1962Pins::from_raw(0x0100), Some(J1962Pins::new(1, 0)));
+    assert_eq!(J1962Pins::new(3, 11).to_raw(), 0x030B);
+    assert_eq!(J1962Pins::from_raw(0x0000), None);
+    assert_eq!(J1962Pins::from_raw(0x0606), None);
+    assert_eq!(J1962Pins::from_raw(0x1106), None);
+    assert_eq!(J1962Pins::from_raw(0x0001060E), None);
+}
+
+#[test]
+fn test_j1939_header() {
+    // Request PGN (PDU1) from 0xF9 to 0x00
+    let h = J1939Header { priority: 6, pgn: 0xEA00, source: 0xF9, destination: 0x00 };
+    assert_eq!(h.to_bytes(), [0x18, 0xEA, 0x00, 0xF9, 0x00]);
+    assert_eq!(J1939Header::from_bytes(&h.to_bytes()), Some(h));
+    // EEC1 (PDU2) is always broadcast
+    let h = J1939Header::from_bytes(&[0x0C, 0xF0, 0x04, 0x00, 0xFF, 0x01]).unwrap();
+    assert_eq!(h.pgn, 0xF004);
+    assert_eq!(h.priority, 3);
+    assert_eq!(J1939Header::from_bytes(&[0x0C, 0xF0, 0x04, 0x00]), None);
+}
+
+#[test]
+fn test_v0500_msg() {
+    let mut buf = [0u8; 6];
+    let mut msg = PASSTHRU_MSG::default();
+    msg.protocol_id = Protocol::ISO15765 as u32;
+    msg.data_size = 6;
+    msg.data[0..6].copy_from_slice(&[0x00, 0x00, 0x07, 0xE8, 0x50, 0x03]);
+    let mut v5 = PASSTHRU_MSG_V0500 {
+        protocol_id: 0, msg_handle: 0, rx_status: 0, tx_flags: 0, timestamp: 0, data_length: 0,
+        extra_data_index: 0, data_buffer: buf.as_mut_ptr(), data_buffer_size: 5
+    };
+    assert!(!unsafe { v5.copy_from_v0404(&msg) });
+    v5.data_buffer_size = 6;
+    assert!(unsafe { v5.copy_from_v0404(&msg) });
+    assert_eq!(buf, [0x00, 0x00, 0x07, 0xE8, 0x50, 0x03]);
+    assert_eq!(unsafe { v5.to_v0404() }, Some(msg));
+}
+
 #[test]
 fn test_fail() {
     let x: u32 = 0x0B;
     let res = Protocol::from_u32(x);
     println!("{:?}", res);
-}
\ No newline at end of file
+}
+#[test]
+fn test_msg_validate() {
+    let mut msg = PASSTHRU_MSG { protocol_id: 0xFFFF, data_size: 4, ..Default::default() };
+    assert!(msg.validate().is_ok());
+    assert!(msg.to_string().starts_with("Protocol: Unknown (0000FFFF)"));
+    msg.extra_data_size = 5;
+    assert!(msg.validate_tx().is_ok());
+    assert!(msg.validate().is_err());
+    msg.data_size = 5000;
+    assert!(msg.validate_tx().is_err());
+    assert!(msg.validate().is_err());
+    assert_eq!(msg.data().len(), 4128);
+}
@@ -0,0 +1,359 @@
+use std::{collections::HashMap, ffi::{c_void, OsStr}, fmt::Display, os::raw::c_char, path::{Path, PathBuf}};
+use libloading::Library;
+use serde::Deserialize;
+use J2534Common::*;
+
+// Loads J2534 passthru libraries from the host side. Drivers are found using
+// the manifests in ~/.passthru (Same format as the M2's macchina.json)
+
+pub type PassThruOpenFn = unsafe extern "stdcall" fn(name: *const c_void, device_id: *mut u32) -> i32;
+pub type PassThruCloseFn = unsafe extern "stdcall" fn(device_id: u32) -> i32;
+pub type PassThruConnectFn = unsafe extern "stdcall" fn(device_id: u32, protocol_id: u32, flags: u32, baud_rate: u32, channel_id: *mut u32) -> i32;
+pub type PassThruDisconnectFn = unsafe extern "stdcall" fn(channel_id: u32) -> i32;
+pub type PassThruReadMsgsFn = unsafe extern "stdcall" fn(channel_id: u32, msgs: *mut PASSTHRU_MSG, num_msgs: *mut u32, timeout: u32) -> i32;
+pub type PassThruWriteMsgsFn = unsafe extern "stdcall" fn(channel_id: u32, msgs: *const PASSTHRU_MSG, num_msgs: *mut u32, timeout: u32) -> i32;
+pub type PassThruStartPeriodicMsgFn = unsafe extern "stdcall" fn(channel_id: u32, msg: *const PASSTHRU_MSG, msg_id: *mut u32, interval: u32) -> i32;
+pub type PassThruStopPeriodicMsgFn = unsafe extern "stdcall" fn(channel_id: u32, msg_id: u32) -> i32;
+pub type PassThruStartMsgFilterFn = unsafe extern "stdcall" fn(channel_id: u32, filter_type: u32, mask: *const PASSTHRU_MSG, pattern: *const PASSTHRU_MSG, flow_control: *const PASSTHRU_MSG, filter_id: *mut u32) -> i32;
+pub type PassThruStopMsgFilterFn = unsafe extern "stdcall" fn(channel_id: u32, filter_id: u32) -> i32;
+pub type PassThruSetProgrammingVoltageFn = unsafe extern "stdcall" fn(device_id: u32, pin: u32, voltage: u32) -> i32;
+pub type PassThruReadVersionFn = unsafe extern "stdcall" fn(device_id: u32, fw_version: *mut c_char, dll_version: *mut c_char, api_version: *mut c_char) -> i32;
+pub type PassThruGetLastErrorFn = unsafe extern "stdcall" fn(error: *mut c_char) -> i32;
+pub type PassThruIoctlFn = unsafe extern "stdcall" fn(handle_id: u32, ioctl_id: u32, input: *mut c_void, output: *mut c_void) -> i32;
+
+/// Result of a PassThru function call
+pub type Result<T> = std::result::Result<T, PassthruError>;
+
+/// Most messages read_messages reads in one call. Matches the Macchina driver's default Rx queue size
+pub const MAX_READ_MSGS: u32 = 500;
+
+/// Error loading a driver manifest or library
+#[derive(Debug)]
+pub enum LoadError {
+    /// Manifest could not be read
+    Io(std::io::Error),
+    /// Manifest is not valid JSON, or is missing a required field
+    Manifest(serde_json::Error),
+    /// Library could not be loaded, or does not export a PassThru function
+    Library(libloading::Error),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "Cannot read driver manifest: {}", e),
+            LoadError::Manifest(e) => write!(f, "Invalid driver manifest: {}", e),
+            LoadError::Library(e) => write!(f, "Cannot load driver library: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(e: serde_json::Error) -> Self {
+        LoadError::Manifest(e)
+    }
+}
+
+impl From<libloading::Error> for LoadError {
+    fn from(e: libloading::Error) -> Self {
+        LoadError::Library(e)
+    }
+}
+
+/// Driver described by a manifest in ~/.passthru
+#[derive(Debug, Clone, Deserialize)]
+pub struct DriverInfo {
+    #[serde(rename = "NAME")]
+    pub name: String,
+    #[serde(rename = "VENDOR")]
+    pub vendor: String,
+    /// Path of the driver library. Can start with ~
+    #[serde(rename = "FUNCTION_LIB")]
+    pub function_lib: String,
+    /// Everything else in the manifest. Protocol support flags, and any vendor specific settings (Like COM-PORT)
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl DriverInfo {
+    pub fn from_json(json: &str) -> std::result::Result<Self, LoadError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn from_file(path: &Path) -> std::result::Result<Self, LoadError> {
+        DriverInfo::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Protocols the manifest says the driver supports
+    pub fn protocols(&self) -> Vec<Protocol> {
+        let mut protocols: Vec<Protocol> = self.extra.iter()
+            .filter(|(_, v)| v.as_bool() == Some(true))
+            .filter_map(|(k, _)| serde_json::from_value(serde_json::Value::String(k.clone())).ok())
+            .collect();
+        protocols.sort_by_key(|p| *p as u32);
+        protocols
+    }
+
+    pub fn library_path(&self) -> PathBuf {
+        PathBuf::from(shellexpand::tilde(&self.function_lib).to_string())
+    }
+}
+
+/// Directory that driver manifests are installed to
+pub fn manifest_dir() -> PathBuf {
+    PathBuf::from(shellexpand::tilde("~/.passthru").to_string())
+}
+
+/// Lists all the drivers installed in ~/.passthru. Manifests that cannot be parsed are skipped
+pub fn list_drivers() -> Vec<DriverInfo> {
+    let entries = match std::fs::read_dir(manifest_dir()) {
+        Ok(e) => e,
+        Err(_) => return Vec::new()
+    };
+    entries.filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension() == Some(OsStr::new("json")))
+        .filter_map(|p| DriverInfo::from_file(&p).ok())
+        .collect()
+}
+
+/// Versions returned by PassThruReadVersion
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrvVersion {
+    pub fw_version: String,
+    pub dll_version: String,
+    pub api_version: String,
+}
+
+/// Converts a PassThru return code into a Result. Vendor specific codes
+/// that are not in the J2534 spec are reported as ERR_FAILED
+fn check(res: i32) -> Result<()> {
+    match PassthruError::from_raw(res as u32) {
+        Some(PassthruError::STATUS_NOERROR) => Ok(()),
+        Some(e) => Err(e),
+        None => Err(PassthruError::ERR_FAILED)
+    }
+}
+
+/// Reads a string the driver wrote into a buffer. Drivers do not always null terminate
+fn buffer_to_string(buf: &[c_char]) -> String {
+    let bytes: Vec<u8> = buf.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+    String::from_utf8_lossy(&bytes).into()
+}
+
+/// Raw PassThru functions of a loaded library, for callers that need to pass
+/// the application's own pointers straight through (Like the API trace shim)
+#[derive(Copy, Clone)]
+pub struct RawApi {
+    pub open: PassThruOpenFn,
+    pub close: PassThruCloseFn,
+    pub connect: PassThruConnectFn,
+    pub disconnect: PassThruDisconnectFn,
+    pub read_msgs: PassThruReadMsgsFn,
+    pub write_msgs: PassThruWriteMsgsFn,
+    pub start_periodic_msg: PassThruStartPeriodicMsgFn,
+    pub stop_periodic_msg: PassThruStopPeriodicMsgFn,
+    pub start_msg_filter: PassThruStartMsgFilterFn,
+    pub stop_msg_filter: PassThruStopMsgFilterFn,
+    pub set_programming_voltage: PassThruSetProgrammingVoltageFn,
+    pub read_version: PassThruReadVersionFn,
+    pub get_last_error: PassThruGetLastErrorFn,
+    pub ioctl: PassThruIoctlFn,
+}
+
+/// Loaded J2534 passthru library
+pub struct PassthruDrv {
+    /// Kept so the library stays loaded whilst its functions are in use
+    _lib: Library,
+    api: RawApi,
+}
+
+impl std::fmt::Debug for PassthruDrv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PassthruDrv").finish()
+    }
+}
+
+impl PassthruDrv {
+    /// Loads the library of a driver listed in ~/.passthru
+    pub fn load(info: &DriverInfo) -> std::result::Result<Self, LoadError> {
+        PassthruDrv::load_lib(info.library_path())
+    }
+
+    /// Loads a passthru library directly
+    pub fn load_lib<P: AsRef<OsStr>>(path: P) -> std::result::Result<Self, LoadError> {
+        let lib = Library::new(path)?;
+        let api = unsafe {
+            RawApi {
+                open: *lib.get::<PassThruOpenFn>(b"PassThruOpen\0")?,
+                close: *lib.get::<PassThruCloseFn>(b"PassThruClose\0")?,
+                connect: *lib.get::<PassThruConnectFn>(b"PassThruConnect\0")?,
+                disconnect: *lib.get::<PassThruDisconnectFn>(b"PassThruDisconnect\0")?,
+                read_msgs: *lib.get::<PassThruReadMsgsFn>(b"PassThruReadMsgs\0")?,
+                write_msgs: *lib.get::<PassThruWriteMsgsFn>(b"PassThruWriteMsgs\0")?,
+                start_periodic_msg: *lib.get::<PassThruStartPeriodicMsgFn>(b"PassThruStartPeriodicMsg\0")?,
+                stop_periodic_msg: *lib.get::<PassThruStopPeriodicMsgFn>(b"PassThruStopPeriodicMsg\0")?,
+                start_msg_filter: *lib.get::<PassThruStartMsgFilterFn>(b"PassThruStartMsgFilter\0")?,
+                stop_msg_filter: *lib.get::<PassThruStopMsgFilterFn>(b"PassThruStopMsgFilter\0")?,
+                set_programming_voltage: *lib.get::<PassThruSetProgrammingVoltageFn>(b"PassThruSetProgrammingVoltage\0")?,
+                read_version: *lib.get::<PassThruReadVersionFn>(b"PassThruReadVersion\0")?,
+                get_last_error: *lib.get::<PassThruGetLastErrorFn>(b"PassThruGetLastError\0")?,
+                ioctl: *lib.get::<PassThruIoctlFn>(b"PassThruIoctl\0")?,
+            }
+        };
+        Ok(PassthruDrv { _lib: lib, api })
+    }
+
+    /// Raw PassThru functions of the library
+    pub fn raw(&self) -> &RawApi {
+        &self.api
+    }
+
+    /// Opens the device, returning its device ID
+    pub fn open(&self) -> Result<u32> {
+        let mut device_id = 0;
+        check(unsafe { (self.api.open)(std::ptr::null(), &mut device_id) })?;
+        Ok(device_id)
+    }
+
+    pub fn close(&self, device_id: u32) -> Result<()> {
+        check(unsafe { (self.api.close)(device_id) })
+    }
+
+    /// Opens a communication channel, returning its channel ID
+    pub fn connect(&self, device_id: u32, protocol: Protocol, flags: u32, baud_rate: u32) -> Result<u32> {
+        let mut channel_id = 0;
+        check(unsafe { (self.api.connect)(device_id, protocol as u32, flags, baud_rate, &mut channel_id) })?;
+        Ok(channel_id)
+    }
+
+    pub fn disconnect(&self, channel_id: u32) -> Result<()> {
+        check(unsafe { (self.api.disconnect)(channel_id) })
+    }
+
+    /// Reads up to max_msgs messages, capped to MAX_READ_MSGS. Messages read before a timeout are still returned
+    pub fn read_messages(&self, channel_id: u32, max_msgs: u32, timeout_ms: u32) -> Result<Vec<PASSTHRU_MSG>> {
+        let max_msgs = max_msgs.min(MAX_READ_MSGS);
+        let mut msgs = vec![PASSTHRU_MSG::default(); max_msgs as usize];
+        let mut num_msgs = max_msgs;
+        let res = check(unsafe { (self.api.read_msgs)(channel_id, msgs.as_mut_ptr(), &mut num_msgs, timeout_ms) });
+        msgs.truncate(num_msgs.min(max_msgs) as usize);
+        match res {
+            Err(PassthruError::ERR_TIMEOUT) | Err(PassthruError::ERR_BUFFER_EMPTY) if !msgs.is_empty() => Ok(msgs),
+            Err(e) => Err(e),
+            Ok(()) => Ok(msgs)
+        }
+    }
+
+    /// Writes messages, returning how many were written. Messages written before a timeout are still counted
+    pub fn write_messages(&self, channel_id: u32, msgs: &[PASSTHRU_MSG], timeout_ms: u32) -> Result<usize> {
+        let mut num_msgs = msgs.len() as u32;
+        let res = check(unsafe { (self.api.write_msgs)(channel_id, msgs.as_ptr(), &mut num_msgs, timeout_ms) });
+        match res {
+            Err(PassthruError::ERR_TIMEOUT) if num_msgs != 0 => Ok(num_msgs as usize),
+            Err(e) => Err(e),
+            Ok(()) => Ok(num_msgs as usize)
+        }
+    }
+
+    /// Starts sending a message periodically, returning its message ID
+    pub fn start_periodic_message(&self, channel_id: u32, msg: &PASSTHRU_MSG, interval_ms: u32) -> Result<u32> {
+        let mut msg_id = 0;
+        check(unsafe { (self.api.start_periodic_msg)(channel_id, msg, &mut msg_id, interval_ms) })?;
+        Ok(msg_id)
+    }
+
+    pub fn stop_periodic_message(&self, channel_id: u32, msg_id: u32) -> Result<()> {
+        check(unsafe { (self.api.stop_periodic_msg)(channel_id, msg_id) })
+    }
+
+    /// Adds a message filter, returning its filter ID. flow_control is only used by flow control filters
+    pub fn start_msg_filter(&self, channel_id: u32, filter_type: FilterType, mask: &PASSTHRU_MSG, pattern: &PASSTHRU_MSG, flow_control: Option<&PASSTHRU_MSG>) -> Result<u32> {
+        let mut filter_id = 0;
+        let fc_ptr = flow_control.map_or(std::ptr::null(), |m| m as *const PASSTHRU_MSG);
+        check(unsafe { (self.api.start_msg_filter)(channel_id, filter_type as u32, mask, pattern, fc_ptr, &mut filter_id) })?;
+        Ok(filter_id)
+    }
+
+    pub fn stop_msg_filter(&self, channel_id: u32, filter_id: u32) -> Result<()> {
+        check(unsafe { (self.api.stop_msg_filter)(channel_id, filter_id) })
+    }
+
+    pub fn set_programming_voltage(&self, device_id: u32, pin: u32, voltage: u32) -> Result<()> {
+        check(unsafe { (self.api.set_programming_voltage)(device_id, pin, voltage) })
+    }
+
+    pub fn read_version(&self, device_id: u32) -> Result<DrvVersion> {
+        let mut fw_version = [0 as c_char; 80];
+        let mut dll_version = [0 as c_char; 80];
+        let mut api_version = [0 as c_char; 80];
+        check(unsafe { (self.api.read_version)(device_id, fw_version.as_mut_ptr(), dll_version.as_mut_ptr(), api_version.as_mut_ptr()) })?;
+        Ok(DrvVersion {
+            fw_version: buffer_to_string(&fw_version),
+            dll_version: buffer_to_string(&dll_version),
+            api_version: buffer_to_string(&api_version),
+        })
+    }
+
+    /// Returns the description of the last error reported by the driver
+    pub fn get_last_error(&self) -> Result<String> {
+        let mut error = [0 as c_char; 80];
+        check(unsafe { (self.api.get_last_error)(error.as_mut_ptr()) })?;
+        Ok(buffer_to_string(&error))
+    }
+
+    /// Runs an IOCTL with raw input and output pointers. Prefer the wrappers below
+    /// # Safety
+    /// input and output must point to whatever the IOCTL expects (See J2534 spec)
+    pub unsafe fn ioctl(&self, handle_id: u32, ioctl_id: IoctlID, input: *mut c_void, output: *mut c_void) -> Result<()> {
+        check((self.api.ioctl)(handle_id, ioctl_id as u32, input, output))
+    }
+
+    pub fn get_config(&self, channel_id: u32, param: IoctlParam) -> Result<u32> {
+        let mut config = SConfig { parameter: param as u32, value: 0 };
+        let mut list = SConfigList { num_of_params: 1, config_ptr: &mut config };
+        unsafe { self.ioctl(channel_id, IoctlID::GET_CONFIG, &mut list as *mut _ as *mut c_void, std::ptr::null_mut())? };
+        Ok(config.value)
+    }
+
+    pub fn set_config(&self, channel_id: u32, param: IoctlParam, value: u32) -> Result<()> {
+        let mut config = SConfig { parameter: param as u32, value };
+        let mut list = SConfigList { num_of_params: 1, config_ptr: &mut config };
+        unsafe { self.ioctl(channel_id, IoctlID::SET_CONFIG, &mut list as *mut _ as *mut c_void, std::ptr::null_mut()) }
+    }
+
+    /// Reads the battery voltage in mV
+    pub fn read_vbatt(&self, device_id: u32) -> Result<u32> {
+        let mut voltage: u32 = 0;
+        unsafe { self.ioctl(device_id, IoctlID::READ_VBATT, std::ptr::null_mut(), &mut voltage as *mut u32 as *mut c_void)? };
+        Ok(voltage)
+    }
+
+    pub fn clear_tx_buffer(&self, channel_id: u32) -> Result<()> {
+        unsafe { self.ioctl(channel_id, IoctlID::CLEAR_TX_BUFFER, std::ptr::null_mut(), std::ptr::null_mut()) }
+    }
+
+    pub fn clear_rx_buffer(&self, channel_id: u32) -> Result<()> {
+        unsafe { self.ioctl(channel_id, IoctlID::CLEAR_RX_BUFFER, std::ptr::null_mut(), std::ptr::null_mut()) }
+    }
+}
+
+#[test]
+fn test_macchina_manifest() {
+    let info = DriverInfo::from_json(include_str!("../../Driver/macchina.json")).unwrap();
+    assert_eq!(info.function_lib, "~/.passthru/macchina.so");
+    assert_eq!(info.extra["COM-PORT"], "/dev/ttyACM0");
+    let protocols = info.protocols();
+    assert!(protocols.iter().any(|p| matches!(p, Protocol::ISO15765)));
+    assert!(!protocols.iter().any(|p| matches!(p, Protocol::J1850PWM)));
+    assert_eq!(check(PassthruError::ERR_TIMEOUT as i32), Err(PassthruError::ERR_TIMEOUT));
+    assert_eq!(check(0x7FFF_FFFF), Err(PassthruError::ERR_FAILED));
+}
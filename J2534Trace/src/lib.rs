@@ -0,0 +1,214 @@
+// Pointers belong to the application, and are handed straight to the traced library
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+use std::{ffi::c_void, os::raw::c_char};
+use serde_json::json;
+use J2534Common::*;
+mod trace;
+use trace::*;
+
+// J2534 04.04 API trace. Every PassThru function is forwarded untouched to the library
+// set in the trace config, and recorded in the trace file along with its decoded arguments
+
+// Dll Load function (Windows only) - Just return true
+#[no_mangle]
+#[cfg(windows)]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn DllMain(_module: u32, _reason: u32, _reserved: *mut c_void) -> bool {
+    return true
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruOpen(pName: *const c_void, pDeviceID: *mut u32) -> i32 {
+    trace_call("PassThruOpen", |api| unsafe { (api.open)(pName, pDeviceID) }, || json!({
+        "device_id": out_u32(pDeviceID),
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruClose(DeviceID: u32) -> i32 {
+    trace_call("PassThruClose", |api| unsafe { (api.close)(DeviceID) }, || json!({
+        "device_id": DeviceID,
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruConnect(
+    DeviceID: u32,
+    ProtocolID: u32,
+    Flags: u32,
+    BaudRate: u32,
+    pChannelID: *mut u32,
+) -> i32 {
+    trace_call("PassThruConnect", |api| unsafe { (api.connect)(DeviceID, ProtocolID, Flags, BaudRate, pChannelID) }, || json!({
+        "device_id": DeviceID,
+        "protocol": protocol(ProtocolID),
+        "flags": flags(Flags),
+        "baud_rate": BaudRate,
+        "channel_id": out_u32(pChannelID),
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruDisconnect(ChannelID: u32) -> i32 {
+    trace_call("PassThruDisconnect", |api| unsafe { (api.disconnect)(ChannelID) }, || json!({
+        "channel_id": ChannelID,
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruReadMsgs(
+    ChannelID: u32,
+    pMsg: *mut PASSTHRU_MSG,
+    pNumMsgs: *mut u32,
+    Timeout: u32,
+) -> i32 {
+    let requested = num_msgs(pNumMsgs);
+    trace_call("PassThruReadMsgs", |api| unsafe { (api.read_msgs)(ChannelID, pMsg, pNumMsgs, Timeout) }, || {
+        // A library that reports more messages than were asked for must not make us read past the array
+        let read = num_msgs(pNumMsgs).min(requested);
+        json!({
+            "channel_id": ChannelID,
+            "num_msgs_requested": requested,
+            "num_msgs": read,
+            "timeout": Timeout,
+            "msgs": msgs(pMsg, read),
+        })
+    })
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruWriteMsgs(
+    ChannelID: u32,
+    pMsg: *const PASSTHRU_MSG,
+    pNumMsgs: *mut u32,
+    Timeout: u32,
+) -> i32 {
+    let requested = num_msgs(pNumMsgs);
+    trace_call("PassThruWriteMsgs", |api| unsafe { (api.write_msgs)(ChannelID, pMsg, pNumMsgs, Timeout) }, || json!({
+        "channel_id": ChannelID,
+        "num_msgs_requested": requested,
+        "num_msgs": out_u32(pNumMsgs),
+        "timeout": Timeout,
+        "msgs": msgs(pMsg, requested),
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruStartPeriodicMsg(
+    ChannelID: u32,
+    pMsg: *const PASSTHRU_MSG,
+    pMsgID: *mut u32,
+    TimeInterval: u32,
+) -> i32 {
+    trace_call("PassThruStartPeriodicMsg", |api| unsafe { (api.start_periodic_msg)(ChannelID, pMsg, pMsgID, TimeInterval) }, || json!({
+        "channel_id": ChannelID,
+        "msg": msg_ptr(pMsg),
+        "msg_id": out_u32(pMsgID),
+        "interval": TimeInterval,
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruStopPeriodicMsg(ChannelID: u32, MsgID: u32) -> i32 {
+    trace_call("PassThruStopPeriodicMsg", |api| unsafe { (api.stop_periodic_msg)(ChannelID, MsgID) }, || json!({
+        "channel_id": ChannelID,
+        "msg_id": MsgID,
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruStartMsgFilter(
+    ChannelID: u32,
+    FilterType: u32,
+    pMaskMsg: *const PASSTHRU_MSG,
+    pPatternMsg: *const PASSTHRU_MSG,
+    pFlowControlMsg: *const PASSTHRU_MSG,
+    pMsgID: *mut u32,
+) -> i32 {
+    trace_call("PassThruStartMsgFilter", |api| unsafe {
+        (api.start_msg_filter)(ChannelID, FilterType, pMaskMsg, pPatternMsg, pFlowControlMsg, pMsgID)
+    }, || json!({
+        "channel_id": ChannelID,
+        "filter_type": filter_type(FilterType),
+        "mask": msg_ptr(pMaskMsg),
+        "pattern": msg_ptr(pPatternMsg),
+        "flow_control": msg_ptr(pFlowControlMsg),
+        "msg_id": out_u32(pMsgID),
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruStopMsgFilter(ChannelID: u32, MsgID: u32) -> i32 {
+    trace_call("PassThruStopMsgFilter", |api| unsafe { (api.stop_msg_filter)(ChannelID, MsgID) }, || json!({
+        "channel_id": ChannelID,
+        "msg_id": MsgID,
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruSetProgrammingVoltage(DeviceID: u32, PinNumber: u32, Voltage: u32) -> i32 {
+    trace_call("PassThruSetProgrammingVoltage", |api| unsafe { (api.set_programming_voltage)(DeviceID, PinNumber, Voltage) }, || json!({
+        "device_id": DeviceID,
+        "pin": PinNumber,
+        "voltage": Voltage,
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruReadVersion(
+    DeviceID: u32,
+    pFirmwareVersion: *mut c_char,
+    pDllVersion: *mut c_char,
+    pApiVersion: *mut c_char,
+) -> i32 {
+    trace_call("PassThruReadVersion", |api| unsafe { (api.read_version)(DeviceID, pFirmwareVersion, pDllVersion, pApiVersion) }, || json!({
+        "device_id": DeviceID,
+        "fw_version": c_str(pFirmwareVersion),
+        "dll_version": c_str(pDllVersion),
+        "api_version": c_str(pApiVersion),
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruGetLastError(pErrorDescription: *mut c_char) -> i32 {
+    // If the traced library never loaded, the application should be told why
+    if let Some(err) = load_error() {
+        write_load_error(err, pErrorDescription);
+        return PassthruError::STATUS_NOERROR as i32
+    }
+    trace_call("PassThruGetLastError", |api| unsafe { (api.get_last_error)(pErrorDescription) }, || json!({
+        "error": c_str(pErrorDescription),
+    }))
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "stdcall" fn PassThruIoctl(
+    HandleID: u32,
+    IoctlID: u32,
+    pInput: *mut c_void,
+    pOutput: *mut c_void,
+) -> i32 {
+    trace_call("PassThruIoctl", |api| unsafe { (api.ioctl)(HandleID, IoctlID, pInput, pOutput) }, || {
+        let (id, input, output) = ioctl(IoctlID, pInput, pOutput);
+        json!({
+            "handle_id": HandleID,
+            "ioctl_id": id,
+            "input": input,
+            "output": output,
+        })
+    })
+}
@@ -0,0 +1,282 @@
+use std::{any::Any, fs::File, io::Write, os::raw::c_char, panic::{catch_unwind, AssertUnwindSafe}, path::PathBuf, sync::Mutex, time::{Instant, SystemTime, UNIX_EPOCH}};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use J2534Common::*;
+use J2534Loader::{PassthruDrv, RawApi};
+
+// Loads the library being traced, and writes a JSON line to the trace file for every call
+
+/// Where the library to trace and the trace file are set
+struct TraceConfig {
+    lib: PathBuf,
+    output: PathBuf,
+}
+
+#[cfg(unix)]
+fn load_config() -> Result<TraceConfig, String> {
+    let path = J2534Loader::manifest_dir().join("trace.json");
+    let info = J2534Loader::DriverInfo::from_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let get_path = |key: &str| info.extra.get(key)
+        .and_then(|v| v.as_str())
+        .map(|s| PathBuf::from(shellexpand::tilde(s).to_string()));
+    Ok(TraceConfig {
+        lib: get_path("TRACE_LIB").ok_or("TRACE_LIB is not set in trace.json")?,
+        output: get_path("TRACE_FILE").unwrap_or_else(|| J2534Loader::manifest_dir().join("trace.jsonl")),
+    })
+}
+
+#[cfg(windows)]
+const REG_KEY: &str = "SOFTWARE\\WOW6432Node\\PassThruSupport.04.04\\J2534-Trace";
+
+#[cfg(windows)]
+fn load_config() -> Result<TraceConfig, String> {
+    use winreg::{RegKey, enums::HKEY_LOCAL_MACHINE};
+    let reg = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey(REG_KEY).map_err(|e| format!("Cannot open {}: {}", REG_KEY, e))?;
+    let lib: String = reg.get_value("TraceLibrary").map_err(|e| format!("TraceLibrary is not set: {}", e))?;
+    let output: String = reg.get_value("TraceFile").map_err(|e| format!("TraceFile is not set: {}", e))?;
+    Ok(TraceConfig { lib: lib.into(), output: output.into() })
+}
+
+lazy_static! {
+    static ref CONFIG: Result<TraceConfig, String> = load_config();
+    /// Library being traced. Err holds why it could not be loaded
+    static ref DRIVER: Result<PassthruDrv, String> = match CONFIG.as_ref() {
+        Ok(cfg) => PassthruDrv::load_lib(&cfg.lib).map_err(|e| format!("{}: {}", cfg.lib.display(), e)),
+        Err(e) => Err(e.clone())
+    };
+    static ref TRACE_FILE: Mutex<Option<File>> = Mutex::new(CONFIG.as_ref().ok().and_then(|cfg| File::create(&cfg.output).ok()));
+}
+
+/// Returns why the traced library could not be loaded, if it failed to load
+pub fn load_error() -> Option<&'static str> {
+    DRIVER.as_ref().err().map(|e| e.as_str())
+}
+
+fn write_record(record: Value) {
+    // Poisoned if a call panicked whilst writing. Recording that panic must not panic again
+    if let Some(f) = TRACE_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let _ = writeln!(f, "{}", record);
+    }
+}
+
+fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Unknown panic".into())
+}
+
+/// Forwards a call to the traced library, and records it. A panic must never unwind into
+/// the application, so it is recorded and the call returns ERR_FAILED
+/// # Params
+/// * name - Name of the PassThru function
+/// * call - Calls the function in the traced library
+/// * describe - Decodes the arguments. This is run after the call so output arguments are filled in
+pub fn trace_call<C, D>(name: &str, call: C, describe: D) -> i32
+where C: FnOnce(&RawApi) -> i32, D: FnOnce() -> Value {
+    match catch_unwind(AssertUnwindSafe(|| forward_call(name, call, describe))) {
+        Ok(ret) => ret,
+        Err(payload) => {
+            write_record(json!({ "call": name, "panic": panic_reason(payload.as_ref()) }));
+            PassthruError::ERR_FAILED as i32
+        }
+    }
+}
+
+fn forward_call<C, D>(name: &str, call: C, describe: D) -> i32
+where C: FnOnce(&RawApi) -> i32, D: FnOnce() -> Value {
+    let api = match DRIVER.as_ref() {
+        Ok(drv) => drv.raw(),
+        Err(e) => {
+            write_record(json!({ "call": name, "error": e }));
+            return PassthruError::ERR_FAILED as i32
+        }
+    };
+    let time_us = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+    let start = Instant::now();
+    let ret = call(api);
+    let duration_us = start.elapsed().as_micros() as u64;
+    // The call has already been made, so the application still gets its result if decoding fails
+    let args = catch_unwind(AssertUnwindSafe(describe)).unwrap_or_else(|payload| json!({ "panic": panic_reason(payload.as_ref()) }));
+    write_record(json!({
+        "time_us": time_us,
+        "thread": format!("{:?}", std::thread::current().id()),
+        "call": name,
+        "args": args,
+        "ret": ret,
+        "status": status(ret),
+        "duration_us": duration_us,
+    }));
+    ret
+}
+
+fn hex(x: u32) -> Value {
+    Value::String(format!("0x{:08X}", x))
+}
+
+fn hex_bytes(data: &[u8]) -> Value {
+    Value::String(data.iter().map(|b| format!("{:02X}", b)).collect())
+}
+
+pub fn status(ret: i32) -> Value {
+    match PassthruError::from_raw(ret as u32) {
+        Some(e) => Value::String(format!("{:?}", e)),
+        None => hex(ret as u32)
+    }
+}
+
+pub fn protocol(id: u32) -> Value {
+    match Protocol::from_raw(id) {
+        Some(p) => Value::String(format!("{:?}", p)),
+        None => hex(id)
+    }
+}
+
+pub fn flags(x: u32) -> Value {
+    hex(x)
+}
+
+pub fn filter_type(x: u32) -> Value {
+    match FilterType::from_raw(x) {
+        Some(f) => Value::String(format!("{:?}", f)),
+        None => hex(x)
+    }
+}
+
+/// Value behind an output pointer, or null
+pub fn out_u32(ptr: *const u32) -> Value {
+    match unsafe { ptr.as_ref() } {
+        Some(x) => json!(*x),
+        None => Value::Null
+    }
+}
+
+/// Message count of a ReadMsgs / WriteMsgs call. 0 if the pointer is null
+pub fn num_msgs(ptr: *const u32) -> u32 {
+    unsafe { ptr.as_ref() }.copied().unwrap_or(0)
+}
+
+/// String written to a version or error buffer (Max 80 chars)
+pub fn c_str(ptr: *const c_char) -> Value {
+    if ptr.is_null() {
+        return Value::Null
+    }
+    let buf = unsafe { std::slice::from_raw_parts(ptr as *const u8, 80) };
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Value::String(String::from_utf8_lossy(&buf[..len]).into())
+}
+
+pub fn msg(msg: &PASSTHRU_MSG) -> Value {
+    let timestamp = msg.timestamp;
+    json!({
+        "protocol": protocol(msg.protocol_id),
+        "rx_status": hex(msg.rx_status),
+        "tx_flags": hex(msg.tx_flags),
+        "timestamp": timestamp,
+        "data": hex_bytes(msg.data()),
+    })
+}
+
+pub fn msg_ptr(ptr: *const PASSTHRU_MSG) -> Value {
+    match unsafe { ptr.as_ref() } {
+        Some(m) => msg(m),
+        None => Value::Null
+    }
+}
+
+/// First count messages of a message array
+pub fn msgs(ptr: *const PASSTHRU_MSG, count: u32) -> Value {
+    if ptr.is_null() {
+        return Value::Null
+    }
+    Value::Array((0..count as usize).map(|i| msg(unsafe { &*ptr.add(i) })).collect())
+}
+
+fn byte_array(ptr: *const SBYTE_ARRAY) -> Value {
+    match unsafe { ptr.as_ref() } {
+        Some(a) if !a.byte_ptr.is_null() => hex_bytes(unsafe { std::slice::from_raw_parts(a.byte_ptr, a.num_of_bytes as usize) }),
+        _ => Value::Null
+    }
+}
+
+fn config_list(ptr: *const SConfigList) -> Value {
+    match unsafe { ptr.as_ref() } {
+        Some(l) if !l.config_ptr.is_null() => {
+            let params = unsafe { std::slice::from_raw_parts(l.config_ptr, l.num_of_params as usize) };
+            Value::Array(params.iter().map(|p| {
+                let param = match IoctlParam::from_raw(p.parameter) {
+                    Some(x) => Value::String(x.to_string()),
+                    None => hex(p.parameter)
+                };
+                let value = p.value;
+                json!({ "param": param, "value": value })
+            }).collect())
+        },
+        _ => Value::Null
+    }
+}
+
+/// Decodes the input and output of an ioctl, for the IDs that have a known layout
+pub fn ioctl(ioctl_id: u32, input: *const std::ffi::c_void, output: *const std::ffi::c_void) -> (Value, Value, Value) {
+    let id = match IoctlID::from_raw(ioctl_id) {
+        Some(x) => x,
+        None => return (hex(ioctl_id), Value::Null, Value::Null)
+    };
+    let (input, output) = match id {
+        IoctlID::GET_CONFIG | IoctlID::SET_CONFIG => (config_list(input as *const SConfigList), Value::Null),
+        IoctlID::READ_VBATT | IoctlID::READ_PROG_VOLTAGE => (Value::Null, out_u32(output as *const u32)),
+        IoctlID::FAST_INIT => (msg_ptr(input as *const PASSTHRU_MSG), msg_ptr(output as *const PASSTHRU_MSG)),
+        IoctlID::FIVE_BAUD_INIT => (byte_array(input as *const SBYTE_ARRAY), byte_array(output as *const SBYTE_ARRAY)),
+        IoctlID::ADD_TO_FUNCT_MSG_LOOKUP_TABLE
+        | IoctlID::DELETE_FROM_FUNCT_MSG_LOOKUP_TABLE
+        | IoctlID::PROTECT_J1939_ADDR
+        | IoctlID::REQUEST_CONNECTION
+        | IoctlID::TEARDOWN_CONNECTION => (byte_array(input as *const SBYTE_ARRAY), Value::Null),
+        _ => (Value::Null, Value::Null)
+    };
+    (Value::String(id.to_string()), input, output)
+}
+
+/// Copies the reason the traced library could not be loaded into an error buffer
+pub fn write_load_error(err: &str, dst: *mut c_char) {
+    if dst.is_null() {
+        return
+    }
+    let s = err.as_bytes();
+    let len = s.len().min(79);
+    unsafe {
+        std::ptr::copy_nonoverlapping(s.as_ptr() as *const c_char, dst, len);
+        *dst.add(len) = 0;
+    }
+}
+
+#[test]
+fn test_decode() {
+    let mut m = PASSTHRU_MSG::new(Protocol::CAN, 0x100, &[0x00, 0x00, 0x07, 0xE0, 0x02]).unwrap();
+    m.rx_status = 0x01;
+    m.timestamp = 1234;
+    assert_eq!(msg(&m), json!({
+        "protocol": "CAN",
+        "rx_status": "0x00000001",
+        "tx_flags": "0x00000100",
+        "timestamp": 1234,
+        "data": "000007E002",
+    }));
+    assert_eq!(msgs([m, m].as_ptr(), 2).as_array().unwrap().len(), 2);
+    assert_eq!(msgs(std::ptr::null(), 2), Value::Null);
+
+    let null = std::ptr::null();
+    let mut params = [SConfig { parameter: IoctlParam::DATA_RATE as u32, value: 500_000 }, SConfig { parameter: 0x7FFF, value: 1 }];
+    let list = SConfigList { num_of_params: 2, config_ptr: params.as_mut_ptr() };
+    assert_eq!(ioctl(IoctlID::SET_CONFIG as u32, &list as *const SConfigList as *const _, null), (
+        Value::String(IoctlID::SET_CONFIG.to_string()),
+        json!([{ "param": IoctlParam::DATA_RATE.to_string(), "value": 500_000 }, { "param": "0x00007FFF", "value": 1 }]),
+        Value::Null,
+    ));
+    let bytes = [0x01, 0x01];
+    let input = SBYTE_ARRAY { num_of_bytes: 2, byte_ptr: bytes.as_ptr() };
+    assert_eq!(ioctl(IoctlID::REQUEST_CONNECTION as u32, &input as *const SBYTE_ARRAY as *const _, null).1, json!("0101"));
+    let vbatt = 12_000u32;
+    assert_eq!(ioctl(IoctlID::READ_VBATT as u32, null, &vbatt as *const u32 as *const _).2, json!(12_000));
+    // IDs without a known layout are not decoded
+    assert_eq!(ioctl(0x1234_5678, &input as *const SBYTE_ARRAY as *const _, null), (json!("0x12345678"), Value::Null, Value::Null));
+}
@@ -52,10 +52,72 @@ void send_v_batt() {
   PCCOMM::respond_ok(MSG_READ_BATT, (uint8_t*)(&v_batt), 4);
 }
 
+// Programming voltage. The 12V IO outputs switch the supply voltage onto a pin (Source),
+// or connect the pin to ground (Sink). The driver maps J1962 pins onto the outputs
+#define NUM_12VIO_OUTPUTS 6
+#define PROG_VOLTAGE_OFF 0
+#define PROG_VOLTAGE_SUPPLY 1
+#define PROG_VOLTAGE_GROUND 2
+
+uint8_t progVoltageModes[NUM_12VIO_OUTPUTS] = {PROG_VOLTAGE_OFF};
+
+bool is_12vio_output(COMM_MSG *msg, uint16_t arg_size) {
+  if (msg->arg_size != arg_size || msg->args[0] < 1 || msg->args[0] > NUM_12VIO_OUTPUTS) {
+    PCCOMM::respond_err(msg->msg_type, ERR_PIN_INVALID, "Not a 12V IO output");
+    return false;
+  }
+  return true;
+}
+
+void set_prog_voltage(COMM_MSG *msg) {
+  if (!is_12vio_output(msg, 2)) {
+    return;
+  }
+  uint8_t output = msg->args[0];
+  switch (msg->args[1]) {
+    case PROG_VOLTAGE_OFF:
+      M2IO.Setpin_12VIO(output, OFF);
+      break;
+    case PROG_VOLTAGE_SUPPLY:
+      M2IO.Setpin_12VIO(output, ON, SOURCE);
+      break;
+    case PROG_VOLTAGE_GROUND:
+      M2IO.Setpin_12VIO(output, ON, SINK);
+      break;
+    default:
+      PCCOMM::respond_err(MSG_SET_PROG_VOLTAGE, ERR_FAILED, "Unknown 12V IO output mode");
+      return;
+  }
+  progVoltageModes[output - 1] = msg->args[1];
+  PCCOMM::respond_ok(MSG_SET_PROG_VOLTAGE, nullptr, 0);
+}
+
+// An output sourcing the supply is at the supply voltage, anything else reads 0
+void read_prog_voltage(COMM_MSG *msg) {
+  if (!is_12vio_output(msg, 1)) {
+    return;
+  }
+  unsigned long v_out = 0;
+  if (progVoltageModes[msg->args[0] - 1] == PROG_VOLTAGE_SUPPLY) {
+    v_out = getVoltage() * 1000;
+  }
+  PCCOMM::respond_ok(MSG_READ_PROG_VOLTAGE, (uint8_t*)(&v_out), 4);
+}
+
+void reset_prog_voltages() {
+  for (uint8_t i = 0; i < NUM_12VIO_OUTPUTS; i++) {
+    if (progVoltageModes[i] != PROG_VOLTAGE_OFF) {
+      M2IO.Setpin_12VIO(i + 1, OFF);
+      progVoltageModes[i] = PROG_VOLTAGE_OFF;
+    }
+  }
+}
+
 bool isConnected = false;
 void set_status_led(uint8_t status) {
     // Clear no matter what!
     reset_all_channels();
+    reset_prog_voltages();
     PCCOMM::reset();
   if (status == 0x00) {
     digitalWrite(DS6, HIGH); // Green Off
@@ -113,6 +175,15 @@ void loop() {
     case MSG_IOCTL_GET:
       ioctl_get(&msg);
       break;
+    case MSG_IOCTL_CMD:
+      ioctl_cmd(&msg);
+      break;
+    case MSG_SET_PROG_VOLTAGE:
+      set_prog_voltage(&msg);
+      break;
+    case MSG_READ_PROG_VOLTAGE:
+      read_prog_voltage(&msg);
+      break;
     case MSG_GET_FW_VERSION:
       get_fw_version(&msg);
       break;
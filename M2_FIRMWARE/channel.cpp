@@ -2,6 +2,10 @@
 
 Channel* canChannel = nullptr; // Channel for physical canbus link
 Channel* klineChannel = nullptr; // Channel for physical kline line
+Channel* canPsChannel = nullptr; // Channel for pin switched CAN (J1962_PINS)
+Channel* swCanChannel = nullptr; // Channel for single wire CAN
+Channel* j1939Channel = nullptr; // Channel for J1939 (J1962_PINS)
+Channel* tp20Channel = nullptr; // Channel for VW TP 2.0 (J1962_PINS)
 
 int little_endian_decode(uint8_t* src) {
     return src[3] << 24 |
@@ -15,22 +19,74 @@ void setup_channel(COMM_MSG* msg) {
     if (msg->msg_type != MSG_OPEN_CHANNEL) {
         PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, "This is NOT a open channel msg!");
     }
-    if (msg->arg_size != 16) {
-        char buf[65];
-        sprintf(buf, "Payload size for OpenChannel is incorrect. Want 16, got %d", msg->arg_size);
+    if (msg->arg_size != 16 && msg->arg_size != 20) {
+        char buf[70];
+        sprintf(buf, "Payload size for OpenChannel is incorrect. Want 16 or 20, got %d", msg->arg_size);
         PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, buf);
+        return;
     }
     unsigned int id = little_endian_decode(&msg->args[0]);
     unsigned int protocol = little_endian_decode(&msg->args[4]);
     unsigned int baud = little_endian_decode(&msg->args[8]);
     unsigned int flags = little_endian_decode(&msg->args[12]);
+    // Optional J1962 pins. If not specified, then use the protocols default pins
+    unsigned int pins = 0;
+    if (msg->arg_size == 20) {
+        pins = little_endian_decode(&msg->args[16]);
+    }
+    int bus = CAN_BUS_0;
     switch (id)
     {
         case CAN_CHANNEL_ID:
             if (canChannel != nullptr) {
                 PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, nullptr);
             } else {
-                create_can_channel(id, protocol, baud, flags);
+                create_can_channel(canChannel, id, protocol, baud, flags, CAN_BUS_0);
+            }
+            break;
+        case CAN_PS_CHANNEL_ID:
+            bus = get_can_bus_for_pins(pins);
+            if (canPsChannel != nullptr) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, nullptr);
+            } else if (bus < 0) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_PIN_INVALID, "CAN is not available on these pins");
+            } else if (can_bus_in_use(bus)) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, "Pins are used by another channel");
+            } else {
+                create_can_channel(canPsChannel, id, protocol, baud, flags, bus);
+            }
+            break;
+        case J1939_CHANNEL_ID:
+            bus = get_can_bus_for_pins(pins);
+            if (j1939Channel != nullptr) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, nullptr);
+            } else if (bus < 0) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_PIN_INVALID, "CAN is not available on these pins");
+            } else if (can_bus_in_use(bus)) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, "Pins are used by another channel");
+            } else {
+                create_can_channel(j1939Channel, id, protocol, baud, flags, bus);
+            }
+            break;
+        case TP20_CHANNEL_ID:
+            bus = get_can_bus_for_pins(pins);
+            if (tp20Channel != nullptr) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, nullptr);
+            } else if (bus < 0) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_PIN_INVALID, "CAN is not available on these pins");
+            } else if (can_bus_in_use(bus)) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, "Pins are used by another channel");
+            } else {
+                create_can_channel(tp20Channel, id, protocol, baud, flags, bus);
+            }
+            break;
+        case SW_CAN_CHANNEL_ID:
+            if (swCanChannel != nullptr) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, nullptr);
+            } else if (pins != 0x0100) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_PIN_INVALID, "Single wire CAN is only available on pin 1");
+            } else {
+                create_can_channel(swCanChannel, id, protocol, baud, flags, CAN_BUS_SW);
             }
             break;
         default:
@@ -39,21 +95,65 @@ void setup_channel(COMM_MSG* msg) {
     }
 }
 
-void create_can_channel(int id, int protocol, int baud, int flags) {
+int get_can_bus_for_pins(unsigned int pins) {
+    switch (pins) {
+        case 0x060E: // Pins 6/14
+            return CAN_BUS_0;
+        case 0x030B: // Pins 3/11
+            return CAN_BUS_1;
+        default:
+            return -1;
+    }
+}
+
+bool can_bus_in_use(int bus) {
+    Channel* can_channels[] = {canChannel, canPsChannel, swCanChannel, j1939Channel, tp20Channel};
+    for (Channel* c : can_channels) {
+        if (c != nullptr && c->get_can_bus() == bus) {
+            return true;
+        }
+    }
+    return false;
+}
+
+void create_can_channel(Channel*& ptr, int id, int protocol, int baud, int flags, uint8_t bus) {
     Channel *c = nullptr;
-    if (protocol == ISO15765) { // ISO-TP
+    if (protocol == ISO15765 || protocol == ISO15765_PS || protocol == SW_ISO15765_PS) { // ISO-TP
         c = new ISO15765Channel();
+    } else if (protocol == J1939_PS) { // J1939
+        c = new J1939Channel();
+    } else if (protocol == TP2_0_PS) { // VW TP 2.0
+        c = new TP20Channel();
     } else { // Standard CAN
         c = new CanChannel();
     }
-    if (!c->setup(id, protocol, baud, flags)) { // This function will return log the error to driver if any error
+    if (!c->setup(id, protocol, baud, flags, bus)) { // This function will return log the error to driver if any error
         delete c;
         return;
     }
-    canChannel = c; // Creation ok!
+    ptr = c; // Creation ok!
     PCCOMM::respond_ok(MSG_OPEN_CHANNEL, nullptr, 0); // Tell driver CAN based channel is ready!
 }
 
+Channel* find_channel(unsigned int id) {
+    switch (id) {
+        case CAN_CHANNEL_ID:
+            return canChannel;
+        case KLINE_CHANNEL_ID:
+            return klineChannel;
+        case CAN_PS_CHANNEL_ID:
+            return canPsChannel;
+        case SW_CAN_CHANNEL_ID:
+            return swCanChannel;
+        case J1939_CHANNEL_ID:
+            return j1939Channel;
+        case TP20_CHANNEL_ID:
+            return tp20Channel;
+        default:
+            return nullptr;
+    }
+}
+
 void remove_channel(COMM_MSG *msg) {
     if (msg->msg_type != MSG_CLOSE_CHANNEL) {
         PCCOMM::respond_err(MSG_CLOSE_CHANNEL, ERR_FAILED, "This is NOT a close channel msg!");
@@ -68,7 +168,18 @@ void remove_channel(COMM_MSG *msg) {
     switch(id) {
         case CAN_CHANNEL_ID:
             delete_channel(canChannel);
-            PCCOMM::respond_ok(MSG_CLOSE_CHANNEL, nullptr, 0);
+            break;
+        case CAN_PS_CHANNEL_ID:
+            delete_channel(canPsChannel);
+            break;
+        case SW_CAN_CHANNEL_ID:
+            delete_channel(swCanChannel);
+            break;
+        case J1939_CHANNEL_ID:
+            delete_channel(j1939Channel);
+            break;
+        case TP20_CHANNEL_ID:
+            delete_channel(tp20Channel);
             break;
         default:
             PCCOMM::respond_err(MSG_CLOSE_CHANNEL, ERR_FAILED, "Protocol unsupported");
@@ -94,6 +205,19 @@ void channel_loop() {
     if (klineChannel != nullptr) {
         klineChannel->update();
     }
+    if (canPsChannel != nullptr) {
+        canPsChannel->update();
+    }
+    if (swCanChannel != nullptr) {
+        swCanChannel->update();
+    }
+    if (j1939Channel != nullptr) {
+        j1939Channel->update();
+    }
+    if (tp20Channel != nullptr) {
+        tp20Channel->update();
+    }
+    CustomCan::pollBusState();
 }
 
 void reset_all_channels() {
@@ -107,6 +231,26 @@ void reset_all_channels() {
         delete klineChannel;
         klineChannel = nullptr;
     }
+    if (canPsChannel != nullptr) {
+        canPsChannel->destroy();
+        delete canPsChannel;
+        canPsChannel = nullptr;
+    }
+    if (swCanChannel != nullptr) {
+        swCanChannel->destroy();
+        delete swCanChannel;
+        swCanChannel = nullptr;
+    }
+    if (j1939Channel != nullptr) {
+        j1939Channel->destroy();
+        delete j1939Channel;
+        j1939Channel = nullptr;
+    }
+    if (tp20Channel != nullptr) {
+        tp20Channel->destroy();
+        delete tp20Channel;
+        tp20Channel = nullptr;
+    }
 }
 
 void del_channel_filter(COMM_MSG* msg) {
@@ -114,28 +258,15 @@ void del_channel_filter(COMM_MSG* msg) {
         PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_FAILED, "Message size not valid");
         return;
     }
-    unsigned int channel_id;
-    unsigned int filter_id;
+    unsigned int channel_id = little_endian_decode(&msg->args[0]);
+    unsigned int filter_id = little_endian_decode(&msg->args[4]);
 
-    if (channel_id == CAN_CHANNEL_ID) {
-        if (canChannel != nullptr) {
-            canChannel->removeFilter(filter_id);
-            return;
-        } else {
-            PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_CHANNEL_ID, "Can based channel null");
-            return;
-        }
-    } else if (channel_id == KLINE_CHANNEL_ID) {
-        if (klineChannel != nullptr) {
-            klineChannel->removeFilter(filter_id);
-            return;
-        } else {
-            PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_CHANNEL_ID, "Kline based channel null");
-            return;
-        }
+    Channel* c = find_channel(channel_id);
+    if (c != nullptr) {
+        c->removeFilter(filter_id);
+    } else {
+        PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_CHANNEL_ID, "Channel is null");
     }
-
-
 }
 
 void add_channel_filter(COMM_MSG* msg) {
@@ -150,7 +281,8 @@ void add_channel_filter(COMM_MSG* msg) {
         return;
     }
     // Check if the channel is valid?
-    if (channel_id != CAN_CHANNEL_ID && channel_id != KLINE_CHANNEL_ID) {
+    Channel* c = find_channel(channel_id);
+    if (c == nullptr) {
         PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_INVALID_CHANNEL_ID, "Channel ID does not exist");
         return;
     }
@@ -172,19 +304,7 @@ void add_channel_filter(COMM_MSG* msg) {
         memcpy(&flowcontrol[0], &msg->args[24+mask_size+pattern_size], flowcontrol_size);
     }
 
-    if (channel_id == CAN_CHANNEL_ID) {
-        if (canChannel != nullptr) {
-            canChannel->addFilter(filter_type, filter_id, mask, pattern, flowcontrol, mask_size, pattern_size, flowcontrol_size);
-        } else {
-            PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_INVALID_CHANNEL_ID, nullptr);
-        }
-    } else if (channel_id == KLINE_CHANNEL_ID) {
-        if (klineChannel != nullptr) {
-             klineChannel->addFilter(filter_type, filter_id, mask, pattern, flowcontrol, mask_size, pattern_size, flowcontrol_size);
-        } else {
-             PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_INVALID_CHANNEL_ID, nullptr);
-        }
-    }
+    c->addFilter(filter_type, filter_id, mask, pattern, flowcontrol, mask_size, pattern_size, flowcontrol_size);
     // Done with these arrays, hardware has applied them, destroy
     delete[] mask;
     delete[] pattern;
@@ -204,9 +324,10 @@ void send_data(COMM_MSG *msg) {
     memcpy(&channel_id, &msg->args[0], 4);
     memcpy(&tx_flags, &msg->args[4], 4);
     memcpy(&buf[0], &msg->args[8], data_size);
-    if (channel_id == CAN_CHANNEL_ID) {
-        if (canChannel != nullptr) {
-            canChannel->sendMsg(tx_flags, buf, data_size, require_response);
+    if (channel_id == CAN_CHANNEL_ID || channel_id == CAN_PS_CHANNEL_ID || channel_id == SW_CAN_CHANNEL_ID || channel_id == J1939_CHANNEL_ID || channel_id == TP20_CHANNEL_ID) {
+        Channel* c = find_channel(channel_id);
+        if (c != nullptr) {
+            c->sendMsg(tx_flags, buf, data_size, require_response);
         } else {
             if (require_response) {
                 PCCOMM::respond_err(MSG_TX_CHAN_DATA, ERR_INVALID_CHANNEL_ID, nullptr);
@@ -237,8 +358,12 @@ void ioctl_get(COMM_MSG *msg) {
     switch (channel_id)
     {
     case CAN_CHANNEL_ID:
-        if (canChannel != nullptr) {
-            canChannel->ioctl_get(ioctl_id);
+    case CAN_PS_CHANNEL_ID:
+    case SW_CAN_CHANNEL_ID:
+    case J1939_CHANNEL_ID:
+    case TP20_CHANNEL_ID:
+        if (find_channel(channel_id) != nullptr) {
+            find_channel(channel_id)->ioctl_get(ioctl_id);
         } else {
             PCCOMM::respond_err(MSG_IOCTL_GET, ERR_FAILED, "Can channel is null!");
         }
@@ -264,8 +389,12 @@ void ioctl_set(COMM_MSG *msg) {
     switch (channel_id)
     {
     case CAN_CHANNEL_ID:
-        if (canChannel != nullptr) {
-            canChannel->ioctl_set(ioctl_id, value);
+    case CAN_PS_CHANNEL_ID:
+    case SW_CAN_CHANNEL_ID:
+    case J1939_CHANNEL_ID:
+    case TP20_CHANNEL_ID:
+        if (find_channel(channel_id) != nullptr) {
+            find_channel(channel_id)->ioctl_set(ioctl_id, value);
         } else {
             PCCOMM::respond_err(MSG_IOCTL_SET, ERR_FAILED, "Can channel is null!");
         }
@@ -275,4 +404,21 @@ void ioctl_set(COMM_MSG *msg) {
         PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_CHANNEL_ID, nullptr);
         break;
     }
-}
\ No newline at end of file
+}
+
+void ioctl_cmd(COMM_MSG *msg) {
+    uint8_t channel_id;
+    uint32_t ioctl_id;
+    if (msg->arg_size < 5) {
+        PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_FAILED, "IOCTL command request invalid length");
+        return;
+    }
+    channel_id = msg->args[0];
+    memcpy(&ioctl_id, &msg->args[1], 4);
+    Channel* c = find_channel(channel_id);
+    if (c != nullptr) {
+        c->ioctl_cmd(ioctl_id, &msg->args[5], msg->arg_size - 5);
+    } else {
+        PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_INVALID_CHANNEL_ID, nullptr);
+    }
+}
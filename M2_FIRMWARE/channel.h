@@ -10,6 +10,10 @@
 #define KLINE_CHANNEL_ID 1
 #define J1850_CHANNEL_ID 2
 #define SCI_CHANNEL_ID 3
+#define CAN_PS_CHANNEL_ID 4
+#define SW_CAN_CHANNEL_ID 5
+#define J1939_CHANNEL_ID 6
+#define TP20_CHANNEL_ID 7
 
 void setup_channel(COMM_MSG* msg);
 void remove_channel(COMM_MSG *msg);
@@ -21,8 +25,26 @@ void send_data(COMM_MSG *msg);
 
 void ioctl_get(COMM_MSG *msg);
 void ioctl_set(COMM_MSG *msg);
+void ioctl_cmd(COMM_MSG *msg);
 
-void create_can_channel(int id, int protocol, int baud, int flags);
+void create_can_channel(Channel*& ptr, int id, int protocol, int baud, int flags, uint8_t bus);
+
+/**
+ * Returns the channel with a matching ID, or nullptr if the channel
+ * is not open
+ */
+Channel* find_channel(unsigned int id);
+
+/**
+ * Returns the CAN interface wired to the requested J1962 pins (0x0000PPSS),
+ * or -1 if the M2 cannot route CAN to those pins
+ */
+int get_can_bus_for_pins(unsigned int pins);
+
+/**
+ * Returns true if an open channel is already using the CAN interface
+ */
+bool can_bus_in_use(int bus);
 
 /**
  * This function is ran when disconnect is called.
@@ -81,14 +81,19 @@ namespace PCCOMM {
         send_message(&res);
     }
 
-    void send_rx_data(uint8_t channel_id, uint32_t rx_status, char* data, uint16_t data_len) {
+    /**
+     * Sends data received by a channel to the PC.
+     * timestamp is the micros() value when the data was received (Or sent, for Tx indications)
+     */
+    void send_rx_data(uint8_t channel_id, uint32_t rx_status, uint32_t timestamp, char* data, uint16_t data_len) {
         memset(&res, 0x00, sizeof(COMM_MSG));
         res.msg_type = MSG_RX_CHAN_DATA;
-        res.arg_size = 5 + min(data_len, COMM_MSG_ARG_SIZE);
+        res.arg_size = 9 + min(data_len, COMM_MSG_ARG_SIZE - 9);
         res.args[0] = channel_id;
         res.msg_id = 0x00;
         memcpy(&res.args[1], &rx_status, 4);
-        memcpy(&res.args[5], data, res.arg_size-5);
+        memcpy(&res.args[5], &timestamp, 4);
+        memcpy(&res.args[9], data, res.arg_size-9);
         send_message(&res);
     }
 
@@ -16,6 +16,9 @@
 #define MSG_READ_BATT 0x08
 #define MSG_IOCTL_SET 0x09
 #define MSG_IOCTL_GET 0x10
+#define MSG_IOCTL_CMD 0x11 // [Channel ID, IOCTL ID (4 bytes), Input data...]
+#define MSG_SET_PROG_VOLTAGE 0x12 // [12V IO output, Mode]
+#define MSG_READ_PROG_VOLTAGE 0x13 // [12V IO output]
 #define MSG_STATUS 0xAA // Args: [0] -> 0x00 = Goodbye, 0x01 = Hellow
 #define MSG_GET_FW_VERSION 0xAB
 #define MSG_TEST 0x0FF
@@ -38,7 +41,7 @@ namespace PCCOMM {
 
     void respond_ok(uint8_t op, uint8_t* args, uint16_t arg_size);
     void respond_err(uint8_t op, uint8_t error_id, char* txt);
-    void send_rx_data(uint8_t channel_id, uint32_t rx_status, char* data, uint16_t data_len);
+    void send_rx_data(uint8_t channel_id, uint32_t rx_status, uint32_t timestamp, char* data, uint16_t data_len);
     void reset();
 }
 
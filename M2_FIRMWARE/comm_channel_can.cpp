@@ -1,11 +1,28 @@
 #include "comm_channels.h"
 
-bool CanChannel::setup(int id, int protocol, int baud, int flags) {
+// Bus errors are sent to the PC at most this often, as a bus with the wrong baud rate errors on every frame
+#define ERROR_FRAME_INTERVAL_MS 10
+
+bool CanChannel::setup(int id, int protocol, int baud, int flags, uint8_t bus) {
     // Here we go, setup a CAN channel!
-    if (!CustomCan::enableCanBus(baud)) {
+    this->can_bus = bus;
+    this->listen_only = (flags & MACCHINA_LISTEN_ONLY) != 0;
+    if (this->listen_only && bus == CAN_BUS_SW) {
+        PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_INVALID_FLAGS, "Single wire CAN cannot listen only");
+        return false;
+    }
+    if (baud == 0) { // Use the baud rate of the traffic already on the bus
+        baud = CustomCan::detectBaud(this->can_bus);
+        if (baud == 0) {
+            PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, "No CAN traffic found at any baud rate");
+            return false;
+        }
+    }
+    if (!CustomCan::enableCanBus(this->can_bus, baud, this->listen_only)) {
          PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, "CAN Controller setup failed!");
          return false;
     }
+    this->baud = baud;
     if (flags & CAN_29BIT_ID) { // extended addressing, 
         PCCOMM::log_message("CAN Extended enabled");
         this->isExtended = true;
@@ -46,19 +63,19 @@ void CanChannel::addFilter(int type, int filter_id, char* mask, char* pattern, c
 
     for (int i = 0; i < mask_len; i++) {
         mask_id <<= 8;
-        mask_id |= mask[i];
+        mask_id |= (uint8_t)mask[i];
     }
 
     for (int i = 0; i < pattern_len; i++) {
         ptn_id <<= 8;
-        ptn_id |= pattern[i];
+        ptn_id |= (uint8_t)pattern[i];
     }
 
     if (type == BLOCK_FILTER) { // Block filter. Set the CAN Filter ID to be open, and then we will block it in software
-        CustomCan::enableCanFilter(filter_id, 0x0000, 0x0000, isExtended); // Open the mailbox filter to everything
+        CustomCan::enableCanFilter(this->can_bus, filter_id, 0x0000, 0x0000, isExtended); // Open the mailbox filter to everything
         blocking_filters[filter_id] = true; // Mark this as yes for the update function
     } else { // Pass filter, use hardware filter
-        CustomCan::enableCanFilter(filter_id, ptn_id, mask_id, isExtended); // Open the mailbox filter to everything
+        CustomCan::enableCanFilter(this->can_bus, filter_id, ptn_id, mask_id, isExtended); // Open the mailbox filter to everything
         blocking_filters[filter_id] = false;
 
     }
@@ -69,36 +86,84 @@ void CanChannel::addFilter(int type, int filter_id, char* mask, char* pattern, c
 }
 
 void CanChannel::update() {
+    if (this->error_frames) {
+        uint8_t state = CustomCan::getBusState(this->can_bus);
+        if (state != this->bus_state) { // State changes are always sent straight away
+            this->bus_state = state;
+            send_bus_event(MACCHINA_BUS_STATE, state);
+        }
+        if (millis() - this->last_error_report >= ERROR_FRAME_INTERVAL_MS) {
+            uint8_t errors = CustomCan::takeBusErrors(this->can_bus);
+            if (errors != 0) {
+                this->last_error_report = millis();
+                send_bus_event(MACCHINA_BUS_ERROR, errors);
+            }
+        }
+    }
     for (int i = 0; i < 7; i++) { // Check all our filters in use
         if (used_mailboxes[i] == true) { // We should this filter
-            if (CustomCan::receiveFrame(i, &f)) {
+            if (CustomCan::receiveFrame(this->can_bus, i, &f)) {
                 bool send_frame = true;
                 if (blocking_filters[i] == true) { // Check block filter
-                    send_frame = masks[i] & f.id != patterns[i]; // Block filter check
+                    send_frame = (masks[i] & f.id) != patterns[i]; // Block filter check
                 }
                 if (send_frame) { // Frame should be sent to the PC
                     char buf[f.length + 4];
                     // TODO - Rx Flags for CAN - Although i don't think they are needed, so leave them 0x0000
                     uint32_t rx_status = 0x0000;
+                    if (this->can_bus == CAN_BUS_SW) {
+                        rx_status |= CustomCan::getSwCanRxStatus();
+                    }
                     buf[0] = f.id >> 24;
                     buf[1] = f.id >> 16;
                     buf[2] = f.id >> 8;
                     buf[3] = f.id >> 0;
                     memcpy(&buf[4], &f.data.bytes[0], f.length);  // Copy CAN Data
-                    PCCOMM::send_rx_data(this->channel_id, rx_status, buf, f.length+4); // Tx to PC
+                    PCCOMM::send_rx_data(this->channel_id, rx_status, f.timestamp, buf, f.length+4); // Tx to PC
                 }
             }
         }
     }
 }
 
+/**
+ * Sends a bus error or state change to the PC. The message has CAN ID 0, followed by
+ * the errors or state, then the transmit and receive error counters
+ */
+void CanChannel::send_bus_event(uint32_t rx_status, uint8_t value) {
+    uint8_t tec, rec;
+    CustomCan::getErrorCounters(this->can_bus, &tec, &rec);
+    char buf[7] = {0x00, 0x00, 0x00, 0x00, (char)value, (char)tec, (char)rec};
+    PCCOMM::send_rx_data(this->channel_id, rx_status, micros(), buf, sizeof(buf));
+}
+
+/**
+ * Restarts the CAN controller at a new baud rate, putting the channel's filters back
+ */
+bool CanChannel::start_bus(uint32_t baud) {
+    if (!CustomCan::enableCanBus(this->can_bus, baud, this->listen_only)) {
+        return false;
+    }
+    this->baud = baud;
+    for (int i = 0; i < 7; i++) {
+        if (used_mailboxes[i] == true) {
+            if (blocking_filters[i] == true) {
+                CustomCan::enableCanFilter(this->can_bus, i, 0x0000, 0x0000, isExtended);
+            } else {
+                CustomCan::enableCanFilter(this->can_bus, i, patterns[i], masks[i], isExtended);
+            }
+        }
+    }
+    return true;
+}
+
 void CanChannel::removeFilter(int id) {
     if (this->used_mailboxes[id] == true) {
         this->used_mailboxes[id] = false;
         this->masks[id] = 0;
         this->patterns[id] = 0;
         this->blocking_filters[id] = false;
-        CustomCan::disableCanFilter(id);
+        CustomCan::disableCanFilter(this->can_bus, id);
         PCCOMM::respond_ok(MSG_REM_CHAN_FILT, nullptr, 0);
     } else {
         PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_FILTER_ID, nullptr);
@@ -106,7 +171,7 @@ void CanChannel::removeFilter(int id) {
 }
 
 void CanChannel::destroy() {
-    CustomCan::disableCanBus();
+    CustomCan::disableCanBus(this->can_bus);
     digitalWrite(DS3, HIGH); // Disable the light
 }
 
@@ -114,12 +179,26 @@ void CanChannel::destroy() {
  * Macchina will NOT respond to this request, just send and leave it
  */
 void CanChannel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool respond) {
+    if (this->listen_only) { // The driver rejects these, so this should never happen
+        if (respond) {
+            PCCOMM::respond_err(MSG_TX_CHAN_DATA, ERR_NOT_SUPPORTED, "Channel is listen only");
+        }
+        return;
+    }
     // First 4 bytes are CAN ID, followed by the CAN Data
     CAN_FRAME f;
     f.length = data_size - 4;
     f.id = data[0] << 24 | data[1] << 16 | data[2] << 8 | data[3] << 0;
     memcpy(&f.data.bytes[0], &data[4], data_size-4);
-    CustomCan::sendFrame(&f);
+    uint32_t tx_time = micros();
+    if (this->can_bus == CAN_BUS_SW && (tx_flags & SW_CAN_HV_TX)) {
+        CustomCan::sendSwCanHvFrame(&f);
+    } else {
+        CustomCan::sendFrame(this->can_bus, &f);
+    }
+    if (this->loopback) { // Echo the frame back to the PC
+        PCCOMM::send_rx_data(this->channel_id, TX_MSG_TYPE, tx_time, data, data_size);
+    }
     if (respond) {
         PCCOMM::respond_ok(MSG_TX_CHAN_DATA, nullptr, 0);
     }
@@ -127,9 +206,65 @@ void CanChannel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool resp
 
 
 void CanChannel::ioctl_get(uint32_t id) {
+    if (id == LOOPBACK) {
+        uint32_t tmp = this->loopback;
+        PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&tmp), 4);
+        return;
+    }
+    if (id == DATA_RATE) {
+        PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&this->baud), 4);
+        return;
+    }
+    if (id == MACCHINA_ERROR_FRAMES) {
+        uint32_t tmp = this->error_frames;
+        PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&tmp), 4);
+        return;
+    }
+    if (bit_timing_ioctl_get(this->can_bus, id)) return;
+    if (sw_can_ioctl_get(this->can_bus, id)) return;
     PCCOMM::respond_err(MSG_IOCTL_GET, ERR_FAILED, "CAN IOCTL get unimplemented");
 }
 
 void CanChannel::ioctl_set(uint32_t id, uint32_t value) {
+    if (id == LOOPBACK) {
+        this->loopback = value != 0;
+        PCCOMM::respond_ok(MSG_IOCTL_SET, nullptr, 0);
+        return;
+    }
+    if (id == MACCHINA_ERROR_FRAMES) {
+        this->error_frames = value != 0;
+        if (this->error_frames) {
+            // Errors from before the mode was turned on are not sent. If the bus is
+            // not error active already, the state is sent on the next update
+            CustomCan::takeBusErrors(this->can_bus);
+            this->bus_state = BUS_STATE_ERROR_ACTIVE;
+        }
+        PCCOMM::respond_ok(MSG_IOCTL_SET, nullptr, 0);
+        return;
+    }
+    if (bit_timing_ioctl_set(this->can_bus, id, value)) return;
+    if (sw_can_ioctl_set(this->can_bus, id, value)) return;
     PCCOMM::respond_err(MSG_IOCTL_SET, ERR_FAILED, "CAN IOCTL set unimplemented");
+}
+
+void CanChannel::ioctl_cmd(uint32_t id, uint8_t* data, int data_len) {
+    if (id == MACCHINA_DETECT_BAUD_RATE) {
+        if (this->can_bus == CAN_BUS_SW) {
+            PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_NOT_SUPPORTED, "Single wire CAN cannot detect its baud rate");
+            return;
+        }
+        uint32_t baud = CustomCan::detectBaud(this->can_bus);
+        // Back on the bus at the old baud rate if there was no traffic
+        if (!start_bus(baud != 0 ? baud : this->baud)) {
+            PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_FAILED, "CAN Controller setup failed!");
+        } else if (baud == 0) {
+            PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_FAILED, "No CAN traffic found at any baud rate");
+        } else {
+            PCCOMM::respond_ok(MSG_IOCTL_CMD, (uint8_t*)(&baud), 4);
+        }
+        return;
+    }
+    if (bus_stats_ioctl_cmd(this->can_bus, id)) return;
+    if (sw_can_ioctl_cmd(this->can_bus, id, data, data_len)) return;
+    PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_INVALID_IOCTL_ID, "CAN invalid IOCTL ID");
 }
\ No newline at end of file
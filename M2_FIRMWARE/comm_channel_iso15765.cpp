@@ -1,8 +1,9 @@
 #include "comm_channels.h"
 
-bool ISO15765Channel::setup(int id, int protocol, int baud, int flags) {
+bool ISO15765Channel::setup(int id, int protocol, int baud, int flags, uint8_t bus) {
     // Here we go, setup a ISO15765 channel!
-    if (!CustomCan::enableCanBus(baud)) {
+    this->can_bus = bus;
+    if (!CustomCan::enableCanBus(this->can_bus, baud)) {
          PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, "CAN Controller setup failed!");
          return false;
     }
@@ -66,7 +67,7 @@ void ISO15765Channel::addFilter(int type, int filter_id, char* mask, char* patte
     this->mask_ids[filter_id] = mask_u32;
     this->pattern_ids[filter_id] = pattern_u32;
     this->flowcontrol_ids[filter_id] = flowcontrol_u32;
-    CustomCan::enableCanFilter(filter_id, pattern_u32, mask_u32, use29bitCid);
+    CustomCan::enableCanFilter(this->can_bus, filter_id, pattern_u32, mask_u32, use29bitCid);
     PCCOMM::respond_ok(MSG_SET_CHAN_FILT, nullptr, 0);
 }
 
@@ -74,7 +75,7 @@ void ISO15765Channel::removeFilter(int id) {
     if (this->used_mailboxes[id] == true) {
         this->used_mailboxes[id] = false;
         this->flowcontrol_ids[id] = 0x00;
-        CustomCan::disableCanFilter(id);
+        CustomCan::disableCanFilter(this->can_bus, id);
         if (this->isReceiving) {
             delete [] this->rxPayload.payload;
         }
@@ -87,14 +88,14 @@ void ISO15765Channel::removeFilter(int id) {
 }
 
 void ISO15765Channel::destroy() {
-    CustomCan::disableCanBus();
+    CustomCan::disableCanBus(this->can_bus);
     digitalWrite(DS3, HIGH); // Disable the light
 }
 
 void ISO15765Channel::update() {
     for (int i = 0; i < 7; i++) {
         if (used_mailboxes[i] == true) {
-            if (CustomCan::receiveFrame(i, &f)) {
+            if (CustomCan::receiveFrame(this->can_bus, i, &f)) {
                 debug_read_frame(f);
                 // which byte do we listen to based on addressing method
                 uint8_t cmp = 0;
@@ -160,7 +161,7 @@ void ISO15765Channel::tx_multi_frame() {
     f.data.bytes[0] = tx_pci;
     memcpy(&f.data.bytes[1], &txPayload.payload[txPayload.payloadPos], max_cpy);
     txPayload.payloadPos += max_cpy;
-    debug_send_frame(f);
+    debug_send_frame(this->can_bus, f);
     tx_pci++;
     this->tx_frames_sent++;
     this->next_send_time = millis() + this->sep_time_tx;
@@ -186,7 +187,7 @@ void ISO15765Channel::rx_single_frame(CAN_FRAME *read) {
         buf[2] = read->id >> 0;
         buf[3] = read->data.bytes[0] >> 0;
         memcpy(&buf[4], &read->data.bytes[2], read->data.bytes[1]);
-        PCCOMM::send_rx_data(this->channel_id, 0x0000, buf, size);
+        PCCOMM::send_rx_data(this->channel_id, 0x0000, read->timestamp, buf, size);
         delete[] buf;
     } else { // Normal addressing
         uint8_t size = read->data.bytes[0] + 4;
@@ -197,7 +198,7 @@ void ISO15765Channel::rx_single_frame(CAN_FRAME *read) {
         buf[2] = read->id >> 8;
         buf[3] = read->id >> 0;
         memcpy(&buf[4], &read->data.bytes[1], read->data.bytes[0]);
-        PCCOMM::send_rx_data(this->channel_id, 0x0000, buf, size);
+        PCCOMM::send_rx_data(this->channel_id, 0x0000, read->timestamp, buf, size);
     }
 }
 
@@ -214,7 +215,7 @@ void ISO15765Channel::rx_multi_frame(CAN_FRAME *read, int id) {
     this->rx_frame_count++;
     if (rxPayload.payloadPos >= rxPayload.payloadSize) { // Got all our data!
         // Send the payload to the PC
-        PCCOMM::send_rx_data(this->channel_id, 0x0000, rxPayload.payload, rxPayload.payloadSize);
+        PCCOMM::send_rx_data(this->channel_id, 0x0000, read->timestamp, rxPayload.payload, rxPayload.payloadSize);
         // Now delete the old payload
         delete[] this->rxPayload.payload;
         this->isReceiving = false;
@@ -229,7 +230,7 @@ void ISO15765Channel::rx_multi_frame(CAN_FRAME *read, int id) {
         f.data.bytes[0] = 0x30;
         f.data.bytes[1] = 8; // BLOCK SIZE
         f.data.bytes[2] = 0x02; // ST_MIN
-        debug_send_frame(f);
+        debug_send_frame(this->can_bus, f);
         // ECU should now continue sending data...
     }
 }
@@ -270,7 +271,7 @@ void ISO15765Channel::send_ff_indication(CAN_FRAME *read, int id) {
     f.data.bytes[0] = 0x30; // Flow control (Clear to send!)
     f.data.bytes[1] = this->block_size; // BLOCK SIZE
     f.data.bytes[2] = this->sep_time; // ST_MIN
-    debug_send_frame(f);
+    debug_send_frame(this->can_bus, f);
     // Send the first frame indication back to the user application
     // 4 additional bytes should be sent which represents the Can ID of the message
     char* buf2 = new char[4];
@@ -279,7 +280,7 @@ void ISO15765Channel::send_ff_indication(CAN_FRAME *read, int id) {
     buf2[1] = request_id >> 16;
     buf2[2] = request_id >> 8;
     buf2[3] = request_id >> 0;
-    PCCOMM::send_rx_data(this->channel_id, ISO15765_FIRST_FRAME, buf2, 4);
+    PCCOMM::send_rx_data(this->channel_id, ISO15765_FIRST_FRAME, read->timestamp, buf2, 4);
     this->rx_frame_count = 0;
     delete[] buf2;
 }
@@ -297,7 +298,7 @@ void ISO15765Channel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool
         f.rtr = false;
         f.data.bytes[0] = data_size - 4; // First byte is the length of the ISO message
         memcpy(&f.data.bytes[1], &data[4], data_size-4); // Copy data to bytes [1] and beyond
-        if (!debug_send_frame(f)) {
+        if (!debug_send_frame(this->can_bus, f)) {
             if (respond) {
                 PCCOMM::respond_err(MSG_TX_CHAN_DATA, ERR_FAILED, "CAN Tx failed");
             } else {
@@ -341,7 +342,7 @@ void ISO15765Channel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool
         this->clear_to_send = false;
         this->isSending = true;
         this->tx_pci = 0x21;
-        debug_send_frame(f);
+        debug_send_frame(this->can_bus, f);
         if (respond) {
             PCCOMM::respond_ok(MSG_TX_CHAN_DATA, nullptr, 0);
         }
@@ -361,6 +362,8 @@ void ISO15765Channel::ioctl_get(uint32_t id) {
         PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&tmp), 4);
         break;
     default:
+        if (bit_timing_ioctl_get(this->can_bus, id)) break;
+        if (sw_can_ioctl_get(this->can_bus, id)) break;
         PCCOMM::respond_err(MSG_IOCTL_GET, ERR_INVALID_IOCTL_ID, "ISO15765 invalid IOCTL ID");
         break;
     }
@@ -378,7 +381,15 @@ void ISO15765Channel::ioctl_set(uint32_t id, uint32_t value) {
         PCCOMM::respond_ok(MSG_IOCTL_SET, nullptr, 0);
         break;
     default:
+        if (bit_timing_ioctl_set(this->can_bus, id, value)) break;
+        if (sw_can_ioctl_set(this->can_bus, id, value)) break;
         PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_ID, "ISO15765 invalid IOCTL ID");
         break;
     }
+}
+
+void ISO15765Channel::ioctl_cmd(uint32_t id, uint8_t* data, int data_len) {
+    if (bus_stats_ioctl_cmd(this->can_bus, id)) return;
+    if (sw_can_ioctl_cmd(this->can_bus, id, data, data_len)) return;
+    PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_INVALID_IOCTL_ID, "ISO15765 invalid IOCTL ID");
 }
\ No newline at end of file
@@ -0,0 +1,504 @@
+#include "comm_channels.h"
+
+#define PGN_REQUEST 0xEA00
+#define PGN_ADDRESS_CLAIM 0xEE00
+#define PGN_TP_CM 0xEC00
+#define PGN_TP_DT 0xEB00
+
+// TP.CM control bytes
+#define TP_CM_RTS 16
+#define TP_CM_CTS 17
+#define TP_CM_EOM_ACK 19
+#define TP_CM_BAM 32
+#define TP_CM_ABORT 255
+
+// Time [ms] other nodes have to contest our address claim
+#define J1939_CLAIM_TIME 250
+#define J1939_MAX_PAYLOAD 1785
+#define J1939_HEADER_SIZE 5
+
+bool is_pdu1(uint32_t pgn) {
+    return ((pgn >> 8) & 0xFF) < 0xF0;
+}
+
+bool J1939Channel::setup(int id, int protocol, int baud, int flags, uint8_t bus) {
+    this->can_bus = bus;
+    if (!CustomCan::enableCanBus(this->can_bus, baud)) {
+         PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, "CAN Controller setup failed!");
+         return false;
+    }
+    // J1939 filters are applied to whole messages (After transport protocol), and the channel must
+    // always see address claims and TP frames, so a single mailbox receives every extended frame
+    CustomCan::enableCanFilter(this->can_bus, 0, 0x0000, 0x0000, true);
+    digitalWrite(DS3, LOW); // Enable the light
+    this->channel_id = id;
+    this->f.length = 0;
+    return true;
+}
+
+void J1939Channel::addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len) {
+    if (type == FLOW_CONTROL_FILTER) {
+        PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_FAILED, "J1939 Channel cannot use flow control filter");
+        return;
+    }
+    if (mask_len > J1939_HEADER_SIZE || mask_len != pattern_len) {
+        PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_FAILED, "J1939 mask and pattern must be the same length, up to 5 bytes");
+        return;
+    }
+    if (filter_id >= 7) {
+        PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_EXCEEDED_LIMIT, nullptr);
+        return;
+    }
+    if (used_filters[filter_id] == true) {
+        PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_FAILED, "Filter ID in use");
+        return;
+    }
+    memcpy(masks[filter_id], mask, mask_len);
+    memcpy(patterns[filter_id], pattern, pattern_len);
+    filter_lens[filter_id] = mask_len;
+    blocking_filters[filter_id] = type == BLOCK_FILTER;
+    used_filters[filter_id] = true;
+    PCCOMM::respond_ok(MSG_SET_CHAN_FILT, nullptr, 0);
+}
+
+void J1939Channel::removeFilter(int id) {
+    if (id >= 0 && id < 7 && this->used_filters[id] == true) {
+        this->used_filters[id] = false;
+        this->blocking_filters[id] = false;
+        this->filter_lens[id] = 0;
+        PCCOMM::respond_ok(MSG_REM_CHAN_FILT, nullptr, 0);
+    } else {
+        PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_FILTER_ID, nullptr);
+    }
+}
+
+void J1939Channel::destroy() {
+    if (this->claim_state == J1939_CLAIM_CLAIMED) {
+        send_address_claim(0xFE); // Give our address back
+    }
+    end_session(rxSession);
+    end_session(txSession);
+    CustomCan::disableCanBus(this->can_bus);
+    digitalWrite(DS3, HIGH); // Disable the light
+}
+
+bool J1939Channel::send_frame(uint8_t priority, uint32_t pgn, uint8_t dest, uint8_t* data, uint8_t len) {
+    CAN_FRAME tx;
+    uint32_t id_pgn = pgn & 0x3FFFF;
+    if (is_pdu1(id_pgn)) {
+        id_pgn = (id_pgn & 0x3FF00) | dest;
+    }
+    tx.id = (priority & 0x07) << 26 | id_pgn << 8 | this->address;
+    tx.extended = true;
+    tx.rtr = 0;
+    tx.length = len;
+    memcpy(&tx.data.bytes[0], data, len);
+    return debug_send_frame(this->can_bus, tx);
+}
+
+void J1939Channel::send_address_claim(uint8_t addr) {
+    uint8_t old = this->address;
+    this->address = addr;
+    send_frame(6, PGN_ADDRESS_CLAIM, 0xFF, this->name, 8);
+    this->address = old;
+}
+
+void J1939Channel::send_claim_indication(uint32_t rx_status) {
+    // Indication is the address claim message with the claimed (Or lost) address as the source
+    char buf[J1939_HEADER_SIZE + 8];
+    uint32_t id = 6 << 26 | (uint32_t)(PGN_ADDRESS_CLAIM | 0xFF) << 8 | this->address;
+    buf[0] = id >> 24;
+    buf[1] = id >> 16;
+    buf[2] = id >> 8;
+    buf[3] = id >> 0;
+    buf[4] = 0xFF;
+    memcpy(&buf[J1939_HEADER_SIZE], this->name, 8);
+    PCCOMM::send_rx_data(this->channel_id, rx_status, micros(), buf, sizeof(buf));
+}
+
+void J1939Channel::send_tp_cm(uint8_t control, j1939TpSession &s, uint8_t dest, uint8_t b3, uint8_t b4) {
+    uint8_t buf[8] = {
+        control,
+        (uint8_t)(s.size & 0xFF),
+        (uint8_t)(s.size >> 8),
+        b3,
+        b4,
+        (uint8_t)(s.pgn & 0xFF),
+        (uint8_t)(s.pgn >> 8),
+        (uint8_t)(s.pgn >> 16)
+    };
+    send_frame(7, PGN_TP_CM, dest, buf, 8);
+}
+
+void J1939Channel::end_session(j1939TpSession &s) {
+    if (s.buffer != nullptr) {
+        delete[] s.buffer;
+    }
+    s = {0x00};
+}
+
+void J1939Channel::handle_address_claim(uint8_t source, uint8_t* other_name) {
+    if (source != this->address) return;
+    if (this->claim_state != J1939_CLAIM_CLAIMING && this->claim_state != J1939_CLAIM_CLAIMED) return;
+    // NAME is sent LSB first, and the lowest NAME wins the address
+    bool we_win = false;
+    for (int i = 7; i >= 0; i--) {
+        if (this->name[i] != other_name[i]) {
+            we_win = this->name[i] < other_name[i];
+            break;
+        }
+    }
+    if (we_win) {
+        send_address_claim(this->address);
+    } else {
+        send_address_claim(0xFE); // Cannot claim address
+        this->claim_state = J1939_CLAIM_LOST;
+        send_claim_indication(J1939_ADDRESS_LOST);
+    }
+}
+
+void J1939Channel::handle_tp_cm(uint8_t source, uint8_t dest, uint8_t* data) {
+    uint32_t pgn = data[5] | data[6] << 8 | data[7] << 16;
+    switch (data[0]) {
+        case TP_CM_BAM:
+        case TP_CM_RTS:
+            if (data[0] == TP_CM_RTS && (dest != this->address || this->claim_state != J1939_CLAIM_CLAIMED)) {
+                return; // Not for us
+            }
+            if (rxSession.active) {
+                if (data[0] == TP_CM_RTS) {
+                    // Only 1 inbound session at a time is supported
+                    j1939TpSession tmp = {0x00};
+                    tmp.pgn = pgn;
+                    send_tp_cm(TP_CM_ABORT, tmp, source, 0x01, 0xFF);
+                    return;
+                }
+                end_session(rxSession); // New BAM replaces an old one
+            }
+            rxSession.active = true;
+            rxSession.bam = data[0] == TP_CM_BAM;
+            rxSession.priority = 7;
+            rxSession.pgn = pgn;
+            rxSession.source = source;
+            rxSession.destination = rxSession.bam ? 0xFF : dest;
+            rxSession.size = data[1] | data[2] << 8;
+            rxSession.packets = data[3];
+            rxSession.next_seq = 1;
+            rxSession.max_window = data[4];
+            if (rxSession.size > J1939_MAX_PAYLOAD || rxSession.packets == 0 || rxSession.packets * 7 < rxSession.size) {
+                end_session(rxSession);
+                return;
+            }
+            rxSession.buffer = new uint8_t[rxSession.packets * 7];
+            if (rxSession.bam) {
+                rxSession.timeout = millis() + this->t1;
+            } else {
+                rxSession.window = min(rxSession.packets, rxSession.max_window);
+                send_tp_cm(TP_CM_CTS, rxSession, source, rxSession.window, rxSession.next_seq);
+                rxSession.timeout = millis() + this->t2;
+            }
+            break;
+        case TP_CM_CTS:
+            if (!txSession.active || txSession.bam || source != txSession.destination) return;
+            if (data[1] == 0) { // Hold the connection open
+                txSession.window = 0;
+                txSession.timeout = millis() + this->t4;
+            } else {
+                txSession.window = data[1];
+                txSession.next_seq = data[2];
+            }
+            break;
+        case TP_CM_EOM_ACK:
+            if (txSession.active && !txSession.bam && source == txSession.destination) {
+                end_session(txSession);
+            }
+            break;
+        case TP_CM_ABORT:
+            if (txSession.active && source == txSession.destination) {
+                PCCOMM::log_message("J1939 transmit aborted by receiver");
+                end_session(txSession);
+            }
+            if (rxSession.active && source == rxSession.source) {
+                end_session(rxSession);
+            }
+            break;
+        default:
+            break;
+    }
+}
+
+void J1939Channel::handle_tp_dt(uint8_t source, uint8_t* data) {
+    if (!rxSession.active || source != rxSession.source) return;
+    uint8_t seq = data[0];
+    if (seq != rxSession.next_seq || seq > rxSession.packets) {
+        if (!rxSession.bam) {
+            send_tp_cm(TP_CM_ABORT, rxSession, source, 0x05, 0xFF); // Bad sequence number
+        }
+        end_session(rxSession);
+        return;
+    }
+    memcpy(&rxSession.buffer[(seq - 1) * 7], &data[1], 7);
+    rxSession.next_seq++;
+    rxSession.timeout = millis() + this->t1;
+    if (seq == rxSession.packets) { // Complete
+        if (!rxSession.bam) {
+            send_tp_cm(TP_CM_EOM_ACK, rxSession, source, rxSession.packets, 0xFF);
+        }
+        forward_msg(rxSession.priority, rxSession.pgn, rxSession.source, rxSession.destination, rxSession.buffer, rxSession.size);
+        end_session(rxSession);
+    } else if (!rxSession.bam && --rxSession.window == 0) {
+        rxSession.window = min(rxSession.packets - seq, rxSession.max_window);
+        send_tp_cm(TP_CM_CTS, rxSession, source, rxSession.window, rxSession.next_seq);
+        rxSession.timeout = millis() + this->t2;
+    }
+}
+
+void J1939Channel::forward_msg(uint8_t priority, uint32_t pgn, uint8_t source, uint8_t dest, uint8_t* data, uint16_t len) {
+    char buf[J1939_HEADER_SIZE + len];
+    uint32_t id_pgn = pgn;
+    if (is_pdu1(pgn)) {
+        id_pgn |= dest;
+    }
+    uint32_t id = (priority & 0x07) << 26 | id_pgn << 8 | source;
+    buf[0] = id >> 24;
+    buf[1] = id >> 16;
+    buf[2] = id >> 8;
+    buf[3] = id >> 0;
+    buf[4] = dest;
+    // Any block filter stops the message, otherwise at least 1 pass filter must match it
+    bool pass = false;
+    for (int i = 0; i < 7; i++) {
+        if (!used_filters[i]) continue;
+        bool matches = true;
+        for (int x = 0; x < filter_lens[i]; x++) {
+            if ((buf[x] & masks[i][x]) != (patterns[i][x] & masks[i][x])) {
+                matches = false;
+                break;
+            }
+        }
+        if (matches && blocking_filters[i]) return;
+        pass |= matches && !blocking_filters[i];
+    }
+    if (!pass) return;
+    memcpy(&buf[J1939_HEADER_SIZE], data, len);
+    PCCOMM::send_rx_data(this->channel_id, 0x0000, micros(), buf, J1939_HEADER_SIZE + len);
+}
+
+void J1939Channel::update_tx_session() {
+    if (!txSession.active) return;
+    if (txSession.bam) {
+        if (millis() < txSession.timeout) return;
+    } else if (txSession.window == 0) {
+        if (millis() > txSession.timeout) { // No CTS or EOM ACK
+            PCCOMM::log_message("J1939 transmit timed out waiting for CTS");
+            send_tp_cm(TP_CM_ABORT, txSession, txSession.destination, 0x03, 0xFF);
+            end_session(txSession);
+        }
+        return;
+    }
+    uint8_t seq = txSession.next_seq;
+    if (seq == 0 || seq > txSession.packets) {
+        send_tp_cm(TP_CM_ABORT, txSession, txSession.destination, 0x05, 0xFF);
+        end_session(txSession);
+        return;
+    }
+    uint8_t buf[8];
+    memset(buf, 0xFF, 8); // Unused bytes in the last packet are 0xFF
+    buf[0] = seq;
+    memcpy(&buf[1], &txSession.buffer[(seq - 1) * 7], min(7, txSession.size - (seq - 1) * 7));
+    send_frame(7, PGN_TP_DT, txSession.destination, buf, 8);
+    txSession.next_seq++;
+    if (txSession.bam) {
+        if (seq == txSession.packets) {
+            end_session(txSession);
+        } else {
+            txSession.timeout = millis() + this->brdcst_min_delay;
+        }
+    } else if (--txSession.window == 0) {
+        txSession.timeout = millis() + this->t3; // Wait for the next CTS or EOM ACK
+    }
+}
+
+void J1939Channel::update() {
+    if (this->claim_state == J1939_CLAIM_CLAIMING && millis() - this->claim_time >= J1939_CLAIM_TIME) {
+        this->claim_state = J1939_CLAIM_CLAIMED;
+        send_claim_indication(J1939_ADDRESS_CLAIMED);
+    }
+    if (rxSession.active && millis() > rxSession.timeout) {
+        if (!rxSession.bam) {
+            send_tp_cm(TP_CM_ABORT, rxSession, rxSession.source, 0x03, 0xFF);
+        }
+        end_session(rxSession);
+    }
+    update_tx_session();
+
+    if (!CustomCan::receiveFrame(this->can_bus, 0, &f) || !f.extended) {
+        return;
+    }
+    debug_read_frame(f);
+    uint8_t priority = (f.id >> 26) & 0x07;
+    uint32_t pgn = (f.id >> 8) & 0x3FFFF;
+    uint8_t source = f.id & 0xFF;
+    uint8_t dest = 0xFF;
+    if (is_pdu1(pgn)) {
+        dest = pgn & 0xFF;
+        pgn &= 0x3FF00;
+    }
+    bool for_us = dest == 0xFF || (dest == this->address && this->claim_state == J1939_CLAIM_CLAIMED);
+    switch (pgn) {
+        case PGN_TP_CM:
+            if (f.length == 8 && for_us) handle_tp_cm(source, dest, f.data.bytes);
+            return;
+        case PGN_TP_DT:
+            if (f.length == 8 && for_us) handle_tp_dt(source, f.data.bytes);
+            return;
+        case PGN_ADDRESS_CLAIM:
+            if (f.length == 8) handle_address_claim(source, f.data.bytes);
+            break;
+        case PGN_REQUEST:
+            // Respond to requests for our address claim
+            if (f.length == 3 && f.data.bytes[0] == 0x00 && f.data.bytes[1] == 0xEE && f.data.bytes[2] == 0x00 &&
+                for_us && this->claim_state == J1939_CLAIM_CLAIMED) {
+                send_address_claim(this->address);
+            }
+            break;
+        default:
+            break;
+    }
+    forward_msg(priority, pgn, source, dest, f.data.bytes, f.length);
+}
+
+/**
+ * Macchina will NOT respond to this request if respond is false, just send and leave it
+ */
+void J1939Channel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool respond) {
+    if (data_size < J1939_HEADER_SIZE || data_size - J1939_HEADER_SIZE > J1939_MAX_PAYLOAD) {
+        if (respond) {
+            PCCOMM::respond_err(MSG_TX_CHAN_DATA, ERR_INVALID_MSG, nullptr);
+        }
+        return;
+    }
+    uint8_t err = STATUS_NOERROR;
+    char* err_txt = nullptr;
+    uint8_t* bytes = (uint8_t*)data;
+    uint32_t id = bytes[0] << 24 | bytes[1] << 16 | bytes[2] << 8 | bytes[3];
+    uint8_t priority = (id >> 26) & 0x07;
+    uint32_t pgn = (id >> 8) & 0x3FFFF;
+    uint8_t dest = bytes[4];
+    int len = data_size - J1939_HEADER_SIZE;
+    if (is_pdu1(pgn)) {
+        pgn &= 0x3FF00;
+    } else {
+        dest = 0xFF;
+    }
+    if (this->claim_state != J1939_CLAIM_CLAIMED || (id & 0xFF) != this->address) {
+        err = ERR_FAILED;
+        err_txt = "Source address has not been claimed";
+    } else if (len <= 8) {
+        send_frame(priority, pgn, dest, &bytes[J1939_HEADER_SIZE], len);
+    } else if (txSession.active) {
+        err = ERR_BUFFER_FULL;
+        err_txt = "J1939 transport session already in progress";
+    } else {
+        txSession.active = true;
+        txSession.bam = dest == 0xFF;
+        txSession.priority = priority;
+        txSession.pgn = pgn;
+        txSession.source = this->address;
+        txSession.destination = dest;
+        txSession.size = len;
+        txSession.packets = (len + 6) / 7;
+        txSession.next_seq = 1;
+        txSession.buffer = new uint8_t[len];
+        memcpy(txSession.buffer, &data[J1939_HEADER_SIZE], len);
+        if (txSession.bam) {
+            send_tp_cm(TP_CM_BAM, txSession, 0xFF, txSession.packets, 0xFF);
+            txSession.timeout = millis() + this->brdcst_min_delay;
+        } else {
+            send_tp_cm(TP_CM_RTS, txSession, dest, txSession.packets, 0xFF);
+            txSession.window = 0;
+            txSession.timeout = millis() + this->t3;
+        }
+    }
+    if (respond) {
+        if (err == STATUS_NOERROR) {
+            PCCOMM::respond_ok(MSG_TX_CHAN_DATA, nullptr, 0);
+        } else {
+            PCCOMM::respond_err(MSG_TX_CHAN_DATA, err, err_txt);
+        }
+    }
+}
+
+void J1939Channel::ioctl_get(uint32_t id) {
+    if (bit_timing_ioctl_get(this->can_bus, id)) return;
+    uint32_t tmp = 0;
+    switch (id) {
+        case J1939_T1:
+            tmp = this->t1;
+            break;
+        case J1939_T2:
+            tmp = this->t2;
+            break;
+        case J1939_T3:
+            tmp = this->t3;
+            break;
+        case J1939_T4:
+            tmp = this->t4;
+            break;
+        case J1939_BRDCST_MIN_DELAY:
+            tmp = this->brdcst_min_delay;
+            break;
+        default:
+            PCCOMM::respond_err(MSG_IOCTL_GET, ERR_INVALID_IOCTL_ID, "J1939 invalid IOCTL ID");
+            return;
+    }
+    PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&tmp), 4);
+}
+
+void J1939Channel::ioctl_set(uint32_t id, uint32_t value) {
+    if (bit_timing_ioctl_set(this->can_bus, id, value)) return;
+    if (value > 0xFFFF) {
+        PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_VALUE, "J1939 timings must be 0-65535ms");
+        return;
+    }
+    switch (id) {
+        case J1939_T1:
+            this->t1 = value;
+            break;
+        case J1939_T2:
+            this->t2 = value;
+            break;
+        case J1939_T3:
+            this->t3 = value;
+            break;
+        case J1939_T4:
+            this->t4 = value;
+            break;
+        case J1939_BRDCST_MIN_DELAY:
+            this->brdcst_min_delay = value;
+            break;
+        default:
+            PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_ID, "J1939 invalid IOCTL ID");
+            return;
+    }
+    PCCOMM::respond_ok(MSG_IOCTL_SET, nullptr, 0);
+}
+
+void J1939Channel::ioctl_cmd(uint32_t id, uint8_t* data, int data_len) {
+    if (bus_stats_ioctl_cmd(this->can_bus, id)) return;
+    if (id != PROTECT_J1939_ADDR) {
+        PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_INVALID_IOCTL_ID, "J1939 invalid IOCTL ID");
+        return;
+    }
+    if (data_len != 9 || data[0] >= 0xFE) {
+        PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_INVALID_IOCTL_VALUE, "Invalid J1939 address or NAME");
+        return;
+    }
+    // Claim result is reported later with a J1939_ADDRESS_CLAIMED or J1939_ADDRESS_LOST indication
+    this->address = data[0];
+    memcpy(this->name, &data[1], 8);
+    this->claim_state = J1939_CLAIM_CLAIMING;
+    this->claim_time = millis();
+    send_address_claim(this->address);
+    PCCOMM::respond_ok(MSG_IOCTL_CMD, nullptr, 0);
+}
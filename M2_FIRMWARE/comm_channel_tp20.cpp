@@ -0,0 +1,465 @@
+#include "comm_channels.h"
+
+// Channel setup opcodes
+#define TP20_SETUP_REQUEST 0xC0
+#define TP20_SETUP_OK 0xD0
+#define TP20_SETUP_NEG_MIN 0xD6
+#define TP20_SETUP_NEG_MAX 0xD8
+
+// Channel opcodes
+#define TP20_PARAMS_REQUEST 0xA0
+#define TP20_PARAMS_RESPONSE 0xA1
+#define TP20_CONNECTION_TEST 0xA3
+#define TP20_BREAK 0xA4
+#define TP20_DISCONNECT 0xA8
+
+// Data frame opcodes (Upper nibble, lower nibble is the sequence number)
+#define TP20_DATA_ACK_MORE 0x00
+#define TP20_DATA_ACK_LAST 0x10
+#define TP20_DATA_MORE 0x20
+#define TP20_DATA_LAST 0x30
+#define TP20_ACK_NOT_READY 0x90
+#define TP20_ACK 0xB0
+
+#define TP20_HEADER_SIZE 4
+#define TP20_MAILBOX_SETUP 0
+#define TP20_MAILBOX_DATA 1
+
+// Decodes a TP 2.0 timing byte into ms. Upper 2 bits are the units (0.1ms, 1ms, 10ms, 100ms)
+uint32_t tp20_decode_time(uint8_t b) {
+    uint32_t v = b & 0x3F;
+    switch (b >> 6) {
+        case 0:
+            return (v + 9) / 10;
+        case 1:
+            return v;
+        case 2:
+            return v * 10;
+        default:
+            return v * 100;
+    }
+}
+
+uint8_t tp20_encode_time(uint32_t ms) {
+    if (ms <= 0x3F) {
+        return 0x40 | ms;
+    } else if (ms / 10 <= 0x3F) {
+        return 0x80 | (ms / 10);
+    }
+    return 0xC0 | min(ms / 100, 0x3F);
+}
+
+bool TP20Channel::setup(int id, int protocol, int baud, int flags, uint8_t bus) {
+    this->can_bus = bus;
+    if (!CustomCan::enableCanBus(this->can_bus, baud)) {
+         PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, "CAN Controller setup failed!");
+         return false;
+    }
+    digitalWrite(DS3, LOW); // Enable the light
+    this->channel_id = id;
+    this->f.length = 0;
+    return true;
+}
+
+void TP20Channel::addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len) {
+    PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_NOT_SUPPORTED, "TP 2.0 messages are routed by connection, not filters");
+}
+
+void TP20Channel::removeFilter(int id) {
+    PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_FILTER_ID, nullptr);
+}
+
+void TP20Channel::destroy() {
+    if (this->connected) {
+        uint8_t buf[1] = {TP20_DISCONNECT};
+        send_frame(this->tx_id, buf, 1);
+        close_connection(false);
+    }
+    CustomCan::disableCanBus(this->can_bus);
+    digitalWrite(DS3, HIGH); // Disable the light
+}
+
+uint32_t TP20Channel::get_param(uint32_t id) {
+    return this->params[id - TP2_0_T_BR_INT];
+}
+
+bool TP20Channel::send_frame(uint32_t id, uint8_t* data, uint8_t len) {
+    CAN_FRAME tx;
+    tx.id = id;
+    tx.extended = false;
+    tx.rtr = 0;
+    tx.length = len;
+    memcpy(&tx.data.bytes[0], data, len);
+    return debug_send_frame(this->can_bus, tx);
+}
+
+bool TP20Channel::wait_frame(int mailbox, unsigned long end) {
+    while (millis() < end) {
+        if (CustomCan::receiveFrame(this->can_bus, mailbox, &f)) {
+            debug_read_frame(f);
+            return true;
+        }
+    }
+    return false;
+}
+
+/**
+ * Sets up a TP 2.0 channel with a module, then negotiates the channel parameters.
+ * This blocks the M2 until the module responds, or TP2_0_T_E expires
+ */
+bool TP20Channel::request_connection(uint8_t addr, uint8_t app_type) {
+    uint32_t setup_id = get_param(TP2_0_IDENTIFER);
+    uint32_t rx_passive = get_param(TP2_0_RXIDPASSIVE);
+    CustomCan::enableCanFilter(this->can_bus, TP20_MAILBOX_SETUP, setup_id + addr, 0x7FF, false);
+    // RX ID is marked invalid (0x10), letting the module choose the ID we send to
+    uint8_t req[7] = {addr, TP20_SETUP_REQUEST, 0x00, 0x10, (uint8_t)(rx_passive & 0xFF), (uint8_t)(rx_passive >> 8), app_type};
+    bool setup_ok = false;
+    for (uint32_t attempt = 0; attempt < get_param(TP2_0_MNTC) && !setup_ok; attempt++) {
+        send_frame(setup_id, req, 7);
+        unsigned long end = millis() + get_param(TP2_0_T_E);
+        while (!setup_ok && millis() < end) {
+            if (!wait_frame(TP20_MAILBOX_SETUP, end) || f.length < 6) {
+                continue;
+            }
+            if (f.data.bytes[1] == TP20_SETUP_OK) {
+                setup_ok = true;
+            } else if (f.data.bytes[1] >= TP20_SETUP_NEG_MIN && f.data.bytes[1] <= TP20_SETUP_NEG_MAX) {
+                PCCOMM::log_message("TP 2.0 module rejected channel setup");
+                CustomCan::disableCanFilter(this->can_bus, TP20_MAILBOX_SETUP);
+                return false;
+            }
+        }
+    }
+    CustomCan::disableCanFilter(this->can_bus, TP20_MAILBOX_SETUP);
+    if (!setup_ok) {
+        return false;
+    }
+    this->rx_id = f.data.bytes[2] | (f.data.bytes[3] & 0x07) << 8;
+    this->tx_id = f.data.bytes[4] | (f.data.bytes[5] & 0x07) << 8;
+    CustomCan::enableCanFilter(this->can_bus, TP20_MAILBOX_DATA, this->rx_id, 0x7FF, false);
+
+    // Channel is open, now negotiate timings
+    uint8_t params_req[6] = {TP20_PARAMS_REQUEST, 0x0F, tp20_encode_time(get_param(TP2_0_T1)), 0xFF, tp20_encode_time(get_param(TP2_0_T3)), 0xFF};
+    send_frame(this->tx_id, params_req, 6);
+    unsigned long end = millis() + get_param(TP2_0_T_E);
+    while (millis() < end) {
+        if (wait_frame(TP20_MAILBOX_DATA, end) && f.length == 6 && f.data.bytes[0] == TP20_PARAMS_RESPONSE) {
+            this->module_block_size = f.data.bytes[1] == 0 ? 0x0F : f.data.bytes[1];
+            this->module_t1 = tp20_decode_time(f.data.bytes[2]);
+            this->module_t3 = tp20_decode_time(f.data.bytes[4]);
+            this->module_addr = addr;
+            this->tx_seq = 0;
+            this->rx_seq = 0;
+            this->keep_alive_fails = 0;
+            this->awaiting_keep_alive = false;
+            this->next_keep_alive = millis() + get_param(TP2_0_T_CTA);
+            this->connected = true;
+            return true;
+        }
+    }
+    CustomCan::disableCanFilter(this->can_bus, TP20_MAILBOX_DATA);
+    return false;
+}
+
+void TP20Channel::send_connection_indication(uint32_t rx_status) {
+    char buf[TP20_HEADER_SIZE] = {0x00, 0x00, 0x00, (char)this->module_addr};
+    PCCOMM::send_rx_data(this->channel_id, rx_status, micros(), buf, TP20_HEADER_SIZE);
+}
+
+void TP20Channel::close_connection(bool notify) {
+    if (notify) {
+        send_connection_indication(CONNECTION_LOST);
+    }
+    this->connected = false;
+    this->isSending = false;
+    this->awaitingAck = false;
+    if (txPayload.payload != nullptr) {
+        delete[] txPayload.payload;
+    }
+    if (rxPayload.payload != nullptr) {
+        delete[] rxPayload.payload;
+    }
+    txPayload = {0x00};
+    rxPayload = {0x00};
+    CustomCan::disableCanFilter(this->can_bus, TP20_MAILBOX_DATA);
+}
+
+void TP20Channel::rx_data_frame() {
+    uint8_t op = f.data.bytes[0] & 0xF0;
+    uint8_t seq = f.data.bytes[0] & 0x0F;
+    if (seq != this->rx_seq) {
+        PCCOMM::log_message("TP 2.0 Rx sequence error");
+    }
+    this->rx_seq = (seq + 1) & 0x0F;
+    uint8_t* data = &f.data.bytes[1];
+    uint8_t len = f.length - 1;
+    if (rxPayload.payload == nullptr) { // First packet starts with the message length
+        if (len < 2) return;
+        rxPayload.size = data[0] << 8 | data[1];
+        if (rxPayload.size == 0 || rxPayload.size > TP20_MAX_PAYLOAD) {
+            PCCOMM::log_message("TP 2.0 Rx message too big, discarding");
+            rxPayload.size = 0;
+            return;
+        }
+        rxPayload.payload = new uint8_t[TP20_HEADER_SIZE + rxPayload.size];
+        rxPayload.payload[0] = 0x00;
+        rxPayload.payload[1] = 0x00;
+        rxPayload.payload[2] = 0x00;
+        rxPayload.payload[3] = this->module_addr;
+        rxPayload.pos = 0;
+        data += 2;
+        len -= 2;
+    }
+    uint16_t cpy = min(len, rxPayload.size - rxPayload.pos);
+    memcpy(&rxPayload.payload[TP20_HEADER_SIZE + rxPayload.pos], data, cpy);
+    rxPayload.pos += cpy;
+
+    if (op == TP20_DATA_ACK_MORE || op == TP20_DATA_ACK_LAST) {
+        uint8_t ack[1] = {(uint8_t)(TP20_ACK | this->rx_seq)};
+        send_frame(this->tx_id, ack, 1);
+    }
+    if (op == TP20_DATA_ACK_LAST || op == TP20_DATA_LAST) {
+        if (rxPayload.pos >= rxPayload.size) {
+            PCCOMM::send_rx_data(this->channel_id, 0x0000, micros(), (char*)rxPayload.payload, TP20_HEADER_SIZE + rxPayload.size);
+        } else {
+            PCCOMM::log_message("TP 2.0 message ended early, discarding");
+        }
+        delete[] rxPayload.payload;
+        rxPayload = {0x00};
+    }
+}
+
+void TP20Channel::handle_frame() {
+    uint8_t op = f.data.bytes[0];
+    if (f.length == 0) return;
+    switch (op) {
+        case TP20_CONNECTION_TEST: {
+            uint8_t resp[6] = {TP20_PARAMS_RESPONSE, 0x0F, tp20_encode_time(get_param(TP2_0_T1)), 0xFF, tp20_encode_time(get_param(TP2_0_T3)), 0xFF};
+            send_frame(this->tx_id, resp, 6);
+            return;
+        }
+        case TP20_PARAMS_RESPONSE:
+            this->awaiting_keep_alive = false;
+            this->keep_alive_fails = 0;
+            return;
+        case TP20_BREAK:
+            if (rxPayload.payload != nullptr) {
+                delete[] rxPayload.payload;
+            }
+            rxPayload = {0x00};
+            return;
+        case TP20_DISCONNECT: {
+            uint8_t resp[1] = {TP20_DISCONNECT};
+            send_frame(this->tx_id, resp, 1);
+            close_connection(true);
+            return;
+        }
+        default:
+            break;
+    }
+    switch (op & 0xF0) {
+        case TP20_DATA_ACK_MORE:
+        case TP20_DATA_ACK_LAST:
+        case TP20_DATA_MORE:
+        case TP20_DATA_LAST:
+            rx_data_frame();
+            break;
+        case TP20_ACK:
+            if (isSending && awaitingAck) {
+                this->awaitingAck = false;
+                if (txPayload.pos >= txPayload.size) { // Whole message was ACKed
+                    delete[] txPayload.payload;
+                    txPayload = {0x00};
+                    this->isSending = false;
+                } else { // Start the next block
+                    this->tx_block_start = txPayload.pos;
+                    this->tx_block_seq = this->tx_seq;
+                    this->tx_block_packets = 0;
+                    this->tx_block_attempts = 1;
+                    this->tx_wait_count = 0;
+                    this->next_send_time = millis() + max(this->module_t3, get_param(TP2_0_T3));
+                }
+            }
+            break;
+        case TP20_ACK_NOT_READY:
+            if (isSending && awaitingAck) {
+                if (++this->tx_wait_count > get_param(TP2_0_MNT)) {
+                    PCCOMM::log_message("TP 2.0 module not ready too many times");
+                    close_connection(true);
+                    return;
+                }
+                // Resend the block once the module is ready
+                this->awaitingAck = false;
+                txPayload.pos = this->tx_block_start;
+                this->tx_seq = this->tx_block_seq;
+                this->tx_block_packets = 0;
+                this->next_send_time = millis() + get_param(TP2_0_T_WAIT);
+            }
+            break;
+        default:
+            break;
+    }
+}
+
+void TP20Channel::tx_data_frame() {
+    uint8_t buf[8];
+    uint8_t len = min(7, txPayload.size - txPayload.pos);
+    bool last = txPayload.pos + len >= txPayload.size;
+    this->tx_block_packets++;
+    bool block_end = last || this->tx_block_packets >= this->module_block_size;
+    uint8_t op;
+    if (block_end) {
+        op = last ? TP20_DATA_ACK_LAST : TP20_DATA_ACK_MORE;
+    } else {
+        op = TP20_DATA_MORE;
+    }
+    buf[0] = op | this->tx_seq;
+    memcpy(&buf[1], &txPayload.payload[txPayload.pos], len);
+    send_frame(this->tx_id, buf, len + 1);
+    this->tx_seq = (this->tx_seq + 1) & 0x0F;
+    txPayload.pos += len;
+    if (block_end) {
+        this->awaitingAck = true;
+        this->ack_timeout = millis() + get_param(TP2_0_T1);
+    }
+    this->next_send_time = millis() + max(this->module_t3, get_param(TP2_0_T3));
+}
+
+void TP20Channel::update() {
+    if (!this->connected) return;
+    if (CustomCan::receiveFrame(this->can_bus, TP20_MAILBOX_DATA, &f)) {
+        debug_read_frame(f);
+        handle_frame();
+        if (!this->connected) return;
+    }
+    // Keep the connection alive
+    if (millis() >= this->next_keep_alive) {
+        if (this->awaiting_keep_alive && ++this->keep_alive_fails >= get_param(TP2_0_MNCT)) {
+            PCCOMM::log_message("TP 2.0 module stopped responding to connection tests");
+            close_connection(true);
+            return;
+        }
+        uint8_t buf[1] = {TP20_CONNECTION_TEST};
+        send_frame(this->tx_id, buf, 1);
+        this->awaiting_keep_alive = true;
+        this->next_keep_alive = millis() + get_param(TP2_0_T_CTA);
+    }
+    if (!isSending) return;
+    if (awaitingAck) {
+        if (millis() > this->ack_timeout) {
+            if (++this->tx_block_attempts > get_param(TP2_0_MNTB)) {
+                PCCOMM::log_message("TP 2.0 module did not ACK data block");
+                close_connection(true);
+                return;
+            }
+            // Resend the whole block
+            this->awaitingAck = false;
+            txPayload.pos = this->tx_block_start;
+            this->tx_seq = this->tx_block_seq;
+            this->tx_block_packets = 0;
+        }
+    } else if (millis() >= this->next_send_time) {
+        tx_data_frame();
+    }
+}
+
+/**
+ * Macchina will NOT respond to this request if respond is false, just send and leave it
+ */
+void TP20Channel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool respond) {
+    uint8_t err = STATUS_NOERROR;
+    char* err_txt = nullptr;
+    int len = data_size - TP20_HEADER_SIZE;
+    if (len <= 0 || len > TP20_MAX_PAYLOAD) {
+        err = ERR_INVALID_MSG;
+    } else if (!this->connected || (uint8_t)data[3] != this->module_addr) {
+        err = ERR_FAILED;
+        err_txt = "No TP 2.0 connection to module";
+    } else if (this->isSending) {
+        err = ERR_BUFFER_FULL;
+        err_txt = "TP 2.0 message already being sent";
+    } else {
+        // Message is sent with its length at the start
+        txPayload.payload = new uint8_t[len + 2];
+        txPayload.payload[0] = len >> 8;
+        txPayload.payload[1] = len;
+        memcpy(&txPayload.payload[2], &data[TP20_HEADER_SIZE], len);
+        txPayload.size = len + 2;
+        txPayload.pos = 0;
+        this->isSending = true;
+        this->awaitingAck = false;
+        this->tx_block_start = 0;
+        this->tx_block_seq = this->tx_seq;
+        this->tx_block_packets = 0;
+        this->tx_block_attempts = 1;
+        this->tx_wait_count = 0;
+        this->next_send_time = millis();
+    }
+    if (respond) {
+        if (err == STATUS_NOERROR) {
+            PCCOMM::respond_ok(MSG_TX_CHAN_DATA, nullptr, 0);
+        } else {
+            PCCOMM::respond_err(MSG_TX_CHAN_DATA, err, err_txt);
+        }
+    }
+}
+
+void TP20Channel::ioctl_get(uint32_t id) {
+    if (bit_timing_ioctl_get(this->can_bus, id)) return;
+    if (id < TP2_0_T_BR_INT || id > TP2_0_RXIDPASSIVE) {
+        PCCOMM::respond_err(MSG_IOCTL_GET, ERR_INVALID_IOCTL_ID, "TP 2.0 invalid IOCTL ID");
+        return;
+    }
+    uint32_t tmp = get_param(id);
+    PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&tmp), 4);
+}
+
+void TP20Channel::ioctl_set(uint32_t id, uint32_t value) {
+    if (bit_timing_ioctl_set(this->can_bus, id, value)) return;
+    if (id < TP2_0_T_BR_INT || id > TP2_0_RXIDPASSIVE) {
+        PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_ID, "TP 2.0 invalid IOCTL ID");
+        return;
+    }
+    uint32_t max_value = (id == TP2_0_IDENTIFER || id == TP2_0_RXIDPASSIVE) ? 0x7FF : 0xFFFF;
+    if (value > max_value) {
+        PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_VALUE, "TP 2.0 parameter out of range");
+        return;
+    }
+    this->params[id - TP2_0_T_BR_INT] = value;
+    PCCOMM::respond_ok(MSG_IOCTL_SET, nullptr, 0);
+}
+
+void TP20Channel::ioctl_cmd(uint32_t id, uint8_t* data, int data_len) {
+    if (bus_stats_ioctl_cmd(this->can_bus, id)) return;
+    char buf[60];
+    switch (id) {
+        case REQUEST_CONNECTION:
+            if (data_len != 1 && data_len != 2) {
+                PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_INVALID_IOCTL_VALUE, nullptr);
+            } else if (this->connected) {
+                sprintf(buf, "Already connected to module 0x%02X", this->module_addr);
+                PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_FAILED, buf);
+            } else if (request_connection(data[0], data_len == 2 ? data[1] : 0x01)) {
+                PCCOMM::respond_ok(MSG_IOCTL_CMD, nullptr, 0);
+                send_connection_indication(CONNECTION_ESTABLISHED);
+            } else {
+                sprintf(buf, "Module 0x%02X did not accept the connection", data[0]);
+                PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_TIMEOUT, buf);
+            }
+            break;
+        case TEARDOWN_CONNECTION:
+            if (data_len != 1 || !this->connected || data[0] != this->module_addr) {
+                PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_FAILED, "No connection to module");
+            } else {
+                uint8_t req[1] = {TP20_DISCONNECT};
+                send_frame(this->tx_id, req, 1);
+                wait_frame(TP20_MAILBOX_DATA, millis() + get_param(TP2_0_T_E)); // Module should respond, but we close regardless
+                close_connection(false);
+                PCCOMM::respond_ok(MSG_IOCTL_CMD, nullptr, 0);
+            }
+            break;
+        default:
+            PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_INVALID_IOCTL_ID, "TP 2.0 invalid IOCTL ID");
+            break;
+    }
+}
@@ -2,7 +2,7 @@
 
 
 // Debug function
-bool debug_send_frame(CAN_FRAME &f) {
+bool debug_send_frame(uint8_t bus, CAN_FRAME &f) {
     #ifdef FW_TEST
     char buf[80] = {0x00};
     char *pos = buf;
@@ -13,7 +13,7 @@ bool debug_send_frame(CAN_FRAME &f) {
     sprintf(pos-1,"]");
     PCCOMM::log_message(buf);
     #endif
-    return CustomCan::sendFrame(&f);
+    return CustomCan::sendFrame(bus, &f);
 }
 
 void debug_read_frame(CAN_FRAME &f) {
@@ -27,4 +27,127 @@ void debug_read_frame(CAN_FRAME &f) {
     sprintf(pos-1,"]");
     PCCOMM::log_message(buf);
     #endif
-}
\ No newline at end of file
+}
+
+bool sw_can_ioctl_get(uint8_t bus, uint32_t id) {
+    if (bus != CAN_BUS_SW) return false;
+    uint32_t tmp = 0;
+    switch (id) {
+        case SW_CAN_HS_DATA_RATE:
+            tmp = CustomCan::getSwCanHsBaud();
+            PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&tmp), 4);
+            return true;
+        case SW_CAN_SPEEDCHANGE_ENABLE:
+            tmp = CustomCan::getSwCanSpeedChange() ? 1 : 0;
+            PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&tmp), 4);
+            return true;
+        case SW_CAN_RES_SWITCH:
+            PCCOMM::respond_err(MSG_IOCTL_GET, ERR_NOT_SUPPORTED, "M2 has a fixed single wire CAN load resistor");
+            return true;
+        default:
+            return false;
+    }
+}
+
+bool sw_can_ioctl_set(uint8_t bus, uint32_t id, uint32_t value) {
+    if (bus != CAN_BUS_SW) return false;
+    switch (id) {
+        case SW_CAN_HS_DATA_RATE:
+            if (value == 0) {
+                PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_VALUE, "Invalid high speed data rate");
+            } else {
+                CustomCan::setSwCanSpeedChange(CustomCan::getSwCanSpeedChange(), value);
+                PCCOMM::respond_ok(MSG_IOCTL_SET, nullptr, 0);
+            }
+            return true;
+        case SW_CAN_SPEEDCHANGE_ENABLE:
+            if (value > 1) {
+                PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_VALUE, "Speed change must be 0 or 1");
+            } else {
+                CustomCan::setSwCanSpeedChange(value == 1, CustomCan::getSwCanHsBaud());
+                PCCOMM::respond_ok(MSG_IOCTL_SET, nullptr, 0);
+            }
+            return true;
+        case SW_CAN_RES_SWITCH:
+            PCCOMM::respond_err(MSG_IOCTL_SET, ERR_NOT_SUPPORTED, "M2 has a fixed single wire CAN load resistor");
+            return true;
+        default:
+            return false;
+    }
+}
+
+bool sw_can_ioctl_cmd(uint8_t bus, uint32_t id, uint8_t* data, int data_len) {
+    if (bus != CAN_BUS_SW) return false;
+    uint8_t mode;
+    switch (id) {
+        case SW_CAN_HS:
+            mode = SW_MODE_HIGH_SPEED;
+            break;
+        case SW_CAN_NS:
+            mode = SW_MODE_NORMAL;
+            break;
+        default:
+            return false;
+    }
+    if (CustomCan::setSwCanMode(mode)) {
+        PCCOMM::respond_ok(MSG_IOCTL_CMD, nullptr, 0);
+    } else {
+        PCCOMM::respond_err(MSG_IOCTL_CMD, ERR_FAILED, "Single wire CAN is not active");
+    }
+    return true;
+}
+
+bool bus_stats_ioctl_cmd(uint8_t bus, uint32_t id) {
+    busStatsMsg stats;
+    switch (id) {
+        case MACCHINA_GET_BUS_STATS:
+            CustomCan::getBusStats(bus, &stats);
+            PCCOMM::respond_ok(MSG_IOCTL_CMD, (uint8_t*)(&stats), sizeof(busStatsMsg));
+            return true;
+        case MACCHINA_CLEAR_BUS_STATS:
+            CustomCan::clearBusStats(bus);
+            PCCOMM::respond_ok(MSG_IOCTL_CMD, nullptr, 0);
+            return true;
+        default:
+            return false;
+    }
+}
+
+bool bit_timing_ioctl_get(uint8_t bus, uint32_t id) {
+    if (id != BIT_SAMPLE_POINT && id != SYNCH_JUMP_WIDTH) return false;
+    if (bus == CAN_BUS_SW) {
+        PCCOMM::respond_err(MSG_IOCTL_GET, ERR_NOT_SUPPORTED, "Single wire CAN bit timing is fixed");
+        return true;
+    }
+    uint8_t sample_point, sjw;
+    CustomCan::getBitTiming(bus, &sample_point, &sjw);
+    uint32_t tmp = (id == BIT_SAMPLE_POINT) ? sample_point : sjw;
+    PCCOMM::respond_ok(MSG_IOCTL_GET, (uint8_t*)(&tmp), 4);
+    return true;
+}
+
+bool bit_timing_ioctl_set(uint8_t bus, uint32_t id, uint32_t value) {
+    if (id != BIT_SAMPLE_POINT && id != SYNCH_JUMP_WIDTH) return false;
+    if (bus == CAN_BUS_SW) {
+        PCCOMM::respond_err(MSG_IOCTL_SET, ERR_NOT_SUPPORTED, "Single wire CAN bit timing is fixed");
+        return true;
+    }
+    if (value > 100) {
+        PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_VALUE, "Bit timing must be 0-100%");
+        return true;
+    }
+    // The other value stays as it is
+    uint8_t sample_point, sjw;
+    CustomCan::getBitTiming(bus, &sample_point, &sjw);
+    if (id == BIT_SAMPLE_POINT) {
+        sample_point = value;
+    } else {
+        sjw = value;
+    }
+    if (CustomCan::setBitTiming(bus, sample_point, sjw)) {
+        PCCOMM::respond_ok(MSG_IOCTL_SET, nullptr, 0);
+    } else {
+        PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_IOCTL_VALUE, "CAN controller cannot run this bit timing at this baud rate");
+    }
+    return true;
+}
@@ -6,12 +6,22 @@
 #include "j2534_mini.h"
 
 
-bool debug_send_frame(CAN_FRAME &f);
+bool debug_send_frame(uint8_t bus, CAN_FRAME &f);
 void debug_read_frame(CAN_FRAME &f);
 
+// Single wire CAN IOCTLs shared by all CAN based channels. These return false if the IOCTL was not handled
+bool sw_can_ioctl_get(uint8_t bus, uint32_t id);
+bool sw_can_ioctl_set(uint8_t bus, uint32_t id, uint32_t value);
+bool sw_can_ioctl_cmd(uint8_t bus, uint32_t id, uint8_t* data, int data_len);
+// Bus statistics IOCTLs shared by all CAN based channels. Returns false if the IOCTL was not handled
+bool bus_stats_ioctl_cmd(uint8_t bus, uint32_t id);
+// BIT_SAMPLE_POINT and SYNCH_JUMP_WIDTH, shared by all CAN based channels. These return false if the IOCTL was not handled
+bool bit_timing_ioctl_get(uint8_t bus, uint32_t id);
+bool bit_timing_ioctl_set(uint8_t bus, uint32_t id, uint32_t value);
+
 class Channel {
     public:
-        virtual bool setup(int id, int protocol, int baud, int flags);
+        virtual bool setup(int id, int protocol, int baud, int flags, uint8_t bus);
         virtual void addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len);
         virtual void removeFilter(int id);
         virtual void sendMsg(uint32_t tx_flags, char* data, int data_size, bool respond);
@@ -19,8 +29,11 @@ class Channel {
         virtual void update();
         virtual void ioctl_get(uint32_t id);
         virtual void ioctl_set(uint32_t id, uint32_t value);
+        virtual void ioctl_cmd(uint32_t id, uint8_t* data, int data_len);
+        uint8_t get_can_bus() { return this->can_bus; }
     protected:
         int channel_id;
+        uint8_t can_bus = CAN_BUS_0;
 };
 
 #define MAX_CAN_BUFFER_SIZE 16
@@ -33,7 +46,7 @@ struct CanRingBuffer {
 
 class CanChannel : public Channel {
     public:
-        bool setup(int id, int protocol, int baud, int flags);
+        bool setup(int id, int protocol, int baud, int flags, uint8_t bus);
         void addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len);
         void removeFilter(int id);
         void destroy();
@@ -41,12 +54,21 @@ class CanChannel : public Channel {
         void update();
         void ioctl_get(uint32_t id);
         void ioctl_set(uint32_t id, uint32_t value);
+        void ioctl_cmd(uint32_t id, uint8_t* data, int data_len);
     private:
+        void send_bus_event(uint32_t rx_status, uint8_t value);
+        bool start_bus(uint32_t baud);
         bool isExtended = false;
+        uint32_t baud = 0;
+        bool loopback = false;
+        bool listen_only = false;
+        bool error_frames = false;
+        uint8_t bus_state = BUS_STATE_ERROR_ACTIVE; // Last state sent to the PC
+        uint32_t last_error_report = 0; // millis() bus errors were last sent to the PC
         CAN_FRAME f;
         bool used_mailboxes[7] = {false};
         bool blocking_filters[7] = {false};
-        bool masks[7] = {false};
+        uint32_t masks[7] = {0x00};
         uint32_t patterns[7] = {0x00};
 };
 
@@ -58,7 +80,7 @@ struct isoPayload {
 
 class ISO15765Channel : public Channel {
     public:
-        bool setup(int id, int protocol, int baud, int flags);
+        bool setup(int id, int protocol, int baud, int flags, uint8_t bus);
         void addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len);
         void removeFilter(int id);
         void destroy();
@@ -66,6 +88,7 @@ class ISO15765Channel : public Channel {
         void update();
         void ioctl_get(uint32_t id);
         void ioctl_set(uint32_t id, uint32_t value);
+        void ioctl_cmd(uint32_t id, uint8_t* data, int data_len);
     private:
         void rx_single_frame(CAN_FRAME *read);
         void rx_multi_frame(CAN_FRAME *read, int filter_id);
@@ -95,4 +118,126 @@ class ISO15765Channel : public Channel {
         bool clear_to_send = false;
 };
 
+// J1939 transport protocol session (BAM or RTS/CTS)
+struct j1939TpSession {
+    bool active;
+    bool bam;
+    uint8_t priority;
+    uint32_t pgn;
+    uint8_t source;
+    uint8_t destination;
+    uint16_t size;
+    uint8_t packets;
+    uint8_t next_seq;
+    uint8_t window; // Packets left to send / receive before the next CTS
+    uint8_t max_window; // Max packets per CTS, from the RTS
+    uint8_t* buffer;
+    unsigned long timeout;
+};
+
+#define J1939_CLAIM_NONE 0
+#define J1939_CLAIM_CLAIMING 1
+#define J1939_CLAIM_CLAIMED 2
+#define J1939_CLAIM_LOST 3
+
+class J1939Channel : public Channel {
+    public:
+        bool setup(int id, int protocol, int baud, int flags, uint8_t bus);
+        void addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len);
+        void removeFilter(int id);
+        void destroy();
+        void sendMsg(uint32_t tx_flags, char* data, int data_size, bool respond);
+        void update();
+        void ioctl_get(uint32_t id);
+        void ioctl_set(uint32_t id, uint32_t value);
+        void ioctl_cmd(uint32_t id, uint8_t* data, int data_len);
+    private:
+        bool send_frame(uint8_t priority, uint32_t pgn, uint8_t dest, uint8_t* data, uint8_t len);
+        void send_address_claim(uint8_t addr);
+        void send_claim_indication(uint32_t rx_status);
+        void send_tp_cm(uint8_t control, j1939TpSession &s, uint8_t dest, uint8_t b3, uint8_t b4);
+        void handle_address_claim(uint8_t source, uint8_t* name);
+        void handle_tp_cm(uint8_t source, uint8_t dest, uint8_t* data);
+        void handle_tp_dt(uint8_t source, uint8_t* data);
+        void update_tx_session();
+        void end_session(j1939TpSession &s);
+        void forward_msg(uint8_t priority, uint32_t pgn, uint8_t source, uint8_t dest, uint8_t* data, uint16_t len);
+        CAN_FRAME f;
+        bool used_filters[7] = {false};
+        bool blocking_filters[7] = {false};
+        uint8_t filter_lens[7] = {0x00};
+        uint8_t masks[7][5] = {0x00};
+        uint8_t patterns[7][5] = {0x00};
+        uint8_t address = 0xFE;
+        uint8_t name[8] = {0x00};
+        uint8_t claim_state = J1939_CLAIM_NONE;
+        unsigned long claim_time = 0;
+        uint32_t t1 = 750;
+        uint32_t t2 = 1250;
+        uint32_t t3 = 1250;
+        uint32_t t4 = 1050;
+        uint32_t brdcst_min_delay = 50;
+        j1939TpSession rxSession = {0x00};
+        j1939TpSession txSession = {0x00};
+};
+
+#define TP20_PARAM_COUNT 12
+#define TP20_MAX_PAYLOAD 4080
+
+// A TP 2.0 message being sent or received
+struct tp20Payload {
+    uint8_t* payload;
+    uint16_t size;
+    uint16_t pos;
+};
+
+class TP20Channel : public Channel {
+    public:
+        bool setup(int id, int protocol, int baud, int flags, uint8_t bus);
+        void addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len);
+        void removeFilter(int id);
+        void destroy();
+        void sendMsg(uint32_t tx_flags, char* data, int data_size, bool respond);
+        void update();
+        void ioctl_get(uint32_t id);
+        void ioctl_set(uint32_t id, uint32_t value);
+        void ioctl_cmd(uint32_t id, uint8_t* data, int data_len);
+    private:
+        uint32_t get_param(uint32_t id);
+        bool send_frame(uint32_t id, uint8_t* data, uint8_t len);
+        bool wait_frame(int mailbox, unsigned long end); // Waits for a frame until millis() reaches end
+        bool request_connection(uint8_t addr, uint8_t app_type);
+        void close_connection(bool notify);
+        void send_connection_indication(uint32_t rx_status);
+        void handle_frame();
+        void rx_data_frame();
+        void tx_data_frame();
+        CAN_FRAME f;
+        uint32_t params[TP20_PARAM_COUNT] = {20, 100, 10, 1000, 5, 5, 5, 100, 100, 5, 0x200, 0x300};
+        bool connected = false;
+        uint8_t module_addr = 0;
+        uint32_t tx_id = 0;
+        uint32_t rx_id = 0;
+        // Parameters the module responded with
+        uint8_t module_block_size = 0x0F;
+        uint32_t module_t1 = 100;
+        uint32_t module_t3 = 5;
+        uint8_t tx_seq = 0;
+        uint8_t rx_seq = 0;
+        unsigned long next_keep_alive = 0;
+        uint16_t keep_alive_fails = 0;
+        bool awaiting_keep_alive = false;
+        tp20Payload txPayload = {0x00};
+        tp20Payload rxPayload = {0x00};
+        bool isSending = false;
+        bool awaitingAck = false;
+        uint16_t tx_block_start = 0; // Payload position of the current block, for resending it
+        uint8_t tx_block_seq = 0;
+        uint8_t tx_block_packets = 0;
+        uint16_t tx_block_attempts = 0;
+        uint16_t tx_wait_count = 0;
+        unsigned long next_send_time = 0;
+        unsigned long ack_timeout = 0;
+};
+
 #endif
\ No newline at end of file
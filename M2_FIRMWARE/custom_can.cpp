@@ -1,19 +1,128 @@
 #include "custom_can.h"
 #include "comm.h"
+#include "j2534_mini.h"
+#include <SamNonDuePin.h>
+#include <mcp2515_sw_can.h>
 
-// 7 RxQueues
-CustomCan::rxQueue rxQueues[7];
+// 7 RxQueues per CAN interface
+CustomCan::rxQueue rxQueues[NUM_CAN_BUSSES][7];
 
-void CustomCan::__delete_check_rx_ring(int i) {
-    rxQueues[i].head = 0;
-    rxQueues[i].tail = 0;
-    Can0.removeCallback(i);
+// Single wire CAN controller (MCP2515 on SPI)
+SWcan SWCAN(SPI0_CS3, SWC_INT);
+
+// The MCP2515 has no mailboxes we can use, so single wire CAN mailboxes are emulated in software
+struct swMailbox {
+    bool enabled;
+    bool extended;
+    uint32_t pattern;
+    uint32_t mask;
+};
+swMailbox swMailboxes[7] = {0x00};
+uint8_t swCanMode = SW_MODE_SLEEP;
+uint32_t swCanBaud = 33333;
+uint32_t swCanHsBaud = 83333;
+bool swCanSpeedChange = false;
+
+// Time it takes a full 8 byte frame to leave the controller at 33.3kbps
+#define SW_CAN_HV_TX_TIME_MS 5
+
+// Frame and error counts of a CAN interface, for MACCHINA_GET_BUS_STATS
+struct busCounters {
+    bool enabled;
+    uint32_t baud;
+    uint32_t rx_frames;
+    uint32_t tx_frames;
+    uint32_t rx_dropped;
+    uint32_t tx_failed;
+    uint32_t error_passive_events;
+    uint32_t bus_off_events;
+    uint32_t frame_bits; // Bits of the frames sent and received since load_start
+    uint32_t load_start; // millis() the bus load started being worked out from
+    uint8_t state;
+    uint8_t errors; // BUS_ERROR_* flags seen since they were last taken
+};
+busCounters counters[NUM_CAN_BUSSES] = {0x00};
+
+// Bit timing set with setBitTiming, reapplied whenever the interface is enabled. Sample point 0 is the default timing
+struct bitTiming {
+    uint8_t sample_point;
+    uint8_t sjw;
+};
+bitTiming bitTimings[NUM_CAN_BUSSES] = {0x00};
+
+// How far the bit timing setBitTiming uses can be from the one requested (Percent of the bit time)
+#define BIT_TIMING_TOLERANCE 2
+
+// Bits a frame takes up on the bus, not counting stuff bits
+uint32_t __frame_bits(CAN_FRAME &f) {
+    return (f.extended ? 67 : 47) + 8 * f.length;
+}
+
+void __reset_counters(uint8_t bus, uint32_t baud) {
+    memset(&counters[bus], 0x00, sizeof(busCounters));
+    counters[bus].enabled = true;
+    counters[bus].baud = baud;
+    counters[bus].load_start = millis();
+}
+
+void __sw_can_isr() {
+    SWCAN.intHandler();
 }
 
-void CustomCan::__create_check_rx_ring(int i) {
-    rxQueues[i].head = 0;
-    rxQueues[i].tail = 0;
+CANRaw& CustomCan::getBus(uint8_t bus) {
+    if (bus == CAN_BUS_1) {
+        return Can1;
+    }
+    return Can0;
+}
+
+void CustomCan::__delete_check_rx_ring(uint8_t bus, int i) {
+    rxQueues[bus][i].head = 0;
+    rxQueues[bus][i].tail = 0;
+    if (bus == CAN_BUS_SW) {
+        swMailboxes[i].enabled = false;
+        return;
+    }
+    getBus(bus).removeCallback(i);
+}
+
+void CustomCan::__create_check_rx_ring(uint8_t bus, int i) {
+    rxQueues[bus][i].head = 0;
+    rxQueues[bus][i].tail = 0;
+    if (bus == CAN_BUS_SW) {
+        swMailboxes[i].enabled = true; // Polled by __poll_sw_can
+        return;
+    }
     // Register callback for hardware interrupt
+    if (bus == CAN_BUS_1) {
+        switch (i)
+        {
+        case 0:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb0);
+            break;
+        case 1:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb1);
+            break;
+        case 2:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb2);
+            break;
+        case 3:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb3);
+            break;
+        case 4:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb4);
+            break;
+        case 5:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb5);
+            break;
+        case 6:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb6);
+            break;
+        default:
+            break;
+        }
+        return;
+    }
     switch (i)
     {
     case 0:
@@ -42,39 +151,163 @@ void CustomCan::__create_check_rx_ring(int i) {
     }
 }
 
-bool CustomCan::enableCanBus(int baud) {
+bool CustomCan::enableCanBus(uint8_t bus, int baud, bool listenOnly) {
+    if (bus >= NUM_CAN_BUSSES) return false; // Invalid interface
+    if (bus == CAN_BUS_SW) {
+        if (listenOnly) return false;
+        for (int i = 0; i < 7; i++) {
+            __delete_check_rx_ring(bus, i);
+        }
+        swCanBaud = baud;
+        swCanSpeedChange = false;
+        swCanHsBaud = 83333;
+        SWCAN.setupSW(baud / 1000); // Takes the speed in kbps
+        attachInterrupt(SWC_INT, __sw_can_isr, FALLING);
+        SWCAN.mode(SW_MODE_NORMAL);
+        swCanMode = SW_MODE_NORMAL;
+        __reset_counters(bus, baud);
+        return true;
+    }
+    // Set before the controller starts, so it never ACKs a frame in listen only mode
+    getBus(bus).setListenOnlyMode(listenOnly);
     // Begin bus
-    if (Can0.init(baud) == 0) {
+    if (getBus(bus).init(baud) == 0) {
         return false;
     }
-    
+    __reset_counters(bus, baud);
+    // Timing set for another baud rate may not be possible at this one
+    if (bitTimings[bus].sample_point != 0 && !setBitTiming(bus, bitTimings[bus].sample_point, bitTimings[bus].sjw)) {
+        bitTimings[bus].sample_point = 0;
+    }
+
     // Block all traffic
     for (int i = 0; i < 7; i++) {
-        Can0.setRXFilter(i, 0xFFFF, 0x0000, false);
+        getBus(bus).setRXFilter(i, 0xFFFF, 0x0000, false);
         // In case rxQueue is still there, delete it
-        __delete_check_rx_ring(i);
+        __delete_check_rx_ring(bus, i);
     }
     // No software queues created in this method
     return true;
 }
 
-void CustomCan::disableCanBus() {
-    Can0.disable();
+void CustomCan::disableCanBus(uint8_t bus) {
+    if (bus >= NUM_CAN_BUSSES) return; // Invalid interface
+    counters[bus].enabled = false;
+    bitTimings[bus].sample_point = 0;
+    if (bus == CAN_BUS_SW) {
+        detachInterrupt(SWC_INT);
+        SWCAN.mode(SW_MODE_SLEEP);
+        swCanMode = SW_MODE_SLEEP;
+        for (int i = 0; i < 7; i++) {
+            __delete_check_rx_ring(bus, i);
+        }
+        return;
+    }
+    getBus(bus).disable();
     // Block all traffic
     for (int i = 0; i < 7; i++) {
-        Can0.setRXFilter(i, 0xFFFF, 0x0000, false);
+        getBus(bus).setRXFilter(i, 0xFFFF, 0x0000, false);
         // In case rxQueue is still there, delete it
-        __delete_check_rx_ring(i);
+        __delete_check_rx_ring(bus, i);
+    }
+}
+
+// Baud rates detectBaud tries, most common first
+const uint32_t DETECT_BAUD_RATES[] = {500000, 250000, 125000, 1000000, 83333, 33333};
+// How long detectBaud listens at each baud rate for a frame
+#define DETECT_BAUD_WAIT_MS 200
+
+uint32_t CustomCan::detectBaud(uint8_t bus) {
+    if (bus == CAN_BUS_SW || bus >= NUM_CAN_BUSSES) return 0; // Invalid interface
+    CANRaw &can = getBus(bus);
+    uint32_t found = 0;
+    for (uint32_t baud : DETECT_BAUD_RATES) {
+        // Listen only, so frames at the wrong baud rate are never error framed
+        can.setListenOnlyMode(true);
+        if (can.init(baud) == 0) continue;
+        // Accept everything. A frame is only received if it is valid at this baud rate
+        for (int i = 0; i < 7; i++) {
+            can.setRXFilter(i, 0x0000, 0x0000, i < 3); // Mailboxes 0-2 extended, 3-6 standard
+        }
+        uint32_t start = millis();
+        while (millis() - start < DETECT_BAUD_WAIT_MS) {
+            if (can.rx_avail()) {
+                found = baud;
+                break;
+            }
+        }
+        can.disable();
+        if (found != 0) break;
+    }
+    can.setListenOnlyMode(false);
+    return found;
+}
+
+bool CustomCan::setBitTiming(uint8_t bus, uint8_t samplePoint, uint8_t sjw) {
+    if (bus == CAN_BUS_SW || bus >= NUM_CAN_BUSSES || !counters[bus].enabled) return false;
+    uint32_t baud = counters[bus].baud;
+    uint32_t best_error = 0xFFFFFFFF;
+    uint8_t best[5] = {0}; // Prescale, propagation, phase 1, phase 2, SJW
+    // Try each number of time quanta per bit, keeping the one closest to what was requested
+    for (uint32_t tq = CAN_MIN_TQ_NUM; tq <= CAN_MAX_TQ_NUM; tq++) {
+        uint32_t prescale = (SystemCoreClock + baud * tq / 2) / (baud * tq);
+        if (prescale < 1 || prescale > CAN_BAUDRATE_MAX_DIV) continue;
+        uint32_t actual = SystemCoreClock / (prescale * tq);
+        if ((actual > baud ? actual - baud : baud - actual) * 200 > baud) continue; // Baud rate more than 0.5% out
+        uint32_t sample = (tq * samplePoint + 50) / 100; // Time quanta up to the sample point
+        if (sample < 3 || tq - sample < 2 || tq - sample > 8) continue;
+        uint32_t phase1 = min(8, sample - 2);
+        uint32_t prog = sample - 1 - phase1;
+        uint32_t phase2 = tq - sample;
+        uint32_t jump = max(1, (tq * sjw + 50) / 100);
+        if (prog > 8 || jump > 4 || jump > phase1 || jump > phase2) continue;
+        // Errors in hundredths of a percent
+        uint32_t sample_error = abs((int)(sample * 10000 / tq) - (int)samplePoint * 100);
+        uint32_t sjw_error = abs((int)(jump * 10000 / tq) - (int)sjw * 100);
+        if (sample_error > BIT_TIMING_TOLERANCE * 100 || sjw_error > BIT_TIMING_TOLERANCE * 100) continue;
+        if (sample_error + sjw_error < best_error) {
+            best_error = sample_error + sjw_error;
+            best[0] = prescale;
+            best[1] = prog;
+            best[2] = phase1;
+            best[3] = phase2;
+            best[4] = jump;
+        }
     }
+    if (best_error == 0xFFFFFFFF) return false;
+    getBus(bus).set_bit_timing(best[0], best[1], best[2], best[3], best[4]);
+    bitTimings[bus].sample_point = samplePoint;
+    bitTimings[bus].sjw = sjw;
+    return true;
 }
 
-void CustomCan::__rx_queue_push_frame(rxQueue &r, CAN_FRAME &f) {
+void CustomCan::getBitTiming(uint8_t bus, uint8_t *samplePoint, uint8_t *sjw) {
+    *samplePoint = 0;
+    *sjw = 0;
+    if (bus == CAN_BUS_SW || bus >= NUM_CAN_BUSSES || !counters[bus].enabled) return;
+    uint32_t br = getBus(bus).get_bit_timing();
+    uint32_t prog = ((br & CAN_BR_PROPAG_Msk) >> CAN_BR_PROPAG_Pos) + 1;
+    uint32_t phase1 = ((br & CAN_BR_PHASE1_Msk) >> CAN_BR_PHASE1_Pos) + 1;
+    uint32_t phase2 = ((br & CAN_BR_PHASE2_Msk) >> CAN_BR_PHASE2_Pos) + 1;
+    uint32_t jump = ((br & CAN_BR_SJW_Msk) >> CAN_BR_SJW_Pos) + 1;
+    uint32_t tq = CAN_BIT_SYNC + prog + phase1 + phase2;
+    *samplePoint = ((CAN_BIT_SYNC + prog + phase1) * 100 + tq / 2) / tq;
+    *sjw = (jump * 100 + tq / 2) / tq;
+}
+
+void CustomCan::__rx_queue_push_frame(uint8_t bus, rxQueue &r, CAN_FRAME &f) {
     digitalWrite(DS7_GREEN, LOW);
+    counters[bus].frame_bits += __frame_bits(f);
     uint8_t nextEntry = (r.head + 1) % MAX_RX_QUEUE;
     // Queue is full, data is lost
-    if (nextEntry == r.tail) return;
+    if (nextEntry == r.tail) {
+        counters[bus].rx_dropped++;
+        return;
+    }
     memcpy((void *)&r.buffer[r.head], (void *)&f, sizeof(CAN_FRAME));
+    r.buffer[r.head].timestamp = micros(); // Time the frame was received, sent to the PC with the frame
     r.head = nextEntry;
+    counters[bus].rx_frames++;
     digitalWrite(DS7_GREEN, HIGH);
 }
 
@@ -88,44 +321,231 @@ bool CustomCan::__rx_queue_pop_frame(rxQueue &r, CAN_FRAME &f) {
     return true;
 }
 
-void CustomCan::enableCanFilter(int id, uint32_t pattern, uint32_t mask, bool isExtended) {
+void CustomCan::enableCanFilter(uint8_t bus, int id, uint32_t pattern, uint32_t mask, bool isExtended) {
+    if (bus >= NUM_CAN_BUSSES) return; // Invalid interface
     if (id < 0 || id >= 7) return; // Invalid mailbox ID
-    
-    Can0.setRXFilter(id, pattern, mask, isExtended);
+
+    if (bus == CAN_BUS_SW) {
+        swMailboxes[id].pattern = pattern;
+        swMailboxes[id].mask = mask;
+        swMailboxes[id].extended = isExtended;
+    } else {
+        getBus(bus).setRXFilter(id, pattern, mask, isExtended);
+    }
     // Delete any old buffer if it for some reason exists
-    __delete_check_rx_ring(id);
+    __delete_check_rx_ring(bus, id);
     // Create our new ring
-    __create_check_rx_ring(id);
+    __create_check_rx_ring(bus, id);
     // Now register the callback so that frames get pushed to our mailbox
 }
 
-void CustomCan::disableCanFilter(int id) {
+void CustomCan::disableCanFilter(uint8_t bus, int id) {
+    if (bus >= NUM_CAN_BUSSES) return; // Invalid interface
     if (id < 0 || id >= 7) return; // Invalid mailbox ID
-    Can0.setRXFilter(id, 0xFFFF, 0x0000, false);
-    __delete_check_rx_ring(id);
+    if (bus != CAN_BUS_SW) {
+        getBus(bus).setRXFilter(id, 0xFFFF, 0x0000, false);
+    }
+    __delete_check_rx_ring(bus, id);
 }
 
-bool CustomCan::receiveFrame(int mailbox_id, CAN_FRAME *f) {
+bool CustomCan::receiveFrame(uint8_t bus, int mailbox_id, CAN_FRAME *f) {
+    if (bus >= NUM_CAN_BUSSES) return false; // Invalid interface
     if (mailbox_id < 0 || mailbox_id >= 7) return false; // Invalid malbox ID
-    return __rx_queue_pop_frame(rxQueues[mailbox_id], *f);
+    if (bus == CAN_BUS_SW) {
+        __poll_sw_can();
+    }
+    return __rx_queue_pop_frame(rxQueues[bus][mailbox_id], *f);
 }
 
-bool CustomCan::sendFrame(CAN_FRAME *cf) {
+bool CustomCan::sendFrame(uint8_t bus, CAN_FRAME *cf) {
+    if (bus >= NUM_CAN_BUSSES) return false; // Invalid interface
     digitalWrite(DS7_GREEN, LOW);
-    Can0.sendFrame(*cf);
+    bool res;
+    if (bus == CAN_BUS_SW) {
+        res = SWCAN.EnqueueTX(*cf);
+    } else {
+        res = getBus(bus).sendFrame(*cf);
+    }
+    if (res) {
+        counters[bus].tx_frames++;
+        counters[bus].frame_bits += __frame_bits(*cf);
+    } else {
+        counters[bus].tx_failed++;
+    }
     digitalWrite(DS7_GREEN, HIGH);
+    return res;
+}
+
+void CustomCan::__poll_sw_can() {
+    CAN_FRAME frame;
+    while (SWCAN.GetRXFrame(frame)) {
+        for (int i = 0; i < 7; i++) {
+            swMailbox &mb = swMailboxes[i];
+            if (mb.enabled && mb.extended == (bool)frame.extended && (frame.id & mb.mask) == (mb.pattern & mb.mask)) {
+                __rx_queue_push_frame(CAN_BUS_SW, rxQueues[CAN_BUS_SW][i], frame);
+                break; // Like the hardware mailboxes, the first match gets the frame
+            }
+        }
+    }
+}
+
+bool CustomCan::setSwCanMode(uint8_t mode) {
+    if (swCanMode == SW_MODE_SLEEP) return false; // Single wire CAN is not enabled
+    if (swCanSpeedChange && mode != swCanMode) {
+        if (mode == SW_MODE_HIGH_SPEED) {
+            SWCAN.setupSW(swCanHsBaud / 1000);
+        } else if (swCanMode == SW_MODE_HIGH_SPEED) {
+            SWCAN.setupSW(swCanBaud / 1000);
+        }
+    }
+    SWCAN.mode(mode);
+    swCanMode = mode;
+    return true;
+}
+
+void CustomCan::setSwCanSpeedChange(bool enable, uint32_t hs_baud) {
+    swCanSpeedChange = enable;
+    swCanHsBaud = hs_baud;
+}
+
+bool CustomCan::getSwCanSpeedChange() {
+    return swCanSpeedChange;
+}
+
+uint32_t CustomCan::getSwCanHsBaud() {
+    return swCanHsBaud;
+}
+
+uint32_t CustomCan::getSwCanRxStatus() {
+    switch (swCanMode) {
+        case SW_MODE_HIGH_SPEED:
+            return SW_CAN_HS_RX;
+        case SW_MODE_HV_WAKEUP:
+            return SW_CAN_HV_RX;
+        default:
+            return SW_CAN_NS_RX;
+    }
 }
 
-void CustomCan::clearMailboxQueue(int mailbox_id) {
+bool CustomCan::sendSwCanHvFrame(CAN_FRAME *cf) {
+    uint8_t prev_mode = swCanMode;
+    if (!setSwCanMode(SW_MODE_HV_WAKEUP)) return false;
+    bool res = sendFrame(CAN_BUS_SW, cf);
+    delay(SW_CAN_HV_TX_TIME_MS); // Wait for the frame to leave before dropping the voltage
+    setSwCanMode(prev_mode);
+    return res;
+}
+
+void CustomCan::getBusStats(uint8_t bus, busStatsMsg *out) {
+    memset(out, 0x00, sizeof(busStatsMsg));
+    if (bus >= NUM_CAN_BUSSES) return; // Invalid interface
+    busCounters &c = counters[bus];
+    out->rx_frames = c.rx_frames;
+    out->tx_frames = c.tx_frames;
+    out->rx_dropped = c.rx_dropped;
+    out->tx_failed = c.tx_failed;
+    out->error_passive_events = c.error_passive_events;
+    out->bus_off_events = c.bus_off_events;
+    out->bus_state = c.state;
+    uint8_t tec, rec;
+    getErrorCounters(bus, &tec, &rec);
+    out->tec = tec;
+    out->rec = rec;
+    uint32_t baud = c.baud;
+    if (bus == CAN_BUS_SW && swCanMode == SW_MODE_HIGH_SPEED && swCanSpeedChange) {
+        baud = swCanHsBaud;
+    }
+    // Bits the bus could have carried since the load was last worked out
+    uint64_t capacity = (uint64_t)baud * (millis() - c.load_start) / 1000;
+    if (capacity > 0) {
+        uint64_t load = (uint64_t)c.frame_bits * 100 / capacity;
+        out->bus_load = load > 100 ? 100 : load;
+    }
+    c.frame_bits = 0;
+    c.load_start = millis();
+}
+
+void CustomCan::clearBusStats(uint8_t bus) {
+    if (bus >= NUM_CAN_BUSSES) return; // Invalid interface
+    busCounters &c = counters[bus];
+    c.rx_frames = 0;
+    c.tx_frames = 0;
+    c.rx_dropped = 0;
+    c.tx_failed = 0;
+    c.error_passive_events = 0;
+    c.bus_off_events = 0;
+    c.frame_bits = 0;
+    c.load_start = millis();
+}
+
+void CustomCan::pollBusState() {
+    // Only the SAM3X controllers, the MCP2515 state is not read
+    for (uint8_t bus = CAN_BUS_0; bus <= CAN_BUS_1; bus++) {
+        busCounters &c = counters[bus];
+        if (!c.enabled) continue;
+        uint32_t status = getBus(bus).get_status();
+        // Reading the status clears the error bits, so the interrupt handler keeps the ones it clears
+        status |= getBus(bus).take_error_flags();
+        if (status & CAN_SR_CERR) c.errors |= BUS_ERROR_CRC;
+        if (status & CAN_SR_SERR) c.errors |= BUS_ERROR_STUFF;
+        if (status & CAN_SR_AERR) c.errors |= BUS_ERROR_ACK;
+        if (status & CAN_SR_FERR) c.errors |= BUS_ERROR_FORM;
+        if (status & CAN_SR_BERR) c.errors |= BUS_ERROR_BIT;
+        uint8_t state = BUS_STATE_ERROR_ACTIVE;
+        if (status & CAN_SR_BOFF) {
+            state = BUS_STATE_BUS_OFF;
+        } else if (status & CAN_SR_ERRP) {
+            state = BUS_STATE_ERROR_PASSIVE;
+        }
+        if (state == BUS_STATE_ERROR_PASSIVE && c.state == BUS_STATE_ERROR_ACTIVE) {
+            c.error_passive_events++;
+        } else if (state == BUS_STATE_BUS_OFF && c.state != BUS_STATE_BUS_OFF) {
+            c.bus_off_events++;
+        }
+        c.state = state;
+    }
+}
+
+uint8_t CustomCan::getBusState(uint8_t bus) {
+    if (bus >= NUM_CAN_BUSSES) return BUS_STATE_ERROR_ACTIVE; // Invalid interface
+    return counters[bus].state;
+}
+
+uint8_t CustomCan::takeBusErrors(uint8_t bus) {
+    if (bus >= NUM_CAN_BUSSES) return 0; // Invalid interface
+    uint8_t errors = counters[bus].errors;
+    counters[bus].errors = 0;
+    return errors;
+}
+
+void CustomCan::getErrorCounters(uint8_t bus, uint8_t *tec, uint8_t *rec) {
+    *tec = 0;
+    *rec = 0;
+    // The MCP2515 error counters are not read
+    if (bus == CAN_BUS_SW || bus >= NUM_CAN_BUSSES || !counters[bus].enabled) return;
+    *tec = getBus(bus).get_tx_error_cnt();
+    *rec = getBus(bus).get_rx_error_cnt();
+}
+
+void CustomCan::clearMailboxQueue(uint8_t bus, int mailbox_id) {
+    if (bus >= NUM_CAN_BUSSES) return; // Invalid interface
     if (mailbox_id < 0 || mailbox_id >= 7) return; // Invalid malbox ID
-    rxQueues[mailbox_id].head = 0;
-    rxQueues[mailbox_id].tail = 0;
+    rxQueues[bus][mailbox_id].head = 0;
+    rxQueues[bus][mailbox_id].tail = 0;
 }
 
-void CustomCan::__callback_mb0(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0], *f); }
-void CustomCan::__callback_mb1(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1], *f); }
-void CustomCan::__callback_mb2(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[2], *f); }
-void CustomCan::__callback_mb3(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[3], *f); }
-void CustomCan::__callback_mb4(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[4], *f); }
-void CustomCan::__callback_mb5(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[5], *f); }
-void CustomCan::__callback_mb6(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[6], *f); }
\ No newline at end of file
+void CustomCan::__callback_mb0(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_0, rxQueues[CAN_BUS_0][0], *f); }
+void CustomCan::__callback_mb1(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_0, rxQueues[CAN_BUS_0][1], *f); }
+void CustomCan::__callback_mb2(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_0, rxQueues[CAN_BUS_0][2], *f); }
+void CustomCan::__callback_mb3(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_0, rxQueues[CAN_BUS_0][3], *f); }
+void CustomCan::__callback_mb4(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_0, rxQueues[CAN_BUS_0][4], *f); }
+void CustomCan::__callback_mb5(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_0, rxQueues[CAN_BUS_0][5], *f); }
+void CustomCan::__callback_mb6(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_0, rxQueues[CAN_BUS_0][6], *f); }
+
+void CustomCan::__callback_can1_mb0(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_1, rxQueues[CAN_BUS_1][0], *f); }
+void CustomCan::__callback_can1_mb1(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_1, rxQueues[CAN_BUS_1][1], *f); }
+void CustomCan::__callback_can1_mb2(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_1, rxQueues[CAN_BUS_1][2], *f); }
+void CustomCan::__callback_can1_mb3(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_1, rxQueues[CAN_BUS_1][3], *f); }
+void CustomCan::__callback_can1_mb4(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_1, rxQueues[CAN_BUS_1][4], *f); }
+void CustomCan::__callback_can1_mb5(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_1, rxQueues[CAN_BUS_1][5], *f); }
+void CustomCan::__callback_can1_mb6(CAN_FRAME *f) { __rx_queue_push_frame(CAN_BUS_1, rxQueues[CAN_BUS_1][6], *f); }
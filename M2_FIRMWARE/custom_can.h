@@ -3,6 +3,45 @@
 
 #include "due_can.h"
 
+// CAN interfaces on the M2
+#define CAN_BUS_0 0 // Can0 - J1962 pins 6/14
+#define CAN_BUS_1 1 // Can1 - J1962 pins 3/11
+#define CAN_BUS_SW 2 // Single wire CAN (MCP2515) - J1962 pin 1
+#define NUM_CAN_BUSSES 3
+
+// Single wire CAN transceiver modes
+#define SW_MODE_SLEEP 0
+#define SW_MODE_HIGH_SPEED 1
+#define SW_MODE_HV_WAKEUP 2
+#define SW_MODE_NORMAL 3
+
+// CAN controller states, as reported in the bus statistics
+#define BUS_STATE_ERROR_ACTIVE 0
+#define BUS_STATE_ERROR_PASSIVE 1
+#define BUS_STATE_BUS_OFF 2
+
+// CAN bus errors, as reported with MACCHINA_BUS_ERROR
+#define BUS_ERROR_CRC 0x01
+#define BUS_ERROR_STUFF 0x02
+#define BUS_ERROR_ACK 0x04
+#define BUS_ERROR_FORM 0x08
+#define BUS_ERROR_BIT 0x10
+
+// Statistics of a CAN interface, sent to the PC with MACCHINA_GET_BUS_STATS.
+// Every field is a uint32_t, in the order the driver reads them
+struct busStatsMsg {
+    uint32_t rx_frames;
+    uint32_t tx_frames;
+    uint32_t rx_dropped;
+    uint32_t tx_failed;
+    uint32_t error_passive_events;
+    uint32_t bus_off_events;
+    uint32_t tec;
+    uint32_t rec;
+    uint32_t bus_load; // Percent
+    uint32_t bus_state;
+};
+
 namespace CustomCan {
 
     // Each mailbox has a rxMailbox of 8 frames
@@ -14,39 +53,95 @@ namespace CustomCan {
     };
 
     /**
-     * Sets up the CAN0 interface on the M2, and pre-configures all the mailboxes
+     * Returns the CAN controller for a CAN interface
+     * @param bus CAN interface (CAN_BUS_0 or CAN_BUS_1)
+     */
+    CANRaw& getBus(uint8_t bus);
+
+    /**
+     * Switches the single wire CAN transceiver mode. If speed change is enabled,
+     * switching to or from high speed mode also changes the bus speed
+     * @param mode SW_MODE_NORMAL, SW_MODE_HIGH_SPEED or SW_MODE_HV_WAKEUP
+     *
+     * @returns Boolean indicating if the mode was changed
+     */
+    bool setSwCanMode(uint8_t mode);
+
+    /**
+     * Sets if switching single wire CAN to high speed mode should also change the bus speed
+     * @param enable Enable or disable speed changing
+     * @param hs_baud Bus speed to use in high speed mode
+     */
+    void setSwCanSpeedChange(bool enable, uint32_t hs_baud);
+
+    /**
+     * Returns if speed changing is enabled for single wire CAN high speed mode
+     */
+    bool getSwCanSpeedChange();
+
+    /**
+     * Returns the bus speed used by single wire CAN in high speed mode
+     */
+    uint32_t getSwCanHsBaud();
+
+    /**
+     * Returns the J2534 Rx status flags for frames received on single wire CAN
+     * in its current transceiver mode
+     */
+    uint32_t getSwCanRxStatus();
+
+    /**
+     * Transmits a frame on single wire CAN as a high voltage wakeup frame, then
+     * returns the transceiver to its previous mode
+     */
+    bool sendSwCanHvFrame(CAN_FRAME *cf);
+
+    /**
+     * Reads all pending frames from the single wire CAN controller, and pushes them to
+     * the Rx ring buffer of the first software mailbox whose filter matches
+     */
+    void __poll_sw_can();
+
+    /**
+     * Sets up a CAN interface on the M2, and pre-configures all the mailboxes
      * to block all traffic
      * 
-     * @param baud Bus speed to initialize the CAN0 controller with
+     * @param bus CAN interface to setup
+     * @param baud Bus speed to initialize the CAN controller with
+     * @param listenOnly Only listen to the bus, never sending frames or ACKing them.
+     * Not supported on single wire CAN
      * 
      * @returns Boolean indicating if CAN was setup successfully
      */
-    bool enableCanBus(int baud);
+    bool enableCanBus(uint8_t bus, int baud, bool listenOnly = false);
 
     /**
      * Deletes one of the mailboxes Rx ring buffer
+     * @param bus CAN interface the mailbox belongs to
      * @param i Mailbox ID to delete its ring buffer
      */
-    void __delete_check_rx_ring(int i);
+    void __delete_check_rx_ring(uint8_t bus, int i);
 
     /**
      * Creates a new Rx ring buffer for a CAN mailbox
      * If the ring buffer is already setup for the target mailbox,
      * it is simply cleared of any data
      * 
+     * @param bus CAN interface the mailbox belongs to
      * @param i Mailbox ID to set up a new Rx ring buffer
      */
-    void __create_check_rx_ring(int i);
+    void __create_check_rx_ring(uint8_t bus, int i);
 
     /**
      * Called on mailbox interrupt. This function will attempt to push
      * a new CAN Frame onto the mailboxes' Rx ring buffer. If the ring
      * buffer is full, then the incoming data is simply discarded
      * 
+     * @param bus CAN interface the frame was received on
      * @param r Rx Queue to push the frame to (This is also the mailbox ID who triggered the interrupt)
      * @param f CAN Frame object to push to the Rx ring buffer
      */
-    void __rx_queue_push_frame(rxQueue &r, CAN_FRAME &f);
+    void __rx_queue_push_frame(uint8_t bus, rxQueue &r, CAN_FRAME &f);
 
     /**
      * Called by receiveFrame to pop a frame from a mailboxes ring buffer.
@@ -76,45 +171,134 @@ namespace CustomCan {
     // Callback function for mailbox 6
     void __callback_mb6(CAN_FRAME *f);
 
+    // Callback functions for the Can1 mailboxes
+    void __callback_can1_mb0(CAN_FRAME *f);
+    void __callback_can1_mb1(CAN_FRAME *f);
+    void __callback_can1_mb2(CAN_FRAME *f);
+    void __callback_can1_mb3(CAN_FRAME *f);
+    void __callback_can1_mb4(CAN_FRAME *f);
+    void __callback_can1_mb5(CAN_FRAME *f);
+    void __callback_can1_mb6(CAN_FRAME *f);
+
+    /**
+     * Disables a CAN interface
+     * @param bus CAN interface to disable
+     */
+    void disableCanBus(uint8_t bus);
+
+    /**
+     * Finds the baud rate of a CAN bus by listening for frames at each common baud rate
+     * (500k, 250k, 125k, 1M, 83.3k, 33.3k). The CAN controller only listens whilst this
+     * runs, so it never disturbs the bus, and is left disabled
+     * 
+     * @param bus CAN interface to listen on. Single wire CAN is not supported
+     * 
+     * @returns Baud rate frames were received at, or 0 if no traffic was found
+     */
+    uint32_t detectBaud(uint8_t bus);
+
+    /**
+     * Sets the bit timing of an enabled CAN interface (BIT_SAMPLE_POINT / SYNCH_JUMP_WIDTH).
+     * The nearest timing the CAN controller can do is used, and kept until the interface is disabled
+     * 
+     * @param bus CAN interface. Single wire CAN is not supported
+     * @param samplePoint Sample point, as a percentage of the bit time
+     * @param sjw Synchronization jump width, as a percentage of the bit time
+     * 
+     * @returns false if the CAN controller cannot get within 2% of either value at the interface's baud rate
+     */
+    bool setBitTiming(uint8_t bus, uint8_t samplePoint, uint8_t sjw);
+
     /**
-     * Disables the Can0 interface
+     * Reads the bit timing a CAN interface is running with
+     * 
+     * @param bus CAN interface. Single wire CAN reports 0
+     * @param samplePoint Sample point, as a percentage of the bit time
+     * @param sjw Synchronization jump width, as a percentage of the bit time
      */
-    void disableCanBus();
+    void getBitTiming(uint8_t bus, uint8_t *samplePoint, uint8_t *sjw);
 
     /**
      * Disables a CAN mailbox filter
+     * @param bus CAN interface the mailbox belongs to
      * @param id Mailbox ID to disable 
      */
-    void disableCanFilter(int id);
+    void disableCanFilter(uint8_t bus, int id);
 
     /**
      * Enables a CAN mailbox with a specified filter
+     * @param bus CAN interface the mailbox belongs to
      * @param id Mailbox ID (0-6)
      * @param pattern Pattern for CAN ID
      * @param mask Mask for CAN ID
      * @param isExtended Boolean indicating if the mailbox should be configured for Extended CAN or not
      */
-    void enableCanFilter(int id, uint32_t pattern, uint32_t mask, bool isExtended);
+    void enableCanFilter(uint8_t bus, int id, uint32_t pattern, uint32_t mask, bool isExtended);
 
     /**
-     * Transmits a CAN Frame to the vehicles CAN Network using the CAN0 interface on the M2
+     * Transmits a CAN Frame to the vehicles CAN Network using one of the M2's CAN interfaces
+     * @param bus CAN interface to transmit on
      */
-    bool sendFrame(CAN_FRAME *cf);
+    bool sendFrame(uint8_t bus, CAN_FRAME *cf);
 
     /**
-     * Attempts to read a frame from one of the pre-configured mailboxes queues on the CAN0 interface
+     * Attempts to read a frame from one of the pre-configured mailboxes queues on a CAN interface
+     * @param bus CAN interface to read from
      * @param mailbox_id mailbox ID (0-6) to grab a frame from
      * @param f Pointer to CAN Frame to read into if data is in the mailbox queue
      * 
      * @returns Boolean indicating if read was successful or not
      */
-    bool receiveFrame(int mailbox_id, CAN_FRAME *f);
+    bool receiveFrame(uint8_t bus, int mailbox_id, CAN_FRAME *f);
+
+    /**
+     * Reads the statistics of a CAN interface. The bus load is worked out over the time
+     * since the statistics were last read, and starts again from now
+     * @param bus CAN interface to read
+     * @param out Statistics of the interface
+     */
+    void getBusStats(uint8_t bus, busStatsMsg *out);
+
+    /**
+     * Resets the frame and event counts of a CAN interface
+     * @param bus CAN interface to reset
+     */
+    void clearBusStats(uint8_t bus);
+
+    /**
+     * Checks the state of every enabled CAN interface, counting each time
+     * an interface goes error passive or bus off. Called from the main loop
+     */
+    void pollBusState();
+
+    /**
+     * Returns the state of a CAN interface (BUS_STATE_*), as of the last pollBusState
+     * @param bus CAN interface
+     */
+    uint8_t getBusState(uint8_t bus);
+
+    /**
+     * Takes the errors seen on a CAN interface since this was last called
+     * @param bus CAN interface
+     *
+     * @returns BUS_ERROR_* flags, or 0 if there were no errors
+     */
+    uint8_t takeBusErrors(uint8_t bus);
+
+    /**
+     * Reads the error counters of a CAN interface. Single wire CAN always reports 0
+     * @param bus CAN interface
+     * @param tec Transmit error counter
+     * @param rec Receive error counter
+     */
+    void getErrorCounters(uint8_t bus, uint8_t *tec, uint8_t *rec);
 
     /**
      * Clears a mailboxes Rx ring buffer queue
+     * @param bus CAN interface the mailbox belongs to
      * @param mailbox_id the mailbox ID to clear
      */
-    void clearMailboxQueue(int mailbox_id);
+    void clearMailboxQueue(uint8_t bus, int mailbox_id);
 }
 
 #endif
\ No newline at end of file
@@ -130,6 +130,7 @@ uint32_t CANRaw::set_baudrate(uint32_t ul_baudrate)
 
 	numBusErrors = 0;
     numRxFrames = 0;
+    errorFlags = 0;
 	busSpeed = ul_baudrate;	
 
 	return 1;
@@ -251,6 +252,7 @@ uint32_t CANRaw::init(uint32_t ul_baudrate)
 	
 	numBusErrors = 0;
     numRxFrames = 0;
+    errorFlags = 0;
 
 	//initialize all function pointers to null
 	for (int i = 0; i < getNumMailBoxes()+1; i++) cbCANFrame[i] = 0;
@@ -716,6 +718,55 @@ uint8_t CANRaw::get_rx_error_cnt()
 	return (uint8_t)(m_pCan->CAN_ECR >> CAN_ECR_REC_Pos);
 }
 
+/**
+ * \brief Take the CAN_SR error bits seen since this was last called.
+ *
+ *
+ * \retval CAN_SR error bits.
+ */
+uint32_t CANRaw::take_error_flags()
+{
+	irqLock();
+	uint32_t flags = errorFlags;
+	errorFlags = 0;
+	irqRelease();
+	return flags;
+}
+
+/**
+ * \brief Write the CAN bit timing, replacing the timing set_baudrate picked.
+ *
+ * \param uc_prescale Baudrate prescale (1 - 128).
+ * \param uc_prog Propagation segment in time quanta (1 - 8).
+ * \param uc_phase1 Phase segment 1 in time quanta (1 - 8).
+ * \param uc_phase2 Phase segment 2 in time quanta (2 - 8).
+ * \param uc_sjw Synchronization jump width in time quanta (1 - 4).
+ */
+void CANRaw::set_bit_timing(uint8_t uc_prescale, uint8_t uc_prog, uint8_t uc_phase1, uint8_t uc_phase2, uint8_t uc_sjw)
+{
+	uint32_t oldCANMR = m_pCan->CAN_MR;
+	m_pCan->CAN_MR &= ~CAN_MR_CANEN;
+
+	m_pCan->CAN_BR = CAN_BR_PHASE2(uc_phase2 - 1) |
+					CAN_BR_PHASE1(uc_phase1 - 1) |
+					CAN_BR_PROPAG(uc_prog - 1) |
+					CAN_BR_SJW(uc_sjw - 1) |
+					CAN_BR_BRP(uc_prescale - 1);
+
+	m_pCan->CAN_MR = oldCANMR; //restore mode register which might re-enable CAN
+}
+
+/**
+ * \brief Get the CAN baudrate register.
+ *
+ *
+ * \retval CAN_BR register value.
+ */
+uint32_t CANRaw::get_bit_timing()
+{
+	return m_pCan->CAN_BR;
+}
+
 /**
  * \brief Reset the internal free-running 16-bit timer.
  *
@@ -1309,6 +1360,7 @@ void CANRaw::interruptHandler()
 	if (ul_status & CAN_SR_AERR)    numBusErrors++ ;  // ack error
 	if (ul_status & CAN_SR_FERR)    numBusErrors++ ;  // form error
 	if (ul_status & CAN_SR_BERR)    numBusErrors++ ;  // bit error
+	errorFlags |= ul_status & (CAN_SR_CERR | CAN_SR_SERR | CAN_SR_AERR | CAN_SR_FERR | CAN_SR_BERR);
 }
 
 /**
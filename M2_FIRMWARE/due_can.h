@@ -191,6 +191,9 @@ public:
 	uint16_t get_timestamp_value();
 	uint8_t get_tx_error_cnt();
 	uint8_t get_rx_error_cnt();
+	uint32_t take_error_flags();
+	void set_bit_timing(uint8_t uc_prescale, uint8_t uc_prog, uint8_t uc_phase1, uint8_t uc_phase2, uint8_t uc_sjw);
+	uint32_t get_bit_timing();
 	void reset_internal_timer();
 	void global_send_transfer_cmd(uint8_t uc_mask);
 	void global_send_abort_cmd(uint8_t uc_mask);
@@ -260,6 +263,7 @@ private:
     
     uint32_t numBusErrors;
     uint32_t numRxFrames;
+    volatile uint32_t errorFlags; // CAN_SR error bits cleared by the interrupt handler, until they are taken
 };
 
 extern CANRaw Can0;
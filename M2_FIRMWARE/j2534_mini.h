@@ -15,6 +15,14 @@
 #define	SCI_B_ENGINE 0x09 // SCI_B_ENGINE TODO - Not supported ATM
 #define	SCI_B_TRANS	 0x0A // SCI_B_TRANS  TODO - Not supported ATM
 
+// J2534-2 Pin switched protocols
+#define CAN_PS       0x8004 // CAN protocol on the pins set by J1962_PINS
+#define ISO15765_PS  0x8005 // ISO15765 protocol on the pins set by J1962_PINS
+#define SW_ISO15765_PS 0x8007 // ISO15765 protocol on single wire CAN (Pin 1)
+#define SW_CAN_PS    0x8008 // CAN protocol on single wire CAN (Pin 1)
+#define J1939_PS     0x800C // J1939 protocol on the pins set by J1962_PINS
+#define TP2_0_PS     0x800E // VW TP 2.0 protocol on the pins set by J1962_PINS
+
 // Error definitions
 #define		STATUS_NOERROR			  0x00	// Function completed successfully.
 #define		ERR_NOT_SUPPORTED		  0x01	// Function option is not supported.
@@ -43,12 +51,15 @@
 #define		ERR_NOT_UNIQUE			  0x18	// An existing filter already matches this header or node identifier.
 #define		ERR_INVALID_BAUDRATE	  0x19	// Unable to honor requested Baud rate within required tolerances.
 #define		ERR_INVALID_DEVICE_ID	  0x1A	// PassThru device identifier is not recognized.
+// J2534-2 errors (ERR_ADDRESS_NOT_CLAIMED, ERR_NO_CONNECTION_ESTABLISHED) do not fit in a status byte,
+// so they are checked by the driver instead
 
 // Some useful flags (Channel creation)
 #define		CAN_29BIT_ID		0x00000100
 #define		ISO9141_NO_CHECKSUM	0x00000200
 #define		CAN_ID_BOTH		    0x00000800
 #define		ISO9141_K_LINE_ONLY	0x00001000
+#define		MACCHINA_LISTEN_ONLY	0x01000000	// Macchina vendor flag. CAN controller only listens, never sending or ACKing frames
 
 // Filter type
 #define PASS_FILTER         0x01
@@ -78,6 +89,34 @@
 #define	TX_MSG_TYPE		        0x00000001	// Receive Indication/Transmit Confirmation: 0 = Rx Frame indication, 1 = Tx Frame confirmation
 
 
+// J2534-2 Ioctl IDs
+#define		SW_CAN_HS			0x8000	// Switch single wire CAN to high speed mode
+#define		SW_CAN_NS			0x8001	// Switch single wire CAN to normal speed mode
+#define		PROTECT_J1939_ADDR	0x8009	// Claim a J1939 source address. Input: [Address, NAME (8 bytes)]
+#define		REQUEST_CONNECTION	0x800A	// Open a TP 2.0 connection. Input: [Module address, (Optional) application type]
+#define		TEARDOWN_CONNECTION	0x800B	// Close a TP 2.0 connection. Input: [Module address]
+
+// Macchina vendor IOCTLs
+#define		MACCHINA_GET_BUS_STATS		0x10002	// Read the CAN bus statistics. Output: busStatsMsg
+#define		MACCHINA_CLEAR_BUS_STATS	0x10003	// Reset the CAN bus statistics
+#define		MACCHINA_DETECT_BAUD_RATE	0x10004	// Find the CAN baud rate from the bus traffic, and switch to it. Output: Baud rate (4 bytes)
+
+// J2534-2 Tx flags
+#define		SW_CAN_HV_TX		0x00000400	// Transmit message as a high voltage wakeup message on single wire CAN
+
+// J2534-2 Rx status flags
+#define		SW_CAN_HV_RX		0x00010000	// Message was received as a high voltage message on single wire CAN
+#define		SW_CAN_HS_RX		0x00020000	// Message was received whilst single wire CAN was in high speed mode
+#define		SW_CAN_NS_RX		0x00040000	// Message was received whilst single wire CAN was in normal speed mode
+#define		J1939_ADDRESS_CLAIMED 0x00010000 // Indication that the J1939 address in the message was successfully claimed
+#define		J1939_ADDRESS_LOST	0x00020000	// Indication that the J1939 address in the message was lost to another node
+#define		CONNECTION_ESTABLISHED 0x00010000 // Indication that a TP 2.0 connection to the module in the message was established
+#define		CONNECTION_LOST		0x00020000	// Indication that the TP 2.0 connection to the module in the message was lost
+
+// Macchina vendor Rx status flags. Data is [CAN ID (0x00000000), Errors / Bus state, TEC, REC]
+#define		MACCHINA_BUS_ERROR	0x01000000	// CAN bus errors were seen (BUS_ERROR_* flags)
+#define		MACCHINA_BUS_STATE	0x02000000	// CAN controller changed state (BUS_STATE_*)
+
 // Ioctl parameters for GET_CONFIG and SET_CONFIG
 #define		DATA_RATE		     0x01	// 5 – 500000 	// Baud rate value used for vehicle network. No default value specified.
 #define		LOOPBACK		     0x03	// 0(OFF)/1(ON)	// 0 = Do not echo transmitted messages to the Receive queue. 1 = Echo transmitted messages to the Receive queue.
@@ -120,4 +159,30 @@
 #define		T3_MAX			    0x24
 #define		ISO15765_WFT_MAX	0x25
 
+// J2534-2 Ioctl parameters
+#define		J1962_PINS			0x8001	// 0x0000PPSS	// PP = Primary pin, SS = Secondary pin (0x00 if unused)
+#define		SW_CAN_HS_DATA_RATE	0x8010	// Baud rate to use when single wire CAN is switched to high speed mode. Default is 83333.
+#define		SW_CAN_SPEEDCHANGE_ENABLE 0x8011 // 0(OFF)/1(ON)	// Change baud rate when switching between normal and high speed mode. Default is 0(OFF).
+#define		SW_CAN_RES_SWITCH	0x8012	// 0(DISCONNECT)/1(CONNECT)/2(AUTO)	// Single wire CAN load resistor switching
+#define		J1939_T1			0x803D	// 0x0-0xFFFF	// Max time [ms] between transport protocol data packets. Default value is 750 ms.
+#define		J1939_T2			0x803E	// 0x0-0xFFFF	// Max time [ms] between CTS and the first data packet. Default value is 1250 ms.
+#define		J1939_T3			0x803F	// 0x0-0xFFFF	// Max time [ms] to wait for a CTS or end of message ACK. Default value is 1250 ms.
+#define		J1939_T4			0x8040	// 0x0-0xFFFF	// Max time [ms] a hold (CTS with 0 packets) can last. Default value is 1050 ms.
+#define		J1939_BRDCST_MIN_DELAY 0x8041 // 0x0-0xFFFF	// Time [ms] between BAM data packets. Default value is 50 ms.
+#define		TP2_0_T_BR_INT		0x8042	// 0x0-0xFFFF	// Time [ms] between broadcast repetitions. Default value is 20 ms.
+#define		TP2_0_T_E			0x8043	// 0x0-0xFFFF	// Time [ms] to wait for a channel setup or parameter response. Default value is 100 ms.
+#define		TP2_0_MNTC			0x8044	// 0x0-0xFFFF	// Max number of channel setup attempts. Default value is 10.
+#define		TP2_0_T_CTA			0x8045	// 0x0-0xFFFF	// Time [ms] between connection tests (Keep alive). Default value is 1000 ms.
+#define		TP2_0_MNCT			0x8046	// 0x0-0xFFFF	// Max number of failed connection tests before the connection is lost. Default value is 5.
+#define		TP2_0_MNTB			0x8047	// 0x0-0xFFFF	// Max number of times a data block is sent without an ACK. Default value is 5.
+#define		TP2_0_MNT			0x8048	// 0x0-0xFFFF	// Max number of 'not ready' ACKs for a data block. Default value is 5.
+#define		TP2_0_T_WAIT		0x8049	// 0x0-0xFFFF	// Time [ms] to wait before resending a block after a 'not ready' ACK. Default value is 100 ms.
+#define		TP2_0_T1			0x804A	// 0x0-0xFFFF	// Time [ms] to wait for an ACK from the module. Default value is 100 ms.
+#define		TP2_0_T3			0x804B	// 0x0-0xFFFF	// Min time [ms] between data packets sent to the module. Default value is 5 ms.
+#define		TP2_0_IDENTIFER		0x804C	// 0x0-0x7FF	// CAN ID used for channel setup requests. Default value is 0x200.
+#define		TP2_0_RXIDPASSIVE	0x804D	// 0x0-0x7FF	// CAN ID the M2 asks the module to send to. Default value is 0x300.
+
+// Macchina vendor config parameters
+#define		MACCHINA_ERROR_FRAMES	0x10002	// 0(OFF)/1(ON)	// Send CAN bus errors and state changes to the PC as Rx messages. Default value is 0(OFF).
+
 #endif
\ No newline at end of file